@@ -50,33 +50,18 @@ def fetch_messages(broker_name, offset, stats_queue, total_bytes_received):
                     )
                     s.sendall(struct.pack(">I", len(message)) + message)
                     
-                    received_len = 0
-                    new_offset_bytes = None
-                    while True:
-                        # Read response length
-                        response_length_bytes = recv_all(s, 4)
-                        response_length = struct.unpack(">I", response_length_bytes)[0]
-                        
-                        if response_length == 0:
-                            if new_offset_bytes != None:
-                               offset = offset + 1
-                            
-                            break  # No more messages in this batch
-                        
-                        
-                            
-                        # Read new offset
-                        new_offset_bytes = recv_all(s, 8)
-                        offset = struct.unpack(">Q", new_offset_bytes)[0]
-                        
-                        if received_len > 1024*1024*100:
-                            print(f"Error: message {offset} length error!! length:{received_len}")
-                            break
-                        # Read message data
-                        total_data  = recv_all(s, response_length)
-                        received_len += len(total_data)
+                    # Response header: total bytes of records and the next offset to fetch
+                    response_length = struct.unpack(">I", recv_all(s, 4))[0]
+                    offset = struct.unpack(">Q", recv_all(s, 8))[0]
+                    total_data = recv_all(s, response_length)
+                    received_len = len(total_data)
+
+                    # Records are [len: u32][offset: u64][data], back to back
+                    position = 0
+                    while position < received_len:
+                        record_length = struct.unpack(">I", total_data[position:position + 4])[0]
+                        position += 12 + record_length
                         message_count += 1
-                        
                     
                     data_volume += received_len
                     
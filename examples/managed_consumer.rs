@@ -0,0 +1,44 @@
+//! Consumes a broker until Ctrl-C, then commits the last handled offset and exits.
+//!
+//! ```
+//! cargo run --example managed_consumer -- 127.0.0.1 8080 <key> <broker> [checkpoint]
+//! ```
+//!
+//! Running it again resumes after the last handled record.
+
+use std::env;
+use std::error::Error;
+
+use sonicrab_client::{Client, ManagedConsumer};
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 5 {
+        eprintln!("usage: {} <ip> <port> <key> <broker> [checkpoint]", args[0]);
+        std::process::exit(2);
+    }
+    let (ip, port, key, broker) = (&args[1], args[2].parse()?, &args[3], &args[4]);
+    let checkpoint = args.get(5).cloned().unwrap_or_else(|| format!("{}.offset", broker));
+
+    let client = Client::new(ip, port, key);
+    let mut consumer = ManagedConsumer::new(client, broker, checkpoint);
+
+    // Ctrl-C 只请求关闭，消费者处理完当前消息并提交偏移后退出
+    let shutdown = consumer.shutdown_handle();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(tokio::signal::ctrl_c()).unwrap();
+        println!("Shutting down");
+        shutdown.shutdown();
+    });
+
+    let committed = consumer.run(|offset, record| {
+        println!("{}: {}", offset, String::from_utf8_lossy(record));
+        Ok(())
+    })?;
+    println!("Committed offset {}", committed);
+    Ok(())
+}
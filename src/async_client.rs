@@ -0,0 +1,278 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::stream::DEFAULT_POLL_INTERVAL;
+use crate::transport::unbracket;
+use crate::{
+    build_message, parse_push_response, parse_records, parse_reply, PullResult, PushAck, Reply, ServerError, TimeoutError,
+    PULL_COMMAND, PULL_REFUSED, PUSH_COMMAND,
+};
+
+pub struct AsyncClient {
+    server_ip: String,
+    server_port: u16,
+    key: Vec<u8>,
+    connection: Mutex<Option<TcpStream>>,
+    last_pressure: AtomicU8,
+}
+
+/// Async counterpart of [`crate::MessageStream`], created by [`AsyncClient::stream`]
+pub struct AsyncMessageStream<'a> {
+    client: &'a AsyncClient,
+    broker: String,
+    next: u64,
+    poll_interval: Duration,
+    buffered: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl AsyncMessageStream<'_> {
+    /// How long to wait before polling again once the stream has caught up; 500 ms by default
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Waits for the next record. A failed request returns the error; calling again
+    /// reconnects and retries from the same offset.
+    pub async fn next_message(&mut self) -> Result<(u64, Vec<u8>), Box<dyn Error + Send + Sync>> {
+        loop {
+            if let Some(record) = self.buffered.pop_front() {
+                return Ok(record);
+            }
+            let (batch, next_offset) = self.client.fetch_with_next(&self.broker, self.next, None).await?;
+            self.buffered.extend(batch);
+            match self.buffered.back() {
+                Some((last, _)) => self.next = last + 1,
+                // 没有记录但服务端给出了更大的偏移：中间的记录已过期或被清理
+                None if next_offset > self.next => self.next = next_offset,
+                None => tokio::time::sleep(self.poll_interval).await,
+            }
+        }
+    }
+}
+
+impl AsyncClient {
+    /// Creates a new async client instance
+    pub fn new(server_ip: &str, server_port: u16, key: &str) -> Self {
+        Self {
+            server_ip: server_ip.to_string(),
+            server_port,
+            key: key.as_bytes().to_vec(),
+            connection: Mutex::new(None),
+            last_pressure: AtomicU8::new(0),
+        }
+    }
+
+    /// Sends a message to the queue, returning its offset and the server's append timestamp
+    pub async fn send_push_message(&self, broker_name: &str, payload: &[u8]) -> Result<PushAck, Box<dyn Error + Send + Sync>> {
+        let message = build_message(&self.key, PUSH_COMMAND, broker_name.as_bytes(), payload, None);
+        let response = self.request(&message).await?;
+        self.accept_push_response(&response)
+    }
+
+    /// Sends a message, giving up with a [`TimeoutError`] once `deadline` has elapsed. A
+    /// timed-out push may have written part of a frame, so the connection is dropped and the
+    /// next call reconnects.
+    pub async fn send_push_message_deadline(
+        &self,
+        broker_name: &str,
+        payload: &[u8],
+        deadline: Duration,
+    ) -> Result<PushAck, Box<dyn Error + Send + Sync>> {
+        let message = build_message(&self.key, PUSH_COMMAND, broker_name.as_bytes(), payload, None);
+        let mut connection = self.connection.lock().await;
+        // 超时丢弃的请求由 Exchange 丢弃连接
+        match tokio::time::timeout(deadline, self.request_locked(&mut connection, &message)).await {
+            Ok(result) => self.accept_push_response(&result?),
+            Err(_) => Err(Box::new(TimeoutError(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "push deadline exceeded",
+            )))),
+        }
+    }
+
+    /// Fetches the record at `offset`, or `None` when there is no record there yet
+    pub async fn fetch_messages(&self, broker_name: &str, offset: u64) -> Result<Option<(u64, Vec<u8>)>, Box<dyn Error + Send + Sync>> {
+        Ok(self.fetch_batch(broker_name, offset, None).await?.into_iter().next())
+    }
+
+    /// Fetches the records one PULL from `offset` returns: the record at `offset` and as many
+    /// following ones as fit in the server's `pull_max_limit`, but at most `max_count` records
+    /// when it is set
+    pub async fn fetch_batch(&self, broker_name: &str, offset: u64, max_count: Option<u32>) -> Result<Vec<(u64, Vec<u8>)>, Box<dyn Error + Send + Sync>> {
+        Ok(self.fetch_with_next(broker_name, offset, max_count).await?.0)
+    }
+
+    // 与 fetch_batch 相同，同时返回服务端给出的下一个偏移
+    async fn fetch_with_next(&self, broker_name: &str, offset: u64, max_count: Option<u32>) -> Result<(Vec<(u64, Vec<u8>)>, u64), Box<dyn Error + Send + Sync>> {
+        let count_bytes = max_count.map(u32::to_be_bytes);
+        let body = count_bytes.as_ref().map_or(&[][..], |bytes| &bytes[..]);
+        let message = build_message(&self.key, PULL_COMMAND, broker_name.as_bytes(), body, Some(offset));
+        let mut connection = self.connection.lock().await;
+        let mut exchange = Exchange::new(&mut connection);
+        let result = pull_batch(self.connect(exchange.connection).await?, &message).await?;
+        exchange.completed = true;
+        Ok(result?)
+    }
+
+    /// Consumes `broker_name` continuously from `start_offset` ([`EARLIEST`](crate::EARLIEST) starts
+    /// at the oldest stored record, [`LATEST`](crate::LATEST) at the most recent one);
+    /// see [`AsyncMessageStream::next_message`]
+    pub fn stream(&self, broker_name: &str, start_offset: u64) -> AsyncMessageStream<'_> {
+        AsyncMessageStream {
+            client: self,
+            broker: broker_name.to_string(),
+            next: start_offset,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    /// Pressure level (0-255) reported with the last successful push; 0 when the server
+    /// does not report pressure
+    pub fn last_push_pressure(&self) -> u8 {
+        self.last_pressure.load(Ordering::Relaxed)
+    }
+
+    fn accept_push_response(&self, response: &[u8]) -> Result<PushAck, Box<dyn Error + Send + Sync>> {
+        let (ack, pressure) = parse_push_response(response)?;
+        self.last_pressure.store(pressure, Ordering::Relaxed);
+        Ok(ack)
+    }
+
+    async fn request(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut connection = self.connection.lock().await;
+        self.request_locked(&mut connection, message).await
+    }
+
+    /// Sends a request frame and reads back a single reply; an error status becomes a
+    /// [`ServerError`]. The connection is discarded on any I/O error, or when the future is
+    /// dropped before the reply was read, so the next call reconnects.
+    async fn request_locked(
+        &self,
+        connection: &mut Option<TcpStream>,
+        message: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut in_flight = Exchange::new(connection);
+        let reply = exchange(self.connect(in_flight.connection).await?, message).await?;
+        in_flight.completed = true;
+        Ok(reply?)
+    }
+
+    // 没有连接时建立新连接
+    async fn connect<'a>(&self, connection: &'a mut Option<TcpStream>) -> io::Result<&'a mut TcpStream> {
+        if connection.is_none() {
+            // 与同步客户端相同，依次尝试解析出的每个地址
+            let stream = TcpStream::connect((unbracket(&self.server_ip), self.server_port)).await?;
+            *connection = Some(stream);
+        }
+        Ok(connection.as_mut().unwrap())
+    }
+}
+
+// 一个请求占用的连接：请求没有完整读完回复就结束（I/O 错误，或调用方丢弃了 future）时丢弃连接，
+// 否则连接上会留下半个回复，被下一个请求当作自己的回复读取
+struct Exchange<'a> {
+    connection: &'a mut Option<TcpStream>,
+    completed: bool,
+}
+
+impl<'a> Exchange<'a> {
+    fn new(connection: &'a mut Option<TcpStream>) -> Self {
+        Exchange { connection, completed: false }
+    }
+}
+
+impl Drop for Exchange<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            *self.connection = None;
+        }
+    }
+}
+
+async fn exchange(stream: &mut TcpStream, message: &[u8]) -> io::Result<Reply> {
+    stream.write_all(&(message.len() as u32).to_be_bytes()).await?;
+    stream.write_all(message).await?;
+    read_reply(stream).await
+}
+
+// 读取一个回复帧 [len: u32][状态: u8][内容]
+async fn read_reply(stream: &mut TcpStream) -> io::Result<Reply> {
+    let mut response = vec![0u8; stream.read_u32().await? as usize];
+    stream.read_exact(&mut response).await?;
+    parse_reply(response)
+}
+
+// 发送 PULL 并读取响应：头部 [字节数: u32][下一个偏移: u64]，之后是该字节数的记录，每条为 [长度: u32][偏移: u64][记录]
+async fn pull_batch(stream: &mut TcpStream, message: &[u8]) -> io::Result<PullResult> {
+    stream.write_all(&(message.len() as u32).to_be_bytes()).await?;
+    stream.write_all(message).await?;
+
+    let size = stream.read_u32().await?;
+    if size == PULL_REFUSED {
+        return Ok(Err(read_reply(stream).await?.map_or_else(|e| e, |reply| ServerError::unexpected(&reply))));
+    }
+    let next_offset = stream.read_u64().await?;
+    let mut body = vec![0u8; size as usize];
+    stream.read_exact(&mut body).await?;
+    Ok(Ok((parse_records(&body)?, next_offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_push_deadline_drops_connection() {
+        // 只接收请求、从不回复的服务端
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        let client = AsyncClient::new("127.0.0.1", port, "test_key");
+        let deadline = Duration::from_millis(100);
+        let err = client
+            .send_push_message_deadline("test_broker", b"hello", deadline)
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<TimeoutError>().is_some());
+        assert!(client.connection.lock().await.is_none());
+
+        assert!(client
+            .send_push_message_deadline("test_broker", b"hello", deadline)
+            .await
+            .is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // 调用方自己丢弃请求时也要丢弃连接
+        let cancelled = tokio::time::timeout(deadline, client.send_push_message("test_broker", b"hello")).await;
+        assert!(cancelled.is_err());
+        assert!(client.connection.lock().await.is_none());
+    }
+}
@@ -0,0 +1,52 @@
+//! Bounded LRU cache of fetched records, keyed by `(broker, offset)`.
+//!
+//! Records are immutable once written, so entries only ever leave the cache by eviction.
+
+use std::collections::{HashMap, VecDeque};
+
+pub(crate) struct RecordCache {
+    capacity: usize,
+    records: HashMap<(String, u64), Vec<u8>>,
+    order: VecDeque<(String, u64)>, // least recently used at the front
+}
+
+impl RecordCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            records: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, broker_name: &str, offset: u64) -> Option<Vec<u8>> {
+        let key = (broker_name.to_string(), offset);
+        let record = self.records.get(&key)?.clone();
+        self.touch(&key);
+        Some(record)
+    }
+
+    pub(crate) fn insert(&mut self, broker_name: &str, offset: u64, record: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (broker_name.to_string(), offset);
+        if self.records.insert(key.clone(), record).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.order.push_back(key);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.records.remove(&oldest);
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &(String, u64)) {
+        if let Some(position) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(position).unwrap();
+            self.order.push_back(key);
+        }
+    }
+}
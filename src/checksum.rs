@@ -0,0 +1,22 @@
+//! Per-record CRC32 checksums stored by brokers with `checksums = true`.
+//!
+//! Such a broker stores each record as `[crc32: u32][record]`, where the CRC covers the rest of
+//! the record as the server stored it (timestamp and headers included). PULL returns the
+//! checksum with the record; [`crate::ClientBuilder::verify_checksums`] checks and removes it.
+
+/// Prefixes `record` with its CRC32
+pub fn add_checksum(record: &[u8]) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(record.len() + 4);
+    stored.extend_from_slice(&crc32fast::hash(record).to_be_bytes());
+    stored.extend_from_slice(record);
+    stored
+}
+
+/// Returns the record without its checksum, or `None` when the checksum does not match
+pub fn verify_checksum(stored: &[u8]) -> Option<&[u8]> {
+    if stored.len() < 4 {
+        return None;
+    }
+    let (checksum, record) = stored.split_at(4);
+    (u32::from_be_bytes(checksum.try_into().unwrap()) == crc32fast::hash(record)).then_some(record)
+}
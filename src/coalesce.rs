@@ -0,0 +1,32 @@
+//! Framing of records stored by brokers with `coalesce = true`.
+//!
+//! Such a broker packs the tiny messages pushed within a short window into one record:
+//! `([len: u32][message])*`. The record has a single offset, so consumers fetch it as usual
+//! and split it with [`split_coalesced`].
+
+use std::io;
+
+/// Appends one message to a coalesced record
+pub fn push_coalesced(record: &mut Vec<u8>, message: &[u8]) {
+    record.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    record.extend_from_slice(message);
+}
+
+/// Splits a coalesced record into the messages it holds, in push order
+pub fn split_coalesced(record: &[u8]) -> io::Result<Vec<&[u8]>> {
+    let mut messages = Vec::new();
+    let mut rest = record;
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated coalesced message length"));
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        rest = &rest[4..];
+        if rest.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated coalesced message"));
+        }
+        messages.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    Ok(messages)
+}
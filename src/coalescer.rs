@@ -0,0 +1,63 @@
+use std::io;
+
+use sonicrab_client::coalesce::push_coalesced;
+use tokio::sync::oneshot;
+use tokio::time::Duration;
+
+// 写入结果：合并记录的偏移和写入时间戳；io::Error 不能复制，多个等待者只收到错误类型
+pub type AppendResult = Result<(u64, i64), io::ErrorKind>;
+
+// coalesce 模式的 broker 把一个时间窗口内收到的小消息合并为一条记录写入
+pub struct Coalescer {
+    window: Duration,
+    max_bytes: usize, // 合并记录达到该大小时立即写入，不再等待窗口结束
+    pending: Vec<u8>,
+    waiters: Vec<oneshot::Sender<AppendResult>>,
+    generation: u64, // 每个窗口的编号，避免定时刷新误写入下一个窗口的消息
+}
+
+// 加入合并记录的一条消息
+pub struct Pending {
+    pub result: oneshot::Receiver<AppendResult>,
+    pub generation: u64,
+    pub opened: bool, // 是否为窗口内的第一条消息，由它负责在窗口结束时写入
+    pub full: bool, // 合并记录已达到上限，应立即写入
+}
+
+impl Coalescer {
+    pub fn new(window: Duration, max_bytes: usize) -> Self {
+        Coalescer {
+            window,
+            max_bytes,
+            pending: Vec::new(),
+            waiters: Vec::new(),
+            generation: 0,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn add(&mut self, message: &[u8]) -> Pending {
+        let opened = self.waiters.is_empty();
+        push_coalesced(&mut self.pending, message);
+        let (sender, result) = oneshot::channel();
+        self.waiters.push(sender);
+        Pending {
+            result,
+            generation: self.generation,
+            opened,
+            full: self.pending.len() >= self.max_bytes,
+        }
+    }
+
+    // 取出 generation 窗口的合并记录和等待者；该窗口已经写入时返回 None
+    pub fn take(&mut self, generation: u64) -> Option<(Vec<u8>, Vec<oneshot::Sender<AppendResult>>)> {
+        if generation != self.generation || self.waiters.is_empty() {
+            return None;
+        }
+        self.generation += 1;
+        Some((std::mem::take(&mut self.pending), std::mem::take(&mut self.waiters)))
+    }
+}
@@ -0,0 +1,143 @@
+//! Compressed PUSH bodies sent with the `PUSH_COMPRESSED` command.
+//!
+//! Layout: `[codec: u8][compressed payload]`. The server decompresses before appending,
+//! so stored records and PULL responses carry the original bytes.
+//!
+//! Brokers with `record_codecs = true` instead store every record as `[codec: u8][payload]`:
+//! compressed pushes keep the codec they arrived with and plain pushes get codec `0`.
+//! [`crate::ClientBuilder::decompress_records`] decodes such records on fetch.
+
+use std::io::{self, Read};
+
+/// Compression codec of a `PUSH_COMPRESSED` body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// LZ4 block format with the uncompressed length prepended
+    Lz4,
+    /// Zstandard frame
+    Zstd,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Codec> {
+        match tag {
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses `payload` into a tagged `PUSH_COMPRESSED` body
+pub fn compress(codec: Codec, payload: &[u8]) -> Vec<u8> {
+    let mut body = vec![codec.tag()];
+    match codec {
+        Codec::Lz4 => body.extend_from_slice(&lz4_flex::compress_prepend_size(payload)),
+        Codec::Zstd => body.extend_from_slice(&zstd::bulk::compress(payload, 0).expect("zstd compression failed")),
+    }
+    body
+}
+
+/// Decompresses a tagged body, refusing output larger than `max_len` bytes
+pub fn decompress(body: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let (&tag, compressed) = body
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty compressed body"))?;
+    match Codec::from_tag(tag) {
+        Some(Codec::Lz4) => {
+            // 先检查声明的原始长度，避免恶意数据导致分配过大的内存
+            let declared = compressed
+                .get(..4)
+                .map(|len| u32::from_le_bytes(len.try_into().unwrap()) as usize)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated lz4 body"))?;
+            if declared > max_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("decompressed size {} exceeds limit {}", declared, max_len),
+                ));
+            }
+            lz4_flex::decompress_size_prepended(compressed)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        Some(Codec::Zstd) => {
+            // zstd 帧不一定声明原始长度，流式解压并在超过上限时停止
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(compressed)?
+                .take(max_len as u64 + 1)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if decompressed.len() > max_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("decompressed size exceeds limit {}", max_len),
+                ));
+            }
+            Ok(decompressed)
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown codec {}", tag),
+        )),
+    }
+}
+
+/// Checks that a tagged body decompresses cleanly to at most `max_len` bytes and returns the
+/// decompressed size. Zstandard output is streamed and discarded rather than kept in memory.
+pub fn validate(body: &[u8], max_len: usize) -> io::Result<usize> {
+    match body.split_first() {
+        Some((&tag, compressed)) if Codec::from_tag(tag) == Some(Codec::Zstd) => {
+            let decoder = zstd::stream::read::Decoder::new(compressed)?;
+            let len = io::copy(&mut decoder.take(max_len as u64 + 1), &mut io::sink())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if len > max_len as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("decompressed size exceeds limit {}", max_len),
+                ));
+            }
+            Ok(len as usize)
+        }
+        // LZ4 块格式只能整体解压，声明的长度已经限制在 max_len 之内
+        _ => decompress(body, max_len).map(|decompressed| decompressed.len()),
+    }
+}
+
+/// Decodes a record stored by a `record_codecs` broker: codec `0` is returned as is,
+/// other codecs are decompressed up to `max_len` bytes
+pub fn decode_record(stored: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    match stored.split_first() {
+        Some((0, payload)) => Ok(payload.to_vec()),
+        Some(_) => decompress(stored, max_len),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "empty record")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_codecs_round_trip() {
+        let payload = b"hello hello hello hello hello".repeat(20);
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let body = compress(codec, &payload);
+            assert_eq!(decode_record(&body, payload.len()).unwrap(), payload);
+            // 解压结果超过上限时报错
+            assert!(decode_record(&body, payload.len() - 1).is_err());
+            assert_eq!(validate(&body, payload.len()).unwrap(), payload.len());
+            assert!(validate(&body, payload.len() - 1).is_err());
+        }
+        let mut plain = vec![0u8];
+        plain.extend_from_slice(b"plain");
+        assert_eq!(decode_record(&plain, 0).unwrap(), b"plain");
+        assert!(decode_record(&[9, 1, 2], 10).is_err());
+        assert!(decode_record(&[], 10).is_err());
+    }
+}
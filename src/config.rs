@@ -1,5 +1,15 @@
 use serde::Deserialize;
 use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use toml::Value;
+
+use tracing::info;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
 
 #[derive(Debug, Deserialize,Clone)]
 pub struct Server {
@@ -7,7 +17,89 @@ pub struct Server {
     pub port: u16,
     pub path: String,
     pub broker_limit: u16,
-    pub authorization: String
+    pub authorization: String,
+    pub frame_timeout: Option<String>, // 读到长度前缀后接收完整消息体的最长时间，如 "30s"
+    pub admin_authorization: Option<String>, // 管理密钥，可以执行 CONNECTIONS 等管理命令，未配置时禁用管理命令
+    pub case_insensitive_names: Option<bool>, // broker 名称是否不区分大小写，未配置时根据数据目录所在的文件系统检测
+    pub slow_pull_ms: Option<u64>, // PULL 耗时超过该值（毫秒）时记录慢查询日志
+    pub log_level: Option<String>, // 输出到控制台的最低日志级别：debug、info、warn、error，默认 info
+    pub push_pressure_depth: Option<usize>, // 设置后 PUSH 回复附带压力等级，排队的 PUSH 达到该数量时等级为 255
+    pub subscriber_heartbeat_ms: Option<u64>, // SUBSCRIBE 订阅者发送心跳的间隔（毫秒），默认 1000，0 表示不检测心跳
+    pub subscriber_heartbeat_misses: Option<u32>, // 订阅者连续错过多少次心跳后被断开，默认 3
+    pub evict_idle: Option<bool>, // 达到 broker_limit 时卸载最久未使用的空闲 broker，为新 broker 腾出位置，默认不卸载
+    pub evict_idle_after: Option<String>, // 没有写入和 PULL 超过该时间的 broker 才可以被卸载，如 "10m"，默认 10 分钟
+    pub max_message_size: Option<String>, // 单个请求帧的大小上限，如 "16m"，默认为 storage.max_file_size 加 64k
+    pub max_connections: Option<usize>, // 数据端口同时打开的连接数上限，超过时新连接立即被关闭，默认不限制
+}
+
+const DEFAULT_FRAME_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_SLOW_PULL_MS: u64 = 500;
+const DEFAULT_SUBSCRIBER_HEARTBEAT_MS: u64 = 1000;
+const DEFAULT_SUBSCRIBER_HEARTBEAT_MISSES: u32 = 3;
+const DEFAULT_EVICT_IDLE_AFTER_SECS: u64 = 10 * 60;
+// 默认帧上限在数据文件大小之外为密钥、命令和消息头等预留的空间
+const FRAME_OVERHEAD_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_FILE_SIZE: usize = 100 * 1024 * 1024;
+
+const DEFAULT_MAX_BUFFERED_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+const DEFAULT_FSYNC_INTERVAL_MS: u64 = 1000;
+
+impl Storage {
+    pub fn max_buffered_response_bytes(&self) -> usize {
+        self.max_buffered_response_bytes
+            .as_deref()
+            .and_then(|s| parse_size(s).ok())
+            .unwrap_or(DEFAULT_MAX_BUFFERED_RESPONSE_BYTES)
+    }
+
+    // 历史文件的保留时间（秒），未设置或格式错误时按文件数量清理
+    pub fn retention_secs(&self) -> Option<u64> {
+        self.retention.as_deref().and_then(|s| parse_duration(s).ok())
+    }
+
+    // 未知的策略名称返回错误，启动时拒绝启动
+    pub fn fsync_policy(&self) -> Result<FsyncPolicy, String> {
+        match self.fsync.as_deref().unwrap_or("never") {
+            "always" => Ok(FsyncPolicy::Always),
+            "interval" => match self.fsync_interval_ms.unwrap_or(DEFAULT_FSYNC_INTERVAL_MS) {
+                0 => Err("storage.fsync_interval_ms must be greater than zero".to_string()),
+                ms => Ok(FsyncPolicy::Interval(ms)),
+            },
+            "never" => Ok(FsyncPolicy::Never),
+            other => Err(format!("storage.fsync: unknown policy {:?}, expected always, interval or never", other)),
+        }
+    }
+}
+
+impl Server {
+    pub fn frame_timeout_secs(&self) -> u64 {
+        self.frame_timeout
+            .as_deref()
+            .and_then(|s| parse_duration(s).ok())
+            .unwrap_or(DEFAULT_FRAME_TIMEOUT_SECS)
+    }
+
+    pub fn slow_pull_ms(&self) -> u64 {
+        self.slow_pull_ms.unwrap_or(DEFAULT_SLOW_PULL_MS)
+    }
+
+    pub fn subscriber_heartbeat_ms(&self) -> u64 {
+        self.subscriber_heartbeat_ms.unwrap_or(DEFAULT_SUBSCRIBER_HEARTBEAT_MS)
+    }
+
+    pub fn subscriber_heartbeat_misses(&self) -> u32 {
+        self.subscriber_heartbeat_misses.unwrap_or(DEFAULT_SUBSCRIBER_HEARTBEAT_MISSES).max(1)
+    }
+
+    // 开启 evict_idle 时返回 broker 可以被卸载的最短空闲时间（秒）
+    pub fn evict_idle_secs(&self) -> Option<u64> {
+        self.evict_idle.unwrap_or(false).then(|| {
+            self.evict_idle_after
+                .as_deref()
+                .and_then(|s| parse_duration(s).ok())
+                .unwrap_or(DEFAULT_EVICT_IDLE_AFTER_SECS)
+        })
+    }
 }
 
 #[derive(Debug, Deserialize,Clone)]
@@ -15,29 +107,541 @@ pub struct Storage {
     pub max_file_size: String,
     pub pull_max_limit: String,
     pub cache_limit: usize,
-    
+    pub index_memory_limit: Option<String>, // 所有 broker 索引内存映射的全局软上限，如 "512m"，默认不限制
+    pub max_buffered_response_bytes: Option<String>, // 不经过 sendfile、在内存中组装的响应的大小上限，如 "64m"
+    pub strict_recovery: Option<bool>, // 启动时发现重复或不连续的数据文件时拒绝启动，默认修复并继续
+    pub file_index: Option<bool>, // 索引不使用内存映射，直接读写索引文件（较慢），用于不支持 mmap 的文件系统
+    pub retention: Option<String>, // 按最后修改时间清理历史文件，如 "7d"；未设置时按 cache_limit 的文件数量清理
+    pub max_records_per_file: Option<u64>, // 每个数据文件的记录数上限，与 max_file_size 任一达到时切换文件
+    pub fsync: Option<String>, // 写入的落盘策略："always"、"interval" 或 "never"，默认 "never"
+    pub fsync_interval_ms: Option<u64>, // fsync = "interval" 时后台落盘的间隔（毫秒），默认 1000
+}
+
+// 写入的落盘策略，决定 PUSH 回复 OK 时数据是否已经写入磁盘
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    Always, // 每次写入后同步数据文件和索引，再回复客户端
+    Interval(u64), // 后台任务每隔若干毫秒同步一次，系统崩溃时最多丢失一个间隔内的写入
+    Never, // 由操作系统决定何时写回磁盘
+}
+
+// 单个 broker 的覆盖配置，对应配置文件中的 [brokers.<name>]
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct BrokerOverride {
+    #[serde(default)]
+    pub dedup: bool, // 是否开启持久化的消息去重索引
+    pub dedup_retention: Option<String>, // 去重ID的保留时间，如 "1h"
+    #[serde(default)]
+    pub headers: bool, // 记录是否带有可单独解析的消息头
+    #[serde(default)]
+    pub archive: bool, // 归档模式：写入时不建立索引，首次读取时补建，以读延迟换取写吞吐
+    pub align: Option<u32>, // 记录数据部分的对齐边界（2的幂），便于基于 mmap 的读取
+    #[serde(default)]
+    pub timestamps: bool, // 每条记录以服务端分配的写入时间戳（毫秒）开始
+    pub max_concurrent_pulls: Option<usize>, // 同时进行的 PULL 数量上限，超出的请求排队，默认不限制
+    pub content_type: Option<String>, // 消息体的内容类型，如 "json"，用于调试读取
+    #[serde(default)]
+    pub require_consumers: bool, // 没有 SUBSCRIBE 订阅者时拒绝写入，返回 NO_CONSUMERS
+    pub compression: Option<String>, // 记录的静态压缩算法，目前只支持 "zstd"
+    #[serde(default)]
+    pub zstd_dictionary: bool, // 用该 broker 的消息训练 zstd 字典，之后的记录用字典压缩
+    pub dictionary_samples: Option<usize>, // 训练字典使用的记录数，默认 1000
+    #[serde(default)]
+    pub coalesce: bool, // 把短时间内收到的小消息合并为一条记录，整批共用一个偏移，由消费者拆分
+    pub coalesce_window_ms: Option<u64>, // 合并等待的时间窗口（毫秒），默认 5
+    pub coalesce_max_bytes: Option<String>, // 合并记录达到该大小时立即写入，如 "64k"，默认 64k
+    #[serde(default)]
+    pub checksums: bool, // 每条记录前保存 CRC32，客户端可以校验记录在磁盘或传输中是否损坏
+    #[serde(default)]
+    pub dedup_consecutive: bool, // 与最后一条记录相同的消息不再写入，返回最后一条记录的偏移
+    #[serde(default)]
+    pub record_codecs: bool, // 每条记录以编码字节开始，PUSH_COMPRESSED 的消息按收到的压缩格式保存，由客户端解压
+    pub max_redeliveries: Option<u32>, // 同一消费者从同一偏移 CONSUMER_PULL 超过该次数后，记录转入 <broker>.dlq，默认不转入
+    #[serde(default)]
+    pub keyed: bool, // 记录前保存 PUSH_KEYED 的键，COMPACT 只保留每个键最新的记录
+    pub message_ttl: Option<String>, // 记录的存活时间，如 "1h"；需要 timestamps = true，过期的记录在 PULL 和 PEEK 时跳过
 }
 
 #[derive(Debug, Deserialize,Clone)]
 pub struct Config {
     pub server: Server,
     pub storage: Storage,
+    pub admin: Option<Admin>,
+    pub metrics: Option<Metrics>,
+    pub tls: Option<Tls>,
+    pub runtime: Option<Runtime>,
+    #[serde(default)]
+    pub brokers: HashMap<String, BrokerOverride>,
+    #[serde(default)]
+    pub keys: Vec<ClientKey>,
+    #[serde(default)]
+    pub acl: Vec<BrokerAcl>,
 }
 
-pub fn parse_size(size_str: &str) -> Result<usize, &'static str> {
-    let re = Regex::new(r"(\d+)([kKmMgG]+)").unwrap();
-    if let Some(captures) = re.captures(size_str) {
-        let value: usize = captures[1].parse().map_err(|_| "Failed to parse number")?;
-        let unit = &captures[2];
-        let multiplier = match unit.to_lowercase().as_str() {
-            "k" => 1024,
-            "m" => 1024 * 1024,
-            "g" => 1024 * 1024 * 1024,
+// 单个 broker 的访问控制，对应配置文件中的 [[acl]]；配置了 ACL 的 broker 只允许列出的密钥访问，
+// 没有 ACL 的 broker 允许 server.authorization 和 [[keys]] 中的密钥访问
+#[derive(Debug, Deserialize, Clone)]
+pub struct BrokerAcl {
+    pub broker: String,
+    pub keys: Vec<String>,
+    pub scopes: Option<Vec<String>>, // 允许的操作："read"、"write"，默认两者都允许
+}
+
+impl BrokerAcl {
+    fn allows(&self, key: &str, write: bool) -> bool {
+        let scope = if write { "write" } else { "read" };
+        self.keys.iter().any(|allowed| allowed == key)
+            && self.scopes.as_ref().is_none_or(|scopes| scopes.iter().any(|s| s == scope))
+    }
+}
+
+// 多租户部署中额外的客户端密钥，对应配置文件中的 [[keys]]，与 server.authorization 具有相同的权限
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientKey {
+    pub name: String, // 租户名称，记录为 broker 的所有者，不在元数据中保存密钥本身
+    pub key: String,
+    pub max_brokers: Option<usize>, // 该密钥最多自动创建的 broker 数量，默认只受 broker_limit 限制
+}
+
+// 独立的管理端口，对应配置文件中的 [admin]；配置后管理命令只能通过该端口执行
+#[derive(Debug, Deserialize, Clone)]
+pub struct Admin {
+    pub address: String,
+    pub port: u16,
+    pub authorization: Option<String>, // 管理端口的密钥，未配置时使用 server.admin_authorization
+}
+
+// Prometheus 指标的 HTTP 端口，对应配置文件中的 [metrics]；需要 metrics-http 特性
+#[derive(Debug, Deserialize, Clone)]
+pub struct Metrics {
+    pub address: Option<String>, // 监听地址，默认与 server.address 相同
+    pub port: u16,
+}
+
+// 客户端端口和管理端口的 TLS，对应配置文件中的 [tls]；需要 tls 特性
+#[derive(Debug, Deserialize, Clone)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct Tls {
+    pub cert: String, // PEM 格式的证书链文件，服务端证书在前
+    pub key: String, // PEM 格式的私钥文件
+}
+
+// tokio 运行时的线程数，对应配置文件中的 [runtime]；未设置的项使用 tokio 的默认值
+#[derive(Debug, Deserialize, Clone)]
+pub struct Runtime {
+    pub worker_threads: Option<usize>, // 工作线程数，默认等于 CPU 核数
+    pub max_blocking_threads: Option<usize>, // 执行阻塞任务的线程数上限，默认 512
+}
+
+// 运行中的配置。RELOAD_CONFIG 重新读取启动时的配置文件并整体替换；每个请求开始时取当前配置的快照，
+// 正在处理的请求不受替换影响
+pub struct LiveConfig {
+    paths: Vec<PathBuf>, // 启动时加载的配置文件，空表示没有可重新读取的文件
+    current: RwLock<Arc<Config>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config, paths: Vec<PathBuf>) -> Self {
+        LiveConfig { paths, current: RwLock::new(Arc::new(config)) }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    // 重新读取配置文件并替换当前配置，返回新的配置。不能在运行中修改的字段变化或新配置无效时
+    // 返回 InvalidInput 错误，当前配置保持不变
+    pub fn reload(&self) -> io::Result<Arc<Config>> {
+        if self.paths.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no config file to reload"));
+        }
+        let mut config = load_config(&self.paths)?;
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        for (name, size) in [("max_file_size", &config.storage.max_file_size), ("pull_max_limit", &config.storage.pull_max_limit)] {
+            parse_size(size).map_err(|e| invalid(format!("storage.{}: {}", name, e)))?;
+        }
+        if let Some(limit) = &config.storage.index_memory_limit {
+            parse_size(limit).map_err(|e| invalid(format!("storage.index_memory_limit: {}", e)))?;
+        }
+        let mut current = self.current.write().unwrap();
+        // 启动时检测出的大小写规则沿用到新配置
+        if config.server.case_insensitive_names.is_none() {
+            config.server.case_insensitive_names = current.server.case_insensitive_names;
+        }
+        if let Some(field) = fixed_field_changed(&current, &config) {
+            return Err(invalid(format!("{} cannot change without a restart", field)));
+        }
+        *current = Arc::new(config);
+        Ok(current.clone())
+    }
+}
+
+// 返回第一个在运行中不能修改却发生了变化的字段：监听地址和证书在启动时绑定，数据目录、
+// 索引和落盘方式以及 broker 的记录格式由已打开的 broker 使用
+fn fixed_field_changed(old: &Config, new: &Config) -> Option<&'static str> {
+    let admin = |config: &Config| config.admin.as_ref().map(|admin| (admin.address.clone(), admin.port));
+    let metrics = |config: &Config| config.metrics.as_ref().map(|metrics| (metrics.address.clone(), metrics.port));
+    let tls = |config: &Config| config.tls.as_ref().map(|tls| (tls.cert.clone(), tls.key.clone()));
+    let runtime = |config: &Config| config.runtime.as_ref().map(|runtime| (runtime.worker_threads, runtime.max_blocking_threads));
+    if old.server.path != new.server.path {
+        Some("server.path")
+    } else if (&old.server.address, old.server.port) != (&new.server.address, new.server.port) {
+        Some("server.address and server.port")
+    } else if old.server.case_insensitive_names != new.server.case_insensitive_names {
+        Some("server.case_insensitive_names")
+    } else if old.server.max_connections != new.server.max_connections {
+        Some("server.max_connections")
+    } else if admin(old) != admin(new) {
+        Some("[admin] address and port")
+    } else if metrics(old) != metrics(new) {
+        Some("[metrics]")
+    } else if tls(old) != tls(new) {
+        Some("[tls]")
+    } else if runtime(old) != runtime(new) {
+        Some("[runtime]")
+    } else if old.storage.max_records_per_file != new.storage.max_records_per_file {
+        Some("storage.max_records_per_file")
+    } else if old.storage.file_index != new.storage.file_index {
+        Some("storage.file_index")
+    } else if (&old.storage.fsync, old.storage.fsync_interval_ms) != (&new.storage.fsync, new.storage.fsync_interval_ms) {
+        Some("storage.fsync")
+    } else if old.brokers != new.brokers {
+        Some("[brokers]")
+    } else {
+        None
+    }
+}
+
+impl Config {
+    // 请求帧的大小上限，超过时在分配内存之前拒绝；一条记录不会超过数据文件的大小
+    pub fn max_message_size(&self) -> usize {
+        self.server
+            .max_message_size
+            .as_deref()
+            .and_then(|s| parse_size(s).ok())
+            .unwrap_or_else(|| {
+                parse_size(&self.storage.max_file_size).unwrap_or(DEFAULT_MAX_FILE_SIZE) + FRAME_OVERHEAD_BYTES
+            })
+    }
+
+    // 管理密钥：优先使用 [admin] 中的密钥
+    pub fn admin_key(&self) -> Option<&str> {
+        self.admin
+            .as_ref()
+            .and_then(|admin| admin.authorization.as_deref())
+            .or(self.server.admin_authorization.as_deref())
+    }
+
+    pub fn client_key(&self, key: &str) -> Option<&ClientKey> {
+        self.keys.iter().find(|client_key| client_key.key == key)
+    }
+
+    // 是否为可以连接的客户端密钥（不含管理密钥）；只出现在 ACL 中的密钥只能访问 ACL 允许的 broker
+    pub fn is_client_key(&self, key: &str) -> bool {
+        key == self.server.authorization
+            || self.client_key(key).is_some()
+            || self.acl.iter().any(|acl| acl.keys.iter().any(|allowed| allowed == key))
+    }
+
+    // 密钥能否读取（write 为 false）或写入 broker
+    pub fn broker_access(&self, broker: &str, key: &str, write: bool) -> bool {
+        let mut acls = self.acl.iter().filter(|acl| acl.broker == broker).peekable();
+        if acls.peek().is_none() {
+            return key == self.server.authorization || self.client_key(key).is_some();
+        }
+        acls.any(|acl| acl.allows(key, write))
+    }
+
+    pub fn broker_override(&self, name: &str) -> BrokerOverride {
+        self.brokers.get(name).cloned().unwrap_or_default()
+    }
+}
+
+// 解析大小，如 "512"、"64k"、"100MB"、"1.5g"，单位后的 b/B 可省略，不带单位时为字节数
+pub fn parse_size(size_str: &str) -> Result<usize, String> {
+    let re = Regex::new(r"^(\d+(?:\.\d+)?)\s*([kKmMgG]?)[bB]?$").unwrap();
+    let invalid = || format!("invalid size {:?}, expected e.g. \"512\", \"64k\", \"100MB\" or \"1.5g\"", size_str);
+    let captures = re.captures(size_str.trim()).ok_or_else(invalid)?;
+    let multiplier: u64 = match captures[2].to_lowercase().as_str() {
+        "" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+    // 整数直接相乘，避免大数值经过浮点数损失精度
+    if let Ok(value) = captures[1].parse::<u64>() {
+        return value
+            .checked_mul(multiplier)
+            .and_then(|size| usize::try_from(size).ok())
+            .ok_or_else(|| format!("size {:?} is too large", size_str));
+    }
+    let value: f64 = captures[1].parse().map_err(|_| invalid())?;
+    let size = (value * multiplier as f64).round();
+    if size > usize::MAX as f64 {
+        return Err(format!("size {:?} is too large", size_str));
+    }
+    Ok(size as usize)
+}
+
+// 解析时间长度，如 "30s"、"10m"、"1h"、"7d"，返回秒数
+pub fn parse_duration(duration_str: &str) -> Result<u64, &'static str> {
+    let re = Regex::new(r"^(\d+)([sSmMhHdD])$").unwrap();
+    if let Some(captures) = re.captures(duration_str.trim()) {
+        let value: u64 = captures[1].parse().map_err(|_| "Failed to parse number")?;
+        let multiplier = match captures[2].to_lowercase().as_str() {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
             _ => return Err("Unknown unit"),
         };
         Ok(value * multiplier)
     } else {
-       
         Err("Invalid format")
     }
-}
\ No newline at end of file
+}
+
+// 监听地址和端口组成 bind 使用的 "host:port"；IPv6 地址（如 "::"、"::1"）需要加方括号，已带方括号的不变
+pub fn socket_address(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// 从命令行参数中收集 --config 路径，可以重复出现；未指定时使用 config.toml
+pub fn config_paths_from_args<I>(args: I) -> Vec<PathBuf>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut paths = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            if let Some(path) = args.next() {
+                paths.push(PathBuf::from(path));
+            }
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            paths.push(PathBuf::from(path));
+        }
+    }
+    if paths.is_empty() {
+        paths.push(PathBuf::from(DEFAULT_CONFIG_FILE));
+    }
+    paths
+}
+
+// 按顺序加载多个配置文件（目录则按文件名顺序加载其中的 *.toml），后加载的字段覆盖先前的字段
+pub fn load_config(paths: &[PathBuf]) -> io::Result<Config> {
+    let mut merged = Value::Table(Default::default());
+    for file in expand_config_paths(paths)? {
+        let content = fs::read_to_string(&file)?;
+        let value: Value = toml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", file.display(), e),
+            )
+        })?;
+        info!("Loaded config file: {}", file.display());
+        let mut conflicts = vec![];
+        merge_values(&mut merged, value, "", &mut conflicts);
+        for key in conflicts {
+            info!("Config key {} overridden by {}", key, file.display());
+        }
+    }
+    merged
+        .try_into()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn expand_config_paths(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = vec![];
+            for entry in fs::read_dir(path)? {
+                let entry_path = entry?.path();
+                if entry_path.extension().and_then(|s| s.to_str()) == Some("toml") {
+                    entries.push(entry_path);
+                }
+            }
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No config file found",
+        ));
+    }
+    Ok(files)
+}
+
+// 递归合并 toml 表，overlay 中的值覆盖 base 中的同名字段，被覆盖且值不同的键记录到 conflicts
+fn merge_values(base: &mut Value, overlay: Value, prefix: &str, conflicts: &mut Vec<String>) {
+    match (base, overlay) {
+        (Value::Table(base_table), Value::Table(overlay_table)) => {
+            for (key, value) in overlay_table {
+                let path = join_key(prefix, &key);
+                match base_table.get_mut(&key) {
+                    Some(existing) if existing.is_table() && value.is_table() => {
+                        merge_values(existing, value, &path, conflicts);
+                    }
+                    Some(existing) => {
+                        if *existing != value {
+                            conflicts.push(path);
+                        }
+                        *existing = value;
+                    }
+                    None => {
+                        base_table.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => {
+            if *base != overlay {
+                conflicts.push(prefix.to_string());
+            }
+            *base = overlay;
+        }
+    }
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_overlay() {
+        let mut base: Value = toml::from_str(
+            "[server]\nport = 8080\npath = \"messages\"\n[storage]\ncache_limit = 10\n",
+        )
+        .unwrap();
+        let overlay: Value = toml::from_str("[server]\nport = 9090\npath = \"messages\"\n").unwrap();
+        let mut conflicts = vec![];
+        merge_values(&mut base, overlay, "", &mut conflicts);
+        assert_eq!(conflicts, vec!["server.port".to_string()]);
+        assert_eq!(base["server"]["port"].as_integer(), Some(9090));
+        assert_eq!(base["storage"]["cache_limit"].as_integer(), Some(10));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("1h"), Ok(3600));
+        assert_eq!(parse_duration("7d"), Ok(7 * 24 * 3600));
+        assert!(parse_duration("7w").is_err());
+    }
+
+    #[test]
+    fn test_socket_address_brackets_ipv6() {
+        assert_eq!(socket_address("0.0.0.0", 8080), "0.0.0.0:8080");
+        assert_eq!(socket_address("localhost", 8080), "localhost:8080");
+        assert_eq!(socket_address("::", 8080), "[::]:8080");
+        assert_eq!(socket_address("[::1]", 8080), "[::1]:8080");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("1.5G"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_size("100MB"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("100m"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("64kb"), Ok(64 * 1024));
+        assert_eq!(parse_size("2048B"), Ok(2048));
+        let err = parse_size("bogus").unwrap_err();
+        assert!(err.contains("\"bogus\""), "{}", err);
+        assert!(parse_size("10T").is_err());
+    }
+
+    #[test]
+    fn test_broker_acl() {
+        let config: Config = toml::from_str(
+            r#"
+[server]
+address = "127.0.0.1"
+port = 8080
+path = "messages"
+broker_limit = 10
+authorization = "shared"
+
+[storage]
+max_file_size = "1m"
+pull_max_limit = "1m"
+cache_limit = 10
+
+[[acl]]
+broker = "orders"
+keys = ["reader"]
+scopes = ["read"]
+
+[[acl]]
+broker = "orders"
+keys = ["writer"]
+"#,
+        )
+        .unwrap();
+        assert!(config.broker_access("orders", "reader", false));
+        assert!(!config.broker_access("orders", "reader", true));
+        assert!(config.broker_access("orders", "writer", true));
+        assert!(!config.broker_access("orders", "shared", false));
+        // 没有 ACL 的 broker 只允许全局密钥
+        assert!(config.broker_access("events", "shared", true));
+        assert!(!config.broker_access("events", "reader", false));
+        assert!(config.is_client_key("reader"));
+        assert!(!config.is_client_key("unknown"));
+    }
+
+    #[test]
+    fn test_reload_rejects_fixed_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config.toml");
+        let write = |broker_limit: u16, path: &str| {
+            let content = format!(
+                "[server]\naddress = \"127.0.0.1\"\nport = 0\npath = \"{}\"\nbroker_limit = {}\nauthorization = \"k\"\n\
+                 [storage]\nmax_file_size = \"1m\"\npull_max_limit = \"1m\"\ncache_limit = 10\n",
+                path, broker_limit
+            );
+            fs::write(&file, content).unwrap();
+        };
+        write(1, "data");
+        let live = LiveConfig::new(load_config(std::slice::from_ref(&file)).unwrap(), vec![file.clone()]);
+        write(5, "data");
+        assert_eq!(live.reload().unwrap().server.broker_limit, 5);
+        // 数据目录不能在运行中修改，当前配置保持不变
+        write(8, "elsewhere");
+        let err = live.reload().unwrap_err();
+        assert!(err.to_string().contains("server.path"));
+        assert_eq!(live.get().server.broker_limit, 5);
+        assert!(LiveConfig::new(live.get().as_ref().clone(), Vec::new()).reload().is_err());
+    }
+
+    #[test]
+    fn test_config_paths_from_args() {
+        let args = ["sonicrab_mq", "--config", "base.toml", "--config=prod.toml"]
+            .iter()
+            .map(|s| s.to_string());
+        assert_eq!(
+            config_paths_from_args(args),
+            vec![PathBuf::from("base.toml"), PathBuf::from("prod.toml")]
+        );
+        assert_eq!(
+            config_paths_from_args(Vec::new()),
+            vec![PathBuf::from(DEFAULT_CONFIG_FILE)]
+        );
+    }
+}
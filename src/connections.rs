@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+
+// 当前连接的注册表，供 CONNECTIONS 管理命令查看
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: DashMap<u64, Arc<Connection>>,
+}
+
+pub struct Connection {
+    pub id: u64,
+    pub peer: String,
+    pub connected_at: i64, // 建立连接的时间，毫秒时间戳
+    admin: AtomicBool, // 最近一次请求是否使用管理密钥
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    shutdown: Notify, // KICK 通知连接任务关闭
+}
+
+// 连接结束时从注册表中移除
+pub struct ConnectionGuard {
+    id: u64,
+    connection: Arc<Connection>,
+    registry: Arc<ConnectionRegistry>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ConnectionRegistry {
+            next_id: AtomicU64::new(0),
+            connections: DashMap::new(),
+        })
+    }
+
+    // 进程内共享的实例
+    pub fn global() -> Arc<Self> {
+        static GLOBAL: OnceLock<Arc<ConnectionRegistry>> = OnceLock::new();
+        GLOBAL.get_or_init(ConnectionRegistry::new).clone()
+    }
+
+    pub fn register(self: &Arc<Self>, peer: String) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let connection = Arc::new(Connection {
+            id,
+            peer,
+            connected_at: chrono::Utc::now().timestamp_millis(),
+            admin: AtomicBool::new(false),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            shutdown: Notify::new(),
+        });
+        self.connections.insert(id, connection.clone());
+        ConnectionGuard {
+            id,
+            connection,
+            registry: self.clone(),
+        }
+    }
+
+    // 通知指定连接关闭，连接不存在时返回 false
+    pub fn kick(&self, id: u64) -> bool {
+        match self.connections.get(&id) {
+            Some(connection) => {
+                connection.shutdown.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // 按建立时间排列的当前连接
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        let mut connections: Vec<(u64, Arc<Connection>)> = self
+            .connections
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+        connections.sort_by_key(|(id, _)| *id);
+        connections.into_iter().map(|(_, connection)| connection).collect()
+    }
+}
+
+impl Connection {
+    pub fn set_admin(&self, admin: bool) {
+        self.admin.store(admin, Ordering::SeqCst);
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.admin.load(Ordering::SeqCst)
+    }
+
+    // 身份只区分普通密钥和管理密钥，不暴露密钥本身
+    pub fn identity(&self) -> &'static str {
+        if self.is_admin() {
+            "admin"
+        } else {
+            "client"
+        }
+    }
+
+    // 等待 KICK 的关闭通知
+    pub async fn kicked(&self) {
+        self.shutdown.notified().await
+    }
+
+    pub fn add_received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    pub fn add_sent(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::SeqCst);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::SeqCst)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::SeqCst)
+    }
+}
+
+impl ConnectionGuard {
+    // 连接的共享引用，多路复用连接中的请求任务各持有一份
+    pub fn shared(&self) -> Arc<Connection> {
+        self.connection.clone()
+    }
+}
+
+impl std::ops::Deref for ConnectionGuard {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.connections.remove(&self.id);
+    }
+}
@@ -0,0 +1,172 @@
+//! High-level consumer that tracks its position in a local checkpoint file.
+//!
+//! [`ManagedConsumer`] pulls records, hands each one to a handler and commits the next offset
+//! to the checkpoint after the records of a batch have been handled. Connection errors are
+//! retried with exponential backoff, and a position that retention has already deleted is
+//! moved forward to the oldest record still on the server.
+
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Client, EARLIEST, LATEST};
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+// 空闲或退避等待时检查关闭信号的间隔
+const SHUTDOWN_CHECK: Duration = Duration::from_millis(50);
+
+/// Requests a [`ManagedConsumer`] to stop; cloneable and usable from any thread or signal handler
+#[derive(Clone, Default)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    /// Asks the consumer to commit its position and return from [`ManagedConsumer::run`]
+    /// after the record it is currently handling
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Consumes a broker from a checkpointed offset until shut down
+pub struct ManagedConsumer {
+    client: Client,
+    broker: String,
+    checkpoint: PathBuf,
+    start_offset: u64,
+    poll_interval: Duration,
+    shutdown: ShutdownHandle,
+}
+
+impl ManagedConsumer {
+    /// Creates a consumer for `broker` that keeps its position in the file at `checkpoint`.
+    /// Without a checkpoint it starts at [`EARLIEST`](crate::EARLIEST), the oldest stored record.
+    pub fn new(client: Client, broker: &str, checkpoint: impl Into<PathBuf>) -> Self {
+        Self {
+            client,
+            broker: broker.to_string(),
+            checkpoint: checkpoint.into(),
+            start_offset: EARLIEST,
+            poll_interval: Duration::from_millis(500),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// Offset to start from when no checkpoint file exists yet
+    pub fn start_offset(mut self, offset: u64) -> Self {
+        self.start_offset = offset;
+        self
+    }
+
+    /// How long to wait before polling again once the consumer has caught up
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Handle used to stop [`ManagedConsumer::run`] gracefully
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Offset the consumer will fetch next: the committed checkpoint, or the start offset
+    pub fn position(&self) -> Result<u64, Box<dyn Error>> {
+        match fs::read_to_string(&self.checkpoint) {
+            Ok(content) => Ok(content.trim().parse()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(self.start_offset),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fetches and handles records until shut down, returning the committed offset. If the
+    /// handler fails, the records before it are committed and its error is returned, so the
+    /// failed record is delivered again on the next run.
+    pub fn run<F>(&mut self, mut handler: F) -> Result<u64, Box<dyn Error>>
+    where
+        F: FnMut(u64, &[u8]) -> Result<(), Box<dyn Error>>,
+    {
+        let mut next = self.position()?;
+        let mut backoff = MIN_BACKOFF;
+        while !self.shutdown.is_shutdown() {
+            let (batch, next_offset) = match self.client.fetch_with_next(&self.broker, next, None) {
+                Ok(fetched) => fetched,
+                Err(_) => {
+                    // 连接断开或服务端重启，重新连接前退避等待
+                    self.client.disconnect();
+                    self.wait(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = MIN_BACKOFF;
+            if batch.is_empty() {
+                // 服务端跳过了过期的记录
+                if next_offset > next {
+                    next = next_offset;
+                    continue;
+                }
+                match self.oldest_after(next) {
+                    Ok(Some(oldest)) => next = oldest,
+                    Ok(None) => self.wait(self.poll_interval),
+                    Err(_) => self.client.disconnect(),
+                }
+                continue;
+            }
+            for (offset, record) in batch {
+                if self.shutdown.is_shutdown() {
+                    break;
+                }
+                if let Err(e) = handler(offset, &record) {
+                    self.commit(next)?;
+                    return Err(e);
+                }
+                next = offset + 1;
+            }
+            self.commit(next)?;
+        }
+        self.commit(next)?;
+        Ok(next)
+    }
+
+    // 当前位置没有数据时，判断是已经读到末尾，还是该位置已被清理
+    // 被清理时返回服务端仍保存的最早偏移（可用的偏移总是连续的后缀，二分查找）
+    fn oldest_after(&self, next: u64) -> Result<Option<u64>, Box<dyn Error>> {
+        let latest = match self.client.fetch_messages(&self.broker, LATEST)? {
+            Some((latest, _)) if latest > next => latest,
+            _ => return Ok(None),
+        };
+        let (mut low, mut high) = (next, latest);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.client.fetch_messages(&self.broker, middle)?.is_some() {
+                high = middle;
+            } else {
+                low = middle + 1;
+            }
+        }
+        Ok(Some(low))
+    }
+
+    fn commit(&self, offset: u64) -> Result<(), Box<dyn Error>> {
+        // 先写临时文件再替换，崩溃时不会留下写了一半的检查点
+        let tmp = self.checkpoint.with_extension("tmp");
+        fs::write(&tmp, offset.to_string())?;
+        fs::rename(&tmp, &self.checkpoint)?;
+        Ok(())
+    }
+
+    fn wait(&self, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        while !self.shutdown.is_shutdown() && Instant::now() < deadline {
+            thread::sleep(SHUTDOWN_CHECK.min(deadline - Instant::now()));
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+const DEDUP_FILE: &str = "dedup.log";
+
+struct DedupEntry {
+    offset: u64,
+    timestamp: i64,
+}
+
+// 持久化的消息去重索引，记录最近的消息ID与其写入的偏移量，重启后依然有效
+pub struct DedupIndex {
+    path: PathBuf,
+    file: File,
+    entries: HashMap<String, DedupEntry>,
+    order: VecDeque<(String, i64)>, // 按写入时间排列，用于淘汰过期的ID
+    retention_ms: i64,
+}
+
+impl DedupIndex {
+    pub fn open(broker_dir: &Path, retention_secs: u64) -> io::Result<Self> {
+        let path = broker_dir.join(DEDUP_FILE);
+        let retention_ms = (retention_secs * 1000) as i64;
+        let mut entries = HashMap::new();
+        let mut order = VecDeque::new();
+        if path.exists() {
+            let cutoff = chrono::Utc::now().timestamp_millis() - retention_ms;
+            let mut reader = BufReader::new(File::open(&path)?);
+            // 日志尾部可能有崩溃时写了一半的记录，读到不完整的记录即停止
+            while let Ok((id, entry)) = read_entry(&mut reader) {
+                if entry.timestamp >= cutoff {
+                    order.push_back((id.clone(), entry.timestamp));
+                    entries.insert(id, entry);
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let mut index = DedupIndex {
+            path,
+            file,
+            entries,
+            order,
+            retention_ms,
+        };
+        // 启动时重写日志，去掉已过期的ID
+        index.compact()?;
+        Ok(index)
+    }
+
+    // 查询消息ID是否已经写入过，返回当时分配的偏移量
+    pub fn get(&mut self, id: &str) -> Option<u64> {
+        self.expire();
+        self.entries.get(id).map(|entry| entry.offset)
+    }
+
+    pub fn insert(&mut self, id: &str, offset: u64) -> io::Result<()> {
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let mut record = Vec::with_capacity(id.len() + 18);
+        write_entry(&mut record, id, offset, timestamp)?;
+        self.file.write_all(&record)?;
+        self.entries
+            .insert(id.to_string(), DedupEntry { offset, timestamp });
+        self.order.push_back((id.to_string(), timestamp));
+        self.expire();
+        // 日志中过期记录过多时压缩
+        if self.order.len() > 1024 && self.order.len() > self.entries.len() * 2 {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn expire(&mut self) {
+        let cutoff = chrono::Utc::now().timestamp_millis() - self.retention_ms;
+        while let Some((id, timestamp)) = self.order.front() {
+            if *timestamp >= cutoff {
+                break;
+            }
+            // 同一个ID只有最新一次写入的时间戳有效
+            if let Some(entry) = self.entries.get(id) {
+                if entry.timestamp == *timestamp {
+                    self.entries.remove(id);
+                }
+            }
+            self.order.pop_front();
+        }
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        self.expire();
+        self.order
+            .retain(|(id, timestamp)| self.entries.get(id).map(|e| e.timestamp) == Some(*timestamp));
+        let tmp_path = self.path.with_extension("log.tmp");
+        let mut content = Vec::new();
+        for (id, _) in self.order.iter() {
+            let entry = &self.entries[id];
+            write_entry(&mut content, id, entry.offset, entry.timestamp)?;
+        }
+        fs::write(&tmp_path, &content)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+// 记录格式：[id_len: u16][id][offset: u64][timestamp: i64]
+fn write_entry(buf: &mut Vec<u8>, id: &str, offset: u64, timestamp: i64) -> io::Result<()> {
+    buf.write_u16::<BigEndian>(id.len() as u16)?;
+    buf.write_all(id.as_bytes())?;
+    buf.write_u64::<BigEndian>(offset)?;
+    buf.write_i64::<BigEndian>(timestamp)?;
+    Ok(())
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> io::Result<(String, DedupEntry)> {
+    let id_len = reader.read_u16::<BigEndian>()? as usize;
+    let mut id_buf = vec![0; id_len];
+    reader.read_exact(&mut id_buf)?;
+    let id = String::from_utf8(id_buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let offset = reader.read_u64::<BigEndian>()?;
+    let timestamp = reader.read_i64::<BigEndian>()?;
+    Ok((id, DedupEntry { offset, timestamp }))
+}
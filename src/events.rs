@@ -0,0 +1,130 @@
+use std::fmt::{self, Write};
+use std::sync::OnceLock;
+
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+// 订阅者跟不上时，超出容量的旧事件被丢弃，不会阻塞服务端
+const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct LogEvent {
+    pub timestamp: i64, // 毫秒时间戳
+    pub level: Level,
+    pub message: String, // 事件内容，后面接所在 span 和事件自身的字段 "名称=值"
+}
+
+impl LogEvent {
+    // LOG_STREAM 中每个事件的格式："时间戳 级别 内容"
+    pub fn to_line(&self) -> String {
+        format!("{} {} {}", self.timestamp, self.level, self.message)
+    }
+}
+
+fn sender() -> &'static broadcast::Sender<LogEvent> {
+    static SENDER: OnceLock<broadcast::Sender<LogEvent>> = OnceLock::new();
+    SENDER.get_or_init(|| broadcast::channel(EVENT_CAPACITY).0)
+}
+
+pub fn subscribe() -> broadcast::Receiver<LogEvent> {
+    sender().subscribe()
+}
+
+// LOG_STREAM 和 log_level 中的级别名称：debug、info、warn、error，不区分大小写
+pub fn parse_level(name: &str) -> Option<Level> {
+    match name.to_lowercase().as_str() {
+        "debug" => Some(Level::DEBUG),
+        "info" => Some(Level::INFO),
+        "warn" => Some(Level::WARN),
+        "error" => Some(Level::ERROR),
+        _ => None,
+    }
+}
+
+// 把 debug 及以上的 tracing 事件广播给 LOG_STREAM 的订阅者，不受控制台日志级别的影响；
+// 没有订阅者时不格式化事件
+pub struct BroadcastLayer;
+
+// 创建 span 时格式化好的字段，事件发生时按从外到内的顺序接在事件内容后面
+struct SpanFields(String);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for BroadcastLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = FieldWriter::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut writer = FieldWriter { message: String::new(), fields: std::mem::take(fields) };
+            values.record(&mut writer);
+            *fields = writer.fields;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() > Level::DEBUG || sender().receiver_count() == 0 {
+            return;
+        }
+        let mut own = FieldWriter::default();
+        event.record(&mut own);
+        let mut message = own.message;
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    message.push_str(fields);
+                }
+            }
+        }
+        message.push_str(&own.fields);
+        let _ = sender().send(LogEvent {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            level: *event.metadata().level(),
+            message,
+        });
+    }
+}
+
+// message 字段作为事件内容，其他字段格式化为 " 名称=值"
+#[derive(Default)]
+struct FieldWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+// 测试共用的订阅者：日志由测试框架捕获，级别由 RUST_LOG 控制，事件同时广播给订阅者；
+// 全局只能安装一次，之后的调用不做任何事
+#[cfg(test)]
+pub fn init_test_logging() {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::EnvFilter;
+
+    let console = tracing_subscriber::fmt::layer().with_test_writer().with_filter(EnvFilter::from_default_env());
+    let _ = tracing_subscriber::registry().with(console).with(BroadcastLayer).try_init();
+}
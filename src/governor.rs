@@ -0,0 +1,175 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+use crate::index::{open_index, IndexAccess, INDEX_ENTRY_SIZE};
+
+// 全局的索引内存映射统计与限制，超过上限时淘汰所有 broker 中最久未使用的历史索引映射
+pub struct IndexGovernor {
+    resident: AtomicU64, // 当前驻留的索引映射字节数（含各 broker 的当前索引）
+    limit: AtomicU64, // 软上限，0 表示不限制
+    evictions: AtomicU64,
+    clock: AtomicU64, // 逻辑时钟，用于记录最近访问顺序
+    slots: Mutex<Vec<Weak<IndexSlot>>>,
+}
+
+// 历史文件的索引映射，被淘汰后在下次访问时重新映射
+pub struct IndexSlot {
+    path: PathBuf,
+    use_mmap: bool, // false 时直接读写索引文件，不占用映射内存
+    map: Mutex<Option<Box<dyn IndexAccess>>>,
+    last_used: AtomicU64,
+    governor: Arc<IndexGovernor>,
+}
+
+impl IndexGovernor {
+    pub fn new(limit: u64) -> Arc<Self> {
+        Arc::new(IndexGovernor {
+            resident: AtomicU64::new(0),
+            limit: AtomicU64::new(limit),
+            evictions: AtomicU64::new(0),
+            clock: AtomicU64::new(0),
+            slots: Mutex::new(Vec::new()),
+        })
+    }
+
+    // 进程内所有 broker 共享的实例
+    pub fn global() -> Arc<Self> {
+        static GLOBAL: OnceLock<Arc<IndexGovernor>> = OnceLock::new();
+        GLOBAL.get_or_init(|| IndexGovernor::new(0)).clone()
+    }
+
+    pub fn set_limit(&self, limit: u64) {
+        self.limit.store(limit, Ordering::SeqCst);
+        self.enforce(None);
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    pub fn resident(&self) -> u64 {
+        self.resident.load(Ordering::SeqCst)
+    }
+
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::SeqCst)
+    }
+
+    // 当前索引映射创建、扩展或释放时调整统计
+    pub fn adjust(&self, released: u64, mapped: u64) {
+        self.resident.fetch_add(mapped, Ordering::SeqCst);
+        self.resident.fetch_sub(released, Ordering::SeqCst);
+        if mapped > released {
+            self.enforce(None);
+        }
+    }
+
+    pub fn register(self: &Arc<Self>, path: PathBuf, use_mmap: bool) -> io::Result<Arc<IndexSlot>> {
+        let slot = Arc::new(IndexSlot {
+            path,
+            use_mmap,
+            map: Mutex::new(None),
+            last_used: AtomicU64::new(0),
+            governor: self.clone(),
+        });
+        slot.load()?;
+        let mut slots = self.slots.lock().unwrap();
+        slots.retain(|slot| slot.strong_count() > 0);
+        slots.push(Arc::downgrade(&slot));
+        drop(slots);
+        self.enforce(Some(&slot));
+        Ok(slot)
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::SeqCst)
+    }
+
+    // 超过上限时按最近访问时间淘汰历史索引映射，keep 为刚刚访问的映射，不参与淘汰
+    fn enforce(&self, keep: Option<&Arc<IndexSlot>>) {
+        let limit = self.limit.load(Ordering::SeqCst);
+        if limit == 0 {
+            return;
+        }
+        while self.resident.load(Ordering::SeqCst) > limit {
+            let slots = self.slots.lock().unwrap();
+            let victim = slots
+                .iter()
+                .filter_map(|slot| slot.upgrade())
+                .filter(|slot| keep.is_none_or(|keep| !Arc::ptr_eq(slot, keep)))
+                .filter(|slot| slot.is_resident())
+                .min_by_key(|slot| slot.last_used.load(Ordering::SeqCst));
+            drop(slots);
+            match victim {
+                Some(slot) => {
+                    slot.unload();
+                    self.evictions.fetch_add(1, Ordering::SeqCst);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl IndexSlot {
+    fn is_resident(&self) -> bool {
+        self.map.lock().unwrap().as_ref().is_some_and(|index| index.resident_bytes() > 0)
+    }
+
+    fn load(&self) -> io::Result<()> {
+        let mut map = self.map.lock().unwrap();
+        self.map_locked(&mut map)
+    }
+
+    fn map_locked(&self, map: &mut Option<Box<dyn IndexAccess>>) -> io::Result<()> {
+        if map.is_none() {
+            let index = open_index(&self.path, false, self.use_mmap)?;
+            self.governor
+                .resident
+                .fetch_add(index.resident_bytes(), Ordering::SeqCst);
+            *map = Some(index);
+        }
+        self.last_used.store(self.governor.tick(), Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn unload(&self) {
+        if let Some(index) = self.map.lock().unwrap().take() {
+            self.governor
+                .resident
+                .fetch_sub(index.resident_bytes(), Ordering::SeqCst);
+        }
+    }
+
+    // 读取索引项 (start, size)，映射已被淘汰时重新映射；越界或遇到结束标记返回 None
+    pub fn read_entry(self: &Arc<Self>, position: usize) -> io::Result<Option<(u64, u32)>> {
+        let mut map = self.map.lock().unwrap();
+        let reloaded = map.is_none();
+        self.map_locked(&mut map)?;
+        let result = read_entry_from(map.as_deref().unwrap(), position);
+        drop(map);
+        if reloaded {
+            self.governor.enforce(Some(self));
+        }
+        result
+    }
+}
+
+fn read_entry_from(index: &dyn IndexAccess, position: usize) -> io::Result<Option<(u64, u32)>> {
+    if position + INDEX_ENTRY_SIZE > index.len() {
+        return Ok(None);
+    }
+    let (start, size) = index.read_entry(position)?;
+    if start == 0 && size == 0 {
+        return Ok(None);
+    }
+    Ok(Some((start, size)))
+}
+
+impl Drop for IndexSlot {
+    fn drop(&mut self) {
+        self.unload();
+    }
+}
@@ -0,0 +1,43 @@
+//! Record headers stored in front of the payload for brokers with `headers = true`.
+//!
+//! Layout: `[count: u16]([key_len: u16][key][value_len: u16][value])*[body]`
+
+use std::io::{self, Cursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+pub type Headers = Vec<(String, String)>;
+
+/// Encodes headers into the block stored ahead of the record body
+pub fn encode_headers(headers: &[(String, String)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    block.extend_from_slice(&(headers.len() as u16).to_be_bytes());
+    for (key, value) in headers {
+        block.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        block.extend_from_slice(key.as_bytes());
+        block.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        block.extend_from_slice(value.as_bytes());
+    }
+    block
+}
+
+/// Splits a stored record into its headers and body
+pub fn decode_headers(record: &[u8]) -> io::Result<(Headers, &[u8])> {
+    let mut cursor = Cursor::new(record);
+    let count = cursor.read_u16::<BigEndian>()?;
+    let mut headers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let key = read_string(&mut cursor)?;
+        let value = read_string(&mut cursor)?;
+        headers.push((key, value));
+    }
+    let position = cursor.position() as usize;
+    Ok((headers, &record[position..]))
+}
+
+fn read_string(cursor: &mut Cursor<&[u8]>) -> io::Result<String> {
+    let len = cursor.read_u16::<BigEndian>()? as usize;
+    let mut buf = vec![0; len];
+    cursor.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,182 @@
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+use memmap2::{Mmap, MmapMut};
+
+use tracing::warn;
+use crate::zerocopy::{read_exact_at, write_all_at};
+
+pub const INDEX_ENTRY_SIZE: usize = 12; // 索引项：[start: u64][size: u32]
+
+// 索引文件的访问方式：默认使用内存映射；配置了 file_index、文件系统不支持 mmap 或映射失败时，
+// 改为按位置直接读写文件（pread/pwrite），功能相同但更慢
+pub trait IndexAccess: Send + Sync {
+    fn len(&self) -> usize;
+
+    fn read_at(&self, position: usize, buf: &mut [u8]) -> io::Result<()>;
+
+    fn write_at(&mut self, position: usize, data: &[u8]) -> io::Result<()>;
+
+    // 计入全局索引内存统计的字节数，直接读写文件时为 0
+    fn resident_bytes(&self) -> u64;
+
+    // 整个索引文件的内容，用于检查索引是否完整
+    fn contents(&self) -> io::Result<Cow<'_, [u8]>>;
+
+    // 把写入的索引项同步到磁盘
+    fn flush(&self) -> io::Result<()>;
+
+    // 读取 position 处的索引项 (start, size)
+    fn read_entry(&self, position: usize) -> io::Result<(u64, u32)> {
+        let mut entry = [0u8; INDEX_ENTRY_SIZE];
+        self.read_at(position, &mut entry)?;
+        Ok((
+            u64::from_be_bytes(entry[0..8].try_into().unwrap()),
+            u32::from_be_bytes(entry[8..12].try_into().unwrap()),
+        ))
+    }
+
+    // 写入索引项，并在后面写入全 0 的结束标记，以便重启的时候设置 position_offset
+    fn write_entry(&mut self, position: usize, start: u64, size: u32) -> io::Result<()> {
+        let mut entry = [0u8; 2 * INDEX_ENTRY_SIZE];
+        entry[0..8].copy_from_slice(&start.to_be_bytes());
+        entry[8..12].copy_from_slice(&size.to_be_bytes());
+        self.write_at(position, &entry)
+    }
+
+    // 清空所有索引项
+    fn clear(&mut self) -> io::Result<()> {
+        let zeros = vec![0u8; self.len()];
+        self.write_at(0, &zeros)
+    }
+}
+
+impl IndexAccess for MmapMut {
+    fn len(&self) -> usize {
+        self[..].len()
+    }
+
+    fn read_at(&self, position: usize, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self[..].len(), position, buf.len())?;
+        buf.copy_from_slice(&self[position..position + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, position: usize, data: &[u8]) -> io::Result<()> {
+        check_range(self[..].len(), position, data.len())?;
+        self[position..position + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn resident_bytes(&self) -> u64 {
+        self[..].len() as u64
+    }
+
+    fn contents(&self) -> io::Result<Cow<'_, [u8]>> {
+        Ok(Cow::Borrowed(&self[..]))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        MmapMut::flush(self)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.fill(0);
+        Ok(())
+    }
+}
+
+// 只读映射，用于历史文件的索引
+impl IndexAccess for Mmap {
+    fn len(&self) -> usize {
+        self[..].len()
+    }
+
+    fn read_at(&self, position: usize, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self[..].len(), position, buf.len())?;
+        buf.copy_from_slice(&self[position..position + buf.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, _position: usize, _data: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "index is mapped read-only"))
+    }
+
+    fn resident_bytes(&self) -> u64 {
+        self[..].len() as u64
+    }
+
+    fn contents(&self) -> io::Result<Cow<'_, [u8]>> {
+        Ok(Cow::Borrowed(&self[..]))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// 不使用内存映射，每次访问按位置读写索引文件
+pub struct FileIndex {
+    file: std::fs::File,
+    len: usize,
+}
+
+impl IndexAccess for FileIndex {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn read_at(&self, position: usize, buf: &mut [u8]) -> io::Result<()> {
+        check_range(self.len, position, buf.len())?;
+        read_exact_at(&self.file, buf, position as u64)
+    }
+
+    fn write_at(&mut self, position: usize, data: &[u8]) -> io::Result<()> {
+        check_range(self.len, position, data.len())?;
+        write_all_at(&self.file, data, position as u64)
+    }
+
+    fn resident_bytes(&self) -> u64 {
+        0
+    }
+
+    fn contents(&self) -> io::Result<Cow<'_, [u8]>> {
+        let mut contents = vec![0u8; self.len];
+        read_exact_at(&self.file, &mut contents, 0)?;
+        Ok(Cow::Owned(contents))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+// 打开索引文件，writable 为 true 时可以写入索引项；use_mmap 为 false 或映射失败时直接读写文件
+pub fn open_index(path: &Path, writable: bool, use_mmap: bool) -> io::Result<Box<dyn IndexAccess>> {
+    let file = OpenOptions::new().read(true).write(writable).open(path)?;
+    if use_mmap {
+        let mapped = if writable {
+            unsafe { MmapMut::map_mut(&file) }.map(|map| Box::new(map) as Box<dyn IndexAccess>)
+        } else {
+            unsafe { Mmap::map(&file) }.map(|map| Box::new(map) as Box<dyn IndexAccess>)
+        };
+        match mapped {
+            Ok(index) => return Ok(index),
+            Err(e) => warn!("Mapping index {:?} failed, falling back to file reads and writes: {}", path, e),
+        }
+    }
+    let len = file.metadata()?.len() as usize;
+    Ok(Box::new(FileIndex { file, len }))
+}
+
+fn check_range(len: usize, position: usize, size: usize) -> io::Result<()> {
+    if position.saturating_add(size) > len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("index access {}+{} beyond index length {}", position, size, len),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,28 @@
+//! Record keys stored in front of the payload for brokers with `keyed = true`.
+//!
+//! Layout: `[key_len: u16][key][body]`. An empty key marks a record pushed without one;
+//! compaction never removes such records.
+
+use std::io::{self, Cursor};
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+/// Encodes a key into the block stored ahead of the record body
+pub fn encode_key(key: &str) -> io::Result<Vec<u8>> {
+    let len = u16::try_from(key.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record key longer than 65535 bytes"))?;
+    let mut block = Vec::with_capacity(2 + key.len());
+    block.extend_from_slice(&len.to_be_bytes());
+    block.extend_from_slice(key.as_bytes());
+    Ok(block)
+}
+
+/// Splits a stored record into its key and body
+pub fn decode_key(record: &[u8]) -> io::Result<(&str, &[u8])> {
+    let len = Cursor::new(record).read_u16::<BigEndian>()? as usize;
+    let key = record
+        .get(2..2 + len)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated record key"))?;
+    let key = std::str::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((key, &record[2 + len..]))
+}
@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::time::{Duration, Instant};
+
+// 每个租约最多包含的记录数，同时受 pull_max_limit 限制
+pub const LEASE_MAX_RECORDS: u64 = 100;
+
+// 一个租约：[start, end) 范围内的记录交给一个消费者处理，到期前其他消费者跳过该范围
+struct Lease {
+    start: u64,
+    end: u64,
+    expires: Instant,
+}
+
+// 工作队列模式下 broker 的租约状态：每条记录同一时间只租给一个消费者，确认后不再租出，
+// 租约到期未确认时范围重新可租（至少一次投递）。只有 acked 保存在元数据中，重启后未确认的记录重新投递
+pub struct LeaseTable {
+    acked: u64, // 该偏移之前的记录都已确认
+    next: u64, // 尚未租出过的第一个偏移
+    leases: HashMap<u64, Lease>, // 租约编号 -> 租约
+    available: BTreeMap<u64, u64>, // 租约到期后重新可租的范围 start -> end
+    done: BTreeMap<u64, u64>, // 已确认、但前面还有未确认记录的范围 start -> end
+    next_id: u64,
+}
+
+impl LeaseTable {
+    pub fn new(acked: u64) -> Self {
+        // 租约编号从当前时间开始，重启前发出的旧编号不会与新租约混淆
+        let next_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| (elapsed.as_millis() as u64) << 16)
+            .unwrap_or(1);
+        LeaseTable {
+            acked,
+            next: acked,
+            leases: HashMap::new(),
+            available: BTreeMap::new(),
+            done: BTreeMap::new(),
+            next_id,
+        }
+    }
+
+    pub fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    // 下一个可租的范围，优先重新租出到期的范围；first 之前的记录已被清理，head 为下一个待写入的偏移
+    // 只计算范围，读取记录后调用 grant 确定实际租出的范围
+    pub fn next_range(&mut self, first: u64, head: u64, now: Instant) -> Option<(u64, u64)> {
+        self.expire(now);
+        self.skip_to(first);
+        if let Some((&start, &end)) = self.available.iter().next() {
+            return Some((start, end.min(start + LEASE_MAX_RECORDS)));
+        }
+        (self.next < head).then(|| (self.next, head.min(self.next + LEASE_MAX_RECORDS)))
+    }
+
+    // 租出 next_range 返回的范围中的 [start, end)，返回租约编号
+    pub fn grant(&mut self, start: u64, end: u64, timeout: Duration, now: Instant) -> u64 {
+        match self.available.remove(&start) {
+            Some(available_end) if end < available_end => {
+                self.available.insert(end, available_end);
+            }
+            Some(_) => {}
+            None => self.next = self.next.max(end),
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.leases.insert(id, Lease { start, end, expires: now + timeout });
+        id
+    }
+
+    // 确认租约，范围内的记录不再租出；租约不存在或已到期时返回 false
+    pub fn ack(&mut self, id: u64, now: Instant) -> bool {
+        self.expire(now);
+        match self.leases.remove(&id) {
+            Some(lease) => {
+                self.done.insert(lease.start, lease.end);
+                self.advance();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired: Vec<u64> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.expires <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            let lease = self.leases.remove(&id).unwrap();
+            self.available.insert(lease.start, lease.end);
+        }
+    }
+
+    // 被清理的记录不再租出，也不再等待确认
+    fn skip_to(&mut self, first: u64) {
+        self.next = self.next.max(first);
+        while let Some((&start, &end)) = self.available.iter().next() {
+            if start >= first {
+                break;
+            }
+            self.available.remove(&start);
+            if end > first {
+                self.available.insert(first, end);
+            }
+        }
+        if self.acked < first {
+            self.acked = first;
+            self.advance();
+        }
+    }
+
+    // 合并连续确认的范围，推进 acked
+    fn advance(&mut self) {
+        while let Some((&start, &end)) = self.done.iter().next() {
+            if start > self.acked {
+                break;
+            }
+            self.done.remove(&start);
+            self.acked = self.acked.max(end);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_lease_is_leased_again() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(30);
+        let mut table = LeaseTable::new(0);
+        let (start, end) = table.next_range(0, 250, now).unwrap();
+        assert_eq!((start, end), (0, LEASE_MAX_RECORDS));
+        let first = table.grant(start, 10, timeout, now);
+        // 其他消费者跳过未到期的租约
+        assert_eq!(table.next_range(0, 250, now), Some((10, 110)));
+        let second = table.grant(10, 20, timeout, now);
+
+        // 第一个租约到期后重新租出，到期的租约不能再确认
+        let later = now + timeout;
+        assert!(table.ack(second, now));
+        assert_eq!(table.next_range(0, 250, later), Some((0, 10)));
+        let third = table.grant(0, 5, timeout, later);
+        assert!(!table.ack(first, later));
+        assert_eq!(table.next_range(0, 250, later), Some((5, 10)));
+        table.grant(5, 10, timeout, later);
+        assert_eq!(table.acked(), 0);
+
+        // 前面的记录确认后，连续确认的位置越过之前已确认的范围
+        assert!(table.ack(third, later));
+        assert_eq!(table.acked(), 5);
+        assert_eq!(table.next_range(0, 250, later), Some((20, 120)));
+    }
+
+    #[test]
+    fn test_cleaned_records_are_skipped() {
+        let now = Instant::now();
+        let mut table = LeaseTable::new(3);
+        let lease = table.grant(3, 8, Duration::from_secs(1), now);
+        assert!(table.ack(lease, now));
+        assert_eq!(table.acked(), 8);
+        // 8 之前的记录和到期的范围都已被清理
+        let expired = table.grant(8, 12, Duration::from_secs(1), now);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(table.next_range(50, 60, later), Some((50, 60)));
+        assert_eq!(table.acked(), 50);
+        assert!(!table.ack(expired, later));
+        assert_eq!(table.next_range(60, 60, later), None);
+    }
+}
@@ -1,16 +1,437 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::Mutex;
+pub mod headers;
+pub mod keys;
+pub mod compression;
+pub mod coalesce;
+pub mod checksum;
+pub mod consumer;
+pub mod stream;
+pub mod multiplex;
+mod cache;
+mod transport;
+#[cfg(feature = "tokio")]
+pub mod async_client;
+
+#[cfg(feature = "tokio")]
+pub use crate::async_client::AsyncClient;
+pub use crate::consumer::{ManagedConsumer, ShutdownHandle};
+pub use crate::multiplex::Multiplexed;
+pub use crate::stream::MessageStream;
+#[cfg(feature = "tls")]
+pub use crate::transport::TlsConfig;
+
+use std::io::{self, Cursor, Read, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use std::error::Error;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::cache::RecordCache;
+use crate::checksum::verify_checksum;
+use crate::compression::{compress, decode_record, Codec};
+use crate::headers::{decode_headers, encode_headers, Headers};
+use crate::keys::encode_key;
+use crate::transport::{connect_any, unbracket, Transport};
+
+/// Offset that PULL resolves to the oldest record still stored, after retention
+pub const EARLIEST: u64 = 0;
+/// Offset that PULL resolves to the most recent record
+pub const LATEST: u64 = u64::MAX;
+
+pub(crate) const PUSH_COMMAND: &[u8] = b"PUSH";
+pub(crate) const PULL_COMMAND: &[u8] = b"PULL";
+const PUSH_ID_COMMAND: &[u8] = b"PUSH_ID";
+const PING_COMMAND: &[u8] = b"PING";
+const PUSH_HEADERS_COMMAND: &[u8] = b"PUSH_HEADERS";
+const HEADERS_COMMAND: &[u8] = b"HEADERS";
+const VERIFY_COMMAND: &[u8] = b"VERIFY";
+const STATS_COMMAND: &[u8] = b"STATS";
+const CONNECTIONS_COMMAND: &[u8] = b"CONNECTIONS";
+const KICK_COMMAND: &[u8] = b"KICK";
+const LOG_STREAM_COMMAND: &[u8] = b"LOG_STREAM";
+const DEBUG_PULL_COMMAND: &[u8] = b"DEBUG_PULL";
+const REBUILD_INDEX_COMMAND: &[u8] = b"REBUILD_INDEX";
+const MIGRATE_PATH_COMMAND: &[u8] = b"MIGRATE_PATH";
+const PUSH_COMPRESSED_COMMAND: &[u8] = b"PUSH_COMPRESSED";
+const GET_META_COMMAND: &[u8] = b"GET_META";
+const SET_META_COMMAND: &[u8] = b"SET_META";
+const SUBSCRIBE_COMMAND: &[u8] = b"SUBSCRIBE";
+const TAIL_BYTES_COMMAND: &[u8] = b"TAIL_BYTES";
+const SEGMENTS_COMMAND: &[u8] = b"SEGMENTS";
+const PIN_SEGMENT_COMMAND: &[u8] = b"PIN_SEGMENT";
+const UNPIN_SEGMENT_COMMAND: &[u8] = b"UNPIN_SEGMENT";
+const PUSH_BATCH_COMMAND: &[u8] = b"PUSH_BATCH";
+const LEASE_COMMAND: &[u8] = b"LEASE";
+const ACK_LEASE_COMMAND: &[u8] = b"ACK_LEASE";
+const COMMIT_OFFSET_COMMAND: &[u8] = b"COMMIT_OFFSET";
+const FETCH_COMMITTED_COMMAND: &[u8] = b"FETCH_COMMITTED";
+const CONSUMER_PULL_COMMAND: &[u8] = b"CONSUMER_PULL";
+const RELOAD_COMMAND: &[u8] = b"RELOAD";
+const DELETE_BROKER_COMMAND: &[u8] = b"DELETE_BROKER";
+const LIST_BROKERS_COMMAND: &[u8] = b"LIST_BROKERS";
+const PEEK_COMMAND: &[u8] = b"PEEK";
+const PUSH_KEYED_COMMAND: &[u8] = b"PUSH_KEYED";
+const COMPACT_COMMAND: &[u8] = b"COMPACT";
+const RELOAD_CONFIG_COMMAND: &[u8] = b"RELOAD_CONFIG";
+const SEEK_TIME_COMMAND: &[u8] = b"SEEK_TIME";
+const MUX_COMMAND: &[u8] = b"MUX";
+
+type FetchedMessage = (u64, Vec<u8>);
+// PULL 被拒绝时服务端用它代替头部的字节数，之后是一个错误回复帧
+pub(crate) const PULL_REFUSED: u32 = u32::MAX;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BASE_BACKOFF: Duration = Duration::from_millis(100);
+// 解压单条记录的大小上限，防止损坏或恶意的记录占用过多内存
+const MAX_DECOMPRESSED_RECORD: usize = 1024 * 1024 * 1024;
+
+/// Result of an online integrity check over a broker's sealed segments
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub segments: u32,
+    pub records: u64,
+    pub errors: Vec<String>,
+}
+
+/// Outcome of compacting a keyed broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactReport {
+    /// Records left after compaction; they now have offsets `0..kept`
+    pub kept: u64,
+    /// Records removed because a later record has the same key
+    pub removed: u64,
+}
+
+/// Where the server stored a pushed message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushAck {
+    /// Offset assigned to the record; fetch from it to read the message back
+    pub offset: u64,
+    /// Server append time in milliseconds since the epoch
+    pub timestamp: i64,
+}
+
+/// An active connection as seen by the server; byte counts are from the server's side
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    /// Id accepted by [`Client::kick_connection`]
+    pub id: u64,
+    pub peer: String,
+    /// `"admin"` for the admin key, `"client"` otherwise
+    pub identity: String,
+    /// Milliseconds since the epoch
+    pub connected_at: i64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// On-disk sizes of one segment of a broker, as returned by [`Client::list_segments`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInfo {
+    /// Offset of the first record in the segment
+    pub base_offset: u64,
+    pub data_bytes: u64,
+    /// Size of the index file, including preallocated space not used yet
+    pub index_bytes: u64,
+    /// Index entries written before the end marker
+    pub index_entries: u64,
+    /// Whether this is the segment new records are appended to
+    pub active: bool,
+}
+
+/// A broker and how far it has progressed, as returned by [`Client::list_brokers`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerInfo {
+    pub name: String,
+    /// Offset of the first record in the active segment
+    pub base_offset: u64,
+    /// Offset the next pushed record will get
+    pub position_offset: u64,
+    /// Bytes written to the active segment's data file
+    pub data_len: u64,
+}
+
+/// Records leased to one consumer by [`Client::lease_fetch`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    /// Pass to [`Client::ack_lease`] once the records are processed
+    pub id: u64,
+    /// `(offset, record)` pairs in the same form as [`Client::fetch_batch`] returns them
+    pub records: Vec<(u64, Vec<u8>)>,
+}
+
+/// A server log event delivered by [`Client::stream_logs`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEvent {
+    /// Milliseconds since the epoch
+    pub timestamp: i64,
+    /// `DEBUG`, `INFO`, `WARN` or `ERROR`
+    pub level: String,
+    pub message: String,
+}
 
-const PUSH_COMMAND: &[u8] = b"PUSH";
-const PULL_COMMAND: &[u8] = b"PULL";
+/// Returned when the server does not answer, or does not accept a request, within the
+/// client's read or write timeout. The connection is dropped and the next call reconnects.
+#[derive(Debug)]
+pub struct TimeoutError(io::Error);
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request timed out: {}", self.0)
+    }
+}
+
+impl Error for TimeoutError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+/// Errors detected by the client itself rather than reported by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// A fetched record does not match its stored CRC32, so it was corrupted on disk or in
+    /// transit; see [`ClientBuilder::verify_checksums`]
+    ChecksumMismatch { offset: u64 },
+    /// A push was attempted on a client created with [`ClientMode::ReadOnly`]; nothing was sent
+    ReadOnly,
+    /// A push named no broker; nothing was sent
+    EmptyBroker,
+    /// A push carried an empty payload; nothing was sent
+    EmptyPayload,
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::ChecksumMismatch { offset } => write!(f, "checksum mismatch in record at offset {}", offset),
+            ClientError::ReadOnly => write!(f, "client is read-only and cannot push"),
+            ClientError::EmptyBroker => write!(f, "empty broker"),
+            ClientError::EmptyPayload => write!(f, "empty payload"),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+/// Which requests a [`Client`] may send
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientMode {
+    /// Every request is allowed
+    #[default]
+    ReadWrite,
+    /// Pushes fail with [`ClientError::ReadOnly`] before anything is sent, so a consumer
+    /// cannot append records by mistake even when its key would allow it. Fetching, peeking,
+    /// subscribing and committing offsets work as usual. Enforced by the client only.
+    ReadOnly,
+}
+
+// 向 broker 追加记录的命令，只读客户端拒绝发送
+const PUSH_COMMANDS: [&[u8]; 6] = [
+    PUSH_COMMAND,
+    PUSH_ID_COMMAND,
+    PUSH_HEADERS_COMMAND,
+    PUSH_COMPRESSED_COMMAND,
+    PUSH_BATCH_COMMAND,
+    PUSH_KEYED_COMMAND,
+];
+
+/// Why the server refused a request. Every reply starts with a status byte: 0 for success,
+/// otherwise one of these codes followed by the server's explanation as text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StatusCode {
+    /// The broker does not exist and cannot be created under that name (`NO_BROKER`)
+    NoBroker = 1,
+    /// The key is invalid or not permitted on the broker (`UNAUTHORIZED`, `FORBIDDEN`, `ADMIN_ONLY`)
+    AuthFailed = 2,
+    /// The request frame or its body is malformed (`BAD_REQUEST: ...`, `BAD_META`, ...)
+    BadRequest = 3,
+    /// A server limit was hit (`BROKER_LIMIT_REACHED`, `BROKER_QUOTA_EXCEEDED`,
+    /// `MESSAGE_TOO_LARGE`, `RESPONSE_TOO_LARGE`)
+    Limit = 4,
+    /// Any other refusal, such as `DISK_FULL` or `NO_CONSUMERS`, or a reply the client did
+    /// not expect; see [`ServerError::detail`]
+    Other = 255,
+}
+
+impl StatusCode {
+    pub(crate) fn from_status(status: u8) -> Self {
+        match status {
+            1 => StatusCode::NoBroker,
+            2 => StatusCode::AuthFailed,
+            3 => StatusCode::BadRequest,
+            4 => StatusCode::Limit,
+            _ => StatusCode::Other,
+        }
+    }
+}
+
+/// A request the server answered with an error instead of a result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerError {
+    pub code: StatusCode,
+    /// The server's explanation, e.g. `BAD_REQUEST: unknown command FOO`
+    pub detail: String,
+}
+
+impl ServerError {
+    // 状态为成功但内容不是该命令应有的格式
+    pub(crate) fn unexpected(reply: &[u8]) -> Self {
+        ServerError { code: StatusCode::Other, detail: String::from_utf8_lossy(reply).into_owned() }
+    }
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.detail)
+    }
+}
+
+impl Error for ServerError {}
 
 pub struct Client {
     server_ip: String,
     server_port: u16,
     key: Vec<u8>,
-    connection: Mutex<Option<TcpStream>>,
+    connection: Arc<Mutex<Option<Transport>>>, // keepalive 线程通过弱引用访问
+    cache: Option<Mutex<RecordCache>>,
+    compression: Option<(Codec, usize)>,
+    last_pressure: AtomicU8,
+    max_retries: u32,
+    base_backoff: Duration,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    verify_checksums: bool, // 校验并去掉记录前的 CRC32，用于开启 checksums 的 broker
+    decompress_records: bool, // 按记录前的编码字节解压，用于开启 record_codecs 的 broker
+    mode: ClientMode,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+/// Builds a [`Client`] with optional features such as the local record cache
+pub struct ClientBuilder {
+    server_ip: String,
+    server_port: u16,
+    key: String,
+    cache_size: usize,
+    compression: Option<(Codec, usize)>,
+    max_retries: u32,
+    base_backoff: Duration,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    verify_checksums: bool, // 校验并去掉记录前的 CRC32，用于开启 checksums 的 broker
+    decompress_records: bool, // 按记录前的编码字节解压，用于开启 record_codecs 的 broker
+    keepalive: Option<Duration>, // 空闲连接上发送 PING 的间隔
+    mode: ClientMode,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+}
+
+impl ClientBuilder {
+    /// Restricts which requests the client may send; see [`ClientMode::ReadOnly`]
+    pub fn mode(mut self, mode: ClientMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Fails a request with [`TimeoutError`] when the server sends nothing for `timeout`
+    /// while the client waits for a response. Subscriptions and log streams are not affected.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails a request with [`TimeoutError`] when the server stops accepting data for `timeout`
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Retries a request up to `max_retries` times after a broken connection, waiting
+    /// `base_backoff` before the first retry and twice as long before each following one.
+    /// Zero disables retries; the broken connection is still dropped.
+    pub fn retries(mut self, max_retries: u32, base_backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.base_backoff = base_backoff;
+        self
+    }
+
+    /// Keeps up to `records` recently fetched records for [`Client::fetch_cached`];
+    /// zero disables the cache
+    pub fn cache_size(mut self, records: usize) -> Self {
+        self.cache_size = records;
+        self
+    }
+
+    /// Compresses PUSH payloads of at least `min_size` bytes with `codec`; smaller
+    /// payloads are sent as-is to avoid the compression overhead
+    pub fn compression(mut self, codec: Codec, min_size: usize) -> Self {
+        self.compression = Some((codec, min_size));
+        self
+    }
+
+    /// Checks the CRC32 that brokers with `checksums = true` store in front of each record
+    /// and strips it from fetched records. A record that does not match fails the fetch with
+    /// [`ClientError::ChecksumMismatch`]. Off by default since it hashes every fetched byte;
+    /// enable it only for brokers that store checksums.
+    pub fn verify_checksums(mut self, verify: bool) -> Self {
+        self.verify_checksums = verify;
+        self
+    }
+
+    /// Decodes records fetched from brokers with `record_codecs = true`, which store each
+    /// record as `[codec: u8][payload]` and keep compressed pushes compressed. Records are
+    /// returned without the codec byte, decompressed when needed. Checksums are verified first
+    /// when [`ClientBuilder::verify_checksums`] is also enabled.
+    pub fn decompress_records(mut self, decompress: bool) -> Self {
+        self.decompress_records = decompress;
+        self
+    }
+
+    /// Sends a PING every `interval` on the open connection from a background thread, so
+    /// load balancers and firewalls do not drop it while the client idles between requests.
+    /// A ping is skipped while a request is using the connection; a failed ping drops the
+    /// connection and the next request reconnects. The thread stops when the client is dropped.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Connects over TLS, verifying the server's certificate against `tls`. The server
+    /// must have a `[tls]` section; a plaintext server fails the handshake.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Creates the client
+    pub fn build(self) -> Client {
+        let mut client = Client::new(&self.server_ip, self.server_port, &self.key);
+        client.compression = self.compression;
+        client.max_retries = self.max_retries;
+        client.base_backoff = self.base_backoff;
+        client.read_timeout = self.read_timeout;
+        client.write_timeout = self.write_timeout;
+        client.verify_checksums = self.verify_checksums;
+        client.decompress_records = self.decompress_records;
+        client.mode = self.mode;
+        #[cfg(feature = "tls")]
+        {
+            client.tls = self.tls;
+        }
+        if self.cache_size > 0 {
+            client.cache = Some(Mutex::new(RecordCache::new(self.cache_size)));
+        }
+        if let Some(interval) = self.keepalive {
+            client.spawn_keepalive(interval);
+        }
+        client
+    }
 }
 
 impl Client {
@@ -20,76 +441,875 @@ impl Client {
             server_ip: server_ip.to_string(),
             server_port,
             key: key.as_bytes().to_vec(),
-            connection: Mutex::new(None),
+            connection: Arc::new(Mutex::new(None)),
+            cache: None,
+            compression: None,
+            last_pressure: AtomicU8::new(0),
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_backoff: DEFAULT_BASE_BACKOFF,
+            read_timeout: None,
+            write_timeout: None,
+            verify_checksums: false,
+            decompress_records: false,
+            mode: ClientMode::ReadWrite,
+            #[cfg(feature = "tls")]
+            tls: None,
         }
     }
 
+    /// Creates a client for consumers that refuses to push; see [`ClientMode::ReadOnly`]
+    pub fn new_consumer(server_ip: &str, server_port: u16, key: &str) -> Self {
+        Self::builder(server_ip, server_port, key).mode(ClientMode::ReadOnly).build()
+    }
+
+    /// Starts building a client with non-default options
+    pub fn builder(server_ip: &str, server_port: u16, key: &str) -> ClientBuilder {
+        ClientBuilder {
+            server_ip: server_ip.to_string(),
+            server_port,
+            key: key.to_string(),
+            cache_size: 0,
+            compression: None,
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_backoff: DEFAULT_BASE_BACKOFF,
+            read_timeout: None,
+            write_timeout: None,
+            verify_checksums: false,
+            decompress_records: false,
+            keepalive: None,
+            mode: ClientMode::ReadWrite,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    // 后台线程定期在已打开的连接上发送 PING，不主动建立连接；客户端释放后线程退出
+    fn spawn_keepalive(&self, interval: Duration) {
+        let connection = Arc::downgrade(&self.connection);
+        let message = build_message(&self.key, PING_COMMAND, &[], &[], None);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let Some(connection) = connection.upgrade() else {
+                break;
+            };
+            // 连接正在被请求使用时不需要保活
+            let Ok(mut connection) = connection.try_lock() else {
+                continue;
+            };
+            if let Some(stream) = connection.as_mut() {
+                if !matches!(exchange(stream, &message), Ok(Ok(response)) if response == b"PONG") {
+                    *connection = None;
+                }
+            }
+        });
+    }
+
+    /// Drops the current connection; the next request reconnects
+    pub(crate) fn disconnect(&self) {
+        *self.connection.lock().unwrap() = None;
+    }
+
     /// Connects to the server
     fn connect(&self) -> Result<(), Box<dyn Error>> {
         let mut connection = self.connection.lock().unwrap();
         if connection.is_none() {
-            let stream = TcpStream::connect((self.server_ip.as_str(), self.server_port))?;
-            *connection = Some(stream);
+            let stream = connect_any((unbracket(&self.server_ip), self.server_port))?;
+            stream.set_read_timeout(self.read_timeout)?;
+            stream.set_write_timeout(self.write_timeout)?;
+            #[cfg(feature = "tls")]
+            let transport = match &self.tls {
+                Some(tls) => Transport::tls(stream, tls)?,
+                None => Transport::plain(stream),
+            };
+            #[cfg(not(feature = "tls"))]
+            let transport = Transport::plain(stream);
+            *connection = Some(transport);
         }
         Ok(())
     }
 
-    /// Sends a message to the queue
-    pub fn send_push_message(&self, broker_name: &str, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-        self.connect()?;
-        let mut connection = self.connection.lock().unwrap();
-        let stream = connection.as_mut().unwrap();
+    /// Runs `exchange` on the cached connection, dropping the connection when it turns out to
+    /// be broken. Failed connects are always retried since nothing was sent; a request that
+    /// may already have reached the server is only sent again when it is `idempotent`.
+    fn with_retries<T>(
+        &self,
+        idempotent: bool,
+        mut exchange: impl FnMut(&mut Transport) -> io::Result<T>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut attempt = 0;
+        loop {
+            let (error, retryable) = match self.connect() {
+                Ok(()) => {
+                    let mut connection = self.connection.lock().unwrap();
+                    match exchange(connection.as_mut().unwrap()) {
+                        Ok(value) => return Ok(value),
+                        Err(e) => {
+                            // 连接已不可用，下次请求重新连接
+                            *connection = None;
+                            (e, idempotent)
+                        }
+                    }
+                }
+                Err(e) => match e.downcast::<io::Error>() {
+                    Ok(e) => (*e, true),
+                    Err(e) => return Err(e),
+                },
+            };
+            // 超时的请求不重发，服务端可能仍在处理
+            if is_timeout(&error) {
+                return Err(Box::new(TimeoutError(error)));
+            }
+            if !retryable || !is_connection_error(&error) || attempt >= self.max_retries {
+                return Err(error.into());
+            }
+            std::thread::sleep(self.base_backoff * 2u32.saturating_pow(attempt));
+            attempt += 1;
+        }
+    }
 
+    /// Sends a message to the queue. A push is not sent again after a broken connection,
+    /// since the server may already have appended it; the next call reconnects. A refused
+    /// push fails with a [`ServerError`] whose code says why. An empty broker name or payload
+    /// fails with [`ClientError`] before anything is sent.
+    pub fn send_push_message(&self, broker_name: &str, payload: &[u8]) -> Result<PushAck, Box<dyn Error>> {
+        if payload.is_empty() {
+            return Err(ClientError::EmptyPayload.into());
+        }
         let broker_name_bytes = broker_name.as_bytes();
-        let message = self.build_message(PUSH_COMMAND, broker_name_bytes, payload, None)?;
+        let message = match self.compression {
+            Some((codec, min_size)) if payload.len() >= min_size => {
+                self.build_message(PUSH_COMPRESSED_COMMAND, broker_name_bytes, &compress(codec, payload), None)?
+            }
+            _ => self.build_message(PUSH_COMMAND, broker_name_bytes, payload, None)?,
+        };
 
-        // Send message length and message body
-        stream.write_all(&(message.len() as u32).to_be_bytes())?;
-        stream.write_all(&message)?;
+        let response = self.with_retries(false, |stream| exchange(stream, &message))??;
+        let (ack, pressure) = parse_push_response(&response)?;
+        self.last_pressure.store(pressure, Ordering::Relaxed);
+        Ok(ack)
+    }
 
-        // Receive response length
-        let mut response_length_bytes = [0u8; 4];
-        stream.read_exact(&mut response_length_bytes)?;
-        let response_length = u32::from_be_bytes(response_length_bytes);
+    /// Sends several messages in one request; the server appends them in order with as few
+    /// file writes as it can. Returns how many were stored: fewer than `payloads.len()` means
+    /// the server stopped part way (for example on a full disk) and the rest were not stored.
+    /// Like [`Client::send_push_message`], a batch is not sent again after a broken connection.
+    pub fn send_push_batch(&self, broker_name: &str, payloads: &[&[u8]]) -> Result<usize, Box<dyn Error>> {
+        let mut body = Vec::with_capacity(4 + payloads.iter().map(|p| p.len() + 4).sum::<usize>());
+        body.extend_from_slice(&(payloads.len() as u32).to_be_bytes());
+        for payload in payloads {
+            body.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            body.extend_from_slice(payload);
+        }
+        let message = self.build_message(PUSH_BATCH_COMMAND, broker_name.as_bytes(), &body, None)?;
+        let response = self.with_retries(false, |stream| exchange(stream, &message))??;
+        match response.strip_prefix(b"OK") {
+            Some(count) if count.len() == 4 => Ok(u32::from_be_bytes(count.try_into().unwrap()) as usize),
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
 
-        // Receive response content
-        let mut response = vec![0u8; response_length as usize];
-        stream.read_exact(&mut response)?;
-        Ok(response)
+    /// Pressure level (0-255) reported with the last successful push; 0 when the server
+    /// does not report pressure. Producers can slow down as it rises, before pushes fail.
+    pub fn last_push_pressure(&self) -> u8 {
+        self.last_pressure.load(Ordering::Relaxed)
     }
 
-    /// Fetches messages from the queue
-    pub fn fetch_messages(&self, broker_name: &str, offset: u64) -> Result<Option<(u64, Vec<u8>)>, Box<dyn Error>> {
-        self.connect()?;
-        let mut connection = self.connection.lock().unwrap();
-        let stream = connection.as_mut().unwrap();
+    /// Sends a message tagged with a producer-assigned id. Brokers with dedup enabled
+    /// answer `DUPLICATE` instead of appending again when the id was already seen.
+    pub fn send_push_message_with_id(&self, broker_name: &str, message_id: &str, payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut body = Vec::with_capacity(2 + message_id.len() + payload.len());
+        body.extend_from_slice(&(message_id.len() as u16).to_be_bytes());
+        body.extend_from_slice(message_id.as_bytes());
+        body.extend_from_slice(payload);
+        let message = self.build_message(PUSH_ID_COMMAND, broker_name.as_bytes(), &body, None)?;
+        self.request(&message)
+    }
+
+    /// Sends a message with headers to a broker that has `headers = true`
+    pub fn send_push_with_headers(&self, broker_name: &str, headers: &[(String, String)], payload: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut body = encode_headers(headers);
+        body.extend_from_slice(payload);
+        let message = self.build_message(PUSH_HEADERS_COMMAND, broker_name.as_bytes(), &body, None)?;
+        self.request(&message)
+    }
+
+    /// Sends a message with a key to a broker that has `keyed = true`. [`Client::compact`]
+    /// later keeps only the most recent message for each key. Fetched records start with the
+    /// key; split it off with [`keys::decode_key`].
+    pub fn send_push_keyed(&self, broker_name: &str, key: &str, payload: &[u8]) -> Result<PushAck, Box<dyn Error>> {
+        let mut body = encode_key(key)?;
+        body.extend_from_slice(payload);
+        let message = self.build_message(PUSH_KEYED_COMMAND, broker_name.as_bytes(), &body, None)?;
+        let response = self.with_retries(false, |stream| exchange(stream, &message))??;
+        Ok(parse_push_response(&response)?.0)
+    }
+
+    /// Returns the offset of the first record appended at or after `unix_millis`, or the
+    /// broker's next offset when every record is older. Feed it to [`Client::fetch_batch`] or
+    /// [`Client::stream`] to consume from a point in time; the broker needs `timestamps = true`.
+    pub fn seek_timestamp(&self, broker_name: &str, unix_millis: i64) -> Result<u64, Box<dyn Error>> {
+        let message = self.build_message(SEEK_TIME_COMMAND, broker_name.as_bytes(), &[], Some(unix_millis as u64))?;
+        let response = self.with_retries(true, |stream| exchange(stream, &message))??;
+        match response.strip_prefix(b"OK") {
+            Some(offset) if offset.len() == 8 => Ok(u64::from_be_bytes(offset.try_into().unwrap())),
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Fetches only the headers of the record at `offset`, without its payload
+    pub fn fetch_headers(&self, broker_name: &str, offset: u64) -> Result<Headers, Box<dyn Error>> {
+        let message = self.build_message(HEADERS_COMMAND, broker_name.as_bytes(), &[], Some(offset))?;
+        let response = self.request(&message)?;
+        match response.strip_prefix(b"OK") {
+            Some(block) => Ok(decode_headers(block)?.0),
+            None => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Serializes `value` as JSON and pushes it
+    pub fn send_push_json<T: Serialize>(&self, broker_name: &str, value: &T) -> Result<PushAck, Box<dyn Error>> {
+        let payload = serde_json::to_vec(value)?;
+        self.send_push_message(broker_name, &payload)
+    }
+
+    /// Serializes `value` as JSON and pushes it with a `content-type: application/json`
+    /// header; the broker must have `headers = true`
+    pub fn send_push_json_tagged<T: Serialize>(&self, broker_name: &str, value: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+        let payload = serde_json::to_vec(value)?;
+        let headers = [("content-type".to_string(), "application/json".to_string())];
+        self.send_push_with_headers(broker_name, &headers, &payload)
+    }
+
+    /// Fetches a message and deserializes its payload from JSON
+    pub fn fetch_json<T: DeserializeOwned>(&self, broker_name: &str, offset: u64) -> Result<Option<(u64, T)>, Box<dyn Error>> {
+        match self.fetch_messages(broker_name, offset)? {
+            Some((new_offset, payload)) => Ok(Some((new_offset, serde_json::from_slice(&payload)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches the record at `offset`. [`EARLIEST`] (0) fetches the oldest record still stored
+    /// and [`LATEST`] the most recent one.
+    pub fn fetch_messages(&self, broker_name: &str, offset: u64) -> Result<Option<FetchedMessage>, Box<dyn Error>> {
+        Ok(self.fetch_batch(broker_name, offset, None)?.into_iter().next())
+    }
 
+    /// Consumes `broker_name` continuously from `start_offset` ([`EARLIEST`] starts at the oldest
+    /// stored record, [`LATEST`] at the most recent one).
+    /// The returned iterator yields each record once, in order, and waits for new records
+    /// once it has caught up. A failed request is yielded as an `Err` item and retried on
+    /// the next call.
+    pub fn stream(&self, broker_name: &str, start_offset: u64) -> MessageStream<'_> {
+        MessageStream::new(self, broker_name, start_offset)
+    }
+
+    /// Fetches the records one PULL from `offset` returns: the record at `offset` and as many
+    /// following ones as fit in the server's `pull_max_limit`, but at most `max_count` records
+    /// when it is set. The next offset to fetch is the last returned offset plus one.
+    pub fn fetch_batch(&self, broker_name: &str, offset: u64, max_count: Option<u32>) -> Result<Vec<FetchedMessage>, Box<dyn Error>> {
+        Ok(self.fetch_with_next(broker_name, offset, max_count)?.0)
+    }
+
+    /// Fetches the records one PULL from `offset` returns, like [`Client::fetch_batch`], but
+    /// with the reply capped at `max_bytes` for this request only. The cap applies to the framed
+    /// records and can only lower the server's `pull_max_limit`. The first record is always
+    /// returned, even if it is larger than `max_bytes`.
+    pub fn fetch_messages_limited(&self, broker_name: &str, offset: u64, max_bytes: u32) -> Result<Vec<FetchedMessage>, Box<dyn Error>> {
+        let mut data = u32::MAX.to_be_bytes().to_vec();
+        data.extend_from_slice(&max_bytes.to_be_bytes());
+        let message = self.build_message(PULL_COMMAND, broker_name.as_bytes(), &data, Some(offset))?;
+        Ok(self.pull(&message)?.0)
+    }
+
+    // 与 fetch_batch 相同，同时返回服务端给出的下一个偏移；没有记录时它可能越过已被清理的记录
+    pub(crate) fn fetch_with_next(&self, broker_name: &str, offset: u64, max_count: Option<u32>) -> Result<(Vec<FetchedMessage>, u64), Box<dyn Error>> {
         let broker_name_bytes = broker_name.as_bytes();
-        let message = self.build_message(PULL_COMMAND, broker_name_bytes, &[], Some(offset))?;
+        let count_bytes = max_count.map(u32::to_be_bytes);
+        let message = self.build_message(PULL_COMMAND, broker_name_bytes, count_bytes.as_ref().map_or(&[][..], |bytes| &bytes[..]), Some(offset))?;
+        self.pull(&message)
+    }
 
-        // Send request
-        stream.write_all(&(message.len() as u32).to_be_bytes())?;
-        stream.write_all(&message)?;
+    // 发送 PULL 或 CONSUMER_PULL，返回校验后的记录和服务端给出的下一个偏移
+    fn pull(&self, message: &[u8]) -> Result<(Vec<FetchedMessage>, u64), Box<dyn Error>> {
+        // PULL 不改变服务端状态，连接断开后可以安全地重发
+        let (records, next_offset) = self.with_retries(true, |stream| pull_batch(stream, message))??;
+        Ok((self.verify_records(records)?, next_offset))
+    }
 
-        // Read response length
-        let mut response_length_bytes = [0u8; 4];
-        stream.read_exact(&mut response_length_bytes)?;
-        let response_length = u32::from_be_bytes(response_length_bytes);
+    // 开启校验时检查并去掉每条记录的 CRC32
+    fn verify_records(&self, records: Vec<FetchedMessage>) -> Result<Vec<FetchedMessage>, Box<dyn Error>> {
+        if !self.verify_checksums && !self.decompress_records {
+            return Ok(records);
+        }
+        records
+            .into_iter()
+            .map(|(offset, mut record)| {
+                if self.verify_checksums {
+                    record = match verify_checksum(&record) {
+                        Some(verified) => verified.to_vec(),
+                        None => return Err(ClientError::ChecksumMismatch { offset }.into()),
+                    };
+                }
+                if self.decompress_records {
+                    record = decode_record(&record, MAX_DECOMPRESSED_RECORD)?;
+                }
+                Ok((offset, record))
+            })
+            .collect()
+    }
 
-        if response_length == 0 {
-            return Ok(None); // No more messages
+    /// Leases the next unprocessed records of a broker to this consumer for `timeout`, for
+    /// work-queue consumption by competing consumers. Other `lease_fetch` calls skip the leased
+    /// records until the lease expires; call [`Client::ack_lease`] after processing them, or they
+    /// are leased again (at-least-once delivery). Returns `None` when no records are available.
+    pub fn lease_fetch(&self, broker_name: &str, timeout: Duration) -> Result<Option<Lease>, Box<dyn Error>> {
+        let timeout_ms = timeout.as_millis().clamp(1, u64::MAX as u128) as u64;
+        let message = self.build_message(LEASE_COMMAND, broker_name.as_bytes(), &[], Some(timeout_ms))?;
+        let response = self.request(&message)?;
+        if response == b"EMPTY" {
+            return Ok(None);
         }
+        let Some(body) = response.strip_prefix(b"LEASE") else {
+            return Err(ServerError::unexpected(&response).into());
+        };
+        let mut cursor = Cursor::new(body);
+        let id = cursor.read_u64::<BigEndian>()?;
+        let mut records = Vec::new();
+        while (cursor.position() as usize) < body.len() {
+            let len = cursor.read_u32::<BigEndian>()? as usize;
+            let offset = cursor.read_u64::<BigEndian>()?;
+            let mut record = vec![0u8; len];
+            cursor.read_exact(&mut record)?;
+            records.push((offset, record));
+        }
+        Ok(Some(Lease { id, records: self.verify_records(records)? }))
+    }
 
-        // Read new offset
-        let mut new_offset_bytes = [0u8; 8];
-        stream.read_exact(&mut new_offset_bytes)?;
-        let new_offset = u64::from_be_bytes(new_offset_bytes);
+    /// Marks the records of a lease as processed so they are never leased again. Fails with
+    /// `NOT_FOUND` when the lease expired first; its records may then be processed elsewhere.
+    pub fn ack_lease(&self, broker_name: &str, lease_id: u64) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(ACK_LEASE_COMMAND, broker_name.as_bytes(), &[], Some(lease_id))?;
+        let response = self.request(&message)?;
+        if response == b"OK" {
+            Ok(())
+        } else {
+            Err(ServerError::unexpected(&response).into())
+        }
+    }
 
-        // Read message body
-        let mut message_data = vec![0u8; response_length as usize];
-        stream.read_exact(&mut message_data)?;
+    /// Fetches records from `start_offset` onwards until reaching the head of the broker,
+    /// `max_records` records, or `max_bytes` of payload, whichever comes first. Returns the
+    /// records and the offset to continue from. A record that would exceed `max_bytes` is left
+    /// for the next call, so a single record larger than `max_bytes` is never returned.
+    pub fn fetch_all(&self, broker_name: &str, start_offset: u64, max_records: usize, max_bytes: usize) -> Result<(Vec<FetchedMessage>, u64), Box<dyn Error>> {
+        let mut records = Vec::new();
+        let mut bytes = 0;
+        let mut next = start_offset;
+        while records.len() < max_records {
+            let remaining = (max_records - records.len()).min(u32::MAX as usize) as u32;
+            let batch = self.fetch_batch(broker_name, next, Some(remaining))?;
+            if batch.is_empty() {
+                break;
+            }
+            for (offset, record) in batch {
+                if records.len() == max_records || bytes + record.len() > max_bytes {
+                    return Ok((records, next));
+                }
+                bytes += record.len();
+                next = offset + 1;
+                records.push((offset, record));
+            }
+        }
+        Ok((records, next))
+    }
+
+    /// Fetches the record at `offset`, serving it from the local cache when it was
+    /// fetched recently. Without a cache configured this is the same as `fetch_messages`.
+    pub fn fetch_cached(&self, broker_name: &str, offset: u64) -> Result<Option<FetchedMessage>, Box<dyn Error>> {
+        if let Some(cache) = &self.cache {
+            if let Some(record) = cache.lock().unwrap().get(broker_name, offset) {
+                return Ok(Some((offset, record)));
+            }
+        }
+        let fetched = self.fetch_messages(broker_name, offset)?;
+        if let (Some(cache), Some((record_offset, record))) = (&self.cache, &fetched) {
+            cache
+                .lock()
+                .unwrap()
+                .insert(broker_name, *record_offset, record.clone());
+        }
+        Ok(fetched)
+    }
+
+    /// Reads exactly the record at `offset`, or `None` when there is no record there. Unlike
+    /// [`Client::fetch_messages`], [`EARLIEST`] and [`LATEST`] are not resolved: offset 0 is
+    /// record 0 even after retention deleted it.
+    pub fn peek(&self, broker_name: &str, offset: u64) -> Result<Option<FetchedMessage>, Box<dyn Error>> {
+        let message = self.build_message(PEEK_COMMAND, broker_name.as_bytes(), &[], Some(offset))?;
+        let response = self.with_retries(true, |stream| exchange(stream, &message))??;
+        if response.is_empty() {
+            return Ok(None);
+        }
+        match response.split_first_chunk::<8>() {
+            Some((record_offset, record)) if u64::from_be_bytes(*record_offset) == offset => {
+                Ok(self.verify_records(vec![(offset, record.to_vec())])?.pop())
+            }
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Fetches the record at `offset` as pretty-printed JSON for debugging; only works for
+    /// brokers with `content_type = "json"` and requires the admin key. The text is a
+    /// reformatted view, not the stored bytes.
+    pub fn fetch_debug(&self, broker_name: &str, offset: u64) -> Result<Option<String>, Box<dyn Error>> {
+        let message = self.build_message(DEBUG_PULL_COMMAND, broker_name.as_bytes(), &[], Some(offset))?;
+        let response = self.request(&message)?;
+        if response == b"NOT_FOUND" {
+            return Ok(None);
+        }
+        match response.strip_prefix(b"DEBUG") {
+            Some(pretty) => Ok(Some(String::from_utf8(pretty.to_vec())?)),
+            None => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Reads the last `n` bytes of a broker's active data file as raw bytes, for debugging.
+    /// The result is clamped to the start of the segment and is not guaranteed to begin on a
+    /// record boundary; the flag is `true` when it starts at the beginning of the data file.
+    pub fn tail_bytes(&self, broker_name: &str, n: u64) -> Result<(Vec<u8>, bool), Box<dyn Error>> {
+        let message = self.build_message(TAIL_BYTES_COMMAND, broker_name.as_bytes(), &[], Some(n))?;
+        let response = self.request(&message)?;
+        match response.strip_prefix(b"TAIL") {
+            Some([from_start, bytes @ ..]) => Ok((bytes.to_vec(), *from_start == 1)),
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
 
-        Ok(Some((new_offset, message_data)))
+    /// Rebuilds a broker's index from the record headers in its data files and returns the
+    /// number of records indexed; requires the admin key
+    pub fn rebuild_index(&self, broker_name: &str) -> Result<u64, Box<dyn Error>> {
+        let message = self.build_message(REBUILD_INDEX_COMMAND, broker_name.as_bytes(), &[], None)?;
+        let response = self.request(&message)?;
+        match response.strip_prefix(b"OK") {
+            Some(count) if count.len() == 8 => Ok(u64::from_be_bytes(count.try_into().unwrap())),
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Rewrites a keyed broker's segments so only the most recent record for each key is
+    /// left, plus every record pushed without a key. The remaining records are renumbered
+    /// from offset 0, so consumers must start over. Requires the admin key.
+    pub fn compact(&self, broker_name: &str) -> Result<CompactReport, Box<dyn Error>> {
+        let message = self.build_message(COMPACT_COMMAND, broker_name.as_bytes(), &[], None)?;
+        let response = self.request(&message)?;
+        match response.strip_prefix(b"OK") {
+            Some(counts) if counts.len() == 16 => Ok(CompactReport {
+                kept: u64::from_be_bytes(counts[..8].try_into().unwrap()),
+                removed: u64::from_be_bytes(counts[8..].try_into().unwrap()),
+            }),
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Makes the server reopen a broker's files after they were changed on disk, e.g. by
+    /// restoring a backup into its directory, and returns the next offset it will assign.
+    /// Requires the admin key.
+    pub fn reload_broker(&self, broker_name: &str) -> Result<u64, Box<dyn Error>> {
+        let message = self.build_message(RELOAD_COMMAND, broker_name.as_bytes(), &[], None)?;
+        let response = self.request(&message)?;
+        match response.strip_prefix(b"OK") {
+            Some(offset) if offset.len() == 8 => Ok(u64::from_be_bytes(offset.try_into().unwrap())),
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Deletes a broker and all of its files on the server. Fails with `BROKER_BUSY` while
+    /// another request or a subscriber is using the broker. Requires the admin key.
+    pub fn delete_broker(&self, broker_name: &str) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(DELETE_BROKER_COMMAND, broker_name.as_bytes(), &[], None)?;
+        let response = self.request(&message)?;
+        if response != b"OK" {
+            return Err(ServerError::unexpected(&response).into());
+        }
+        Ok(())
+    }
+
+    /// Lists the brokers this key may read, sorted by name, with their current offsets
+    pub fn list_brokers(&self) -> Result<Vec<BrokerInfo>, Box<dyn Error>> {
+        let message = self.build_message(LIST_BROKERS_COMMAND, &[], &[], None)?;
+        let (response, frames) = self.with_retries(true, |stream| {
+            let response = exchange(stream, &message)?;
+            let mut frames = Vec::new();
+            if response.as_deref() == Ok(b"OK") {
+                // 每个 broker 一帧，以长度为 0 的帧结束
+                loop {
+                    let frame = read_frame(stream)?;
+                    if frame.is_empty() {
+                        break;
+                    }
+                    frames.push(frame);
+                }
+            }
+            Ok((response, frames))
+        })?;
+        let response = response?;
+        if response != b"OK" {
+            return Err(ServerError::unexpected(&response).into());
+        }
+        let mut brokers = Vec::with_capacity(frames.len());
+        for frame in frames {
+            if frame.len() < 24 {
+                return Err(format!("malformed broker entry of {} bytes", frame.len()).into());
+            }
+            brokers.push(BrokerInfo {
+                base_offset: u64::from_be_bytes(frame[0..8].try_into().unwrap()),
+                position_offset: u64::from_be_bytes(frame[8..16].try_into().unwrap()),
+                data_len: u64::from_be_bytes(frame[16..24].try_into().unwrap()),
+                name: String::from_utf8(frame[24..].to_vec())?,
+            });
+        }
+        Ok(brokers)
+    }
+
+    /// Lists the segments of a broker with their data and index file sizes, oldest first;
+    /// requires the admin key
+    pub fn list_segments(&self, broker_name: &str) -> Result<Vec<SegmentInfo>, Box<dyn Error>> {
+        let message = self.build_message(SEGMENTS_COMMAND, broker_name.as_bytes(), &[], None)?;
+        let (response, lines) = self.with_retries(true, |stream| {
+            let response = exchange(stream, &message)?;
+            let mut lines = Vec::new();
+            if response.as_deref() == Ok(b"OK") {
+                // 每个文件一帧，以长度为 0 的帧结束
+                loop {
+                    let mut length_bytes = [0u8; 4];
+                    stream.read_exact(&mut length_bytes)?;
+                    let length = u32::from_be_bytes(length_bytes) as usize;
+                    if length == 0 {
+                        break;
+                    }
+                    let mut line = vec![0u8; length];
+                    stream.read_exact(&mut line)?;
+                    lines.push(line);
+                }
+            }
+            Ok((response, lines))
+        })?;
+        let response = response?;
+        if response != b"OK" {
+            return Err(ServerError::unexpected(&response).into());
+        }
+        let mut segments = Vec::with_capacity(lines.len());
+        for line in lines {
+            let line = String::from_utf8(line)?;
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() != 5 {
+                return Err(format!("malformed segment line: {}", line).into());
+            }
+            segments.push(SegmentInfo {
+                base_offset: fields[0].parse()?,
+                data_bytes: fields[1].parse()?,
+                index_bytes: fields[2].parse()?,
+                index_entries: fields[3].parse()?,
+                active: fields[4] == "1",
+            });
+        }
+        Ok(segments)
+    }
+
+    /// Pins the segment starting at `base_offset` so retention never deletes it; the pin is
+    /// kept in the broker's metadata across restarts. Requires the admin key.
+    pub fn pin_segment(&self, broker_name: &str, base_offset: u64) -> Result<(), Box<dyn Error>> {
+        self.segment_pin_request(PIN_SEGMENT_COMMAND, broker_name, base_offset)
+    }
+
+    /// Releases a pin set by [`Client::pin_segment`]; the segment is again subject to retention
+    pub fn unpin_segment(&self, broker_name: &str, base_offset: u64) -> Result<(), Box<dyn Error>> {
+        self.segment_pin_request(UNPIN_SEGMENT_COMMAND, broker_name, base_offset)
+    }
+
+    fn segment_pin_request(&self, command: &[u8], broker_name: &str, base_offset: u64) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(command, broker_name.as_bytes(), &[], Some(base_offset))?;
+        let response = self.with_retries(true, |stream| exchange(stream, &message))??;
+        if response == b"OK" {
+            Ok(())
+        } else {
+            Err(ServerError::unexpected(&response).into())
+        }
+    }
+
+    /// Moves a broker's files to `<new_path>/<broker>` on the server without stopping it;
+    /// writes pause only while the active segment is copied. Requires the admin key.
+    pub fn migrate_broker(&self, broker_name: &str, new_path: &str) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(MIGRATE_PATH_COMMAND, broker_name.as_bytes(), new_path.as_bytes(), None)?;
+        let response = self.request(&message)?;
+        if response == b"OK" {
+            Ok(())
+        } else {
+            Err(ServerError::unexpected(&response).into())
+        }
+    }
+
+    /// Reads a metadata value attached to a broker, `None` when the key is not set
+    pub fn get_meta(&self, broker_name: &str, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let message = self.build_message(GET_META_COMMAND, broker_name.as_bytes(), key.as_bytes(), None)?;
+        let response = self.request(&message)?;
+        if response == b"NOT_FOUND" {
+            return Ok(None);
+        }
+        match response.strip_prefix(b"OK") {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Attaches a metadata key/value pair to a broker; keys are 1 to 128 bytes and
+    /// values at most 4096 bytes
+    pub fn set_meta(&self, broker_name: &str, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let pair = encode_headers(&[(key.to_string(), value.to_string())]);
+        let message = self.build_message(SET_META_COMMAND, broker_name.as_bytes(), &pair, None)?;
+        let response = self.request(&message)?;
+        if response == b"OK" {
+            Ok(())
+        } else {
+            Err(ServerError::unexpected(&response).into())
+        }
+    }
+
+    /// Stores `offset` on the server as the position `consumer_id` should resume from, usually
+    /// the offset after the last record it processed. Consumer ids are 1 to 128 bytes.
+    pub fn commit_offset(&self, broker_name: &str, consumer_id: &str, offset: u64) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(COMMIT_OFFSET_COMMAND, broker_name.as_bytes(), consumer_id.as_bytes(), Some(offset))?;
+        // 重复提交同一个偏移结果相同，可以安全地重发
+        let response = self.with_retries(true, |stream| exchange(stream, &message))??;
+        if response == b"OK" {
+            Ok(())
+        } else {
+            Err(ServerError::unexpected(&response).into())
+        }
+    }
+
+    /// Returns the offset last committed by `consumer_id` with [`Client::commit_offset`],
+    /// `None` when it never committed one
+    pub fn fetch_committed(&self, broker_name: &str, consumer_id: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        let message = self.build_message(FETCH_COMMITTED_COMMAND, broker_name.as_bytes(), consumer_id.as_bytes(), None)?;
+        let response = self.with_retries(true, |stream| exchange(stream, &message))??;
+        if response == b"NOT_FOUND" {
+            return Ok(None);
+        }
+        match response.strip_prefix(b"OK") {
+            Some(offset) if offset.len() == 8 => Ok(Some(u64::from_be_bytes(offset.try_into().unwrap()))),
+            _ => Err(ServerError::unexpected(&response).into()),
+        }
+    }
+
+    /// Fetches like [`Client::fetch_batch`] on behalf of `consumer_id`, so a broker with
+    /// `max_redeliveries` can count how often the consumer pulls the same offset. Once that
+    /// offset was pulled more than `max_redeliveries` times without a commit past it, the
+    /// server moves the record to `<broker>.dlq` and returns the batch starting after it.
+    /// A first returned offset above `offset` means the records in between were skipped.
+    pub fn fetch_as_consumer(&self, broker_name: &str, consumer_id: &str, offset: u64) -> Result<Vec<FetchedMessage>, Box<dyn Error>> {
+        let message = self.build_message(CONSUMER_PULL_COMMAND, broker_name.as_bytes(), consumer_id.as_bytes(), Some(offset))?;
+        Ok(self.pull(&message)?.0)
+    }
+
+    /// Checks the sealed segments of a broker while the server keeps running
+    pub fn verify_broker(&self, broker_name: &str) -> Result<VerifyReport, Box<dyn Error>> {
+        let message = self.build_message(VERIFY_COMMAND, broker_name.as_bytes(), &[], None)?;
+        let response = self.request(&message)?;
+        let body = match response.strip_prefix(b"OK") {
+            Some(body) => body,
+            None => return Err(ServerError::unexpected(&response).into()),
+        };
+        let mut cursor = Cursor::new(body);
+        let mut report = VerifyReport {
+            segments: cursor.read_u32::<BigEndian>()?,
+            records: cursor.read_u64::<BigEndian>()?,
+            errors: vec![],
+        };
+        let error_count = cursor.read_u32::<BigEndian>()?;
+        for _ in 0..error_count {
+            let len = cursor.read_u16::<BigEndian>()? as usize;
+            let mut error = vec![0u8; len];
+            cursor.read_exact(&mut error)?;
+            report.errors.push(String::from_utf8_lossy(&error).into_owned());
+        }
+        Ok(report)
+    }
+
+    /// Checks that the server answers on the current connection, reconnecting if needed.
+    /// PING does not touch any broker.
+    pub fn ping(&self) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(PING_COMMAND, &[], &[], None)?;
+        let response = self.request(&message)?;
+        if response != b"PONG" {
+            return Err(format!("unexpected ping response: {}", String::from_utf8_lossy(&response)).into());
+        }
+        Ok(())
+    }
+
+    /// Measures the round-trip time of a single PING/PONG exchange
+    pub fn ping_latency(&self) -> Result<Duration, Box<dyn Error>> {
+        self.connect()?;
+        let message = self.build_message(PING_COMMAND, &[], &[], None)?;
+        let started = Instant::now();
+        let response = self.request(&message)?;
+        let elapsed = started.elapsed();
+
+        if response != b"PONG" {
+            return Err(format!("unexpected ping response: {}", String::from_utf8_lossy(&response)).into());
+        }
+        Ok(elapsed)
+    }
+
+    /// Averages the round-trip time over `samples` fresh PING/PONG exchanges
+    pub fn ping_latency_avg(&self, samples: u32) -> Result<Duration, Box<dyn Error>> {
+        if samples == 0 {
+            return Err("samples must be greater than zero".into());
+        }
+        let mut total = Duration::ZERO;
+        for _ in 0..samples {
+            total += self.ping_latency()?;
+        }
+        Ok(total / samples)
+    }
+
+    /// Fetches the server's process-wide counters, such as `index_mmap_bytes`
+    pub fn stats(&self) -> Result<Vec<(String, u64)>, Box<dyn Error>> {
+        self.connect()?;
+        let message = self.build_message(STATS_COMMAND, &[], &[], None)?;
+        let response = self.request(&message)?;
+        let text = String::from_utf8(response)?;
+        let mut stats = Vec::new();
+        for line in text.lines() {
+            let (name, value) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("malformed stats line: {}", line))?;
+            stats.push((name.to_string(), value.parse()?));
+        }
+        Ok(stats)
+    }
+
+    /// Lists the server's active connections; requires the client to use the admin key
+    pub fn list_connections(&self) -> Result<Vec<ConnectionInfo>, Box<dyn Error>> {
+        let message = self.build_message(CONNECTIONS_COMMAND, &[], &[], None)?;
+        let response = self.request(&message)?;
+        let text = String::from_utf8(response)?;
+        let mut connections = Vec::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            if fields.len() != 6 {
+                return Err(format!("malformed connection line: {}", line).into());
+            }
+            connections.push(ConnectionInfo {
+                id: fields[0].parse()?,
+                peer: fields[1].to_string(),
+                identity: fields[2].to_string(),
+                connected_at: fields[3].parse()?,
+                bytes_received: fields[4].parse()?,
+                bytes_sent: fields[5].parse()?,
+            });
+        }
+        Ok(connections)
+    }
+
+    /// Closes the server connection with the given id; requires the admin key
+    pub fn kick_connection(&self, id: u64) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(KICK_COMMAND, &[], &[], Some(id))?;
+        let response = self.request(&message)?;
+        if response == b"OK" {
+            Ok(())
+        } else {
+            Err(ServerError::unexpected(&response).into())
+        }
+    }
+
+    /// Makes the server re-read its config files and apply the new limits, keys and ACLs
+    /// without dropping connections; requires the admin key. Fails with `RELOAD_REJECTED`, and
+    /// the server keeps its current config, when a field that needs a restart has changed.
+    pub fn reload_config(&self) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(RELOAD_CONFIG_COMMAND, &[], &[], None)?;
+        let response = self.request(&message)?;
+        if response == b"OK" {
+            Ok(())
+        } else {
+            Err(ServerError::unexpected(&response).into())
+        }
+    }
+
+    /// Subscribes to server log events at `min_level` or above (`debug`, `info`, `warn`,
+    /// `error`) and hands each one to `callback` until it returns `false`; requires the
+    /// admin key. The connection is dedicated to the stream and is closed afterwards.
+    pub fn stream_logs<F: FnMut(LogEvent) -> bool>(&self, min_level: &str, mut callback: F) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(LOG_STREAM_COMMAND, &[], min_level.as_bytes(), None)?;
+        let response = self.request(&message)?;
+        if response != b"OK" {
+            *self.connection.lock().unwrap() = None;
+            return Err(ServerError::unexpected(&response).into());
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let stream = connection.as_mut().unwrap();
+        // 日志流可能长时间没有事件，不使用读超时
+        stream.set_read_timeout(None)?;
+        let result = read_log_events(stream, &mut callback);
+        *connection = None;
+        result
+    }
+
+    /// Subscribes to a broker from `offset` ([`EARLIEST`] and [`LATEST`] resolve as for PULL),
+    /// receiving existing records and then new ones as they are pushed. Each record is handed to `callback` until it returns `false`; the
+    /// connection is dedicated to the subscription and is closed afterwards. While subscribed,
+    /// a background thread sends heartbeats at the interval the server asks for, so the server
+    /// can drop subscribers that have gone away.
+    pub fn subscribe<F: FnMut(u64, Vec<u8>) -> bool>(&self, broker_name: &str, offset: u64, mut callback: F) -> Result<(), Box<dyn Error>> {
+        let message = self.build_message(SUBSCRIBE_COMMAND, broker_name.as_bytes(), &[], Some(offset))?;
+        let response = self.request(&message)?;
+        let interval = match response.strip_prefix(b"OK") {
+            Some([]) => None,
+            Some(&[a, b, c, d]) => Some(Duration::from_millis(u32::from_be_bytes([a, b, c, d]) as u64)),
+            _ => {
+                *self.connection.lock().unwrap() = None;
+                return Err(ServerError::unexpected(&response).into());
+            }
+        };
+        let mut connection = self.connection.lock().unwrap();
+        let stream = connection.as_mut().unwrap();
+        // 订阅可能长时间没有新消息，不使用读超时
+        stream.set_read_timeout(None)?;
+        let result = match (interval, &*stream) {
+            (Some(interval), Transport::Plain(socket)) => {
+                let mut heartbeat = socket.try_clone()?;
+                // 心跳是长度为 0 的帧；订阅结束后连接被关闭，写入失败时线程退出
+                std::thread::spawn(move || loop {
+                    std::thread::sleep(interval);
+                    if heartbeat.write_all(&0u32.to_be_bytes()).is_err() {
+                        break;
+                    }
+                });
+                read_subscription(stream, &mut callback)
+            }
+            // TLS 连接不能在两个线程中同时读写，读取等待超过心跳间隔时由读取的线程发送心跳
+            #[cfg(feature = "tls")]
+            (Some(interval), Transport::Tls(_)) => {
+                stream.set_read_timeout(Some(interval))?;
+                read_subscription(&mut HeartbeatReader(stream), &mut callback)
+            }
+            (None, _) => read_subscription(stream, &mut callback),
+        };
+        let _ = stream.shutdown();
+        *connection = None;
+        result
+    }
+
+    /// Opens a separate connection on which several threads can have requests in flight at
+    /// once; see [`Multiplexed`]. Only plain TCP is supported: a client configured with
+    /// [`ClientBuilder::tls`] gets an error.
+    pub fn multiplexed(&self) -> Result<Multiplexed<'_>, Box<dyn Error>> {
+        Multiplexed::connect(self)
+    }
+
+    /// Sends a request frame and reads back a single reply; an error status becomes a [`ServerError`]
+    fn request(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(self.with_retries(false, |stream| exchange(stream, message))??)
     }
 
     /// Constructs a message
@@ -100,21 +1320,204 @@ impl Client {
         payload: &[u8],
         offset: Option<u64>,
     ) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut message = Vec::new();
-        message.extend_from_slice(&(self.key.len() as u16).to_be_bytes());
-        message.extend_from_slice(&self.key);
-        message.extend_from_slice(&(command.len() as u16).to_be_bytes());
-        message.extend_from_slice(command);
-        message.extend_from_slice(&(broker_name.len() as u16).to_be_bytes());
-        message.extend_from_slice(broker_name);
+        // 只读客户端在建立连接之前拒绝写入
+        if self.mode == ClientMode::ReadOnly && PUSH_COMMANDS.contains(&command) {
+            return Err(ClientError::ReadOnly.into());
+        }
+        // 服务端以 BAD_REQUEST 拒绝没有 broker 名称的写入
+        if broker_name.is_empty() && PUSH_COMMANDS.contains(&command) {
+            return Err(ClientError::EmptyBroker.into());
+        }
+        Ok(build_message(&self.key, command, broker_name, payload, offset))
+    }
+}
+
+// 发送请求并读取回复
+fn exchange(stream: &mut Transport, message: &[u8]) -> io::Result<Reply> {
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(message)?;
+    read_reply(stream)
+}
+
+// 一个请求的回复：状态为 0 时是回复内容，否则是服务端拒绝该请求的错误
+pub(crate) type Reply = Result<Vec<u8>, ServerError>;
+
+// 读取一个回复帧 [len: u32][状态: u8][内容]
+pub(crate) fn read_reply<R: Read>(stream: &mut R) -> io::Result<Reply> {
+    parse_reply(read_frame(stream)?)
+}
+
+// 拆分回复帧的状态字节和内容，错误回复的内容是说明错误的文本
+pub(crate) fn parse_reply(mut frame: Vec<u8>) -> io::Result<Reply> {
+    match frame.first() {
+        Some(0) => {
+            frame.remove(0);
+            Ok(Ok(frame))
+        }
+        Some(&status) => Ok(Err(ServerError {
+            code: StatusCode::from_status(status),
+            detail: String::from_utf8_lossy(&frame[1..]).into_owned(),
+        })),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "reply without a status byte")),
+    }
+}
+
+// 读取一个带长度前缀的帧
+pub(crate) fn read_frame<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut length_bytes = [0u8; 4];
+    stream.read_exact(&mut length_bytes)?;
+    let length = u32::from_be_bytes(length_bytes);
+
+    let mut frame = vec![0u8; length as usize];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+// 发送 PULL 并读取响应，返回记录和下一次应当读取的偏移
+fn pull_batch(stream: &mut Transport, message: &[u8]) -> io::Result<PullResult> {
+    stream.write_all(&(message.len() as u32).to_be_bytes())?;
+    stream.write_all(message)?;
+    read_pull_reply(stream)
+}
+
+// PULL 的结果：记录和下一个偏移，或服务端拒绝该请求的错误
+pub(crate) type PullResult = Result<(Vec<FetchedMessage>, u64), ServerError>;
+
+// 读取 PULL 响应：头部 [字节数: u32][下一个偏移: u64]，之后是该字节数的记录，
+// 每条为 [长度: u32][偏移: u64][记录]；字节数为 PULL_REFUSED 时之后是一个错误回复帧
+pub(crate) fn read_pull_reply<R: Read>(stream: &mut R) -> io::Result<PullResult> {
+    let size = stream.read_u32::<BigEndian>()?;
+    if size == PULL_REFUSED {
+        return Ok(Err(read_reply(stream)?.map_or_else(|e| e, |reply| ServerError::unexpected(&reply))));
+    }
+    let next_offset = stream.read_u64::<BigEndian>()?;
+    let mut body = vec![0u8; size as usize];
+    stream.read_exact(&mut body)?;
+    Ok(Ok((parse_records(&body)?, next_offset)))
+}
+
+// 拆分 PULL 响应中首尾相接的记录，最后一条记录必须正好在响应末尾结束
+pub(crate) fn parse_records(mut body: &[u8]) -> io::Result<Vec<FetchedMessage>> {
+    let mut records = Vec::new();
+    while !body.is_empty() {
+        if body.len() < 12 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated record header in pull response"));
+        }
+        let len = u32::from_be_bytes(body[..4].try_into().unwrap()) as usize;
+        let offset = u64::from_be_bytes(body[4..12].try_into().unwrap());
+        let record = body[12..]
+            .get(..len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated record in pull response"))?;
+        records.push((offset, record.to_vec()));
+        body = &body[12 + len..];
+    }
+    Ok(records)
+}
+
+// 对端关闭或重启时出现的错误，重新连接后可能恢复
+fn is_connection_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+// 设置了读写超时的套接字超时后返回 WouldBlock（Unix）或 TimedOut（Windows）
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn read_subscription<R: Read, F: FnMut(u64, Vec<u8>) -> bool>(stream: &mut R, callback: &mut F) -> Result<(), Box<dyn Error>> {
+    loop {
+        let mut header = [0u8; 12];
+        stream.read_exact(&mut header)?;
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let offset = u64::from_be_bytes(header[4..].try_into().unwrap());
+        let mut record = vec![0u8; len as usize];
+        stream.read_exact(&mut record)?;
+        if !callback(offset, record) {
+            return Ok(());
+        }
+    }
+}
+
+// 读取订阅推送的记录，超过读超时没有数据时发送一次心跳后继续等待
+#[cfg(feature = "tls")]
+struct HeartbeatReader<'a>(&'a mut Transport);
+
+#[cfg(feature = "tls")]
+impl Read for HeartbeatReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(e) if is_timeout(&e) => self.0.write_all(&0u32.to_be_bytes())?,
+                result => return result,
+            }
+        }
+    }
+}
+
+fn read_log_events<F: FnMut(LogEvent) -> bool>(stream: &mut Transport, callback: &mut F) -> Result<(), Box<dyn Error>> {
+    loop {
+        let mut length_bytes = [0u8; 4];
+        stream.read_exact(&mut length_bytes)?;
+        let mut line = vec![0u8; u32::from_be_bytes(length_bytes) as usize];
+        stream.read_exact(&mut line)?;
+        let line = String::from_utf8(line)?;
+        let mut fields = line.splitn(3, ' ');
+        let event = LogEvent {
+            timestamp: fields.next().unwrap_or_default().parse()?,
+            level: fields.next().unwrap_or_default().to_string(),
+            message: fields.next().unwrap_or_default().to_string(),
+        };
+        if !callback(event) {
+            return Ok(());
+        }
+    }
+}
 
-        if let Some(offset_value) = offset {
-            message.extend_from_slice(&offset_value.to_be_bytes());
+/// Parses a PUSH response of `"OK"` followed by the assigned offset, the server's
+/// append timestamp in milliseconds since the epoch and an optional pressure level byte
+pub(crate) fn parse_push_response(response: &[u8]) -> Result<(PushAck, u8), ServerError> {
+    match response.strip_prefix(b"OK") {
+        Some(rest) if rest.len() == 16 || rest.len() == 17 => {
+            let ack = PushAck {
+                offset: u64::from_be_bytes(rest[..8].try_into().unwrap()),
+                timestamp: i64::from_be_bytes(rest[8..16].try_into().unwrap()),
+            };
+            Ok((ack, rest.get(16).copied().unwrap_or(0)))
         }
+        _ => Err(ServerError::unexpected(response)),
+    }
+}
+
+/// Constructs a request frame body shared by the sync and async clients
+pub(crate) fn build_message(
+    key: &[u8],
+    command: &[u8],
+    broker_name: &[u8],
+    payload: &[u8],
+    offset: Option<u64>,
+) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.extend_from_slice(&(key.len() as u16).to_be_bytes());
+    message.extend_from_slice(key);
+    message.extend_from_slice(&(command.len() as u16).to_be_bytes());
+    message.extend_from_slice(command);
+    message.extend_from_slice(&(broker_name.len() as u16).to_be_bytes());
+    message.extend_from_slice(broker_name);
 
-        message.extend_from_slice(payload);
-        Ok(message)
+    if let Some(offset_value) = offset {
+        message.extend_from_slice(&offset_value.to_be_bytes());
     }
+
+    message.extend_from_slice(payload);
+    message
 }
 
 #[cfg(test)]
@@ -133,4 +1536,171 @@ mod tests {
         let result = client.fetch_messages("test_broker", 0);
         assert!(result.is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_server_error_codes_from_replies() {
+        let reply = |status: u8, detail: &[u8]| parse_reply([&[status][..], detail].concat()).unwrap();
+        assert_eq!(reply(0, b"OK"), Ok(b"OK".to_vec()));
+        assert_eq!(reply(0, b""), Ok(Vec::new()));
+        let error = reply(3, b"BAD_REQUEST: unknown command FOO").unwrap_err();
+        assert_eq!((error.code, error.to_string()), (StatusCode::BadRequest, "BAD_REQUEST: unknown command FOO".to_string()));
+        assert_eq!(reply(1, b"NO_BROKER").unwrap_err().code, StatusCode::NoBroker);
+        assert_eq!(reply(2, b"UNAUTHORIZED").unwrap_err().code, StatusCode::AuthFailed);
+        assert_eq!(reply(4, b"MESSAGE_TOO_LARGE").unwrap_err().code, StatusCode::Limit);
+        // 未知的状态码归为 Other
+        assert_eq!(reply(255, b"DISK_FULL").unwrap_err().code, StatusCode::Other);
+        assert_eq!(reply(9, b"NEW_ERROR").unwrap_err().code, StatusCode::Other);
+        assert!(parse_reply(Vec::new()).is_err());
+        assert_eq!(StatusCode::BadRequest as u8, 3);
+        assert!(parse_push_response(b"DUPLICATE").is_err_and(|e| e.code == StatusCode::Other));
+    }
+
+    // 只包含一条记录的 PULL 响应
+    fn pull_reply(offset: u64, record: &[u8]) -> Vec<u8> {
+        let mut response = ((record.len() + 12) as u32).to_be_bytes().to_vec();
+        response.extend_from_slice(&(offset + 1).to_be_bytes());
+        response.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        response.extend_from_slice(&offset.to_be_bytes());
+        response.extend_from_slice(record);
+        response
+    }
+
+    #[test]
+    fn test_parse_records_requires_exact_framing() {
+        let mut body = pull_reply(3, b"").split_off(12);
+        body.extend_from_slice(&pull_reply(4, b"hello")[12..]);
+        // 消息体为空的记录不会被当作响应的结尾
+        assert_eq!(parse_records(&body).unwrap(), vec![(3, vec![]), (4, b"hello".to_vec())]);
+        assert!(parse_records(&[]).unwrap().is_empty());
+        assert!(parse_records(&body[..body.len() - 1]).is_err());
+        assert!(parse_records(&body[..8]).is_err());
+    }
+
+    #[test]
+    fn test_fetch_cached_serves_repeat_from_cache() {
+        use std::net::TcpListener;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // 对每个 PULL 回复一条偏移为 3 的记录并计数
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pulls = Arc::new(AtomicUsize::new(0));
+        let counter = pulls.clone();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 4];
+            while stream.read_exact(&mut len).is_ok() {
+                let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut frame).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                stream.write_all(&pull_reply(3, b"hello")).unwrap();
+            }
+        });
+
+        let client = Client::builder("127.0.0.1", port, "test_key").cache_size(8).build();
+        assert_eq!(client.fetch_cached("events", 3).unwrap(), Some((3, b"hello".to_vec())));
+        assert_eq!(client.fetch_cached("events", 3).unwrap(), Some((3, b"hello".to_vec())));
+        assert_eq!(pulls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_verify_checksums_detects_corruption_in_transit() {
+        use std::net::TcpListener;
+
+        // PULL 偏移 3 回复完整的记录，偏移 4 回复传输中被改坏一个字节的记录
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut len = [0u8; 4];
+            while stream.read_exact(&mut len).is_ok() {
+                let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut frame).unwrap();
+                let offset = u64::from_be_bytes(frame[frame.len() - 8..].try_into().unwrap());
+                let mut record = checksum::add_checksum(b"hello");
+                if offset == 4 {
+                    record[6] ^= 0x20;
+                }
+                stream.write_all(&pull_reply(offset, &record)).unwrap();
+            }
+        });
+
+        let client = Client::builder("127.0.0.1", port, "test_key").verify_checksums(true).build();
+        assert_eq!(client.fetch_messages("events", 3).unwrap(), Some((3, b"hello".to_vec())));
+        let err = client.fetch_messages("events", 4).unwrap_err();
+        assert_eq!(err.downcast_ref::<ClientError>(), Some(&ClientError::ChecksumMismatch { offset: 4 }));
+        // 连接在校验失败后仍可继续使用
+        assert_eq!(client.fetch_messages("events", 3).unwrap(), Some((3, b"hello".to_vec())));
+    }
+
+    #[test]
+    fn test_fetch_reconnects_after_server_restart() {
+        use std::net::TcpListener;
+
+        // 回复一次 PULL 后关闭连接和监听
+        fn serve_one_pull(listener: TcpListener) -> std::thread::JoinHandle<()> {
+            std::thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut len = [0u8; 4];
+                stream.read_exact(&mut len).unwrap();
+                let mut frame = vec![0u8; u32::from_be_bytes(len) as usize];
+                stream.read_exact(&mut frame).unwrap();
+                stream.write_all(&pull_reply(3, b"hello")).unwrap();
+            })
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = Client::builder("127.0.0.1", port, "test_key")
+            .retries(5, Duration::from_millis(20))
+            .build();
+        let first = serve_one_pull(listener);
+        assert_eq!(client.fetch_messages("events", 3).unwrap(), Some((3, b"hello".to_vec())));
+        first.join().unwrap();
+
+        // 服务端在同一端口重启，缓存的连接已经失效
+        let second = serve_one_pull(TcpListener::bind(("127.0.0.1", port)).unwrap());
+        assert_eq!(client.fetch_messages("events", 3).unwrap(), Some((3, b"hello".to_vec())));
+        second.join().unwrap();
+    }
+
+    #[test]
+    fn test_fetch_times_out_when_server_is_silent() {
+        use std::net::TcpListener;
+
+        // 接受连接、读取请求但从不回复的服务端
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut buf = [0u8; 1024];
+                    while matches!(stream.read(&mut buf), Ok(n) if n > 0) {}
+                });
+            }
+        });
+
+        let client = Client::builder("127.0.0.1", port, "test_key")
+            .read_timeout(Duration::from_millis(200))
+            .build();
+        let started = Instant::now();
+        let err = client.fetch_messages("events", 1).unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(err.downcast_ref::<TimeoutError>().is_some(), "unexpected error: {}", err);
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        assert!(client.connection.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_empty_push_is_rejected_before_sending() {
+        // 没有服务端监听，请求被拒绝时不会建立连接
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let client = Client::new("127.0.0.1", port, "test_key");
+        let client_error = |result: Result<PushAck, Box<dyn Error>>| result.unwrap_err().downcast_ref::<ClientError>().cloned();
+        assert_eq!(client_error(client.send_push_message("", b"hello")), Some(ClientError::EmptyBroker));
+        assert_eq!(client_error(client.send_push_message("events", b"")), Some(ClientError::EmptyPayload));
+        assert!(client.connection.lock().unwrap().is_none());
+    }
+}
@@ -1,225 +1,5572 @@
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, WriteBytesExt};
 use dashmap::DashMap;
-use std::fs;
-use std::path::PathBuf;
-use std::io::Cursor;
-use std::io::{self,Read, Write};
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use std::sync::{Arc, OnceLock};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, RwLock, Semaphore};
+use tokio::task::JoinSet;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
 use tokio::time::{self, Duration};
-use std::os::unix::io::AsFd;
 mod storage;
-use crate::storage::DataStorage;
+use crate::storage::{commit_compaction, DataStorage, RecordRange, StorageError, StorePosition, VerifyReport, verify_segments};
 mod config;
-use crate::config::Config;
+use crate::config::{BrokerOverride, Config, FsyncPolicy, LiveConfig, config_paths_from_args, load_config, parse_duration, parse_size, socket_address};
+mod dedup;
+use crate::dedup::DedupIndex;
+use sonicrab_client::compression::{decode_record, decompress, validate};
+use sonicrab_client::StatusCode;
+use sonicrab_client::headers::{decode_headers, encode_headers};
+use sonicrab_client::keys::{decode_key, encode_key};
 mod fileclear;
 use fileclear::delete_old_files;
+mod governor;
+use crate::governor::IndexGovernor;
+mod connections;
+use crate::connections::{Connection, ConnectionRegistry};
+mod events;
+use crate::events::LogEvent;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Level, Span};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, Layer, Registry};
+mod migrate;
+mod metrics;
+mod meta;
+use crate::meta::{is_reserved_key, BrokerMeta, CREATED_BY_KEY};
+mod pressure;
+use crate::pressure::IngestQueues;
+mod zstd_store;
+use crate::zstd_store::ZstdStore;
+mod protocol;
+use crate::protocol::{parse_frame, ProtocolError};
+mod zerocopy;
+mod tls;
+use crate::tls::ServerStream;
+use crate::zerocopy::ZeroCopySend;
+mod index;
+mod coalescer;
+use crate::coalescer::{AppendResult, Coalescer, Pending};
+mod lease;
+use crate::lease::LeaseTable;
+mod offsets;
+use crate::offsets::OffsetStore;
+mod redelivery;
+use crate::redelivery::{Delivery, RedeliveryTracker, DLQ_SUFFIX};
+use sonicrab_client::coalesce::push_coalesced;
+use sonicrab_client::checksum::add_checksum;
 
 const PUSH_COMMAND:&str = "PUSH";
 const PULL_COMMAND:&str = "PULL";
+const PUSH_ID_COMMAND:&str = "PUSH_ID";
+const PING_COMMAND:&str = "PING";
+const PUSH_HEADERS_COMMAND:&str = "PUSH_HEADERS";
+const HEADERS_COMMAND:&str = "HEADERS";
+const VERIFY_COMMAND:&str = "VERIFY";
+const STATS_COMMAND:&str = "STATS";
+const CONNECTIONS_COMMAND:&str = "CONNECTIONS";
+const KICK_COMMAND:&str = "KICK";
+const LOG_STREAM_COMMAND:&str = "LOG_STREAM";
+const DEBUG_PULL_COMMAND:&str = "DEBUG_PULL";
+const REBUILD_INDEX_COMMAND:&str = "REBUILD_INDEX";
+const MIGRATE_PATH_COMMAND:&str = "MIGRATE_PATH";
+const PUSH_COMPRESSED_COMMAND:&str = "PUSH_COMPRESSED";
+const GET_META_COMMAND:&str = "GET_META";
+const SET_META_COMMAND:&str = "SET_META";
+const SUBSCRIBE_COMMAND:&str = "SUBSCRIBE";
+const EXPORT_SUBCOMMAND: &str = "export";
+const TAIL_BYTES_COMMAND:&str = "TAIL_BYTES";
+const SEGMENTS_COMMAND:&str = "SEGMENTS";
+const PUSH_BATCH_COMMAND:&str = "PUSH_BATCH";
+const PIN_SEGMENT_COMMAND:&str = "PIN_SEGMENT";
+const UNPIN_SEGMENT_COMMAND:&str = "UNPIN_SEGMENT";
+const LEASE_COMMAND:&str = "LEASE";
+const ACK_LEASE_COMMAND:&str = "ACK_LEASE";
+const COMMIT_OFFSET_COMMAND:&str = "COMMIT_OFFSET";
+const FETCH_COMMITTED_COMMAND:&str = "FETCH_COMMITTED";
+const RELOAD_COMMAND:&str = "RELOAD";
+const DELETE_BROKER_COMMAND:&str = "DELETE_BROKER";
+const LIST_BROKERS_COMMAND:&str = "LIST_BROKERS";
+const PEEK_COMMAND:&str = "PEEK";
+const CONSUMER_PULL_COMMAND:&str = "CONSUMER_PULL";
+const PUSH_KEYED_COMMAND:&str = "PUSH_KEYED";
+const COMPACT_COMMAND:&str = "COMPACT";
+const RELOAD_CONFIG_COMMAND:&str = "RELOAD_CONFIG";
+const SEEK_TIME_COMMAND:&str = "SEEK_TIME";
+const MUX_COMMAND:&str = "MUX";
+// 写入 broker 的命令，[[acl]] 中需要 write 权限，其他命令需要 read 权限
+const WRITE_COMMANDS: &[&str] = &[
+    PUSH_COMMAND,
+    PUSH_ID_COMMAND,
+    PUSH_HEADERS_COMMAND,
+    PUSH_COMPRESSED_COMMAND,
+    PUSH_BATCH_COMMAND,
+    PUSH_KEYED_COMMAND,
+    SET_META_COMMAND,
+];
+// 只读取已有 broker、不会自动创建 broker 的命令，不受 broker_limit 限制
+const LOOKUP_ONLY_COMMANDS: &[&str] = &[
+    PULL_COMMAND,
+    CONSUMER_PULL_COMMAND,
+    PEEK_COMMAND,
+    DELETE_BROKER_COMMAND,
+    COMPACT_COMMAND,
+    SEEK_TIME_COMMAND,
+    HEADERS_COMMAND,
+    GET_META_COMMAND,
+    FETCH_COMMITTED_COMMAND,
+    LEASE_COMMAND,
+    ACK_LEASE_COMMAND,
+    COMMIT_OFFSET_COMMAND,
+    TAIL_BYTES_COMMAND,
+    VERIFY_COMMAND,
+    SUBSCRIBE_COMMAND,
+    DEBUG_PULL_COMMAND,
+    REBUILD_INDEX_COMMAND,
+    RELOAD_COMMAND,
+    SEGMENTS_COMMAND,
+    PIN_SEGMENT_COMMAND,
+    UNPIN_SEGMENT_COMMAND,
+    MIGRATE_PATH_COMMAND,
+];
+// 需要管理密钥的命令
+const ADMIN_COMMANDS: &[&str] = &[
+    CONNECTIONS_COMMAND,
+    KICK_COMMAND,
+    LOG_STREAM_COMMAND,
+    DEBUG_PULL_COMMAND,
+    REBUILD_INDEX_COMMAND,
+    RELOAD_COMMAND,
+    DELETE_BROKER_COMMAND,
+    MIGRATE_PATH_COMMAND,
+    SEGMENTS_COMMAND,
+    PIN_SEGMENT_COMMAND,
+    UNPIN_SEGMENT_COMMAND,
+    COMPACT_COMMAND,
+    RELOAD_CONFIG_COMMAND,
+];
+// 一直占用连接推送数据的命令，不能在多路复用连接上使用
+const STREAMING_COMMANDS: &[&str] = &[SUBSCRIBE_COMMAND, LOG_STREAM_COMMAND];
+// 回复以 PULL 头部开始的命令，错误回复前先发 PULL_REFUSED 代替头部
+const PULL_REPLY_COMMANDS: &[&str] = &[PULL_COMMAND, CONSUMER_PULL_COMMAND];
+// 不可能出现的 PULL 字节数（回复上限远小于 4GB），之后是一个普通的错误回复帧
+const PULL_REFUSED: u32 = u32::MAX;
+// 回复帧的第一个字节是状态码，0 表示成功，错误回复使用 StatusCode 的值
+const STATUS_OK: u8 = 0;
+
+const DEFAULT_DEDUP_RETENTION_SECS: u64 = 60 * 60;
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10; // 关闭时等待正在处理的请求完成的最长时间
+const POSITION_SAVE_SECS: u64 = 10; // 定期保存 broker 当前位置的间隔
+const DEFAULT_DICTIONARY_SAMPLES: usize = 1000;
+const DEFAULT_COALESCE_WINDOW_MS: u64 = 5;
+const DEFAULT_COALESCE_MAX_BYTES: usize = 64 * 1024;
+const MUX_PIPE_SIZE: usize = 64 * 1024; // 多路复用连接中每个请求的内存管道大小
+const MUX_QUEUE: usize = 64; // 多路复用连接同时处理的请求数量，也是等待写出的回复数量
 
 struct Broker {
-    store:DataStorage
+    dir: PathBuf, // 数据文件实际所在的目录，迁移后不在 server.path 下
+    store:DataStorage,
+    dedup: Option<DedupIndex>,
+    headers: bool, // 记录前是否带有消息头
+    keyed: bool, // 记录前是否带有用于压缩的键
+    timestamps: bool, // 记录前是否带有写入时间戳
+    message_ttl_ms: Option<i64>, // 记录的存活时间（毫秒），写入时间戳早于 now - ttl 的记录不再返回
+    checksums: bool, // 记录前是否带有 CRC32 校验和
+    dedup_consecutive: bool, // 与最后一条记录相同的消息不再写入
+    pull_permits: Option<Arc<Semaphore>>, // 限制并发 PULL，避免大量冷数据读取压垮磁盘
+    content_type: Option<String>, // 消息体的内容类型，"json" 时支持 DEBUG_PULL
+    meta: BrokerMeta, // 用户自定义的元数据
+    offsets: OffsetStore, // 消费者通过 COMMIT_OFFSET 提交的偏移
+    subscribers: Arc<AtomicUsize>, // 当前通过 SUBSCRIBE 连接的订阅者数量
+    appended: watch::Sender<u64>, // 写入新消息后通知订阅者，值为下一个待分配的偏移
+    require_consumers: bool, // 没有订阅者时拒绝写入
+    zstd: Option<ZstdStore>, // 开启静态压缩时，记录压缩后保存，读取时解压
+    max_buffered: usize, // 在内存中组装的响应的大小上限
+    coalescer: Option<Coalescer>, // coalesce 模式下等待合并写入的消息
+    record_codecs: bool, // 每条记录以编码字节开始，压缩消息按收到的格式保存
+    leases: LeaseTable, // LEASE 租出的记录范围，用于多个消费者竞争消费
+    redeliveries: Option<RedeliveryTracker>, // 配置了 max_redeliveries 时统计 CONSUMER_PULL 的重新投递
+    last_used: AtomicI64, // 最后一次写入或 PULL 的时间（毫秒），用于 evict_idle 选择最久未使用的 broker
+}
+
+// 订阅连接结束时减少订阅者计数
+struct SubscriberGuard(Arc<AtomicUsize>);
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Broker {
     
-    async fn new(name: String,config:&Config) -> Self {
+    async fn new(name: String,config:&Config) -> io::Result<Self> {
         let broker_path = config.server.path.clone() + "/" + name.as_str();
-        if create_directory_if_not_exists(broker_path.as_str()).is_err() {
-            println!("crate breaker {} path failed!", name)
-        }
+        create_directory_if_not_exists(broker_path.as_str())?;
         let file_dir = PathBuf::from(broker_path);
-        let manager = DataStorage::new(file_dir,&config.storage).await.unwrap();
-        
-        Broker {
+        let broker_config = config.broker_override(&name);
+        let manager = DataStorage::new(file_dir.clone(),&config.storage,&broker_config).await?;
+        let dedup = if broker_config.dedup {
+            let retention = broker_config
+                .dedup_retention
+                .as_deref()
+                .and_then(|s| parse_duration(s).ok())
+                .unwrap_or(DEFAULT_DEDUP_RETENTION_SECS);
+            Some(DedupIndex::open(&file_dir, retention)?)
+        } else {
+            None
+        };
+        let meta = BrokerMeta::open(&file_dir)?;
+        let offsets = OffsetStore::open(&file_dir)?;
+        let leases = LeaseTable::new(meta.lease_acked());
+        let zstd = open_zstd_store(&name, &file_dir, &broker_config)?;
+        let coalescer = open_coalescer(&name, &broker_config);
+        let record_codecs = record_codecs_enabled(&name, &broker_config);
+        let keyed = keyed_enabled(&name, &broker_config);
+        let message_ttl_ms = message_ttl_ms(&name, &broker_config);
+        broker_positions().insert(broker_key(config, &name), manager.position());
+
+        Ok(Broker {
+           dir: file_dir,
            store: manager,
+           dedup,
+           headers: broker_config.headers,
+           keyed,
+           timestamps: broker_config.timestamps,
+           message_ttl_ms,
+           checksums: broker_config.checksums,
+           dedup_consecutive: broker_config.dedup_consecutive,
+           pull_permits: broker_config
+               .max_concurrent_pulls
+               .map(|limit| Arc::new(Semaphore::new(limit.max(1)))),
+           content_type: broker_config.content_type,
+           meta,
+           offsets,
+           subscribers: Arc::new(AtomicUsize::new(0)),
+           appended: watch::channel(0).0,
+           require_consumers: broker_config.require_consumers,
+           zstd,
+           max_buffered: config.storage.max_buffered_response_bytes(),
+           coalescer,
+           leases,
+           redeliveries: broker_config.max_redeliveries.map(RedeliveryTracker::new),
+           last_used: AtomicI64::new(chrono::Utc::now().timestamp_millis()),
+           record_codecs,
+        })
+    }
+
+    // 提交消费者偏移，越过的记录不再统计重新投递
+    fn commit_offset(&mut self, consumer_id: &str, offset: u64) -> io::Result<()> {
+        self.offsets.commit(consumer_id, offset)?;
+        if let Some(redeliveries) = self.redeliveries.as_mut() {
+            redeliveries.committed(consumer_id, offset);
+        }
+        Ok(())
+    }
+
+    // 重写存储，只保留每个键最新的记录和没有键的记录，偏移从 0 重新编号，返回 (保留的记录数, 删除的记录数)。
+    // 调用方持有写锁，压缩期间不能写入或读取该 broker
+    async fn compact(&mut self, name: &str, config: &Config) -> io::Result<(u64, u64)> {
+        self.store.catch_up_index().await?;
+        // 被同一个键之后的记录取代的偏移，无法解析出键的记录保留
+        let mut latest: HashMap<String, u64> = HashMap::new();
+        let mut superseded = HashSet::new();
+        self.store
+            .for_each_record(|offset, stored| {
+                let record = match &self.zstd {
+                    Some(zstd) => zstd.decode(&stored)?,
+                    None => stored,
+                };
+                if let Ok((key, _)) = decode_key(self.strip_prefixes(&record)) {
+                    if !key.is_empty() {
+                        if let Some(previous) = latest.insert(key.to_string(), offset) {
+                            superseded.insert(previous);
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .await?;
+        let broker_config = config.broker_override(name);
+        let kept = self.store.write_compacted(&config.storage, &broker_config, &superseded).await?;
+        commit_compaction(&self.dir)?;
+        let dir = self.dir.clone();
+        self.reopen(name, dir, config).await?;
+        // 偏移已经重新编号，之前的租约和重新投递计数不再有效
+        self.leases = LeaseTable::new(self.meta.lease_acked());
+        self.redeliveries = broker_config.max_redeliveries.map(RedeliveryTracker::new);
+        self.appended.send_replace(self.store.next_offset());
+        Ok((kept, superseded.len() as u64))
+    }
+
+    // 记录写入或 PULL 的时间，只需要读锁
+    fn touch(&self) {
+        self.last_used.store(chrono::Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    // 在新目录上打开存储和去重索引，用于迁移后切换，或在 RELOAD 时重新读取原目录中的文件
+    async fn reopen(&mut self, name: &str, dir: PathBuf, config: &Config) -> io::Result<()> {
+        let broker_config = config.broker_override(name);
+        let mut store = DataStorage::new(dir.clone(), &config.storage, &broker_config).await?;
+        let dedup = match &self.dedup {
+            Some(_) => {
+                let retention = broker_config
+                    .dedup_retention
+                    .as_deref()
+                    .and_then(|s| parse_duration(s).ok())
+                    .unwrap_or(DEFAULT_DEDUP_RETENTION_SECS);
+                Some(DedupIndex::open(&dir, retention)?)
+            }
+            None => None,
+        };
+        let meta = BrokerMeta::open(&dir)?;
+        let offsets = OffsetStore::open(&dir)?;
+        let zstd = open_zstd_store(name, &dir, &broker_config)?;
+        // LIST_BROKERS 持有的位置继续反映新的存储
+        store.share_position(&self.store);
+        self.store = store;
+        self.dedup = dedup;
+        self.meta = meta;
+        self.offsets = offsets;
+        self.zstd = zstd;
+        self.dir = dir;
+        Ok(())
+    }
+
+    // 接收消息并保存到文件中，返回分配的偏移量和写入时间戳
+    async fn receive_message(&mut self, payload: Vec<u8>) -> io::Result<(u64, i64)>{
+        self.append(payload).await
+    }
+
+    async fn append(&mut self, payload: Vec<u8>) -> io::Result<(u64, i64)> {
+        if self.coalescer.is_some() {
+            // coalesce 模式的每条记录都使用合并格式，单独写入的消息也是只有一条消息的合并记录
+            let mut record = Vec::with_capacity(payload.len() + 4);
+            push_coalesced(&mut record, &payload);
+            self.append_record(&record).await
+        } else if self.headers {
+            // 开启消息头的 broker 中每条记录都以消息头开始，普通 PUSH 写入空消息头
+            let mut record = encode_headers(&[]);
+            record.extend_from_slice(&payload);
+            self.append_record(&record).await
+        } else if self.keyed {
+            // 开启键的 broker 中普通 PUSH 写入空键，压缩时不会被删除
+            let mut record = encode_key("")?;
+            record.extend_from_slice(&payload);
+            self.append_record(&record).await
+        } else if self.record_codecs {
+            // 普通 PUSH 的消息以编码 0（未压缩）保存
+            let mut record = Vec::with_capacity(payload.len() + 1);
+            record.push(0);
+            record.extend_from_slice(&payload);
+            self.append_record(&record).await
+        } else {
+            self.append_record(&payload).await
+        }
+    }
+
+    // record_codecs 模式下 PUSH_COMPRESSED 的消息体 [codec][压缩数据] 原样保存，不在服务端解压
+    async fn receive_compressed(&mut self, body: &[u8]) -> io::Result<(u64, i64)> {
+        self.append_record(body).await
+    }
+
+    // 写入一条记录，开启时间戳的 broker 在记录前保存写入时间戳，返回的时间戳与保存的完全一致
+    async fn append_record(&mut self, record: &[u8]) -> io::Result<(u64, i64)> {
+        self.check_consumers()?;
+        self.touch();
+        if self.dedup_consecutive {
+            if let Some(last) = self.last_if_identical(record).await? {
+                return Ok(last);
+            }
+        }
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let stored = self.encode_record(record, timestamp)?;
+        let offset = self.store.append_data(&stored).await?;
+        self.appended.send_replace(offset + 1);
+        Ok((offset, timestamp))
+    }
+
+    // PUSH_BATCH：按顺序写入多条消息，合并为尽量少的文件写入，返回写入的数量
+    async fn receive_batch(&mut self, payloads: &[&[u8]]) -> io::Result<usize> {
+        self.check_consumers()?;
+        self.touch();
+        // coalesce 模式下整批消息合并为一条记录
+        if self.coalescer.is_some() {
+            let mut record = Vec::with_capacity(payloads.iter().map(|p| p.len() + 4).sum());
+            for payload in payloads {
+                push_coalesced(&mut record, payload);
+            }
+            if !payloads.is_empty() {
+                self.append_record(&record).await?;
+            }
+            return Ok(payloads.len());
+        }
+        // 逐条与前一条记录比较，相同的消息不写入
+        if self.dedup_consecutive {
+            for payload in payloads {
+                self.append(payload.to_vec()).await?;
+            }
+            return Ok(payloads.len());
+        }
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let mut records = Vec::with_capacity(payloads.len());
+        for payload in payloads {
+            let record = if self.headers {
+                let mut record = encode_headers(&[]);
+                record.extend_from_slice(payload);
+                self.encode_record(&record, timestamp)?.into_owned()
+            } else if self.keyed {
+                let mut record = encode_key("")?;
+                record.extend_from_slice(payload);
+                self.encode_record(&record, timestamp)?.into_owned()
+            } else if self.record_codecs {
+                let mut record = Vec::with_capacity(payload.len() + 1);
+                record.push(0);
+                record.extend_from_slice(payload);
+                self.encode_record(&record, timestamp)?.into_owned()
+            } else {
+                self.encode_record(payload, timestamp)?.into_owned()
+            };
+            records.push(record);
         }
+        let appended = self.store.append_batch(&records).await?;
+        self.appended.send_replace(self.store.next_offset());
+        Ok(appended)
     }
 
-    // 接收消息并保存到文件中，同时记录消息ID与文件偏移量
-    async fn receive_message(&mut self, payload: Vec<u8>) -> io::Result<()>{
-        self.store.append_data(&payload).await?;
-        Ok(())       
+    // 写入 generation 窗口的合并记录，并把结果通知该窗口的所有消息；该窗口已经写入时什么也不做
+    async fn flush_coalesced(&mut self, generation: u64) {
+        let Some((record, waiters)) = self.coalescer.as_mut().and_then(|c| c.take(generation)) else {
+            return;
+        };
+        let result: AppendResult = self.append_record(&record).await.map_err(|e| e.kind());
+        for waiter in waiters {
+            let _ = waiter.send(result);
+        }
     }
 
-    // 根据客户端提供的最后一条消息ID来获取文件偏移量，并用 sendfile 发送消息给客户端
-    async fn send_messages_since(&mut self, last_id: usize, stream: &mut TcpStream) -> io::Result<()>{
-        match self.store.sendfile(last_id as u64, stream.as_fd()).await {
-            Ok(size) => println!("send data {} bytes",size),
-            Err(e) => println!("Error: {}", e)
+    // 要求至少一个订阅者的 broker 在没有订阅者时拒绝写入，由调用方回复 NO_CONSUMERS
+    fn check_consumers(&self) -> io::Result<()> {
+        if self.require_consumers && self.subscribers.load(Ordering::SeqCst) == 0 {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "no consumers subscribed"));
         }
-        let end = (0u32).to_be_bytes();
-        stream.write_all(&end).await?;
         Ok(())
     }
-}
 
-async fn handle_client(
-    mut stream: TcpStream,
-    brokers: Arc<DashMap<String, Arc<RwLock<Broker>>>>,
-    config:Config
-) -> io::Result<()>{
-    loop {
-        let mut len_buf = [0; 4];
-        if AsyncReadExt::read_exact(&mut stream, &mut len_buf)
-            .await
-            .is_err()
-        {
-            break;
+    // 记录在磁盘上的形式：按配置加上写入时间戳和 CRC32，再压缩整条记录（包括时间戳和消息头），读取时先解压
+    // 最后一条记录（去掉校验和与时间戳后）与 record 相同时返回它的偏移和写入时间戳
+    async fn last_if_identical(&mut self, record: &[u8]) -> io::Result<Option<(u64, i64)>> {
+        let end = self.store.next_offset();
+        if end == 0 {
+            return Ok(None);
         }
-        let message_len =
-            ReadBytesExt::read_u32::<BigEndian>(&mut Cursor::new(len_buf)).unwrap() as usize;
-        let mut buffer = vec![0; message_len];
-        if AsyncReadExt::read_exact(&mut stream, &mut buffer)
+        self.store.catch_up_index().await?;
+        let Some(last) = self.read_record(end - 1).await? else {
+            return Ok(None);
+        };
+        if self.strip_prefixes(&last) != record {
+            return Ok(None);
+        }
+        let stamped = if self.checksums { &last[4..] } else { &last[..] };
+        let timestamp = if self.timestamps {
+            i64::from_be_bytes(stamped[..8].try_into().unwrap())
+        } else {
+            chrono::Utc::now().timestamp_millis()
+        };
+        Ok(Some((end - 1, timestamp)))
+    }
+
+    fn encode_record<'a>(&mut self, record: &'a [u8], timestamp: i64) -> io::Result<Cow<'a, [u8]>> {
+        let mut stored = Cow::Borrowed(record);
+        if self.timestamps {
+            let mut stamped = Vec::with_capacity(record.len() + 8);
+            stamped.extend_from_slice(&timestamp.to_be_bytes());
+            stamped.extend_from_slice(record);
+            stored = Cow::Owned(stamped);
+        }
+        // 校验和覆盖时间戳和消息头，PULL 解压后原样返回给客户端校验
+        if self.checksums {
+            stored = Cow::Owned(add_checksum(&stored));
+        }
+        if let Some(zstd) = self.zstd.as_mut() {
+            stored = Cow::Owned(zstd.encode(&stored)?);
+        }
+        Ok(stored)
+    }
+
+    fn subscribe(&self) -> (SubscriberGuard, watch::Receiver<u64>) {
+        self.subscribers.fetch_add(1, Ordering::SeqCst);
+        (SubscriberGuard(self.subscribers.clone()), self.appended.subscribe())
+    }
+
+    // 导出每条记录的消息体：解压静态压缩的数据，去掉校验和、时间戳、消息头和键，
+    // record_codecs 的记录按编码字节解压，最多 max_len 字节
+    async fn export<W: Write>(&self, writer: W, max_len: usize) -> io::Result<u64> {
+        self.store
+            .export_with(writer, |stored| {
+                let record = match &self.zstd {
+                    Some(zstd) => zstd.decode(&stored)?,
+                    None => stored,
+                };
+                let body = self.record_body(&record)?;
+                let body = if self.keyed { decode_key(body)?.1 } else { body };
+                if self.record_codecs {
+                    decode_record(body, max_len)
+                } else {
+                    Ok(body.to_vec())
+                }
+            })
             .await
-            .is_err()
-        {
-            break;
+    }
+
+    // 去掉记录前的校验和与时间戳，返回消息头和消息体
+    fn strip_prefixes<'a>(&self, record: &'a [u8]) -> &'a [u8] {
+        let record = if self.checksums { record.get(4..).unwrap_or_default() } else { record };
+        if self.timestamps { record.get(8..).unwrap_or_default() } else { record }
+    }
+
+    // 去掉记录前的校验和、时间戳和消息头，返回消息体
+    fn record_body<'a>(&self, record: &'a [u8]) -> io::Result<&'a [u8]> {
+        let record = self.strip_prefixes(record);
+        if self.headers {
+            Ok(decode_headers(record)?.1)
+        } else {
+            Ok(record)
         }
+    }
 
-        let mut cursor = Cursor::new(buffer);
-        let key_len = ReadBytesExt::read_u16::<BigEndian>(&mut cursor).unwrap() as usize;
-        let mut key_buf = vec![0; key_len];
-        std::io::Read::read_exact(&mut cursor, &mut key_buf).unwrap();
-        let key = String::from_utf8(key_buf).unwrap();
-        if key != config.server.authorization {
-            let mut response = Vec::new();
-            let content = b"Server authentication failed.";
-            WriteBytesExt::write_u32::<BigEndian>(&mut response, content.len() as u32).unwrap();
-            Write::write_all(&mut response, content).unwrap();
-            let _ = tokio::io::AsyncWriteExt::write_all(&mut stream, &response).await;
-            return Ok(())
-        }
-
-        let command_len = ReadBytesExt::read_u16::<BigEndian>(&mut cursor).unwrap() as usize;
-        let mut command_buf = vec![0; command_len];
-        std::io::Read::read_exact(&mut cursor, &mut command_buf).unwrap();
-        let command = String::from_utf8(command_buf).unwrap();
-
-        if command == PUSH_COMMAND {
-            let broker_len = ReadBytesExt::read_u16::<BigEndian>(&mut cursor).unwrap() as usize;
-            let mut broker_buf = vec![0; broker_len];
-            Read::read_exact(&mut cursor, &mut broker_buf).unwrap();
-            let broker_name = String::from_utf8(broker_buf).unwrap();
-            let position = cursor.position() as usize;
-            let payload = cursor.into_inner()[position..].to_vec();
-           
-            if let Some(broker) = get_broker(&brokers, broker_name,&config).await{
-                broker.write().await.receive_message(payload).await?;
-                let mut response = Vec::new();
-                WriteBytesExt::write_u32::<BigEndian>(&mut response, 2).unwrap();
-                Write::write_all(&mut response, b"OK").unwrap();
-                let _ = tokio::io::AsyncWriteExt::write_all(&mut stream, &response).await;
-            } else {
-                let mut response = Vec::new();
-                let content = b"NO_BROKER";
-                WriteBytesExt::write_u32::<BigEndian>(&mut response, content.len() as u32).unwrap();
-                Write::write_all(&mut response, content).unwrap();
-                let _ = tokio::io::AsyncWriteExt::write_all(&mut stream, &response).await;
-            }
-        } else if command == PULL_COMMAND {
-            let broker_len = ReadBytesExt::read_u16::<BigEndian>(&mut cursor).unwrap() as usize;
-            let mut broker_buf = vec![0; broker_len];
-            Read::read_exact(&mut cursor, &mut broker_buf).unwrap();
-            let broker_name = String::from_utf8(broker_buf).unwrap();
-            
-            let offset = ReadBytesExt::read_u64::<BigEndian>(&mut cursor).unwrap();
-
-            if let Some(broker) = get_broker(&brokers, broker_name,&config).await{
-                broker
-                    .write()
-                    .await
-                    .send_messages_since(offset as usize, &mut stream)
-                    .await?;
-            } else {
-                let mut response = Vec::new();
-                let content = b"NO_BROKER";
-                WriteBytesExt::write_u32::<BigEndian>(&mut response, content.len() as u32).unwrap();
-                Write::write_all(&mut response, content).unwrap();
-                let _ = tokio::io::AsyncWriteExt::write_all(&mut stream, &response).await;
+    // 租出下一段记录，返回租约编号和 PULL 格式的记录 [len: u32][offset: u64][记录]*，没有可租的记录时返回 None
+    // 与 PULL 一样不超过 pull_max_limit 和 max_buffered，至少包含一条记录
+    async fn lease_records(&mut self, timeout: Duration) -> io::Result<Option<(u64, Vec<u8>)>> {
+        let now = time::Instant::now();
+        let first = self.store.first_offset().await;
+        let head = self.store.next_offset();
+        let Some((start, end)) = self.leases.next_range(first, head, now) else {
+            return Ok(None);
+        };
+        let limit = self.store.pull_max_limit().min(self.max_buffered);
+        let mut records = Vec::new();
+        let mut offset = start;
+        while offset < end {
+            // 已被清理的记录跳过
+            if let Some(record) = self.read_record(offset).await? {
+                if records.len() + record.len() + 12 > limit {
+                    if records.is_empty() && record.len() + 12 > self.max_buffered {
+                        return Err(io::Error::new(
+                            io::ErrorKind::OutOfMemory,
+                            format!("record {} exceeds max_buffered_response_bytes", offset),
+                        ));
+                    }
+                    if !records.is_empty() {
+                        break;
+                    }
+                }
+                records.extend_from_slice(&(record.len() as u32).to_be_bytes());
+                records.extend_from_slice(&offset.to_be_bytes());
+                records.extend_from_slice(&record);
             }
+            offset += 1;
         }
+        let id = self.leases.grant(start, offset, timeout, now);
+        Ok(Some((id, records)))
     }
-    Ok(())
-}
 
-async fn get_broker(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, broker_name: String, config:&Config) -> Option<Arc<RwLock<Broker>>> {
-        
-        if brokers.contains_key(&broker_name) {
-            Some(brokers.get(&broker_name).unwrap().clone())
-        } else {
-            if (brokers.len() + 1) as u16 <= config.server.broker_limit {
-                let new_broker = Arc::new(RwLock::new(Broker::new(broker_name.clone(),config).await));
-                brokers.insert(broker_name, new_broker.clone());
-                Some(new_broker)
-            } else {
+    // 读取一条记录，压缩保存的记录解压后返回
+    async fn read_record(&self, offset: u64) -> io::Result<Option<Vec<u8>>> {
+        match (self.store.read_record(offset).await?, &self.zstd) {
+            (Some(stored), Some(zstd)) => Ok(Some(zstd.decode(&stored)?)),
+            (record, _) => Ok(record),
+        }
+    }
+
+    // 记录中保存的写入时间戳，在校验和之后；没有开启 timestamps 时返回 None
+    fn record_timestamp(&self, record: &[u8]) -> Option<i64> {
+        if !self.timestamps {
+            return None;
+        }
+        let stamped = if self.checksums { record.get(4..).unwrap_or_default() } else { record };
+        stamped.first_chunk::<8>().map(|timestamp| i64::from_be_bytes(*timestamp))
+    }
+
+    // 记录已超过 message_ttl
+    fn expired(&self, record: &[u8], now: i64) -> bool {
+        match (self.message_ttl_ms, self.record_timestamp(record)) {
+            (Some(ttl), Some(timestamp)) => timestamp.saturating_add(ttl) < now,
+            _ => false,
+        }
+    }
+
+    // 从 low 开始第一条写入时间不早于 timestamp 的记录的偏移，没有这样的记录时返回下一个待分配的偏移。
+    // 时间戳随偏移递增，在历史文件和当前文件的全部记录上二分查找；已被清理的记录视为更早
+    async fn seek_time(&self, low: u64, timestamp: i64) -> io::Result<u64> {
+        let mut low = low.max(self.store.first_offset().await);
+        let mut high = self.store.next_offset();
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let record = self.read_record(middle).await?;
+            match record.as_deref().and_then(|record| self.record_timestamp(record)) {
+                Some(stored) if stored >= timestamp => high = middle,
+                _ => low = middle + 1,
+            }
+        }
+        Ok(low)
+    }
+
+    // 跳过 offset 开始的过期记录，返回第一条未过期记录的偏移（都已过期时为下一个待分配的偏移）。
+    // 过期的记录总是最早的一段；offset 已经由 resolve_offset 解析
+    async fn skip_expired(&self, offset: u64) -> io::Result<u64> {
+        let Some(ttl) = self.message_ttl_ms else {
+            return Ok(offset);
+        };
+        let live = self.seek_time(offset, chrono::Utc::now().timestamp_millis().saturating_sub(ttl)).await?;
+        Ok(live.max(offset))
+    }
+
+    // 调试用：把 JSON 消息体格式化后返回，不影响存储的数据
+    async fn read_pretty_json(&self, offset: u64) -> io::Result<Option<String>> {
+        match self.read_record(offset).await? {
+            Some(record) => {
+                let value: serde_json::Value = serde_json::from_slice(self.record_body(&record)?)?;
+                Ok(Some(serde_json::to_string_pretty(&value)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // 读取指定偏移记录的消息头，不返回消息体
+    async fn read_headers(&self, offset: u64) -> io::Result<Option<Vec<u8>>> {
+        match self.read_record(offset).await? {
+            Some(record) => {
+                let (headers, _) = decode_headers(self.strip_prefixes(&record))?;
+                Ok(Some(encode_headers(&headers)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // 带消息ID的写入，开启去重的 broker 对重复的ID不再写入，返回 true 表示该消息是重复的
+    async fn receive_message_with_id(&mut self, id: &str, payload: Vec<u8>) -> io::Result<bool> {
+        if let Some(dedup) = self.dedup.as_mut() {
+            if dedup.get(id).is_some() {
+                return Ok(true);
+            }
+        }
+        let (offset, _) = self.append(payload).await?;
+        if let Some(dedup) = self.dedup.as_mut() {
+            dedup.insert(id, offset)?;
+        }
+        Ok(false)
+    }
+
+    // PULL 的响应：先发送头部 [字节数: u32][下一个偏移: u64]，再发送该字节数的记录，每条为 [len: u32][offset: u64][记录]。
+    // 从 since_offset 开始（EARLIEST 表示最早的消息，LATEST 表示最新的消息）最多 max_count 条、不超过 pull_max_limit 和请求的 max_bytes（至少一条）的
+    // 连续记录；没有记录时字节数为 0，下一个偏移是应当继续读取的位置。这里只准备响应，由 send_pull_reply 在释放锁之后发送
+    async fn prepare_pull(&self, since_offset: u64, max_count: Option<u32>, max_bytes: Option<u32>) -> io::Result<PullReply> {
+        self.touch();
+        let max_count = max_count.map_or(u32::MAX, |max_count| max_count.max(1));
+        // 请求的上限只能收紧 pull_max_limit，不能放宽
+        let max_bytes = max_bytes.map_or(self.store.pull_max_limit(), |max_bytes| {
+            (max_bytes as usize).min(self.store.pull_max_limit())
+        });
+        let since_offset = self.store.resolve_offset(since_offset).await;
+        // 过期的记录不返回，响应中的下一个偏移越过它们
+        let since_offset = self.skip_expired(since_offset).await?;
+        // 压缩保存的记录不能直接发送文件内容，解压后在内存中组装
+        if self.zstd.is_some() {
+            let (records, next_offset) = match self.decoded_records(since_offset, max_count, max_bytes).await {
+                Ok(decoded) => decoded,
+                // 空回复会让消费者在同一偏移反复重试，记录过大或无法解压时明确拒绝
+                Err(e) => {
+                    error!("Pull at offset {} failed: {}", since_offset, e);
+                    let refused: (StatusCode, &'static [u8]) = if e.kind() == io::ErrorKind::OutOfMemory {
+                        (StatusCode::Limit, b"RESPONSE_TOO_LARGE")
+                    } else {
+                        (StatusCode::Other, b"READ_FAILED")
+                    };
+                    return Ok(PullReply { header: Vec::new(), records: Vec::new(), file: None, refused: Some(refused) });
+                }
+            };
+            return Ok(PullReply { header: pull_header(records.len(), next_offset)?, records, file: None, refused: None });
+        }
+        let range = match self.store.pull_range(since_offset, max_count, max_bytes).await {
+            Ok(range) => Some(range),
+            // 该偏移没有记录（消费者已经读到最新，或记录已被清理）不是错误，从仍然保存的第一条记录继续
+            Err(StorageError::OffsetOutOfRange { offset, next }) => {
+                debug!("No records at offset {} (next offset {})", offset, next);
+                None
+            }
+            Err(e) => {
+                error!("Error: {}", e);
                 None
             }
+        };
+        match range {
+            Some(range) => Ok(PullReply {
+                header: pull_header(range.size, range.first + range.count as u64)?,
+                records: Vec::new(),
+                file: Some((self.store.range_file(&range).await?, range)),
+                refused: None,
+            }),
+            None => Ok(PullReply {
+                header: pull_header(0, since_offset.max(self.store.first_offset().await))?,
+                records: Vec::new(),
+                file: None,
+                refused: None,
+            }),
+        }
+    }
+
+    // 与 pull_range 相同的语义，解压后组装最多 max_count 条记录，返回组装的数据和下一个偏移。
+    // 响应在内存中组装，同时受 max_buffered 限制；单条记录超过上限时返回错误
+    async fn decoded_records(&self, since_offset: u64, max_count: u32, max_bytes: usize) -> io::Result<(Vec<u8>, u64)> {
+        let end = self.store.next_offset();
+        let first = self.store.first_offset().await;
+        // 已被清理的记录跳过，从仍然保存的第一条记录开始
+        let mut offset = since_offset.max(first);
+        let limit = max_bytes.min(self.max_buffered);
+        let mut response = Vec::new();
+        let mut count = 0;
+        while offset < end && count < max_count {
+            let record = self.read_record(offset).await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "index entry not found")
+            })?;
+            if response.len() + record.len() + 12 > limit {
+                if response.is_empty() && record.len() + 12 > self.max_buffered {
+                    return Err(io::Error::new(
+                        io::ErrorKind::OutOfMemory,
+                        format!("record {} exceeds max_buffered_response_bytes", offset),
+                    ));
+                }
+                // 与 pull_range 一致，至少返回一条记录
+                if !response.is_empty() {
+                    break;
+                }
+            }
+            response.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            response.extend_from_slice(&offset.to_be_bytes());
+            response.extend_from_slice(&record);
+            offset += 1;
+            count += 1;
         }
-        
+        Ok((response, offset))
+    }
 }
 
-fn create_directory_if_not_exists(path: &str) -> std::io::Result<()> {
-    if !std::fs::metadata(path).map(|m| m.is_dir()).unwrap_or(false) {
-        std::fs::create_dir_all(path)?;
-        println!("Directory created: {}", path);
-    } else {
-        println!("Directory already exists: {}", path);
+// prepare_pull 准备好的 PULL 响应：头部加上内存中组装的记录，或者通过 sendfile 发送的一段文件，或者拒绝时的错误回复
+struct PullReply {
+    header: Vec<u8>,
+    records: Vec<u8>,
+    file: Option<(std::fs::File, RecordRange)>,
+    refused: Option<(StatusCode, &'static [u8])>, // 无法读取时发给客户端的错误回复，此时其他字段为空
+}
+
+// 发送 PULL 响应，返回发送的记录字节数。调用时不持有 broker 的锁，慢消费者只阻塞自己的连接
+async fn send_pull_reply(reply: PullReply, stream: &mut ServerStream, connection: &Connection) -> io::Result<usize> {
+    if let Some((code, refused)) = reply.refused {
+        send_refusal(stream, connection, PULL_COMMAND, code, refused).await?;
+        return Ok(0);
     }
-    Ok(())
+    let mut response = reply.header;
+    response.extend_from_slice(&reply.records);
+    connection.add_sent(response.len());
+    stream.write_all(&response).await?;
+    let Some((file, range)) = reply.file else {
+        return Ok(reply.records.len());
+    };
+    // 头部已经发出，发送失败时只能断开连接
+    let sent = stream.send_file_range(&file, range.start, range.size).await?;
+    connection.add_sent(sent);
+    debug!("send data {} bytes", sent);
+    Ok(sent)
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let config_content = fs::read_to_string("config.toml")?;
-    
-    let config: Config = toml::from_str(&config_content)?;
+// PULL 响应的头部 [字节数: u32][下一个偏移: u64]
+fn pull_header(size: usize, next_offset: u64) -> io::Result<Vec<u8>> {
+    let size = u32::try_from(size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("pull response of {} bytes is too large", size)))?;
+    let mut header = Vec::with_capacity(12);
+    header.extend_from_slice(&size.to_be_bytes());
+    header.extend_from_slice(&next_offset.to_be_bytes());
+    Ok(header)
+}
 
-    create_directory_if_not_exists(&config.server.path)?;
-    let brokers = Arc::new(DashMap::new());
-    
-    for broker_folder in std::fs::read_dir(PathBuf::from(&config.server.path))? {
-        let folder = broker_folder?;
-        let file_type = folder.file_type()?;
-        if file_type.is_dir() {
-            let file_name = folder.file_name().to_string_lossy().to_string();
-            let new_broker = Arc::new(RwLock::new(Broker::new(file_name.clone(),&config).await));
-            brokers.insert(file_name, new_broker.clone());
+fn open_zstd_store(name: &str, dir: &std::path::Path, broker_config: &BrokerOverride) -> io::Result<Option<ZstdStore>> {
+    match broker_config.compression.as_deref() {
+        Some("zstd") => {
+            let samples = broker_config.dictionary_samples.unwrap_or(DEFAULT_DICTIONARY_SAMPLES);
+            Ok(Some(ZstdStore::open(dir, broker_config.zstd_dictionary, samples)?))
+        }
+        Some(other) => {
+            warn!("Broker {}: unsupported compression {:?}, storing records uncompressed", name, other);
+            Ok(None)
         }
+        None => Ok(None),
     }
-    
-    
-    let address = format!("{}:{}",config.server.address,config.server.port);
-    let listener = TcpListener::bind(address).await?;
-    
-    
-    let config_for_clear = config.clone();
-    // 启动一个独立的任务来定期执行文件清理
-    tokio::spawn(async move {
-        loop {
-            let path = &config_for_clear.server.path.as_str();
-            let files_limit = config_for_clear.storage.cache_limit+1;
-            match delete_old_files(path,files_limit).await {
-                Ok(_) => println!("Old files deleted successfully."),
-                Err(e) => eprintln!("Error deleting old files: {}", e),
-            }
-            // 每20秒执行一次
-            time::sleep(Duration::from_secs(40)).await;
+}
+
+fn open_coalescer(name: &str, broker_config: &BrokerOverride) -> Option<Coalescer> {
+    if !broker_config.coalesce {
+        return None;
+    }
+    // 带消息头的记录以消息头开始，不能再按合并格式拆分
+    if broker_config.headers {
+        warn!("Broker {}: coalesce cannot be combined with headers, storing messages individually", name);
+        return None;
+    }
+    let window = Duration::from_millis(broker_config.coalesce_window_ms.unwrap_or(DEFAULT_COALESCE_WINDOW_MS));
+    let max_bytes = broker_config
+        .coalesce_max_bytes
+        .as_deref()
+        .and_then(|s| parse_size(s).ok())
+        .unwrap_or(DEFAULT_COALESCE_MAX_BYTES);
+    Some(Coalescer::new(window, max_bytes))
+}
+
+// record_codecs 要求每条记录以编码字节开始，与消息头、时间戳和合并格式冲突
+fn record_codecs_enabled(name: &str, broker_config: &BrokerOverride) -> bool {
+    if !broker_config.record_codecs {
+        return false;
+    }
+    if broker_config.headers || broker_config.timestamps || broker_config.coalesce {
+        warn!("Broker {}: record_codecs cannot be combined with headers, timestamps or coalesce, storing records without codec", name);
+        return false;
+    }
+    true
+}
+
+// 键保存在记录开头，与同样放在开头的消息头、编码字节和合并格式冲突
+fn keyed_enabled(name: &str, broker_config: &BrokerOverride) -> bool {
+    if !broker_config.keyed {
+        return false;
+    }
+    if broker_config.headers || broker_config.record_codecs || broker_config.coalesce {
+        warn!("Broker {}: keyed cannot be combined with headers, record_codecs or coalesce, storing records without keys", name);
+        return false;
+    }
+    true
+}
+
+// message_ttl 依赖记录中的写入时间戳，没有开启 timestamps 或格式错误时忽略
+fn message_ttl_ms(name: &str, broker_config: &BrokerOverride) -> Option<i64> {
+    let ttl = broker_config.message_ttl.as_deref()?;
+    if !broker_config.timestamps {
+        warn!("Broker {}: message_ttl requires timestamps = true, records never expire", name);
+        return None;
+    }
+    match parse_duration(ttl) {
+        Ok(secs) => Some(secs.saturating_mul(1000).min(i64::MAX as u64) as i64),
+        Err(e) => {
+            warn!("Broker {}: invalid message_ttl {:?} ({}), records never expire", name, ttl, e);
+            None
         }
-    });
+    }
+}
 
-    println!("Broker server is running on 0.0.0.0:8080");
+// PUSH 写入一条消息；coalesce 模式下消息加入当前窗口，等合并记录写入后返回它的偏移和时间戳
+async fn push_message(broker: &RwLock<Broker>, payload: Vec<u8>) -> io::Result<(u64, i64)> {
+    let (pending, window): (Pending, Duration) = {
+        let mut broker = broker.write().await;
+        match broker.coalescer.as_mut() {
+            Some(coalescer) => (coalescer.add(&payload), coalescer.window()),
+            None => return broker.receive_message(payload).await,
+        }
+    };
+    if pending.full {
+        broker.write().await.flush_coalesced(pending.generation).await;
+    } else if pending.opened {
+        // 窗口内的第一条消息负责在窗口结束时写入
+        time::sleep(window).await;
+        broker.write().await.flush_coalesced(pending.generation).await;
+    }
+    match pending.result.await {
+        Ok(result) => result.map_err(|kind| io::Error::new(kind, "coalesced write failed")),
+        Err(_) => Err(io::Error::other("coalesced write dropped")),
+    }
+}
 
+// 接受连接并为每个连接启动处理任务，admin_listener 表示这是 [admin] 配置的管理端口
+// 接受连接直到 shutdown 变为 true；之后不再接受新连接，通知已有连接在当前请求完成后关闭，
+// 最多等待 SHUTDOWN_TIMEOUT_SECS
+async fn serve(
+    listener: TcpListener,
+    brokers: Arc<DashMap<String, Arc<RwLock<Broker>>>>,
+    config: Arc<LiveConfig>,
+    admin_listener: bool,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<()> {
+    // 证书在启动时加载，RELOAD_CONFIG 不能修改 [tls]
+    #[cfg(feature = "tls")]
+    let acceptor = config.get().tls.as_ref().map(tls::acceptor).transpose()?;
+    #[cfg(not(feature = "tls"))]
+    if config.get().tls.is_some() {
+        warn!("Ignoring [tls]: built without the tls feature");
+    }
+    let registry = ConnectionRegistry::global();
+    // 管理端口不受连接数上限限制，数据端口被占满时仍然可以管理
+    let connection_permits = match config.get().server.max_connections {
+        Some(limit) if !admin_listener => Some(Arc::new(Semaphore::new(limit))),
+        _ => None,
+    };
+    let mut tasks = JoinSet::new();
+    // 任务编号到连接编号；按任务编号移除，任务 panic 时也能从 JoinError 取得任务编号
+    let mut open = HashMap::new();
     loop {
-        let (stream, _) = listener.accept().await?;
-        let brokers = brokers.clone();
-        let config = config.clone();
-        tokio::spawn(async move {
-            handle_client(stream, brokers,config).await.unwrap();
-        });
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let peer = stream
+                    .peer_addr()
+                    .map(|addr| addr.to_string())
+                    .unwrap_or_else(|_| "unknown".to_string());
+                // 许可在连接的任务结束时释放；达到上限时直接关闭新连接，不排队占用文件描述符
+                let permit = match &connection_permits {
+                    Some(permits) => match permits.clone().try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            warn!("Rejected connection from {}: max_connections reached", peer);
+                            drop(stream);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                let connection = registry.register(peer);
+                let id = connection.id;
+                // 连接上的所有日志都带有连接编号和对端地址
+                let span = info_span!("connection", id, peer = %connection.peer);
+                let brokers = brokers.clone();
+                let config = config.clone();
+                #[cfg(feature = "tls")]
+                let acceptor = acceptor.clone();
+                let task = tasks.spawn(async move {
+                    let _permit = permit;
+                    // 握手在连接自己的任务中进行，慢速或失败的握手不影响接受其他连接
+                    #[cfg(feature = "tls")]
+                    let stream = match acceptor {
+                        Some(acceptor) => {
+                            let handshake = Duration::from_secs(config.get().server.frame_timeout_secs());
+                            match time::timeout(handshake, acceptor.accept(stream)).await {
+                                Ok(Ok(stream)) => ServerStream::Tls(Box::new(stream)),
+                                Ok(Err(e)) => {
+                                    warn!("TLS handshake with {} failed: {}", connection.peer, e);
+                                    return;
+                                }
+                                Err(_) => {
+                                    warn!("TLS handshake with {} timed out", connection.peer);
+                                    return;
+                                }
+                            }
+                        }
+                        None => ServerStream::Plain(stream),
+                    };
+                    #[cfg(not(feature = "tls"))]
+                    let stream = ServerStream::Plain(stream);
+                    if let Err(e) = handle_client(stream, connection.shared(), brokers, config, admin_listener).await {
+                        error!("Error: {}", e);
+                    }
+                }.instrument(span));
+                open.insert(task.id(), id);
+            }
+            Some(joined) = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                open.remove(&finished_task(joined));
+            }
+            _ = shutdown_requested(&mut shutdown) => break,
+        }
+    }
+    drop(listener);
+
+    // 与 KICK 相同：空闲的连接立即关闭，正在处理的请求完成后关闭
+    for id in open.values() {
+        registry.kick(*id);
+    }
+    let drained = time::timeout(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS), async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!("{} connections still open after {} s, aborting them", tasks.len(), SHUTDOWN_TIMEOUT_SECS);
+        tasks.shutdown().await;
+    }
+    Ok(())
+}
+
+// 等待关闭请求；发送端已释放且没有请求关闭时一直等待
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|requested| *requested).await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+// 等待 Ctrl-C 或 SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// 结束的连接任务的任务编号，任务 panic 时记录错误
+fn finished_task(joined: Result<(tokio::task::Id, ()), tokio::task::JoinError>) -> tokio::task::Id {
+    match joined {
+        Ok((task, ())) => task,
+        Err(e) => {
+            error!("Connection task failed: {}", e);
+            e.id()
+        }
+    }
+}
+
+// 一个请求处理完之后连接的去向
+enum AfterRequest {
+    Continue,  // 读取下一个请求
+    Close,     // 关闭连接
+    Multiplex, // MUX 之后改用带请求编号的帧
+}
+
+async fn handle_client(
+    mut stream: ServerStream,
+    connection: Arc<Connection>,
+    brokers: Arc<DashMap<String, Arc<RwLock<Broker>>>>,
+    live_config: Arc<LiveConfig>,
+    admin_listener: bool,
+) -> io::Result<()>{
+    // 多路复用连接中的一个请求：管道中只有这一个请求，KICK 由连接的读取循环处理
+    let multiplexed = matches!(stream, ServerStream::Multiplexed(..));
+    if !multiplexed {
+        debug!("Connection opened");
+    }
+    loop {
+        let mut len_buf = [0; 4];
+        // 在等待下一条请求时响应 KICK，正在处理的请求不会被打断
+        tokio::select! {
+            result = AsyncReadExt::read_exact(&mut stream, &mut len_buf) => {
+                if result.is_err() {
+                    break;
+                }
+            }
+            _ = connection.kicked(), if !multiplexed => {
+                info!("Connection kicked");
+                break;
+            }
+        }
+        // 每个请求使用收到时的配置，RELOAD_CONFIG 之后的请求使用新配置
+        let config = live_config.get();
+        let frame_timeout = Duration::from_secs(config.server.frame_timeout_secs());
+        let slow_pull = Duration::from_millis(config.server.slow_pull_ms());
+        let max_message_size = config.max_message_size();
+        let message_len = u32::from_be_bytes(len_buf) as usize;
+        // 在分配缓冲区之前检查长度前缀，防止恶意的长度耗尽内存；消息体没有读取，只能关闭连接。
+        // PUSH_BATCH 中的每条消息都在帧内，同样受这个上限约束
+        if message_len > max_message_size {
+            warn!(
+                "Rejecting frame of {} bytes from {}: max_message_size is {}",
+                message_len,
+                connection.peer,
+                max_message_size
+            );
+            let _ = send_error(&mut stream, &connection, StatusCode::Limit, b"MESSAGE_TOO_LARGE").await;
+            break;
+        }
+        let mut buffer = vec![0; message_len];
+        // 读到长度前缀后，消息体必须在限定时间内到齐，防止慢速攻击长期占用连接
+        match time::timeout(frame_timeout, AsyncReadExt::read_exact(&mut stream, &mut buffer)).await {
+            Ok(Ok(_)) => connection.add_received(4 + message_len),
+            Ok(Err(_)) => break,
+            Err(_) => {
+                warn!(
+                    "Slowloris warning: frame from {:?} not completed within {:?}, closing connection",
+                    stream.peer_addr().ok(),
+                    frame_timeout
+                );
+                break;
+            }
+        }
+
+        // 格式错误的请求回复 BAD_REQUEST，长度前缀完整，连接可以继续使用
+        let frame = match parse_frame(&buffer) {
+            Ok(frame) => frame,
+            Err(e) => {
+                send_bad_request(&mut stream, &connection, &e).await?;
+                continue;
+            }
+        };
+        // 每个请求在自己的 span 中处理，请求期间的日志都带有命令、broker 和请求中的偏移
+        let span = info_span!("request", command = %frame.command, broker = %frame.broker, offset = field::Empty);
+        let request = async {
+            debug!("Request received");
+            let admin = config.admin_key() == Some(frame.key.as_str());
+            // 管理端口只接受管理密钥
+            if (admin_listener || !config.is_client_key(&frame.key)) && !admin {
+                warn!("Authentication failed");
+                metrics::AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+                // 回复后关闭连接，发送失败也不再处理
+                let _ = send_refusal(&mut stream, &connection, &frame.command, StatusCode::AuthFailed, b"Server authentication failed.").await;
+                return Ok(AfterRequest::Close)
+            }
+
+            let command = frame.command.as_str();
+            // 多路复用连接上的请求并发处理，管理权限由 MUX 请求决定，之后的请求不能改变
+            if multiplexed && admin != connection.is_admin() {
+                send_refusal(&mut stream, &connection, command, StatusCode::AuthFailed, b"UNAUTHORIZED").await?;
+                return Ok(AfterRequest::Continue);
+            }
+            connection.set_admin(admin);
+
+            // 配置了独立的管理端口时，管理命令只能在管理端口执行，管理端口也只执行管理命令
+            if config.admin.is_some() {
+                let admin_command = ADMIN_COMMANDS.contains(&command);
+                if admin_command && !admin_listener {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"ADMIN_ONLY").await?;
+                    return Ok(AfterRequest::Continue);
+                }
+                if admin_listener && !admin_command && command != PING_COMMAND && command != STATS_COMMAND {
+                    send_refusal(&mut stream, &connection, command, StatusCode::AuthFailed, b"NOT_ADMIN_COMMAND").await?;
+                    return Ok(AfterRequest::Continue);
+                }
+            }
+
+            // PING 不涉及任何 broker，直接回复 PONG
+            if command == PING_COMMAND {
+                send_response(&mut stream, &connection, b"PONG").await?;
+                return Ok(AfterRequest::Continue);
+            }
+
+            if multiplexed && (command == MUX_COMMAND || STREAMING_COMMANDS.contains(&command)) {
+                send_bad_request(&mut stream, &connection, &ProtocolError::NotMultiplexable(command.to_string())).await?;
+                return Ok(AfterRequest::Continue);
+            }
+            // 回复 OK 之后连接改用带请求编号的帧，请求可以并发处理、乱序回复
+            if command == MUX_COMMAND {
+                send_response(&mut stream, &connection, b"OK").await?;
+                return Ok(AfterRequest::Multiplex);
+            }
+
+            // 写入命令必须指定 broker，在访问存储之前拒绝
+            if frame.broker.is_empty() && WRITE_COMMANDS.contains(&command) {
+                send_bad_request(&mut stream, &connection, &ProtocolError::EmptyBroker).await?;
+                return Ok(AfterRequest::Continue);
+            }
+
+            // 解析出 broker 名称后按 [[acl]] 检查密钥对该 broker 的读写权限，管理密钥不受 ACL 限制
+            if !frame.broker.is_empty() && !admin {
+                let write = WRITE_COMMANDS.contains(&command);
+                if !config.broker_access(&frame.broker, &frame.key, write) {
+                    warn!(
+                        "Rejecting {} on broker {} from {}: key not permitted",
+                        command,
+                        frame.broker,
+                        connection.peer
+                    );
+                    metrics::AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+                    send_refusal(&mut stream, &connection, command, StatusCode::AuthFailed, b"UNAUTHORIZED").await?;
+                    return Ok(AfterRequest::Continue);
+                }
+            }
+
+            // 租户密钥自动创建的 broker 达到 max_brokers 后不能再创建新的 broker，已有的 broker 不受影响
+            if !frame.broker.is_empty() && broker_quota_exceeded(&brokers, &frame.broker, &config, &frame.key).await {
+                send_refusal(&mut stream, &connection, command, StatusCode::Limit, b"BROKER_QUOTA_EXCEEDED").await?;
+                return Ok(AfterRequest::Continue);
+            }
+
+            // 要创建新 broker 但已达到 broker_limit，开启 evict_idle 时先尝试卸载一个空闲的 broker
+            if !frame.broker.is_empty()
+                && !LOOKUP_ONLY_COMMANDS.contains(&command)
+                && broker_limit_reached(&brokers, &frame.broker, &config).await
+            {
+                send_error(&mut stream, &connection, StatusCode::Limit, b"BROKER_LIMIT_REACHED").await?;
+                return Ok(AfterRequest::Continue);
+            }
+
+            // CONNECTIONS 列出当前连接，每行 "编号 地址 身份 连接时间(毫秒) 接收字节 发送字节"，只允许管理密钥
+            if command == CONNECTIONS_COMMAND {
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                    return Ok(AfterRequest::Continue);
+                }
+                let mut lines = String::new();
+                for other in ConnectionRegistry::global().list() {
+                    lines.push_str(&format!(
+                        "{} {} {} {} {} {}\n",
+                        other.id,
+                        other.peer,
+                        other.identity(),
+                        other.connected_at,
+                        other.bytes_in(),
+                        other.bytes_out()
+                    ));
+                }
+                send_response(&mut stream, &connection, lines.as_bytes()).await?;
+                return Ok(AfterRequest::Continue);
+            }
+
+            // RELOAD_CONFIG 重新读取配置文件，替换限制、密钥和 ACL；不能在运行中修改的字段变化时拒绝并保留当前配置
+            if command == RELOAD_CONFIG_COMMAND {
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                    return Ok(AfterRequest::Continue);
+                }
+                match reload_config(&live_config, &brokers).await {
+                    Ok(()) => send_response(&mut stream, &connection, b"OK").await?,
+                    Err(e) => {
+                        warn!("Config reload rejected: {}", e);
+                        send_error(&mut stream, &connection, StatusCode::Other, format!("RELOAD_REJECTED: {}", e).as_bytes()).await?;
+                    }
+                }
+                return Ok(AfterRequest::Continue);
+            }
+
+            // KICK 关闭指定编号的连接，只允许管理密钥
+            if command == KICK_COMMAND {
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                    return Ok(AfterRequest::Continue);
+                }
+                let id = match frame.offset() {
+                    Ok(id) => id,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                if ConnectionRegistry::global().kick(id) {
+                    send_response(&mut stream, &connection, b"OK").await?;
+                } else {
+                    send_response(&mut stream, &connection, b"NOT_FOUND").await?;
+                }
+                return Ok(AfterRequest::Continue);
+            }
+
+            // LOG_STREAM 订阅服务端日志事件，之后该连接只用于推送事件，只允许管理密钥
+            if command == LOG_STREAM_COMMAND {
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                    return Ok(AfterRequest::Continue);
+                }
+                let level = String::from_utf8_lossy(&frame.body).into_owned();
+                match events::parse_level(&level) {
+                    Some(min_level) => {
+                        let events = events::subscribe();
+                        send_response(&mut stream, &connection, b"OK").await?;
+                        stream_events(&mut stream, &connection, events, min_level).await?;
+                    }
+                    None => send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_LEVEL").await?,
+                }
+                return Ok(AfterRequest::Close);
+            }
+
+            // STATS 返回进程级的统计信息，每行一个 "名称 值"
+            if command == STATS_COMMAND {
+                let governor = IndexGovernor::global();
+                let stats = format!(
+                    "brokers {}\nindex_mmap_bytes {}\nindex_mmap_limit {}\nindex_mmap_evictions {}\nslow_pulls {}\n",
+                    brokers.len(),
+                    governor.resident(),
+                    governor.limit(),
+                    governor.evictions(),
+                    metrics::SLOW_PULLS.load(std::sync::atomic::Ordering::Relaxed)
+                );
+                send_response(&mut stream, &connection, stats.as_bytes()).await?;
+                return Ok(AfterRequest::Continue);
+            }
+
+            // LIST_BROKERS 回复 OK 后每个 broker 一帧 [当前文件 base_offset: u64][下一个偏移: u64][当前数据文件字节: u64][名称]，
+            // 以长度为 0 的帧结束；只列出该密钥有读权限的 broker
+            if command == LIST_BROKERS_COMMAND {
+                let loaded: Vec<String> = brokers
+                    .iter()
+                    .filter(|entry| admin || config.broker_access(entry.key(), &frame.key, false))
+                    .map(|entry| entry.key().clone())
+                    .collect();
+                // 偏移和长度从共享的 StorePosition 读取，不获取 broker 的锁，COMPACT 等持有写锁的操作不会阻塞列出
+                let mut listed = Vec::with_capacity(loaded.len());
+                for name in loaded {
+                    if let Some(position) = broker_positions().get(&broker_key(&config, &name)) {
+                        listed.push((name, position.snapshot()));
+                    }
+                }
+                // 被卸载的 broker 也列出，使用卸载时的偏移和长度
+                let server_path = Path::new(&config.server.path);
+                for entry in evicted_brokers().iter() {
+                    let Some(name) = entry.key().strip_prefix(server_path).ok().and_then(|name| name.to_str()) else {
+                        continue;
+                    };
+                    if admin || config.broker_access(name, &frame.key, false) {
+                        listed.push((name.to_string(), *entry.value()));
+                    }
+                }
+                // 列出期间重新打开的 broker 只保留一项
+                listed.sort_by(|a, b| a.0.cmp(&b.0));
+                listed.dedup_by(|a, b| a.0 == b.0);
+                send_response(&mut stream, &connection, b"OK").await?;
+                for (name, listing) in listed {
+                    let mut content = Vec::with_capacity(24 + name.len());
+                    for value in listing {
+                        content.extend_from_slice(&value.to_be_bytes());
+                    }
+                    content.extend_from_slice(name.as_bytes());
+                    send_frame(&mut stream, &connection, &content).await?;
+                }
+                send_frame(&mut stream, &connection, &[]).await?;
+                return Ok(AfterRequest::Continue);
+            }
+
+            if command == PUSH_COMMAND || command == PUSH_COMPRESSED_COMMAND {
+                let broker_name = frame.broker.clone();
+                let mut payload = frame.body;
+                if payload.is_empty() {
+                    send_bad_request(&mut stream, &connection, &ProtocolError::EmptyPayload).await?;
+                    return Ok(AfterRequest::Continue);
+                }
+
+                if let Some(broker) = get_broker(&brokers, broker_name.clone(), &config, &frame.key).await{
+                    // 压缩传输的消息在写入前解压，保存的是原始数据；record_codecs 的 broker 解压校验后保存收到的压缩数据
+                    let mut keep_compressed = false;
+                    if command == PUSH_COMPRESSED_COMMAND {
+                        keep_compressed = broker.read().await.record_codecs;
+                        // 解压后的消息与未压缩的 PUSH 受同样的 max_message_size 限制；保存压缩数据时只校验，不保留解压结果
+                        let max_len = config.max_message_size();
+                        let checked = if keep_compressed {
+                            validate(&payload, max_len).map(|_| ())
+                        } else {
+                            decompress(&payload, max_len).map(|decompressed| payload = decompressed)
+                        };
+                        match checked {
+                            Ok(()) => {}
+                            Err(e) => {
+                                warn!("Error: {}", e);
+                                send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_COMPRESSION").await?;
+                                return Ok(AfterRequest::Continue);
+                            }
+                        }
+                    }
+                    // 等待写锁期间计入该 broker 的写入队列
+                    let queued = IngestQueues::global().enter(&Path::new(&config.server.path).join(&broker_name));
+                    let pushed = if keep_compressed {
+                        broker.write().await.receive_compressed(&payload).await
+                    } else {
+                        push_message(&broker, payload).await
+                    };
+                    match pushed {
+                        Ok((offset, timestamp)) => {
+                            metrics::PUSHES.fetch_add(1, Ordering::Relaxed);
+                            // 回复 "OK" + 偏移量 + 写入时间戳（毫秒），开启压力提示时再附加一个压力等级字节
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(&offset.to_be_bytes());
+                            content.extend_from_slice(&timestamp.to_be_bytes());
+                            if let Some(limit) = config.server.push_pressure_depth {
+                                content.push(queued.level(limit));
+                            }
+                            drop(queued);
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        Err(e) => match push_error_reply(&e) {
+                            Some((code, reply)) => send_error(&mut stream, &connection, code, reply).await?,
+                            None => return Err(e),
+                        },
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == PUSH_BATCH_COMMAND {
+                let broker_name = frame.broker.clone();
+                let payloads = match frame.batch() {
+                    Ok(payloads) => payloads,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+
+                if let Some(broker) = get_broker(&brokers, broker_name.clone(), &config, &frame.key).await{
+                    let _queued = IngestQueues::global().enter(&Path::new(&config.server.path).join(&broker_name));
+                    match broker.write().await.receive_batch(&payloads).await {
+                        // 回复 "OK" + 写入的消息数（u32），少于请求的数量时其余消息未写入
+                        Ok(appended) => {
+                            metrics::PUSHES.fetch_add(appended as u64, Ordering::Relaxed);
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(&(appended as u32).to_be_bytes());
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        Err(e) => match push_error_reply(&e) {
+                            Some((code, reply)) => send_error(&mut stream, &connection, code, reply).await?,
+                            None => return Err(e),
+                        },
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == PUSH_ID_COMMAND {
+                let broker_name = frame.broker.clone();
+                let (message_id, payload) = match frame.message_id() {
+                    Ok((message_id, payload)) => (message_id, payload.to_vec()),
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+
+                if let Some(broker) = get_broker(&brokers, broker_name, &config, &frame.key).await{
+                    match broker
+                        .write()
+                        .await
+                        .receive_message_with_id(&message_id, payload)
+                        .await
+                    {
+                        Ok(true) => send_response(&mut stream, &connection, b"DUPLICATE").await?,
+                        Ok(false) => {
+                            metrics::PUSHES.fetch_add(1, Ordering::Relaxed);
+                            send_response(&mut stream, &connection, b"OK").await?
+                        }
+                        Err(e) => match push_error_reply(&e) {
+                            Some((code, reply)) => send_error(&mut stream, &connection, code, reply).await?,
+                            None => return Err(e),
+                        },
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == PUSH_HEADERS_COMMAND {
+                let broker_name = frame.broker.clone();
+                // 消息头和消息体原样保存，写入前校验消息头格式
+                let record = frame.body;
+
+                if let Some(broker) = get_broker(&brokers, broker_name, &config, &frame.key).await{
+                    let mut broker = broker.write().await;
+                    if !broker.headers {
+                        send_error(&mut stream, &connection, StatusCode::Other, b"HEADERS_DISABLED").await?;
+                    } else if decode_headers(&record).is_err() {
+                        send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_HEADERS").await?;
+                    } else {
+                        match broker.append_record(&record).await {
+                            Ok(_) => {
+                                metrics::PUSHES.fetch_add(1, Ordering::Relaxed);
+                                send_response(&mut stream, &connection, b"OK").await?
+                            }
+                            Err(e) => match push_error_reply(&e) {
+                                Some((code, reply)) => send_error(&mut stream, &connection, code, reply).await?,
+                                None => return Err(e),
+                            },
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == PUSH_KEYED_COMMAND {
+                let broker_name = frame.broker.clone();
+                // 消息体为 [键长度: u16][键][消息]，原样保存；回复与 PUSH 相同的 "OK" + 偏移量 + 写入时间戳
+                let record = frame.body;
+
+                if let Some(broker) = get_broker(&brokers, broker_name, &config, &frame.key).await{
+                    let mut broker = broker.write().await;
+                    if !broker.keyed {
+                        send_error(&mut stream, &connection, StatusCode::Other, b"KEYS_DISABLED").await?;
+                    } else if decode_key(&record).is_err() {
+                        send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_KEY").await?;
+                    } else {
+                        match broker.append_record(&record).await {
+                            Ok((offset, timestamp)) => {
+                                metrics::PUSHES.fetch_add(1, Ordering::Relaxed);
+                                let mut content = b"OK".to_vec();
+                                content.extend_from_slice(&offset.to_be_bytes());
+                                content.extend_from_slice(&timestamp.to_be_bytes());
+                                send_response(&mut stream, &connection, &content).await?
+                            }
+                            Err(e) => match push_error_reply(&e) {
+                                Some((code, reply)) => send_error(&mut stream, &connection, code, reply).await?,
+                                None => return Err(e),
+                            },
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == PEEK_COMMAND {
+                let broker_name = frame.broker.clone();
+                let offset = match frame.offset() {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                Span::current().record("offset", offset);
+
+                // 只读取指定偏移的一条记录，不使用 sendfile，也不受 pull_max_limit 影响；
+                // 回复 [偏移: u64][记录]，记录不存在时回复空帧
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    catch_up_index(&broker).await?;
+                    let broker = broker.read().await;
+                    let now = chrono::Utc::now().timestamp_millis();
+                    match broker.read_record(offset).await?.filter(|record| !broker.expired(record, now)) {
+                        Some(record) if record.len() + 12 > broker.max_buffered => {
+                            send_error(&mut stream, &connection, StatusCode::Limit, b"RESPONSE_TOO_LARGE").await?;
+                        }
+                        Some(record) => {
+                            let mut content = Vec::with_capacity(record.len() + 8);
+                            content.extend_from_slice(&offset.to_be_bytes());
+                            content.extend_from_slice(&record);
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        None => send_response(&mut stream, &connection, &[]).await?,
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == HEADERS_COMMAND {
+                let broker_name = frame.broker.clone();
+                let offset = match frame.offset() {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                Span::current().record("offset", offset);
+
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    catch_up_index(&broker).await?;
+                    let broker = broker.read().await;
+                    if !broker.headers {
+                        send_error(&mut stream, &connection, StatusCode::Other, b"HEADERS_DISABLED").await?;
+                    } else {
+                        match broker.read_headers(offset).await {
+                            Ok(Some(block)) => {
+                                let mut content = b"OK".to_vec();
+                                content.extend_from_slice(&block);
+                                send_response(&mut stream, &connection, &content).await?;
+                            }
+                            Ok(None) => send_response(&mut stream, &connection, b"NOT_FOUND").await?,
+                            Err(e) => {
+                                error!("Error: {}", e);
+                                send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_HEADERS").await?;
+                            }
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == DEBUG_PULL_COMMAND {
+                let broker_name = frame.broker.clone();
+                let offset = match frame.offset() {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                Span::current().record("offset", offset);
+
+                // 仅供人工调试，只允许管理密钥，且只支持 content_type = "json" 的 broker
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    catch_up_index(&broker).await?;
+                    let broker = broker.read().await;
+                    if broker.content_type.as_deref() != Some("json") {
+                        send_error(&mut stream, &connection, StatusCode::Other, b"NOT_JSON_BROKER").await?;
+                    } else {
+                        match broker.read_pretty_json(offset).await {
+                            // 格式化后的 JSON 比原始记录大，同样受内存响应上限约束
+                            Ok(Some(pretty)) if pretty.len() + 5 > broker.max_buffered => {
+                                send_error(&mut stream, &connection, StatusCode::Limit, b"RESPONSE_TOO_LARGE").await?;
+                            }
+                            Ok(Some(pretty)) => {
+                                let mut content = b"DEBUG".to_vec();
+                                content.extend_from_slice(pretty.as_bytes());
+                                send_response(&mut stream, &connection, &content).await?;
+                            }
+                            Ok(None) => send_response(&mut stream, &connection, b"NOT_FOUND").await?,
+                            Err(e) => {
+                                warn!("Error: {}", e);
+                                send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_JSON").await?;
+                            }
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == REBUILD_INDEX_COMMAND {
+                let broker_name = frame.broker.clone();
+
+                // 重写索引文件的维护操作，只允许管理密钥
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    match broker.write().await.store.rebuild_index().await {
+                        Ok(records) => {
+                            info!("Rebuilt index of broker {} ({} records)", broker_name, records);
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(&records.to_be_bytes());
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        Err(e) => {
+                            error!("Error: {}", e);
+                            send_error(&mut stream, &connection, StatusCode::Other, b"REBUILD_FAILED").await?;
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == COMPACT_COMMAND {
+                let broker_name = frame.broker.clone();
+
+                // 只保留每个键最新的记录，回复 "OK" + 保留的记录数 [u64] + 删除的记录数 [u64]，只允许管理密钥
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let mut broker = broker.write().await;
+                    if !broker.keyed {
+                        send_error(&mut stream, &connection, StatusCode::Other, b"KEYS_DISABLED").await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                    match broker.compact(&broker_name, &config).await {
+                        Ok((kept, removed)) => {
+                            info!("Compacted broker {}: kept {} records, removed {}", broker_name, kept, removed);
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(&kept.to_be_bytes());
+                            content.extend_from_slice(&removed.to_be_bytes());
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        Err(e) => {
+                            error!("Compacting broker {} failed: {}", broker_name, e);
+                            send_error(&mut stream, &connection, StatusCode::Other, b"COMPACT_FAILED").await?;
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == RELOAD_COMMAND {
+                let broker_name = frame.broker.clone();
+
+                // 运维人员在服务运行时修改了 broker 的文件（例如恢复备份）后，持有写锁重新打开目录中的文件，
+                // 回复 "OK" + 重新加载后下一个待写入的偏移 [u64]
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let mut broker = broker.write().await;
+                    let dir = broker.dir.clone();
+                    match broker.reopen(&broker_name, dir, &config).await {
+                        Ok(()) => {
+                            // 文件内容已经改变，之前的租约不再有效
+                            broker.leases = LeaseTable::new(broker.meta.lease_acked());
+                            let next_offset = broker.store.next_offset();
+                            info!("Reloaded broker {}: next offset {}", broker_name, next_offset);
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(&next_offset.to_be_bytes());
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        Err(e) => {
+                            error!("Reloading broker {} failed: {}", broker_name, e);
+                            send_error(&mut stream, &connection, StatusCode::Other, b"RELOAD_FAILED").await?;
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == DELETE_BROKER_COMMAND {
+                let broker_name = frame.broker.clone();
+
+                // 删除 broker 及其目录，不会自动创建 broker；正在读写的 broker 回复 BROKER_BUSY，稍后重试
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else {
+                    match delete_broker(&brokers, &broker_name, &config).await {
+                        Ok(true) => {
+                            info!("Deleted broker {}", broker_name);
+                            send_response(&mut stream, &connection, b"OK").await?;
+                        }
+                        Ok(false) => send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?,
+                        Err(e) if e.kind() == io::ErrorKind::ResourceBusy => {
+                            send_error(&mut stream, &connection, StatusCode::Other, b"BROKER_BUSY").await?;
+                        }
+                        Err(e) => {
+                            error!("Deleting broker {} failed: {}", broker_name, e);
+                            send_error(&mut stream, &connection, StatusCode::Other, b"DELETE_FAILED").await?;
+                        }
+                    }
+                }
+            } else if command == SEGMENTS_COMMAND {
+                let broker_name = frame.broker.clone();
+
+                // 回复 OK 后每个文件一帧 "base_offset 数据文件字节 索引文件字节 索引项数 是否当前文件"，以长度为 0 的帧结束
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let segments = broker.read().await.store.segment_stats().await?;
+                    send_response(&mut stream, &connection, b"OK").await?;
+                    for segment in segments {
+                        let line = format!(
+                            "{} {} {} {} {}",
+                            segment.base_offset,
+                            segment.data_bytes,
+                            segment.index_bytes,
+                            segment.index_entries,
+                            segment.active as u8
+                        );
+                        send_frame(&mut stream, &connection, line.as_bytes()).await?;
+                    }
+                    send_frame(&mut stream, &connection, &[]).await?;
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == PIN_SEGMENT_COMMAND || command == UNPIN_SEGMENT_COMMAND {
+                let broker_name = frame.broker.clone();
+                let base_offset = match frame.offset() {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+
+                // 固定的文件记录在 broker 元数据中，清理任务跳过这些文件；固定不存在的文件或取消未固定的文件回复 NOT_FOUND
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let mut broker = broker.write().await;
+                    let mut pins = broker.meta.pinned_segments();
+                    let changed = if command == PIN_SEGMENT_COMMAND {
+                        let segments = broker.store.segment_stats().await?;
+                        segments.iter().any(|segment| segment.base_offset == base_offset) && {
+                            pins.insert(base_offset);
+                            true
+                        }
+                    } else {
+                        pins.remove(&base_offset)
+                    };
+                    if changed {
+                        broker.meta.set_pinned_segments(&pins)?;
+                        info!("{} {} of broker {}", command, base_offset, broker_name);
+                        send_response(&mut stream, &connection, b"OK").await?;
+                    } else {
+                        send_response(&mut stream, &connection, b"NOT_FOUND").await?;
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == LEASE_COMMAND {
+                let broker_name = frame.broker.clone();
+                // 数据开头的 u64 为租约时长（毫秒）
+                let timeout_ms = match frame.offset() {
+                    Ok(timeout_ms) => timeout_ms,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+
+                // 回复 "LEASE" + [lease_id: u64] + PULL 格式的记录，没有可租的记录时回复 EMPTY
+                if timeout_ms == 0 {
+                    send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_TIMEOUT").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let mut broker = broker.write().await;
+                    if broker.store.needs_index_catch_up() {
+                        broker.store.catch_up_index().await?;
+                    }
+                    match broker.lease_records(Duration::from_millis(timeout_ms)).await {
+                        Ok(Some((id, records))) => {
+                            let mut content = Vec::with_capacity(records.len() + 13);
+                            content.extend_from_slice(b"LEASE");
+                            content.extend_from_slice(&id.to_be_bytes());
+                            content.extend_from_slice(&records);
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        Ok(None) => send_response(&mut stream, &connection, b"EMPTY").await?,
+                        Err(e) if e.kind() == io::ErrorKind::OutOfMemory => {
+                            send_error(&mut stream, &connection, StatusCode::Limit, b"RESPONSE_TOO_LARGE").await?;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == ACK_LEASE_COMMAND {
+                let broker_name = frame.broker.clone();
+                let lease_id = match frame.offset() {
+                    Ok(lease_id) => lease_id,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+
+                // 到期的租约已被重新租出，确认时回复 NOT_FOUND，消费者应放弃这批记录的处理结果
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let mut broker = broker.write().await;
+                    let acked = broker.leases.acked();
+                    if broker.leases.ack(lease_id, time::Instant::now()) {
+                        if broker.leases.acked() != acked {
+                            let acked = broker.leases.acked();
+                            broker.meta.set_lease_acked(acked)?;
+                        }
+                        send_response(&mut stream, &connection, b"OK").await?;
+                    } else {
+                        send_response(&mut stream, &connection, b"NOT_FOUND").await?;
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == MIGRATE_PATH_COMMAND {
+                let broker_name = frame.broker.clone();
+                let new_path = String::from_utf8_lossy(&frame.body).into_owned();
+
+                if !connection.is_admin() {
+                    send_error(&mut stream, &connection, StatusCode::AuthFailed, b"FORBIDDEN").await?;
+                } else if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    match migrate_broker(&broker, &broker_name, &new_path, &config).await {
+                        Ok(new_dir) => {
+                            info!("Migrated broker {} to {}", broker_name, new_dir.display());
+                            send_response(&mut stream, &connection, b"OK").await?;
+                        }
+                        Err(e) => {
+                            error!("Migrating broker {} failed: {}", broker_name, e);
+                            send_error(&mut stream, &connection, StatusCode::Other, b"MIGRATE_FAILED").await?;
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == GET_META_COMMAND {
+                let broker_name = frame.broker.clone();
+                let key = String::from_utf8_lossy(&frame.body).into_owned();
+
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let broker = broker.read().await;
+                    match broker.meta.get(&key) {
+                        Some(value) => {
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(value.as_bytes());
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        None => send_response(&mut stream, &connection, b"NOT_FOUND").await?,
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == SET_META_COMMAND {
+                let broker_name = frame.broker.clone();
+                // 键值对与消息头使用相同的编码
+                let pair = decode_headers(&frame.body)
+                    .ok()
+                    .and_then(|(mut pairs, _)| if pairs.len() == 1 { pairs.pop() } else { None })
+                    // 固定文件列表和租约确认位置由服务端维护
+                    .filter(|(key, _)| !is_reserved_key(key));
+
+                if let Some(broker) = get_broker(&brokers, broker_name, &config, &frame.key).await{
+                    match pair {
+                        Some((key, value)) => match broker.write().await.meta.set(&key, &value) {
+                            Ok(()) => send_response(&mut stream, &connection, b"OK").await?,
+                            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                                send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_META").await?;
+                            }
+                            Err(e) => return Err(e),
+                        },
+                        None => send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_META").await?,
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == COMMIT_OFFSET_COMMAND {
+                let broker_name = frame.broker.clone();
+                // 数据：[offset: u64][consumer_id]
+                let offset = match frame.offset() {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                Span::current().record("offset", offset);
+                let consumer_id = std::str::from_utf8(&frame.body[8..]).ok().map(str::to_string);
+
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    match consumer_id {
+                        Some(consumer_id) => match broker.write().await.commit_offset(&consumer_id, offset) {
+                            Ok(()) => send_response(&mut stream, &connection, b"OK").await?,
+                            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                                send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_CONSUMER_ID").await?;
+                            }
+                            Err(e) => return Err(e),
+                        },
+                        None => send_error(&mut stream, &connection, StatusCode::BadRequest, b"BAD_CONSUMER_ID").await?,
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == FETCH_COMMITTED_COMMAND {
+                let broker_name = frame.broker.clone();
+                let consumer_id = String::from_utf8_lossy(&frame.body).into_owned();
+
+                // 回复 "OK" + [offset: u64]，该消费者没有提交过时回复 NOT_FOUND
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    match broker.read().await.offsets.get(&consumer_id) {
+                        Some(offset) => {
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(&offset.to_be_bytes());
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        None => send_response(&mut stream, &connection, b"NOT_FOUND").await?,
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == SEEK_TIME_COMMAND {
+                let broker_name = frame.broker.clone();
+                let timestamp = match frame.offset() {
+                    Ok(timestamp) => timestamp as i64,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+
+                // 回复 "OK" + [offset: u64]：第一条写入时间不早于该时间（毫秒）的记录，都更早时为下一个待分配的偏移；
+                // 没有开启 timestamps 的 broker 回复 NO_TIMESTAMPS
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    catch_up_index(&broker).await?;
+                    let broker = broker.read().await;
+                    if !broker.timestamps {
+                        send_error(&mut stream, &connection, StatusCode::Other, b"NO_TIMESTAMPS").await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                    let offset = broker.seek_time(0, timestamp).await?;
+                    let mut content = b"OK".to_vec();
+                    content.extend_from_slice(&offset.to_be_bytes());
+                    send_response(&mut stream, &connection, &content).await?;
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == SUBSCRIBE_COMMAND {
+                let broker_name = frame.broker.clone();
+                let offset = match frame.offset() {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                Span::current().record("offset", offset);
+
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    // 开启心跳检测时，回复中附带客户端应使用的心跳间隔（毫秒）
+                    let interval = config.server.subscriber_heartbeat_ms();
+                    let mut reply = b"OK".to_vec();
+                    let heartbeat = if interval > 0 {
+                        reply.extend_from_slice(&(interval.min(u32::MAX as u64) as u32).to_be_bytes());
+                        Some(Duration::from_millis(interval) * config.server.subscriber_heartbeat_misses())
+                    } else {
+                        None
+                    };
+                    send_response(&mut stream, &connection, &reply).await?;
+                    stream_subscription(&mut stream, &connection, &broker, offset, heartbeat).await?;
+                    // 订阅占用整个连接，结束后关闭
+                    return Ok(AfterRequest::Close);
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == TAIL_BYTES_COMMAND {
+                let broker_name = frame.broker.clone();
+                let n = match frame.offset() {
+                    Ok(n) => n,
+                    Err(e) => {
+                        send_bad_request(&mut stream, &connection, &e).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    // 回复长度是 u32，加上状态字节、"TAIL" 和标志字节后不能超出
+                    let n = n.min(u32::MAX as u64 - 6);
+                    // 归档模式下数据已经写入文件，不需要补建索引；只在取范围和文件句柄时持有读锁，
+                    // 文件已写入的部分不会改变，发送期间不阻塞 PUSH
+                    let broker_guard = broker.read().await;
+                    let (start, size) = broker_guard.store.tail_range(n);
+                    let file = broker_guard.store.active_file().await?;
+                    drop(broker_guard);
+                    // 响应：[len: u32][STATUS_OK]["TAIL"][是否从文件开头开始: u8][原始字节]，不保证从记录边界开始
+                    let mut header = Vec::with_capacity(10);
+                    header.extend_from_slice(&(size as u32 + 6).to_be_bytes());
+                    header.push(STATUS_OK);
+                    header.extend_from_slice(b"TAIL");
+                    header.push((start == 0) as u8);
+                    connection.add_sent(header.len());
+                    stream.write_all(&header).await?;
+                    let sent = stream.send_file_range(&file, start, size).await?;
+                    connection.add_sent(sent);
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == VERIFY_COMMAND {
+                let broker_name = frame.broker.clone();
+
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    // 只在获取文件列表时短暂持有读锁，历史文件是只读的，校验过程不阻塞写入
+                    let (data_dir, offsets) = broker.read().await.store.sealed_segments().await?;
+                    match tokio::task::spawn_blocking(move || verify_segments(&data_dir, &offsets)).await {
+                        Ok(Ok(report)) => {
+                            let mut content = b"OK".to_vec();
+                            content.extend_from_slice(&encode_verify_report(&report));
+                            send_response(&mut stream, &connection, &content).await?;
+                        }
+                        Ok(Err(e)) => {
+                            error!("Error: {}", e);
+                            send_error(&mut stream, &connection, StatusCode::Other, b"VERIFY_FAILED").await?;
+                        }
+                        Err(e) => {
+                            error!("Error: {}", e);
+                            send_error(&mut stream, &connection, StatusCode::Other, b"VERIFY_FAILED").await?;
+                        }
+                    }
+                } else {
+                    send_error(&mut stream, &connection, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == CONSUMER_PULL_COMMAND {
+                let broker_name = frame.broker.clone();
+                // 数据：[offset: u64][consumer_id]，回复与不带 max_count 的 PULL 相同
+                let offset = match frame.offset() {
+                    Ok(offset) => offset,
+                    Err(e) => {
+                        send_refusal(&mut stream, &connection, command, StatusCode::BadRequest, &bad_request(&connection, &e)).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                Span::current().record("offset", offset);
+                let consumer_id = String::from_utf8_lossy(&frame.body[8..]).into_owned();
+
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    let delivery = {
+                        let mut broker = broker.write().await;
+                        if broker.store.needs_index_catch_up() {
+                            broker.store.catch_up_index().await?;
+                        }
+                        // 已读到末尾的消费者反复拉取下一个偏移是在等待新消息，不算重新投递
+                        let exists = offset < broker.store.next_offset();
+                        match broker.redeliveries.as_mut() {
+                            Some(redeliveries) if exists => redeliveries.pull(&consumer_id, offset),
+                            _ => Delivery::Deliver,
+                        }
+                    };
+                    let mut start = offset;
+                    match delivery {
+                        Delivery::Deliver => {}
+                        Delivery::Skip => start += 1,
+                        // 写入死信 broker 失败时回复 DLQ_UNAVAILABLE，记录仍留在原位，下一次拉取再尝试
+                        Delivery::DeadLetter => match dead_letter(&brokers, &broker, &broker_name, offset, &config).await {
+                            Ok(true) => {
+                                if let Some(redeliveries) = broker.write().await.redeliveries.as_mut() {
+                                    redeliveries.dead_lettered(&consumer_id);
+                                }
+                                warn!("Record {} of broker {} moved to {}{} after repeated pulls by {}", offset, broker_name, broker_name, DLQ_SUFFIX, consumer_id);
+                                start += 1;
+                            }
+                            // 记录已被清理
+                            Ok(false) => {}
+                            Err(e) => {
+                                error!("Dead-lettering record {} of broker {} failed: {}", offset, broker_name, e);
+                                send_refusal(&mut stream, &connection, command, StatusCode::Other, b"DLQ_UNAVAILABLE").await?;
+                                return Ok(AfterRequest::Continue);
+                            }
+                        },
+                    }
+                    let reply = broker.read().await.prepare_pull(start, None, None).await?;
+                    let sent = send_pull_reply(reply, &mut stream, &connection).await?;
+                    metrics::PULLS.fetch_add(1, Ordering::Relaxed);
+                    metrics::BYTES_SENT.fetch_add(sent as u64, Ordering::Relaxed);
+                } else {
+                    send_refusal(&mut stream, &connection, command, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else if command == PULL_COMMAND {
+                let broker_name = frame.broker.clone();
+                let (offset, max_count, max_bytes) = match frame.offset().and_then(|offset| Ok((offset, frame.max_count()?, frame.max_bytes()?))) {
+                    Ok(pull) => pull,
+                    Err(e) => {
+                        send_refusal(&mut stream, &connection, command, StatusCode::BadRequest, &bad_request(&connection, &e)).await?;
+                        return Ok(AfterRequest::Continue);
+                    }
+                };
+                Span::current().record("offset", offset);
+
+                // PULL 只读取已有的 broker，不存在（或已被 DELETE_BROKER 删除）时回复 NO_BROKER
+                if let Some(broker) = lookup_broker(&brokers, &broker_name, &config, &frame.key).await {
+                    // 超出并发上限的 PULL 在这里排队，PUSH 不受影响
+                    let pull_permits = broker.read().await.pull_permits.clone();
+                    let _permit = match pull_permits {
+                        Some(semaphore) => Some(semaphore.acquire_owned().await.map_err(io::Error::other)?),
+                        None => None,
+                    };
+                    let started = time::Instant::now();
+                    // 归档模式的 broker 在读取前补建索引
+                    catch_up_index(&broker).await?;
+                    let broker_guard = broker.read().await;
+                    let resolved = broker_guard.store.resolve_offset(offset).await;
+                    let segment = if broker_guard.store.is_active(resolved) { "active" } else { "historical" };
+                    let reply = broker_guard.prepare_pull(offset, max_count, max_bytes).await?;
+                    // 发送前释放读锁：tokio 的 RwLock 优先写者，发送期间持有读锁会让等待写锁的 PUSH 一直排队
+                    drop(broker_guard);
+                    let sent = send_pull_reply(reply, &mut stream, &connection).await?;
+                    metrics::PULLS.fetch_add(1, Ordering::Relaxed);
+                    metrics::BYTES_SENT.fetch_add(sent as u64, Ordering::Relaxed);
+                    // 慢查询日志，用于发现冷数据读取和磁盘争用
+                    let elapsed = started.elapsed();
+                    if elapsed > slow_pull {
+                        metrics::SLOW_PULLS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        warn!(
+                            "Slow pull: broker={} offset={} bytes={} segment={} elapsed_ms={}",
+                            broker_name,
+                            offset,
+                            sent,
+                            segment,
+                            elapsed.as_millis()
+                        );
+                    }
+                } else {
+                    send_refusal(&mut stream, &connection, command, StatusCode::NoBroker, b"NO_BROKER").await?;
+                }
+            } else {
+                let e = ProtocolError::UnknownCommand(command.to_string());
+                send_bad_request(&mut stream, &connection, &e).await?;
+            }
+            Ok::<_, io::Error>(AfterRequest::Continue)
+        };
+        match request.instrument(span).await? {
+            AfterRequest::Continue => {}
+            AfterRequest::Close => break,
+            AfterRequest::Multiplex => {
+                return serve_multiplexed(stream, connection, brokers, live_config, admin_listener).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 校验结果格式：[segments: u32][records: u64][error_count: u32]([len: u16][error])*
+fn encode_verify_report(report: &VerifyReport) -> Vec<u8> {
+    let mut content = Vec::new();
+    content.extend_from_slice(&report.segments.to_be_bytes());
+    content.extend_from_slice(&report.records.to_be_bytes());
+    content.extend_from_slice(&(report.errors.len() as u32).to_be_bytes());
+    for error in &report.errors {
+        content.extend_from_slice(&(error.len() as u16).to_be_bytes());
+        content.extend_from_slice(error.as_bytes());
+    }
+    content
+}
+
+// 发送带4字节长度前缀的响应
+// 从 offset 开始推送已有和新写入的消息，每条消息的格式与 PULL 相同：[len: u32][offset: u64][数据]
+// 直到客户端断开或被 KICK
+async fn stream_subscription(
+    stream: &mut ServerStream,
+    connection: &Connection,
+    broker: &RwLock<Broker>,
+    offset: u64,
+    heartbeat: Option<Duration>, // 超过该时长没有收到心跳的订阅者被断开，None 表示不检测
+) -> io::Result<()> {
+    let (_guard, mut appended) = broker.read().await.subscribe();
+    let mut next = broker.read().await.store.resolve_offset(offset).await;
+    let mut last_heartbeat = time::Instant::now();
+    let mut buf = [0u8; 64];
+    loop {
+        // 在读锁内读出不超过 pull_max_limit 的一批记录，释放锁之后再写入连接，停止读取的订阅者不会阻塞 PUSH
+        catch_up_index(broker).await?;
+        let (frames, end) = {
+            let broker = broker.read().await;
+            let end = broker.store.next_offset();
+            let limit = broker.store.pull_max_limit();
+            let mut frames = Vec::new();
+            while next < end && (frames.is_empty() || frames.len() < limit) {
+                // 已被清理的历史消息跳过
+                if let Some(record) = broker.read_record(next).await? {
+                    frames.extend_from_slice(&(record.len() as u32).to_be_bytes());
+                    frames.extend_from_slice(&next.to_be_bytes());
+                    frames.extend_from_slice(&record);
+                }
+                next += 1;
+            }
+            (frames, end)
+        };
+        if !frames.is_empty() {
+            connection.add_sent(frames.len());
+            // 订阅者停止读取时写入会一直阻塞，超过心跳窗口视为失效
+            match heartbeat {
+                Some(window) => time::timeout(window, stream.write_all(&frames))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "subscriber stopped reading"))??,
+                None => stream.write_all(&frames).await?,
+            }
+            if next < end {
+                continue;
+            }
+        }
+        let reap_at = heartbeat.map(|window| last_heartbeat + window);
+        tokio::select! {
+            biased;
+            // 订阅后客户端只发送心跳（长度为 0 的帧），收到任何数据都说明订阅者仍然存活，连接关闭则结束订阅
+            read = stream.read(&mut buf) => match read {
+                Ok(n) if n > 0 => last_heartbeat = time::Instant::now(),
+                _ => break,
+            },
+            changed = appended.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = connection.kicked() => break,
+            _ = async { time::sleep_until(reap_at.unwrap()).await }, if reap_at.is_some() => {
+                warn!("Dropping subscriber {} after missing heartbeats", connection.peer);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 推送不低于 min_level 的日志事件，直到客户端断开或被 KICK；跟不上的订阅者丢失的事件以一条警告代替
+async fn stream_events(
+    stream: &mut ServerStream,
+    connection: &Connection,
+    mut events: tokio::sync::broadcast::Receiver<LogEvent>,
+    min_level: Level,
+) -> io::Result<()> {
+    use tokio::sync::broadcast::error::RecvError;
+    let mut probe = [0u8; 1];
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    // tracing 中越详细的级别越大
+                    if event.level <= min_level {
+                        send_frame(stream, connection, event.to_line().as_bytes()).await?;
+                    }
+                }
+                Err(RecvError::Lagged(dropped)) => {
+                    let notice = LogEvent {
+                        timestamp: chrono::Utc::now().timestamp_millis(),
+                        level: Level::WARN,
+                        message: format!("{} events dropped", dropped),
+                    };
+                    send_frame(stream, connection, notice.to_line().as_bytes()).await?;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // 订阅后客户端不再发送请求，读到数据或连接关闭都结束推送
+            _ = stream.read(&mut probe) => break,
+            _ = connection.kicked() => break,
+        }
+    }
+    Ok(())
+}
+
+// MUX 之后的连接：请求帧为 [长度: u32][请求编号: u32][请求]，每个请求在自己的任务中按普通连接的方式处理，
+// 回复写入内存管道。写任务把一个请求的全部回复作为 [长度: u32][请求编号: u32][回复] 发出，按完成的先后顺序。
+// 返回装箱的 Future，打断与 handle_client 之间的递归类型
+fn serve_multiplexed(
+    stream: ServerStream,
+    connection: Arc<Connection>,
+    brokers: Arc<DashMap<String, Arc<RwLock<Broker>>>>,
+    live_config: Arc<LiveConfig>,
+    admin_listener: bool,
+) -> std::pin::Pin<Box<dyn std::future::Future<Output = io::Result<()>> + Send>> {
+    Box::pin(async move {
+        let peer = stream.peer_addr()?;
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (replies, mut outgoing) = mpsc::channel::<(u32, Vec<u8>)>(MUX_QUEUE);
+        // 每个请求的回复完整缓存在内存中，限制同时处理的请求数，达到上限后暂停读取新请求
+        let in_flight = Arc::new(Semaphore::new(MUX_QUEUE));
+        let writer_task = tokio::spawn(async move {
+            while let Some((request_id, reply)) = outgoing.recv().await {
+                let mut frame = Vec::with_capacity(reply.len() + 8);
+                WriteBytesExt::write_u32::<BigEndian>(&mut frame, reply.len() as u32 + 4)?;
+                WriteBytesExt::write_u32::<BigEndian>(&mut frame, request_id)?;
+                frame.extend_from_slice(&reply);
+                writer.write_all(&frame).await?;
+            }
+            io::Result::Ok(())
+        });
+
+        loop {
+            let mut len_buf = [0; 4];
+            tokio::select! {
+                result = reader.read_exact(&mut len_buf) => {
+                    if result.is_err() {
+                        break;
+                    }
+                }
+                _ = connection.kicked() => {
+                    info!("Connection kicked");
+                    break;
+                }
+            }
+            let config = live_config.get();
+            let message_len = u32::from_be_bytes(len_buf) as usize;
+            // 与普通连接相同，在分配缓冲区之前检查长度，超出上限时只能关闭连接
+            if message_len < 4 || message_len - 4 > config.max_message_size() {
+                warn!(
+                    "Rejecting multiplexed frame of {} bytes from {}: max_message_size is {}",
+                    message_len,
+                    connection.peer,
+                    config.max_message_size()
+                );
+                break;
+            }
+            let mut buffer = vec![0; message_len];
+            let frame_timeout = Duration::from_secs(config.server.frame_timeout_secs());
+            match time::timeout(frame_timeout, reader.read_exact(&mut buffer)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    warn!(
+                        "Slowloris warning: frame from {} not completed within {:?}, closing connection",
+                        connection.peer,
+                        frame_timeout
+                    );
+                    break;
+                }
+            }
+            // 请求编号换成长度前缀，管道中的数据与普通连接上收到的帧相同
+            let request_id = u32::from_be_bytes(buffer[..4].try_into().unwrap());
+            buffer[..4].copy_from_slice(&((message_len - 4) as u32).to_be_bytes());
+
+            let (mut pipe, server_end) = tokio::io::duplex(MUX_PIPE_SIZE);
+            let handler = handle_client(
+                ServerStream::Multiplexed(server_end, peer),
+                connection.clone(),
+                brokers.clone(),
+                live_config.clone(),
+                admin_listener,
+            );
+            let replies = replies.clone();
+            let permit = in_flight.clone().acquire_owned().await.map_err(io::Error::other)?;
+            tokio::spawn(async move {
+                // 写入请求后关闭写端，处理完这一个请求后 handle_client 读到 EOF 退出并关闭管道
+                let exchange = async {
+                    pipe.write_all(&buffer).await?;
+                    pipe.shutdown().await?;
+                    let mut reply = Vec::new();
+                    pipe.read_to_end(&mut reply).await?;
+                    io::Result::Ok(reply)
+                };
+                let (handled, reply) = tokio::join!(handler, exchange);
+                if let Err(e) = handled {
+                    error!("Error: {}", e);
+                }
+                if let Ok(reply) = reply {
+                    let _ = replies.send((request_id, reply)).await;
+                }
+                // 回复进入写出队列后才允许处理下一个请求
+                drop(permit);
+            }.in_current_span());
+        }
+        // 正在处理的请求完成并写出回复后连接关闭
+        drop(replies);
+        writer_task.await.map_err(io::Error::other)?
+    })
+}
+
+// PUSH 类命令写入失败时的回复：没有订阅者回复 NO_CONSUMERS，磁盘已满（索引无法扩展）回复 DISK_FULL，
+// 连接继续可用；其他错误返回 None，由调用方关闭连接
+fn push_error_reply(error: &io::Error) -> Option<(StatusCode, &'static [u8])> {
+    match error.kind() {
+        io::ErrorKind::NotConnected => Some((StatusCode::Other, b"NO_CONSUMERS")),
+        io::ErrorKind::StorageFull => {
+            error!("Error: {}", error);
+            Some((StatusCode::Other, b"DISK_FULL"))
+        }
+        _ => None,
+    }
+}
+
+async fn send_bad_request(stream: &mut ServerStream, connection: &Connection, error: &ProtocolError) -> io::Result<()> {
+    send_error(stream, connection, StatusCode::BadRequest, &bad_request(connection, error)).await
+}
+
+// 记录一个格式错误的请求，返回 BAD_REQUEST 回复
+fn bad_request(connection: &Connection, error: &ProtocolError) -> Vec<u8> {
+    metrics::BAD_REQUESTS.fetch_add(1, Ordering::Relaxed);
+    warn!("Malformed request from {}: {}", connection.peer, error);
+    format!("BAD_REQUEST: {}", error).into_bytes()
+}
+
+// 拒绝一个请求；PULL 类命令的客户端先读取 PULL 头部，回复前加 PULL_REFUSED 让它知道之后是错误
+async fn send_refusal(stream: &mut ServerStream, connection: &Connection, command: &str, code: StatusCode, content: &[u8]) -> io::Result<()> {
+    if PULL_REPLY_COMMANDS.contains(&command) {
+        connection.add_sent(4);
+        tokio::io::AsyncWriteExt::write_all(stream, &PULL_REFUSED.to_be_bytes()).await?;
+    }
+    send_error(stream, connection, code, content).await
+}
+
+// 成功的回复：[len: u32][STATUS_OK][content]
+async fn send_response(stream: &mut ServerStream, connection: &Connection, content: &[u8]) -> io::Result<()> {
+    send_reply(stream, connection, STATUS_OK, content).await
+}
+
+// 错误回复：[len: u32][状态码][content]，content 是说明错误的文本
+async fn send_error(stream: &mut ServerStream, connection: &Connection, code: StatusCode, content: &[u8]) -> io::Result<()> {
+    send_reply(stream, connection, code as u8, content).await
+}
+
+async fn send_reply(stream: &mut ServerStream, connection: &Connection, status: u8, content: &[u8]) -> io::Result<()> {
+    let mut response = Vec::with_capacity(content.len() + 5);
+    WriteBytesExt::write_u32::<BigEndian>(&mut response, content.len() as u32 + 1)?;
+    response.push(status);
+    Write::write_all(&mut response, content)?;
+    connection.add_sent(response.len());
+    tokio::io::AsyncWriteExt::write_all(stream, &response).await
+}
+
+// 回复之后的数据帧（LIST_BROKERS 的条目、SEGMENTS 的行、LOG_STREAM 的事件）：[len: u32][content]，没有状态字节
+async fn send_frame(stream: &mut ServerStream, connection: &Connection, content: &[u8]) -> io::Result<()> {
+    let mut response = Vec::with_capacity(content.len() + 4);
+    WriteBytesExt::write_u32::<BigEndian>(&mut response, content.len() as u32)?;
+    Write::write_all(&mut response, content)?;
+    connection.add_sent(response.len());
+    tokio::io::AsyncWriteExt::write_all(stream, &response).await
+}
+
+// 读取前为归档模式的 broker 补建索引：先在读锁下检查，只有当前文件还有未建立索引的记录时才取写锁，
+// 其他 broker 的读取不会与 PUSH 争抢写锁
+async fn catch_up_index(broker: &RwLock<Broker>) -> io::Result<()> {
+    if broker.read().await.store.needs_index_catch_up() {
+        broker.write().await.store.catch_up_index().await?;
+    }
+    Ok(())
+}
+
+async fn get_broker(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, broker_name: String, config:&Config, key: &str) -> Option<Arc<RwLock<Broker>>> {
+    if let Some(entry) = brokers.get(&broker_name) {
+        return Some(entry.value().clone());
+    }
+    open_broker(brokers, broker_name, config, key, true).await
+}
+
+// 只读取已有 broker 的命令用它查找 broker：被 evict_idle 卸载的 broker 重新打开，不存在的 broker 不会创建
+async fn lookup_broker(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, broker_name: &str, config: &Config, key: &str) -> Option<Arc<RwLock<Broker>>> {
+    if let Some(entry) = brokers.get(broker_name) {
+        return Some(entry.value().clone());
+    }
+    if !evicted_brokers().contains_key(&broker_key(config, broker_name)) || broker_limit_reached(brokers, broker_name, config).await {
+        return None;
+    }
+    open_broker(brokers, broker_name.to_string(), config, key, false).await
+}
+
+// 持有名称锁打开 broker，create 为 false 时只重新打开被卸载的 broker
+async fn open_broker(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, broker_name: String, config: &Config, key: &str, create: bool) -> Option<Arc<RwLock<Broker>>> {
+    let _name_lock = lock_broker_name(config, &broker_name).await;
+    // 等待名称锁期间其他请求可能已经打开了它
+    if let Some(entry) = brokers.get(&broker_name) {
+        return Some(entry.value().clone());
+    }
+    let reopened = evicted_brokers().contains_key(&broker_key(config, &broker_name));
+    if !reopened && !create {
+        return None;
+    }
+    if let Some(existing) = case_collision(brokers, &broker_name, config) {
+        warn!("Rejecting broker {}: collides with existing broker {}", broker_name, existing);
+        return None;
+    }
+    if (brokers.len() + 1) as u16 > config.server.broker_limit {
+        return None;
+    }
+    let new_broker = match Broker::new(broker_name.clone(), config).await {
+        Ok(broker) => Arc::new(RwLock::new(broker)),
+        // 打不开的 broker 按不存在处理，请求收到 NO_BROKER
+        Err(e) => {
+            error!("Opening broker {} failed: {}", broker_name, e);
+            return None;
+        }
+    };
+    if reopened {
+        evicted_brokers().remove(&broker_key(config, &broker_name));
+        info!("Reopened evicted broker {}", broker_name);
+    } else if let Some(client_key) = config.client_key(key) {
+        // 记录租户密钥创建的 broker 的所有者，用于统计 max_brokers
+        if let Err(e) = new_broker.write().await.meta.set(CREATED_BY_KEY, &client_key.name) {
+            warn!("Recording owner of broker {} failed: {}", broker_name, e);
+        }
+    }
+    brokers.insert(broker_name, new_broker.clone());
+    Some(new_broker)
+}
+
+// 区分 broker 的键：server.path 下的目录，同一进程中的多个服务（测试）互不影响
+fn broker_key(config: &Config, broker_name: &str) -> PathBuf {
+    Path::new(&config.server.path).join(broker_name)
+}
+
+// 同一个 broker 的创建、卸载和删除依次进行，避免两个 DataStorage 同时打开一个目录
+async fn lock_broker_name(config: &Config, broker_name: &str) -> tokio::sync::OwnedMutexGuard<()> {
+    static LOCKS: OnceLock<DashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>> = OnceLock::new();
+    let lock = LOCKS.get_or_init(DashMap::new).entry(broker_key(config, broker_name)).or_default().clone();
+    lock.lock_owned().await
+}
+
+// 删除各个 broker 超过 retention 的历史文件。已打开的 broker 在写锁内由存储给出历史文件列表，删除后同步更新；
+// 被卸载的 broker 在名称锁内清理，当前文件取卸载时记录的位置，清理期间不会被重新打开
+async fn expire_segments(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, config: &Config, retention: Duration) -> io::Result<()> {
+    for entry in std::fs::read_dir(&config.server.path)? {
+        let entry = entry?;
+        if !entry.path().is_dir() {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if let Err(e) = expire_broker_segments(brokers, config, &name, retention).await {
+            warn!("Cleaning up broker {} failed: {}", name, e);
+        }
+    }
+    Ok(())
+}
+
+async fn expire_broker_segments(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, config: &Config, name: &str, retention: Duration) -> io::Result<()> {
+    let loaded = brokers.get(name).map(|entry| entry.value().clone());
+    if let Some(broker) = loaded {
+        // 写锁内不会切换文件，也没有读取正在使用历史文件列表
+        let broker = broker.write().await;
+        let (dir, sealed) = broker.store.sealed_segments().await?;
+        let removed = fileclear::clean_expired_segments(&dir, &sealed, &broker.meta.pinned_segments(), retention)?;
+        broker.store.forget_segments(&removed).await;
+        return Ok(());
+    }
+    let _name_lock = lock_broker_name(config, name).await;
+    // 等待名称锁期间被重新打开的 broker 留到下一轮清理
+    if brokers.contains_key(name) {
+        return Ok(());
+    }
+    // 没有打开过的目录（例如启动时打开失败）不知道当前文件，不清理
+    let dir = broker_key(config, name);
+    let Some(active) = evicted_brokers().get(&dir).map(|entry| entry.value()[0]) else {
+        return Ok(());
+    };
+    let sealed = storage::sealed_segments_in(&dir, active)?;
+    fileclear::clean_expired_segments(&dir, &sealed, &BrokerMeta::open(&dir)?.pinned_segments(), retention)?;
+    Ok(())
+}
+
+// 被 evict_idle 卸载的 broker，值为卸载时的 [当前文件起始偏移, 下一个偏移, 数据长度]，供 LIST_BROKERS 列出
+fn evicted_brokers() -> &'static DashMap<PathBuf, [u64; 3]> {
+    static EVICTED: OnceLock<DashMap<PathBuf, [u64; 3]>> = OnceLock::new();
+    EVICTED.get_or_init(DashMap::new)
+}
+
+// 已打开的 broker 的存储位置，按目录索引，LIST_BROKERS 不获取 broker 的锁就能读取
+fn broker_positions() -> &'static DashMap<PathBuf, StorePosition> {
+    static POSITIONS: OnceLock<DashMap<PathBuf, StorePosition>> = OnceLock::new();
+    POSITIONS.get_or_init(DashMap::new)
+}
+
+// 把 broker 中 offset 处的记录按读出的格式写入 <broker>.dlq，该偏移没有记录时返回 false。
+// 死信 broker 以服务端密钥创建：不记录所有者，不受消费者密钥的 ACL 和 max_brokers 限制，但计入 broker_limit
+async fn dead_letter(
+    brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>,
+    broker: &RwLock<Broker>,
+    broker_name: &str,
+    offset: u64,
+    config: &Config,
+) -> io::Result<bool> {
+    let Some(record) = broker.read().await.read_record(offset).await? else {
+        return Ok(false);
+    };
+    let dlq_name = format!("{}{}", broker_name, DLQ_SUFFIX);
+    if broker_limit_reached(brokers, &dlq_name, config).await {
+        return Err(io::Error::other(format!("cannot create dead-letter broker {}: broker_limit reached", dlq_name)));
+    }
+    match get_broker(brokers, dlq_name.clone(), config, &config.server.authorization).await {
+        Some(dlq) => {
+            dlq.write().await.receive_message(record).await?;
+            Ok(true)
+        }
+        None => Err(io::Error::other(format!("cannot open dead-letter broker {}", dlq_name))),
+    }
+}
+
+// DELETE_BROKER：从 brokers 中移除并删除目录，broker 不存在时返回 false；
+// 有请求持有锁或有订阅者时返回 ResourceBusy，不删除任何数据
+async fn delete_broker(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, broker_name: &str, config: &Config) -> io::Result<bool> {
+    let _name_lock = lock_broker_name(config, broker_name).await;
+    let link = broker_key(config, broker_name);
+    let Some(broker) = brokers.get(broker_name).map(|entry| entry.value().clone()) else {
+        // 被卸载的 broker 没有打开的文件，直接删除目录
+        if evicted_brokers().remove(&link).is_none() {
+            return Ok(false);
+        }
+        std::fs::remove_dir_all(link.canonicalize()?)?;
+        remove_broker_link(&link)?;
+        return Ok(true);
+    };
+    let busy = || io::Error::new(io::ErrorKind::ResourceBusy, format!("broker {} is in use", broker_name));
+    let guard = broker.try_write().map_err(|_| busy())?;
+    if guard.subscribers.load(Ordering::SeqCst) > 0 {
+        return Err(busy());
+    }
+    // 持有写锁期间移除，之后的请求不会再取到这个 broker。只有 brokers 和这里持有时才移除：
+    // 已经取到这个 broker 的请求在写锁释放后还会写入，不能删除它的文件
+    if brokers.remove_if(broker_name, |_, entry| Arc::strong_count(entry) == 2).is_none() {
+        return Err(busy());
+    }
+    broker_positions().remove(&link);
+    std::fs::remove_dir_all(&guard.dir)?;
+    remove_broker_link(&link)?;
+    Ok(true)
+}
+
+// 迁移过的 broker 在 server.path 下只留有指向新目录的符号链接
+fn remove_broker_link(link: &Path) -> io::Result<()> {
+    if link.is_symlink() {
+        std::fs::remove_file(link)?;
+    }
+    Ok(())
+}
+
+// 要创建的 broker 是否超出 broker_limit；开启 evict_idle 时先卸载一个空闲的 broker，卸载成功则不算超出
+async fn broker_limit_reached(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, broker_name: &str, config: &Config) -> bool {
+    if brokers.contains_key(broker_name) || brokers.len() < config.server.broker_limit as usize {
+        return false;
+    }
+    if let Some(idle_secs) = config.server.evict_idle_secs() {
+        if evict_idle_broker(brokers, idle_secs, config).await {
+            return false;
+        }
+    }
+    warn!("Rejecting broker {}: broker_limit {} reached", broker_name, config.server.broker_limit);
+    true
+}
+
+// 卸载空闲超过 idle_secs 的 broker 中最久未使用的一个：落盘后从 brokers 中移除，文件保留在磁盘上，
+// 再次访问时重新打开。正在使用或有订阅者的 broker 不会被卸载
+async fn evict_idle_broker(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, idle_secs: u64, config: &Config) -> bool {
+    let idle_before = chrono::Utc::now().timestamp_millis() - (idle_secs * 1000) as i64;
+    let all: Vec<(String, Arc<RwLock<Broker>>)> = brokers
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    let mut oldest: Option<(i64, String, Arc<RwLock<Broker>>)> = None;
+    for (name, broker) in all {
+        let Ok(guard) = broker.try_read() else {
+            continue;
+        };
+        let last_used = guard.last_used.load(Ordering::Relaxed);
+        if last_used > idle_before || guard.subscribers.load(Ordering::SeqCst) > 0 {
+            continue;
+        }
+        if oldest.as_ref().is_none_or(|(oldest_used, _, _)| last_used < *oldest_used) {
+            drop(guard);
+            oldest = Some((last_used, name, broker));
+        }
+    }
+    let Some((_, name, broker)) = oldest else {
+        return false;
+    };
+    let _name_lock = lock_broker_name(config, &name).await;
+    let Ok(guard) = broker.try_write() else {
+        return false;
+    };
+    if let Err(e) = guard.store.flush().await {
+        warn!("Evicting broker {} failed: {}", name, e);
+        return false;
+    }
+    drop(guard);
+    // 只有 brokers 和这里持有时才移除：已经取到这个 broker 的请求会继续使用它，不能再打开第二份
+    if brokers.remove_if(&name, |_, entry| Arc::strong_count(entry) == 2).is_none() {
+        return false;
+    }
+    let key = broker_key(config, &name);
+    broker_positions().remove(&key);
+    let listed = broker.read().await.store.position().snapshot();
+    evicted_brokers().insert(key, listed);
+    // 在名称锁内关闭文件，之后重新打开的 broker 不会与它同时访问目录
+    drop(broker);
+    info!("Evicted idle broker {}", name);
+    true
+}
+
+// 租户密钥要创建的 broker 是否超出其 max_brokers；已存在的 broker 和不限制数量的密钥返回 false
+async fn broker_quota_exceeded(brokers: &Arc<DashMap<String, Arc<RwLock<Broker>>>>, broker_name: &str, config: &Config, key: &str) -> bool {
+    let Some((owner, max_brokers)) = config
+        .client_key(key)
+        .and_then(|client_key| client_key.max_brokers.map(|max| (client_key.name.as_str(), max)))
+    else {
+        return false;
+    };
+    if brokers.contains_key(broker_name) {
+        return false;
+    }
+    // 先取出所有 broker 再等待读锁，不在持有 DashMap 引用时等待
+    let all: Vec<Arc<RwLock<Broker>>> = brokers.iter().map(|entry| entry.value().clone()).collect();
+    let mut owned = 0;
+    for broker in all {
+        if broker.read().await.meta.get(CREATED_BY_KEY) == Some(owner) {
+            owned += 1;
+        }
+    }
+    if owned >= max_brokers {
+        warn!("Rejecting broker {}: key {} already owns {} brokers", broker_name, owner, owned);
+        return true;
+    }
+    false
+}
+
+// 在线迁移 broker 的存储到 new_base/<broker>：先在不阻塞写入的情况下复制已封存的文件，
+// 再持有写锁复制其余文件并切换到新目录。切换成功前旧目录保持不变，失败时删除新目录中的副本。
+// 切换后在原位置留下指向新目录的符号链接，重启时仍能找到该 broker
+async fn migrate_broker(broker: &RwLock<Broker>, name: &str, new_base: &str, config: &Config) -> io::Result<PathBuf> {
+    let new_dir = PathBuf::from(new_base).join(name);
+    if new_dir.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", new_dir.display()),
+        ));
+    }
+    std::fs::create_dir_all(&new_dir)?;
+    let (old_dir, active_offset) = {
+        let broker = broker.read().await;
+        (broker.dir.clone(), broker.store.active_base_offset())
+    };
+    let result = async {
+        let (src, dst) = (old_dir.clone(), new_dir.clone());
+        let copied = tokio::task::spawn_blocking(move || migrate::copy_sealed_segments(&src, &dst, active_offset))
+            .await
+            .map_err(io::Error::other)??;
+        let mut broker = broker.write().await;
+        migrate::copy_remaining(&old_dir, &new_dir, &copied)?;
+        broker.reopen(name, new_dir.clone(), config).await
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = std::fs::remove_dir_all(&new_dir);
+        return Err(e);
+    }
+
+    std::fs::remove_dir_all(&old_dir)?;
+    let link = PathBuf::from(&config.server.path).join(name);
+    if link.is_symlink() {
+        std::fs::remove_file(&link)?;
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&new_dir, &link)?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_dir(&new_dir, &link)?;
+    Ok(new_dir)
+}
+
+// 重新读取配置文件并把新的存储限制应用到已加载的 broker；broker_limit、密钥和 ACL 在之后的请求中生效
+async fn reload_config(live_config: &LiveConfig, brokers: &DashMap<String, Arc<RwLock<Broker>>>) -> io::Result<()> {
+    let config = live_config.reload()?;
+    let limit = match &config.storage.index_memory_limit {
+        Some(limit) => parse_size(limit).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))? as u64,
+        None => 0, // 不限制
+    };
+    IndexGovernor::global().set_limit(limit);
+    let all: Vec<(String, Arc<RwLock<Broker>>)> =
+        brokers.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect();
+    for (name, broker) in all {
+        if let Err(e) = broker.write().await.store.apply_limits(&config.storage) {
+            error!("Applying reloaded limits to broker {} failed: {}", name, e);
+        }
+    }
+    info!("Config reloaded");
+    Ok(())
+}
+
+// 不区分大小写的文件系统上，只有大小写不同的 broker 名称会指向同一个目录，返回已存在的冲突名称
+fn case_collision(brokers: &DashMap<String, Arc<RwLock<Broker>>>, broker_name: &str, config: &Config) -> Option<String> {
+    if config.server.case_insensitive_names != Some(true) {
+        return None;
+    }
+    let lower = broker_name.to_lowercase();
+    brokers
+        .iter()
+        .map(|entry| entry.key().clone())
+        .find(|name| name != broker_name && name.to_lowercase() == lower)
+}
+
+// 在数据目录中创建一个探测文件，用另一种大小写访问它来判断文件系统是否区分大小写
+fn detect_case_insensitive(path: &str) -> io::Result<bool> {
+    let probe = PathBuf::from(path).join(".CaseProbe");
+    std::fs::write(&probe, b"")?;
+    let insensitive = PathBuf::from(path).join(".caseprobe").exists();
+    std::fs::remove_file(&probe)?;
+    Ok(insensitive)
+}
+
+fn create_directory_if_not_exists(path: &str) -> std::io::Result<()> {
+    if !std::fs::metadata(path).map(|m| m.is_dir()).unwrap_or(false) {
+        std::fs::create_dir_all(path)?;
+        info!("Directory created: {}", path);
+    } else {
+        info!("Directory already exists: {}", path);
+    }
+    Ok(())
+}
+
+// `sonicrab_mq export <broker> [--raw] [--config <file>]`：把 broker 的全部记录以 NDJSON 写到标准输出后退出。
+// 默认导出 broker 解码后的消息体，--raw 时按保存的原样导出。
+// 打开 broker 时会做与启动相同的恢复，只能在服务停止时运行
+async fn export_broker(args: &[String]) -> io::Result<()> {
+    let Some(name) = args.first().filter(|name| !name.starts_with("--")) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "usage: sonicrab_mq export <broker> [--raw] [--config <file>]"));
+    };
+    let raw = args[1..].iter().any(|arg| arg == "--raw");
+    let config = load_config(&config_paths_from_args(args[1..].iter().cloned()))?;
+    let dir = PathBuf::from(&config.server.path).join(name);
+    if !dir.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no broker {} in {}", name, config.server.path)));
+    }
+    let writer = io::BufWriter::new(io::stdout().lock());
+    if raw {
+        let store = DataStorage::new(dir, &config.storage, &config.broker_override(name)).await?;
+        store.export(writer).await?;
+    } else {
+        Broker::new(name.clone(), &config).await?.export(writer, config.max_message_size()).await?;
+    }
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    // RUST_LOG 优先于配置的 log_level，在读取配置之前生效，加载配置文件的日志也按它过滤
+    let env_filter = env_log_filter(std::env::var("RUST_LOG").ok().as_deref())?;
+    let env_set = env_filter.is_some();
+    let console = init_logging(env_filter.unwrap_or_else(|| level_filter(Level::INFO)));
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some(EXPORT_SUBCOMMAND) {
+        return build_runtime(None)?.block_on(export_broker(&args[1..]));
+    }
+    // 运行时的线程数来自配置，先读取配置再创建运行时
+    let config_paths = config_paths_from_args(args);
+    let config: Config = load_config(&config_paths)?;
+    if let Some(level) = &config.server.log_level {
+        let level = events::parse_level(level)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unknown log level {}", level)))?;
+        if !env_set {
+            console.reload(level_filter(level)).map_err(io::Error::other)?;
+        }
+    }
+    build_runtime(config.runtime.as_ref())?.block_on(run_server(config, config_paths))
+}
+
+// 控制台日志输出到标准错误，标准输出留给 export 等命令的数据；LOG_STREAM 的广播不受控制台级别影响。
+// 返回控制台过滤器的句柄，读取配置后按 log_level 替换
+fn init_logging(filter: EnvFilter) -> reload::Handle<EnvFilter, Registry> {
+    let (filter, console) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_writer(io::stderr).with_filter(filter))
+        .with(events::BroadcastLayer)
+        .init();
+    console
+}
+
+fn level_filter(level: Level) -> EnvFilter {
+    EnvFilter::default().add_directive(LevelFilter::from_level(level).into())
+}
+
+// 解析 RUST_LOG，未设置或为空时返回 None。支持 tracing 的过滤语法（如 "info,sonicrab_mq::storage=debug"），
+// 不带目标的部分必须是级别名称：拼错的级别作为配置错误返回，而不是被当作目标名悄悄忽略
+fn env_log_filter(value: Option<&str>) -> io::Result<Option<EnvFilter>> {
+    let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+        return Ok(None);
+    };
+    let invalid = |detail: String| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid RUST_LOG {:?}: {}", value, detail));
+    for directive in value.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+        if !directive.contains(['=', '[']) && directive.parse::<LevelFilter>().is_err() {
+            return Err(invalid(format!("unknown level {:?}", directive)));
+        }
+    }
+    EnvFilter::try_new(value).map(Some).map_err(|e| invalid(e.to_string()))
+}
+
+// 按 [runtime] 创建多线程运行时，未设置的项与 #[tokio::main] 的默认值相同
+fn build_runtime(settings: Option<&crate::config::Runtime>) -> io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(settings) = settings {
+        // tokio 遇到 0 时直接 panic，这里作为配置错误返回
+        let positive = |value: Option<usize>, name: &str| match value {
+            Some(0) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("runtime.{} must be greater than zero", name))),
+            value => Ok(value),
+        };
+        if let Some(threads) = positive(settings.worker_threads, "worker_threads")? {
+            builder.worker_threads(threads);
+        }
+        if let Some(threads) = positive(settings.max_blocking_threads, "max_blocking_threads")? {
+            builder.max_blocking_threads(threads);
+        }
+    }
+    builder.build()
+}
+
+async fn run_server(mut config: Config, config_paths: Vec<PathBuf>) -> io::Result<()> {
+    create_directory_if_not_exists(&config.server.path)?;
+    if config.server.case_insensitive_names.is_none() {
+        let insensitive = detect_case_insensitive(&config.server.path)?;
+        info!("Broker names are case-{}", if insensitive { "insensitive" } else { "sensitive" });
+        config.server.case_insensitive_names = Some(insensitive);
+    }
+    if let Some(limit) = &config.storage.index_memory_limit {
+        let limit = parse_size(limit).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        IndexGovernor::global().set_limit(limit as u64);
+        info!("Index mmap memory limited to {} bytes", limit);
+    }
+    let fsync_policy = config.storage.fsync_policy().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let brokers = Arc::new(DashMap::new());
+    
+    // 恢复各个 broker 的存储，逐个报告进度，便于区分启动缓慢和卡死
+    let recovery_started = time::Instant::now();
+    for broker_folder in std::fs::read_dir(PathBuf::from(&config.server.path))? {
+        let folder = broker_folder?;
+        // 迁移过的 broker 以指向新目录的符号链接保存
+        if folder.path().is_dir() {
+            let file_name = folder.file_name().to_string_lossy().to_string();
+            if let Some(existing) = case_collision(&brokers, &file_name, &config) {
+                warn!("Skipping broker directory {}: collides with broker {}", file_name, existing);
+                continue;
+            }
+            info!("Recovering broker {}", file_name);
+            let started = time::Instant::now();
+            let new_broker = Broker::new(file_name.clone(), &config)
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("recovering broker {} failed: {}", file_name, e)))?;
+            info!(
+                "Recovered broker {}: next offset {} in {} ms",
+                file_name,
+                new_broker.store.next_offset(),
+                started.elapsed().as_millis()
+            );
+            brokers.insert(file_name, Arc::new(RwLock::new(new_broker)));
+        }
+    }
+    info!(
+        "Recovered {} brokers in {:.2} s",
+        brokers.len(),
+        recovery_started.elapsed().as_secs_f64()
+    );
+    
+    
+    let address = socket_address(&config.server.address, config.server.port);
+    let listener = TcpListener::bind(&address).await?;
+    
+    
+    let live_config = Arc::new(LiveConfig::new(config.clone(), config_paths));
+    let config_for_clear = live_config.clone();
+    let brokers_for_clear = brokers.clone();
+    // 启动一个独立的任务来定期执行文件清理
+    tokio::spawn(async move {
+        loop {
+            // 使用 RELOAD_CONFIG 之后的保留设置
+            let config = config_for_clear.get();
+            let path = &config.server.path.as_str();
+            let files_limit = config.storage.cache_limit+1;
+            let cleared = match config.storage.retention_secs() {
+                Some(retention) => expire_segments(&brokers_for_clear, &config, Duration::from_secs(retention)).await.map_err(|e| e.to_string()),
+                None => delete_old_files(path,files_limit).await.map_err(|e| e.to_string()),
+            };
+            match cleared {
+                Ok(_) => info!("Old files deleted successfully."),
+                Err(e) => error!("Error deleting old files: {}", e),
+            }
+            // 每20秒执行一次
+            time::sleep(Duration::from_secs(40)).await;
+        }
+    });
+
+    // fsync = "interval" 时定期把每个 broker 当前文件的数据和索引写入磁盘
+    if let FsyncPolicy::Interval(interval_ms) = fsync_policy {
+        let brokers = brokers.clone();
+        tokio::spawn(async move {
+            let mut ticker = time::interval(Duration::from_millis(interval_ms));
+            loop {
+                ticker.tick().await;
+                let all: Vec<(String, Arc<RwLock<Broker>>)> =
+                    brokers.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect();
+                for (name, broker) in all {
+                    if let Err(e) = broker.read().await.store.flush().await {
+                        error!("Periodic fsync of broker {} failed: {}", name, e);
+                    }
+                }
+            }
+        });
+    }
+
+    // 定期保存每个 broker 当前文件的位置，重启时不必扫描索引寻找结束标记
+    {
+        let brokers = brokers.clone();
+        tokio::spawn(async move {
+            let mut ticker = time::interval(Duration::from_secs(POSITION_SAVE_SECS));
+            loop {
+                ticker.tick().await;
+                let all: Vec<(String, Arc<RwLock<Broker>>)> =
+                    brokers.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect();
+                for (name, broker) in all {
+                    if let Err(e) = broker.read().await.store.save_position() {
+                        warn!("Saving position of broker {} failed: {}", name, e);
+                    }
+                }
+            }
+        });
+    }
+
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutting down: no longer accepting connections");
+        let _ = shutdown_sender.send(true);
+    });
+
+    if let Some(metrics_config) = &config.metrics {
+        let metrics_address = socket_address(
+            metrics_config.address.as_deref().unwrap_or(&config.server.address),
+            metrics_config.port,
+        );
+        #[cfg(feature = "metrics-http")]
+        {
+            let metrics_listener = TcpListener::bind(&metrics_address).await?;
+            info!("Metrics are served on http://{}/metrics", metrics_address);
+            let brokers = brokers.clone();
+            tokio::spawn(metrics::serve_metrics(metrics_listener, move || brokers.len()));
+        }
+        #[cfg(not(feature = "metrics-http"))]
+        warn!("Ignoring [metrics] on {}: built without the metrics-http feature", metrics_address);
+    }
+
+    let admin_server = match &config.admin {
+        Some(admin) => {
+            let admin_address = socket_address(&admin.address, admin.port);
+            let admin_listener = TcpListener::bind(&admin_address).await?;
+            info!("Admin commands are served on {}", admin_address);
+            Some(tokio::spawn(serve(admin_listener, brokers.clone(), live_config.clone(), true, shutdown.clone())))
+        }
+        None => None,
+    };
+
+    info!("Broker server is running on {}", listener.local_addr()?);
+
+    serve(listener, brokers.clone(), live_config, false, shutdown).await?;
+    if let Some(admin_server) = admin_server {
+        admin_server.await.map_err(io::Error::other)??;
+    }
+
+    // 所有连接关闭后把每个 broker 当前文件的数据和索引写入磁盘
+    let all: Vec<(String, Arc<RwLock<Broker>>)> =
+        brokers.iter().map(|entry| (entry.key().clone(), entry.value().clone())).collect();
+    for (name, broker) in all {
+        if let Err(e) = broker.read().await.store.flush().await {
+            error!("Flushing broker {} failed: {}", name, e);
+        }
+    }
+    info!("Shutdown complete");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sonicrab_client::{EARLIEST, LATEST};
+    use tokio::net::TcpStream;
+
+    pub(crate) fn test_config(path: &std::path::Path, extra: &str) -> Config {
+        let content = format!(
+            r#"
+[server]
+address = "127.0.0.1"
+port = 0
+path = "{}"
+broker_limit = 10
+authorization = "test_key"
+
+[storage]
+max_file_size = "1m"
+pull_max_limit = "1m"
+cache_limit = 10
+{}
+"#,
+            path.display(),
+            extra
+        );
+        toml::from_str(&content).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dedup_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "[brokers.orders]\ndedup = true\n");
+
+        let mut broker = Broker::new("orders".to_string(), &config).await.unwrap();
+        assert!(!broker.receive_message_with_id("id-1", b"first".to_vec()).await.unwrap());
+        assert!(broker.receive_message_with_id("id-1", b"first".to_vec()).await.unwrap());
+        drop(broker);
+
+        // 模拟服务重启后重放同一个消息ID
+        let mut broker = Broker::new("orders".to_string(), &config).await.unwrap();
+        assert!(broker.receive_message_with_id("id-1", b"first".to_vec()).await.unwrap());
+        assert!(!broker.receive_message_with_id("id-2", b"second".to_vec()).await.unwrap());
+        assert_eq!(broker.store.append_data(b"third").await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_export_decodes_records() {
+        use base64::Engine;
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[brokers.packed]\ncompression = \"zstd\"\nchecksums = true\ntimestamps = true\n";
+        let config = test_config(dir.path(), extra);
+        let mut broker = Broker::new("packed".to_string(), &config).await.unwrap();
+        for i in 0..3u8 {
+            broker.receive_message(vec![i; 100]).await.unwrap();
+        }
+
+        // 默认导出的是消息本身
+        let mut out = Vec::new();
+        assert_eq!(broker.export(&mut out, 1024).await.unwrap(), 3);
+        let lines: Vec<serde_json::Value> = out.split(|&b| b == b'\n').filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        for (i, line) in lines.iter().enumerate() {
+            let payload = base64::engine::general_purpose::STANDARD.decode(line["payload"].as_str().unwrap()).unwrap();
+            assert_eq!(payload, vec![i as u8; 100]);
+            assert_eq!(line["len"], 100);
+        }
+
+        // 原样导出的是压缩后保存的字节
+        let mut raw = Vec::new();
+        broker.store.export(&mut raw).await.unwrap();
+        assert_ne!(raw, out);
+
+        // record_codecs 的记录按编码字节解压
+        use sonicrab_client::compression::{compress, Codec};
+        let config = test_config(dir.path(), "[brokers.codecs]\nrecord_codecs = true\n");
+        let mut broker = Broker::new("codecs".to_string(), &config).await.unwrap();
+        broker.receive_compressed(&compress(Codec::Lz4, &[7; 500])).await.unwrap();
+        let mut out = Vec::new();
+        broker.export(&mut out, 1024).await.unwrap();
+        let line: serde_json::Value = serde_json::from_slice(out.trim_ascii_end()).unwrap();
+        assert_eq!(line["len"], 500);
+    }
+
+    // 在随机端口上启动服务，返回监听地址
+    pub(crate) async fn spawn_server(config: Config) -> std::net::SocketAddr {
+        spawn_server_with_brokers(config).await.0
+    }
+
+    type Brokers = Arc<DashMap<String, Arc<RwLock<Broker>>>>;
+
+    // 同时返回 broker 表，便于测试直接检查 broker 的状态
+    pub(crate) async fn spawn_server_with_brokers(config: Config) -> (std::net::SocketAddr, Brokers) {
+        events::init_test_logging();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let brokers: Brokers = Arc::new(DashMap::new());
+        tokio::spawn(serve(listener, brokers.clone(), Arc::new(LiveConfig::new(config, Vec::new())), false, watch::channel(false).1));
+        (address, brokers)
+    }
+
+    // 启动数据端口和 [admin] 管理端口，两者共享 broker 表，返回 (数据端口地址, 管理端口地址)
+    pub(crate) async fn spawn_server_with_admin(config: Config) -> (std::net::SocketAddr, std::net::SocketAddr) {
+        events::init_test_logging();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addresses = (listener.local_addr().unwrap(), admin_listener.local_addr().unwrap());
+        let brokers: Brokers = Arc::new(DashMap::new());
+        let config = Arc::new(LiveConfig::new(config, Vec::new()));
+        tokio::spawn(serve(listener, brokers.clone(), config.clone(), false, watch::channel(false).1));
+        tokio::spawn(serve(admin_listener, brokers, config, true, watch::channel(false).1));
+        addresses
+    }
+
+    #[test]
+    fn test_push_error_reply() {
+        let reply = |kind| push_error_reply(&io::Error::new(kind, "push failed"));
+        assert_eq!(reply(io::ErrorKind::NotConnected), Some((StatusCode::Other, b"NO_CONSUMERS".as_slice())));
+        assert_eq!(reply(io::ErrorKind::StorageFull), Some((StatusCode::Other, b"DISK_FULL".as_slice())));
+        // 其他错误关闭连接
+        assert_eq!(reply(io::ErrorKind::PermissionDenied), None);
+    }
+
+    #[tokio::test]
+    async fn test_panicked_connection_task_is_forgotten() {
+        let mut tasks = JoinSet::new();
+        let mut open = HashMap::new();
+        open.insert(tasks.spawn(async {}).id(), 1);
+        open.insert(tasks.spawn(async { panic!("handler bug") }).id(), 2);
+        while let Some(joined) = tasks.join_next_with_id().await {
+            open.remove(&finished_task(joined));
+        }
+        assert!(open.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dedup_consecutive_skips_identical_pushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "[brokers.sensor]\ndedup_consecutive = true\ntimestamps = true\nchecksums = true\n");
+        let mut broker = Broker::new("sensor".to_string(), &config).await.unwrap();
+        let (first, timestamp) = broker.receive_message(b"21.5".to_vec()).await.unwrap();
+        assert_eq!(broker.receive_message(b"21.5".to_vec()).await.unwrap(), (first, timestamp));
+        assert_eq!(broker.receive_batch(&[b"21.5", b"21.5"]).await.unwrap(), 2);
+        assert_eq!(broker.store.next_offset(), 1);
+
+        // 只跳过与最后一条相同的消息，值变回去时重新写入
+        assert_eq!(broker.receive_message(b"22.0".to_vec()).await.unwrap().0, 1);
+        assert_eq!(broker.receive_message(b"21.5".to_vec()).await.unwrap().0, 2);
+        drop(broker);
+
+        // 重启后与磁盘上最后一条记录比较
+        let mut broker = Broker::new("sensor".to_string(), &config).await.unwrap();
+        assert_eq!(broker.receive_message(b"21.5".to_vec()).await.unwrap().0, 2);
+        assert_eq!(broker.store.next_offset(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_idle_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        let server = tokio::spawn(serve(listener, Arc::new(DashMap::new()), Arc::new(LiveConfig::new(test_config(dir.path(), ""), Vec::new())), false, shutdown));
+
+        // 客户端保持连接空闲，关闭时不需要等待超时
+        let mut idle = TcpStream::connect(address).await.unwrap();
+        let client = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"data").unwrap();
+            client
+        })
+        .await
+        .unwrap();
+        shutdown_sender.send(true).unwrap();
+        time::timeout(Duration::from_secs(SHUTDOWN_TIMEOUT_SECS / 2), server)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(idle.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert!(TcpStream::connect(address).await.is_err());
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_limit_are_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.max_connections = Some(2);
+        let address = spawn_server(config).await;
+
+        let first = TcpStream::connect(address).await.unwrap();
+        let mut second = TcpStream::connect(address).await.unwrap();
+        // 超过上限的连接被立即关闭
+        let mut rejected = TcpStream::connect(address).await.unwrap();
+        let read = time::timeout(Duration::from_secs(5), rejected.read(&mut [0u8; 1])).await.unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+        // 已经接受的连接不受影响
+        assert!(time::timeout(Duration::from_millis(100), second.read(&mut [0u8; 1])).await.is_err());
+
+        // 连接关闭后释放许可，新连接可以正常使用
+        drop(first);
+        time::sleep(Duration::from_millis(100)).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.ping_latency().unwrap();
+        })
+        .await
+        .unwrap();
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn test_ping_latency() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        let (first, avg) = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let first = client.ping_latency().unwrap();
+            let avg = client.ping_latency_avg(5).unwrap();
+            (first, avg)
+        })
+        .await
+        .unwrap();
+        assert!(first > Duration::ZERO);
+        assert!(avg > Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_stats_reports_index_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        let stats = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("stats_broker", b"hello").unwrap();
+            client.stats().unwrap()
+        })
+        .await
+        .unwrap();
+        let names: Vec<&str> = stats.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["brokers", "index_mmap_bytes", "index_mmap_limit", "index_mmap_evictions", "slow_pulls"]);
+        assert!(stats[1].1 > 0);
+    }
+
+    #[tokio::test]
+    async fn test_slow_frame_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.frame_timeout = Some("1s".to_string());
+        let address = spawn_server(config).await;
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&100u32.to_be_bytes()).await.unwrap();
+        stream.write_all(&[0u8]).await.unwrap();
+        // 消息体没有在期限内到齐，服务端应关闭连接
+        let mut buf = [0u8; 1];
+        let read = time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("server did not drop the slow connection");
+        assert_eq!(read.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.max_message_size = Some("1k".to_string());
+        let address = spawn_server(config).await;
+
+        // 声明 4 GiB 的帧，服务端在分配之前拒绝并关闭连接
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+        let len = stream.read_u32().await.unwrap();
+        let mut response = vec![0u8; len as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"\x04MESSAGE_TOO_LARGE");
+        let mut buf = [0u8; 1];
+        let read = time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("server did not close the connection");
+        assert_eq!(read.unwrap_or(0), 0);
+
+        // 不超过上限的请求不受影响
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", &[7; 900]).unwrap();
+            assert!(client.send_push_message("events", &[7; 2000]).is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "[brokers.events]\nheaders = true\n")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let headers = vec![
+                ("content-type".to_string(), "application/json".to_string()),
+                ("trace-id".to_string(), "abc".to_string()),
+            ];
+            assert_eq!(client.send_push_with_headers("events", &headers, b"{}").unwrap(), b"OK");
+            assert_eq!(client.send_push_message("events", b"plain").unwrap().offset, 1);
+            assert_eq!(client.fetch_headers("events", 0).unwrap(), headers);
+            assert!(client.fetch_headers("events", 1).unwrap().is_empty());
+            assert!(client.fetch_headers("events", 2).is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Order {
+        id: u64,
+        item: String,
+        tags: Vec<String>,
+    }
+
+    #[tokio::test]
+    async fn test_json_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let order = Order {
+                id: 7,
+                item: "coffee".to_string(),
+                tags: vec!["hot".to_string()],
+            };
+            assert_eq!(client.send_push_json("orders", &order).unwrap().offset, 0);
+            let (offset, fetched) = client.fetch_json::<Order>("orders", 0).unwrap().unwrap();
+            assert_eq!(offset, 0);
+            assert_eq!(fetched, order);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_returns_stored_timestamp() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "[brokers.events]\ntimestamps = true\n")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let sonicrab_client::PushAck { offset, timestamp } = client.send_push_message("events", b"created").unwrap();
+            assert_eq!(offset, 0);
+            let (_, record) = client.fetch_messages("events", 0).unwrap().unwrap();
+            assert_eq!(i64::from_be_bytes(record[..8].try_into().unwrap()), timestamp);
+            assert_eq!(&record[8..], b"created");
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_checksummed_records_verify_on_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[brokers.events]\nchecksums = true\nheaders = true\ntimestamps = true\n";
+        let address = spawn_server(test_config(dir.path(), extra)).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                .verify_checksums(true)
+                .build();
+            let headers = vec![("source".to_string(), "web".to_string())];
+            client.send_push_with_headers("events", &headers, b"created").unwrap();
+            let ack = client.send_push_message("events", b"updated").unwrap();
+            // 校验通过后去掉 CRC32，剩下时间戳、消息头和消息体
+            let (_, record) = client.fetch_messages("events", ack.offset).unwrap().unwrap();
+            assert_eq!(i64::from_be_bytes(record[..8].try_into().unwrap()), ack.timestamp);
+            assert_eq!(decode_headers(&record[8..]).unwrap().1, b"updated");
+            // 服务端读取消息头时跳过校验和
+            assert_eq!(client.fetch_headers("events", 0).unwrap(), headers);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_broker_quota_per_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[[keys]]\nname = \"tenant-a\"\nkey = \"key_a\"\nmax_brokers = 2\n\
+                     [[keys]]\nname = \"tenant-b\"\nkey = \"key_b\"\nmax_brokers = 2\n";
+        let address = spawn_server(test_config(dir.path(), extra)).await;
+        tokio::task::spawn_blocking(move || {
+            let tenant_a = sonicrab_client::Client::new("127.0.0.1", address.port(), "key_a");
+            tenant_a.send_push_message("a1", b"data").unwrap();
+            tenant_a.send_push_message("a2", b"data").unwrap();
+            let err = tenant_a.send_push_message("a3", b"data").unwrap_err();
+            assert_eq!(err.to_string(), "BROKER_QUOTA_EXCEEDED");
+            // 已经创建的 broker 不受限制
+            tenant_a.send_push_message("a1", b"more").unwrap();
+
+            // 其他租户仍然可以创建 broker，也可以使用 tenant-a 的 broker
+            let tenant_b = sonicrab_client::Client::new("127.0.0.1", address.port(), "key_b");
+            tenant_b.send_push_message("b1", b"data").unwrap();
+            tenant_b.send_push_message("a2", b"data").unwrap();
+            assert_eq!(tenant_b.get_meta("a2", "created_by").unwrap().as_deref(), Some("tenant-a"));
+            assert_eq!(tenant_b.get_meta("b1", "created_by").unwrap().as_deref(), Some("tenant-b"));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_emits_warning() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        let mut events = events::subscribe();
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "wrong_key");
+            assert!(client.send_push_message("auth_probe", b"data").is_err());
+        })
+        .await
+        .unwrap();
+
+        // 事件通道由所有测试共享，只检查这次请求产生的事件
+        let event = time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.message.starts_with("Authentication failed") && event.message.contains("broker=auth_probe") => {
+                        return event;
+                    }
+                    Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(e) => panic!("event channel closed: {}", e),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        // 连接和请求 span 的字段接在事件内容后面
+        assert_eq!(event.level, Level::WARN);
+        assert!(event.message.contains(" id="));
+        assert!(event.message.contains(" peer=127.0.0.1:"));
+        assert!(event.message.contains(" command=PUSH"));
+    }
+
+    #[tokio::test]
+    async fn test_acl_read_only_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[[acl]]\nbroker = \"orders\"\nkeys = [\"reader\"]\nscopes = [\"read\"]\n\
+                     [[acl]]\nbroker = \"orders\"\nkeys = [\"writer\"]\n";
+        let address = spawn_server(test_config(dir.path(), extra)).await;
+        tokio::task::spawn_blocking(move || {
+            let writer = sonicrab_client::Client::new("127.0.0.1", address.port(), "writer");
+            writer.send_push_message("orders", b"one").unwrap();
+            writer.send_push_message("orders", b"two").unwrap();
+
+            let reader = sonicrab_client::Client::new("127.0.0.1", address.port(), "reader");
+            assert_eq!(reader.fetch_messages("orders", 1).unwrap(), Some((1, b"two".to_vec())));
+            let err = reader.send_push_message("orders", b"three").unwrap_err();
+            assert_eq!(err.to_string(), "UNAUTHORIZED");
+            // ACL 中的密钥不能访问其他 broker，全局密钥也不能访问配置了 ACL 的 broker
+            assert!(reader.get_meta("events", "owner").is_err());
+            let shared = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            assert!(shared.send_push_message("orders", b"three").is_err());
+            shared.send_push_message("events", b"one").unwrap();
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_competing_consumers_lease_each_record_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let jobs: Vec<Vec<u8>> = (0..250).map(|i| format!("job-{}", i).into_bytes()).collect();
+            let jobs: Vec<&[u8]> = jobs.iter().map(|job| job.as_slice()).collect();
+            client.send_push_batch("jobs", &jobs).unwrap();
+            let consumers: Vec<_> = (0..3)
+                .map(|_| {
+                    std::thread::spawn(move || {
+                        let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+                        let mut processed = vec![];
+                        while let Some(lease) = client.lease_fetch("jobs", std::time::Duration::from_secs(30)).unwrap() {
+                            processed.extend(lease.records.into_iter().map(|(offset, _)| offset));
+                            client.ack_lease("jobs", lease.id).unwrap();
+                        }
+                        processed
+                    })
+                })
+                .collect();
+            let mut processed: Vec<u64> = consumers.into_iter().flat_map(|c| c.join().unwrap()).collect();
+            processed.sort();
+            assert_eq!(processed, (0..250).collect::<Vec<u64>>());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_lease_is_redelivered() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("jobs", b"first").unwrap();
+            client.send_push_message("jobs", b"second").unwrap();
+            let timeout = std::time::Duration::from_millis(200);
+            let lease = client.lease_fetch("jobs", timeout).unwrap().unwrap();
+            assert_eq!(lease.records, vec![(0, b"first".to_vec()), (1, b"second".to_vec())]);
+            assert!(client.lease_fetch("jobs", timeout).unwrap().is_none());
+
+            // 消费者没有确认，租约到期后记录交给下一个消费者
+            std::thread::sleep(timeout);
+            let again = client.lease_fetch("jobs", timeout).unwrap().unwrap();
+            assert_eq!(again.records, lease.records);
+            assert!(client.ack_lease("jobs", lease.id).is_err());
+            client.ack_lease("jobs", again.id).unwrap();
+            assert!(client.lease_fetch("jobs", timeout).unwrap().is_none());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pulls_wait_for_permit() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "[brokers.cold]\nmax_concurrent_pulls = 1\n");
+        let (address, brokers) = spawn_server_with_brokers(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("cold", b"data").unwrap();
+            client.send_push_message("cold", b"more").unwrap();
+        })
+        .await
+        .unwrap();
+
+        // 占用唯一的许可，PULL 必须等待
+        let broker = brokers.get("cold").unwrap().clone();
+        let semaphore = broker.read().await.pull_permits.clone().unwrap();
+        let permit = semaphore.acquire_owned().await.unwrap();
+        let pull = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.fetch_messages("cold", 1).unwrap()
+        });
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(!pull.is_finished());
+
+        // PUSH 不受 PULL 许可的限制
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("cold", b"last").unwrap();
+        })
+        .await
+        .unwrap();
+
+        drop(permit);
+        let (_, record) = time::timeout(Duration::from_secs(5), pull)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(record, b"more");
+    }
+
+    #[tokio::test]
+    async fn test_list_connections_requires_admin() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"hello").unwrap();
+            assert!(client.list_connections().is_err());
+
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let connections = admin.list_connections().unwrap();
+            let client_connection = connections
+                .iter()
+                .find(|c| c.identity == "client")
+                .expect("client connection listed");
+            assert!(client_connection.bytes_received > 0);
+            assert!(client_connection.bytes_sent > 0);
+            assert!(connections.iter().any(|c| c.identity == "admin"));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_and_keepalive_on_idle_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                .keepalive(std::time::Duration::from_millis(20))
+                .build();
+            // 没有任何 broker 时也能 PING
+            client.ping().unwrap();
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let received = |admin: &sonicrab_client::Client| {
+                admin.list_connections().unwrap().into_iter().find(|c| c.identity == "client").unwrap().bytes_received
+            };
+            let before = received(&admin);
+            // 空闲期间后台线程继续发送 PING
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            assert!(received(&admin) > before);
+            client.ping().unwrap();
+            assert!(client.list_brokers().unwrap().is_empty());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_kick_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+
+        let mut victim = TcpStream::connect(address).await.unwrap();
+        let victim_peer = victim.local_addr().unwrap().to_string();
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            assert!(client.kick_connection(0).is_err());
+
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let target = loop {
+                let connections = admin.list_connections().unwrap();
+                if let Some(c) = connections.iter().find(|c| c.peer == victim_peer) {
+                    break c.id;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            };
+            admin.kick_connection(target).unwrap();
+            assert!(admin.kick_connection(u64::MAX).is_err());
+        })
+        .await
+        .unwrap();
+
+        // 被踢掉的连接由服务端正常关闭
+        let mut buf = [0u8; 1];
+        let read = time::timeout(Duration::from_secs(5), victim.read(&mut buf))
+            .await
+            .expect("server did not close the kicked connection");
+        assert_eq!(read.unwrap_or(0), 0);
+    }
+
+    #[tokio::test]
+    async fn test_case_colliding_broker_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(!detect_case_insensitive(dir.path().to_str().unwrap()).unwrap());
+
+        let mut config = test_config(dir.path(), "");
+        config.server.case_insensitive_names = Some(true);
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("Orders", b"first").unwrap();
+            client.send_push_message("Orders", b"second").unwrap();
+            assert!(client.send_push_message("orders", b"third").is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+
+        // 订阅调试级别的事件，不断打开新连接直到订阅者收到连接打开的事件
+        let subscriber = tokio::task::spawn_blocking(move || {
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let mut seen = None;
+            admin
+                .stream_logs("debug", |event| {
+                    if event.message.contains("opened") {
+                        seen = Some(event);
+                        return false;
+                    }
+                    true
+                })
+                .unwrap();
+            seen.unwrap()
+        });
+        let mut connections = vec![];
+        while !subscriber.is_finished() {
+            connections.push(TcpStream::connect(address).await.unwrap());
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        let event = subscriber.await.unwrap();
+        assert_eq!(event.level, "DEBUG");
+
+        let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+        let result = tokio::task::spawn_blocking(move || client.stream_logs("info", |_| true).is_err())
+            .await
+            .unwrap();
+        assert!(result);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_debug_pretty_prints_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[brokers.orders]\ncontent_type = \"json\"\ntimestamps = true\n";
+        let mut config = test_config(dir.path(), extra);
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("orders", br#"{"id":1,"items":["tea"]}"#).unwrap();
+            client.send_push_message("plain", b"raw").unwrap();
+            assert!(client.fetch_debug("orders", 0).is_err());
+
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let pretty = admin.fetch_debug("orders", 0).unwrap().unwrap();
+            assert_eq!(pretty, "{\n  \"id\": 1,\n  \"items\": [\n    \"tea\"\n  ]\n}");
+            assert_eq!(admin.fetch_debug("orders", 1).unwrap(), None);
+            assert!(admin.fetch_debug("plain", 0).is_err());
+            // 调试命令不会创建不存在的 broker
+            assert!(admin.fetch_debug("missing", 0).unwrap_err().to_string().contains("NO_BROKER"));
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn test_tail_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("logs", b"one").unwrap();
+            client.send_push_message("logs", b"two").unwrap();
+            // 最后 5 个字节落在第二条记录的头部中间
+            let (tail, from_start) = client.tail_bytes("logs", 5).unwrap();
+            assert_eq!(tail, [0, 1, b't', b'w', b'o']);
+            assert!(!from_start);
+            // 超出文件长度时从文件开头开始
+            let (all, from_start) = client.tail_bytes("logs", 1 << 20).unwrap();
+            assert_eq!(all.len(), 2 * (12 + 3));
+            assert_eq!(&all[..4], &3u32.to_be_bytes());
+            assert!(from_start);
+            // 极大的 n 不会让回复长度溢出
+            assert_eq!(client.tail_bytes("logs", u64::MAX).unwrap(), (all, true));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_managed_consumer_resumes_from_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        let checkpoint = dir.path().join("consumer.offset");
+        tokio::task::spawn_blocking(move || {
+            let producer = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for payload in [b"zero", b"one1", b"two2"] {
+                producer.send_push_message("jobs", payload).unwrap();
+            }
+
+            let consume = |count: usize| {
+                let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+                let mut consumer = sonicrab_client::ManagedConsumer::new(client, "jobs", &checkpoint)
+                    .start_offset(1)
+                    .poll_interval(std::time::Duration::from_millis(10));
+                let shutdown = consumer.shutdown_handle();
+                let mut handled = Vec::new();
+                let committed = consumer
+                    .run(|offset, record| {
+                        handled.push((offset, record.to_vec()));
+                        if handled.len() == count {
+                            shutdown.shutdown();
+                        }
+                        Ok(())
+                    })
+                    .unwrap();
+                (committed, handled)
+            };
+
+            let (committed, handled) = consume(2);
+            assert_eq!(committed, 3);
+            assert_eq!(handled, vec![(1, b"one1".to_vec()), (2, b"two2".to_vec())]);
+            assert_eq!(std::fs::read_to_string(&checkpoint).unwrap(), "3");
+
+            // 重新启动的消费者从检查点继续，不重复处理
+            producer.send_push_message("jobs", b"three").unwrap();
+            let (committed, handled) = consume(1);
+            assert_eq!(committed, 4);
+            assert_eq!(handled, vec![(3, b"three".to_vec())]);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_pressure_rises_with_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.push_pressure_depth = Some(4);
+        let broker_dir = dir.path().join("busy");
+        let (address, brokers) = spawn_server_with_brokers(config).await;
+        let port = address.port();
+        let push = move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", port, "test_key");
+            client.send_push_message("busy", b"payload").unwrap();
+            client.last_push_pressure()
+        };
+        assert_eq!(tokio::task::spawn_blocking(push).await.unwrap(), 0);
+
+        // 持有写锁，让后续的 PUSH 依次排队
+        let broker = brokers.get("busy").unwrap().clone();
+        let guard = broker.write().await;
+        let mut pending = Vec::new();
+        for queued in 1..=3 {
+            pending.push(tokio::task::spawn_blocking(push));
+            while IngestQueues::global().depth(&broker_dir) < queued {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+        drop(guard);
+        let mut levels = Vec::new();
+        for task in pending {
+            levels.push(task.await.unwrap());
+        }
+        assert_eq!(levels, vec![0, 63, 127]);
+    }
+
+    #[tokio::test]
+    async fn test_admin_port_separates_admin_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[admin]\naddress = \"127.0.0.1\"\nport = 0\nauthorization = \"admin_key\"\n";
+        let (data, admin) = spawn_server_with_admin(test_config(dir.path(), extra)).await;
+        tokio::task::spawn_blocking(move || {
+            let producer = sonicrab_client::Client::new("127.0.0.1", data.port(), "test_key");
+            producer.send_push_message("events", b"one").unwrap();
+            // 数据端口拒绝管理命令，即使使用管理密钥
+            let data_admin = sonicrab_client::Client::new("127.0.0.1", data.port(), "admin_key");
+            assert_eq!(data_admin.rebuild_index("events").unwrap_err().to_string(), "ADMIN_ONLY");
+            assert!(data_admin.list_connections().is_err());
+
+            // 管理端口只接受管理密钥和管理命令，broker 与数据端口共享
+            let admin_client = sonicrab_client::Client::new("127.0.0.1", admin.port(), "admin_key");
+            assert_eq!(admin_client.rebuild_index("events").unwrap(), 1);
+            assert!(admin_client.send_push_message("events", b"two").is_err());
+            let data_key = sonicrab_client::Client::new("127.0.0.1", admin.port(), "test_key");
+            assert!(data_key.rebuild_index("events").is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_zstd_dictionary_training() {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[brokers.events]\ncompression = \"zstd\"\nzstd_dictionary = true\ndictionary_samples = 200\ntimestamps = true\n";
+        let (address, brokers) = spawn_server_with_brokers(test_config(dir.path(), extra)).await;
+        let event = |i: u32| {
+            format!(r#"{{"event_type":"page_view","user_id":{},"session":"s-{}","path":"/products/{}","referrer":"https://example.com/search"}}"#, i, i * 7, i % 13)
+        };
+        let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+        let client = tokio::task::spawn_blocking(move || {
+            client.send_push_message("events", event(0).as_bytes()).unwrap();
+            client
+        })
+        .await
+        .unwrap();
+        // 直接写入 broker，避免几百次请求往返
+        let broker = brokers.get("events").unwrap().clone();
+        for i in 1..300 {
+            broker.write().await.receive_message(event(i).into_bytes()).await.unwrap();
+        }
+        assert!(dir.path().join("events").join("zstd.dict").exists());
+
+        tokio::task::spawn_blocking(move || {
+            // 训练前后压缩的记录都能读出原始数据
+            let records = client.fetch_batch("events", 1, None).unwrap();
+            assert_eq!(records.len(), 299);
+            for (offset, record) in records {
+                assert_eq!(&record[8..], event(offset as u32).as_bytes());
+            }
+        })
+        .await
+        .unwrap();
+
+        // 使用字典压缩的记录明显更小
+        let broker = broker.read().await;
+        let before = broker.store.read_record(100).await.unwrap().unwrap();
+        let after = broker.store.read_record(250).await.unwrap().unwrap();
+        assert_eq!(&before[..4], &0u32.to_be_bytes());
+        assert_eq!(&after[..4], &1u32.to_be_bytes());
+        assert!(after.len() < before.len());
+    }
+
+    #[tokio::test]
+    async fn test_pull_reply_header_frames_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for payload in [&b"a"[..], b"bb", b"ccc"] {
+                client.send_push_message("events", payload).unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        // 回复 [字节数: u32][下一个偏移: u64]，之后正好是该字节数的记录，没有结束标记
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        async fn pull(stream: &mut TcpStream, offset: u64, max_count: Option<u32>) -> (Vec<u8>, u64) {
+            let mut frame = Vec::new();
+            for field in [&b"test_key"[..], b"PULL", b"events"] {
+                frame.extend_from_slice(&(field.len() as u16).to_be_bytes());
+                frame.extend_from_slice(field);
+            }
+            frame.extend_from_slice(&offset.to_be_bytes());
+            if let Some(max_count) = max_count {
+                frame.extend_from_slice(&max_count.to_be_bytes());
+            }
+            stream.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+            stream.write_all(&frame).await.unwrap();
+            let size = stream.read_u32().await.unwrap();
+            let next_offset = stream.read_u64().await.unwrap();
+            let mut records = vec![0u8; size as usize];
+            stream.read_exact(&mut records).await.unwrap();
+            (records, next_offset)
+        }
+        let (records, next_offset) = pull(&mut stream, 1, None).await;
+        assert_eq!((records.len(), next_offset), (12 + 2 + 12 + 3, 3));
+        assert_eq!(&records[..4], &2u32.to_be_bytes());
+        assert_eq!(&records[4..12], &1u64.to_be_bytes());
+        assert_eq!(&records[12..14], b"bb");
+        assert_eq!(pull(&mut stream, 1, Some(1)).await, (records[..14].to_vec(), 2));
+        // 已经读到最新时字节数为 0，连接继续可用
+        assert_eq!(pull(&mut stream, 3, None).await, (vec![], 3));
+        assert_eq!(pull(&mut stream, 0, None).await.1, 3);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_pull_does_not_block_push() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.storage.max_file_size = "32m".to_string();
+        config.storage.pull_max_limit = "32m".to_string();
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for _ in 0..16 {
+                client.send_push_message("events", &vec![b'x'; 1024 * 1024]).unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        // 发出 PULL 后不读取响应，16m 的回复填满套接字缓冲区后发送停住
+        let mut stalled = TcpStream::connect(address).await.unwrap();
+        let mut frame = Vec::new();
+        for field in [&b"test_key"[..], b"PULL", b"events"] {
+            frame.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            frame.extend_from_slice(field);
+        }
+        frame.extend_from_slice(&1u64.to_be_bytes());
+        stalled.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+        stalled.write_all(&frame).await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+
+        let push = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"after").unwrap();
+        });
+        time::timeout(Duration::from_secs(5), push).await.expect("push blocked by a stalled pull").unwrap();
+        drop(stalled);
+    }
+
+    #[tokio::test]
+    async fn test_multiplexed_requests_reply_out_of_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.storage.max_file_size = "32m".to_string();
+        config.storage.pull_max_limit = "32m".to_string();
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for i in 0..16u8 {
+                client.send_push_message("large", &vec![i; 1024 * 1024]).unwrap();
+            }
+
+            // 同一个连接上先发出两个 16m 和 8m 的 PULL，再发出一个很小的 PUSH
+            let mux = client.multiplexed().unwrap();
+            let mux = &mux;
+            let ((first, first_done), (second, second_done), pushed) = std::thread::scope(|scope| {
+                let pull = |offset| {
+                    scope.spawn(move || {
+                        let records = mux.fetch_batch("large", offset, None).unwrap();
+                        (records, std::time::Instant::now())
+                    })
+                };
+                let first = pull(0);
+                let second = pull(8);
+                std::thread::sleep(Duration::from_millis(1));
+                let ack = mux.send_push_message("small", b"fast").unwrap();
+                let pushed = std::time::Instant::now();
+                assert_eq!(ack.offset, 0);
+                (first.join().unwrap(), second.join().unwrap(), pushed)
+            });
+
+            // 每个请求收到的是自己的回复
+            assert_eq!(first.len(), 16);
+            assert!(first.iter().enumerate().all(|(i, (offset, record))| *offset == i as u64 && record[0] == i as u8));
+            assert_eq!(second.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), (8..16).collect::<Vec<u64>>());
+            assert!(second.iter().all(|(offset, record)| record.len() == 1024 * 1024 && record[0] as u64 == *offset));
+            // PUSH 不用等待先发出的 PULL
+            assert!(pushed < first_done && pushed < second_done);
+            assert_eq!(mux.fetch_messages("small", 0).unwrap(), Some((0, b"fast".to_vec())));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_at_tail_sends_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "[brokers.events]\narchive = true\n")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for i in 0..3u8 {
+                client.send_push_message("events", &[i; 10]).unwrap();
+            }
+            // 已经读到最新：没有数据，下一个偏移不变，不会从文件开头重新发送
+            assert_eq!(client.fetch_all("events", 3, usize::MAX, usize::MAX).unwrap(), (Vec::new(), 3));
+            client.send_push_message("events", b"tail").unwrap();
+            assert_eq!(client.fetch_all("events", 3, usize::MAX, usize::MAX).unwrap(), (vec![(3, b"tail".to_vec())], 4));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_all_respects_caps() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for i in 0..6u8 {
+                client.send_push_message("batch", &[i; 10]).unwrap();
+            }
+
+            let (records, next) = client.fetch_all("batch", 1, 3, usize::MAX).unwrap();
+            assert_eq!(records.iter().map(|r| r.0).collect::<Vec<_>>(), vec![1, 2, 3]);
+            assert_eq!(next, 4);
+
+            let (records, next) = client.fetch_all("batch", next, 10, 15).unwrap();
+            assert_eq!(records, vec![(4, vec![4; 10])]);
+            assert_eq!(next, 5);
+
+            // 到达末尾时返回剩余的全部消息
+            let (records, next) = client.fetch_all("batch", next, 10, usize::MAX).unwrap();
+            assert_eq!(records, vec![(5, vec![5; 10])]);
+            assert_eq!(next, 6);
+            assert_eq!(client.fetch_all("batch", next, 10, usize::MAX).unwrap(), (vec![], 6));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_buffered_pull_respects_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "[brokers.packed]\ncompression = \"zstd\"\n");
+        config.storage.max_buffered_response_bytes = Some("1k".to_string());
+        let (address, brokers) = spawn_server_with_brokers(config).await;
+        let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+        let client = tokio::task::spawn_blocking(move || {
+            client.send_push_message("packed", &[0; 300]).unwrap();
+            client
+        })
+        .await
+        .unwrap();
+        let broker = brokers.get("packed").unwrap().clone();
+        for i in 1..10u8 {
+            broker.write().await.receive_message(vec![i; 300]).await.unwrap();
+        }
+        broker.write().await.receive_message(vec![10; 2000]).await.unwrap();
+
+        tokio::task::spawn_blocking(move || {
+            // 每条记录解压后 312 字节，1k 的上限内只能放下 3 条
+            let records = client.fetch_batch("packed", 1, None).unwrap();
+            assert_eq!(records.iter().map(|r| r.0).collect::<Vec<_>>(), vec![1, 2, 3]);
+            assert_eq!(client.fetch_batch("packed", 9, None).unwrap().len(), 1);
+            // 单条记录超过上限时回复 RESPONSE_TOO_LARGE，而不是消费者会一直重试的空回复
+            let err = client.fetch_batch("packed", 10, None).unwrap_err();
+            let code = err.downcast_ref::<sonicrab_client::ServerError>().map(|e| e.code);
+            assert_eq!(code, Some(sonicrab_client::StatusCode::Limit));
+            // 连接继续可用
+            assert_eq!(client.fetch_batch("packed", 9, None).unwrap().len(), 1);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_index_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"one").unwrap();
+            client.send_push_message("events", b"two").unwrap();
+            assert!(client.rebuild_index("events").is_err());
+
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            assert_eq!(admin.rebuild_index("events").unwrap(), 2);
+            assert_eq!(admin.send_push_message("events", b"three").unwrap().offset, 2);
+            assert!(admin.rebuild_index("missing").unwrap_err().to_string().contains("NO_BROKER"));
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn test_compact_keeps_latest_record_per_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "[brokers.changelog]\nkeyed = true\n");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for value in [&b"a1"[..], b"a2", b"a3"] {
+                client.send_push_keyed("changelog", "A", value).unwrap();
+            }
+            assert_eq!(client.send_push_keyed("changelog", "B", b"b1").unwrap().offset, 3);
+            assert!(client.send_push_keyed("events", "A", b"a1").is_err());
+            assert!(client.compact("changelog").is_err());
+
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let report = admin.compact("changelog").unwrap();
+            assert_eq!((report.kept, report.removed), (2, 2));
+            let record = |offset| {
+                let (_, record) = client.peek("changelog", offset).unwrap().unwrap();
+                let (key, body) = decode_key(&record).unwrap();
+                (key.to_string(), body.to_vec())
+            };
+            assert_eq!(record(0), ("A".to_string(), b"a3".to_vec()));
+            assert_eq!(record(1), ("B".to_string(), b"b1".to_vec()));
+            assert!(client.peek("changelog", 2).unwrap().is_none());
+            // 压缩后继续从新的偏移写入
+            assert_eq!(client.send_push_keyed("changelog", "A", b"a4").unwrap().offset, 2);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_picks_up_restored_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        let broker_dir = dir.path().join("events");
+        let backup_dir = backup.path().to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"one").unwrap();
+            client.send_push_message("events", b"two").unwrap();
+            let copy_files = |from: &Path, to: &Path| {
+                for entry in std::fs::read_dir(from).unwrap() {
+                    let path = entry.unwrap().path();
+                    std::fs::copy(&path, to.join(path.file_name().unwrap())).unwrap();
+                }
+            };
+            copy_files(&broker_dir, &backup_dir);
+            client.send_push_message("events", b"three").unwrap();
+            client.send_push_message("events", b"four").unwrap();
+
+            // 把备份恢复到 broker 目录，RELOAD 之后回到备份时的状态
+            copy_files(&backup_dir, &broker_dir);
+            assert!(client.reload_broker("events").is_err());
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            assert_eq!(admin.reload_broker("events").unwrap(), 2);
+            assert_eq!(client.fetch_messages("events", 1).unwrap(), Some((1, b"two".to_vec())));
+            assert_eq!(client.send_push_message("events", b"three again").unwrap().offset, 2);
+            assert!(admin.reload_broker("missing").unwrap_err().to_string().contains("NO_BROKER"));
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn test_async_client_push_and_fetch() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        // 直接在 tokio 任务中使用，不需要 spawn_blocking
+        let client = sonicrab_client::AsyncClient::new("127.0.0.1", address.port(), "test_key");
+        assert_eq!(client.send_push_message("events", b"one").await.unwrap().offset, 0);
+        assert_eq!(client.send_push_message("events", b"two").await.unwrap().offset, 1);
+        assert_eq!(client.send_push_message("events", b"three").await.unwrap().offset, 2);
+        assert_eq!(client.fetch_messages("events", 1).await.unwrap(), Some((1, b"two".to_vec())));
+        assert_eq!(
+            client.fetch_batch("events", 1, None).await.unwrap(),
+            vec![(1, b"two".to_vec()), (2, b"three".to_vec())]
+        );
+        assert_eq!(client.fetch_messages("events", 3).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_stream_yields_pushed_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        let client = sonicrab_client::AsyncClient::new("127.0.0.1", address.port(), "test_key");
+        client.send_push_message("events", b"before").await.unwrap();
+        let streamed = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client
+                .stream("events", 1)
+                .poll_interval(Duration::from_millis(10))
+                .take(5)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())
+        });
+        // 流追上后等待新消息
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for i in 1..=5u64 {
+            assert_eq!(client.send_push_message("events", format!("m{}", i).as_bytes()).await.unwrap().offset, i);
+        }
+        let streamed = time::timeout(Duration::from_secs(5), streamed).await.unwrap().unwrap().unwrap();
+        let expected: Vec<_> = (1..=5u64).map(|i| (i, format!("m{}", i).into_bytes())).collect();
+        assert_eq!(streamed, expected);
+
+        let mut stream = client.stream("events", 3).poll_interval(Duration::from_millis(10));
+        for i in 3..=5u64 {
+            assert_eq!(stream.next_message().await.unwrap(), (i, format!("m{}", i).into_bytes()));
+        }
+        let pending = time::timeout(Duration::from_millis(50), stream.next_message()).await;
+        assert!(pending.is_err());
+    }
+
+    #[cfg(feature = "metrics-http")]
+    #[tokio::test]
+    async fn test_metrics_endpoint_counts_pushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (address, brokers) = spawn_server_with_brokers(test_config(dir.path(), "")).await;
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_address = metrics_listener.local_addr().unwrap();
+        tokio::spawn(metrics::serve_metrics(metrics_listener, move || brokers.len()));
+
+        async fn scrape(address: std::net::SocketAddr, path: &str) -> String {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        }
+        fn value(response: &str, name: &str) -> u64 {
+            let line = response.lines().find(|line| line.starts_with(&format!("{} ", name))).unwrap();
+            line.rsplit(' ').next().unwrap().parse().unwrap()
+        }
+
+        let before = scrape(metrics_address, "/metrics").await;
+        assert!(before.starts_with("HTTP/1.1 200 OK"));
+        assert!(before.contains("# TYPE sonicrab_pushed_messages_total counter"));
+        let client = sonicrab_client::AsyncClient::new("127.0.0.1", address.port(), "test_key");
+        client.send_push_message("events", b"hello").await.unwrap();
+        let after = scrape(metrics_address, "/metrics").await;
+        // 计数器是进程级的，并行的其他测试也可能增加计数
+        assert!(value(&after, "sonicrab_pushed_messages_total") > value(&before, "sonicrab_pushed_messages_total"));
+        assert_eq!(value(&after, "sonicrab_brokers"), 1);
+        assert!(scrape(metrics_address, "/other").await.starts_with("HTTP/1.1 404"));
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn test_tls_round_trip_with_self_signed_cert() {
+        let dir = tempfile::tempdir().unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        let data = dir.path().join("data");
+        std::fs::create_dir(&data).unwrap();
+        let extra = format!("[tls]\ncert = \"{}\"\nkey = \"{}\"\n", cert_path.display(), key_path.display());
+        let address = spawn_server(test_config(&data, &extra)).await;
+        tokio::task::spawn_blocking(move || {
+            let tls = sonicrab_client::TlsConfig::new(&cert_path, "localhost").unwrap();
+            let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key").tls(tls).build();
+            let payloads: Vec<Vec<u8>> = (0..50u8).map(|i| vec![i; 1000]).collect();
+            for payload in &payloads {
+                client.send_push_message("events", payload).unwrap();
+            }
+            // PULL 在 TLS 连接上走缓冲拷贝，多条记录的响应完整解密
+            assert_eq!(client.fetch_messages("events", 7).unwrap(), Some((7, payloads[7].clone())));
+            let batch = client.fetch_batch("events", 1, None).unwrap();
+            assert_eq!(batch.len(), 49);
+            assert!(batch.iter().all(|(offset, record)| record == &payloads[*offset as usize]));
+
+            // 明文客户端无法与 TLS 端口通信，证书名称不匹配时握手失败
+            let plain = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                .retries(0, Duration::from_millis(1))
+                .read_timeout(Duration::from_millis(500))
+                .build();
+            assert!(plain.send_push_message("events", b"plain").is_err());
+            let wrong_name = sonicrab_client::TlsConfig::new(&cert_path, "example.com").unwrap();
+            let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key").tls(wrong_name).build();
+            assert!(client.ping().is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_errors_carry_status_codes() {
+        use sonicrab_client::{ServerError, StatusCode};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.broker_limit = 2;
+        config.server.case_insensitive_names = Some(true);
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let code = |err: Box<dyn std::error::Error>| err.downcast_ref::<ServerError>().map(|e| e.code);
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            assert_eq!(client.send_push_message("events", b"one").unwrap().offset, 0);
+            // 与已有 broker 只差大小写的名称无法创建
+            assert_eq!(code(client.send_push_message("EVENTS", b"two").unwrap_err()), Some(StatusCode::NoBroker));
+            client.send_push_message("orders", b"three").unwrap();
+            assert_eq!(code(client.send_push_message("third", b"four").unwrap_err()), Some(StatusCode::Limit));
+            // 消费者 ID 为空的提交是格式错误的请求
+            assert_eq!(code(client.commit_offset("events", "", 1).unwrap_err()), Some(StatusCode::BadRequest));
+            let intruder = sonicrab_client::Client::new("127.0.0.1", address.port(), "wrong_key");
+            assert_eq!(code(intruder.send_push_message("events", b"five").unwrap_err()), Some(StatusCode::AuthFailed));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unopenable_broker_gets_no_broker() {
+        let dir = tempfile::tempdir().unwrap();
+        // 与 broker 同名的普通文件使目录无法创建
+        std::fs::write(dir.path().join("blocked"), b"").unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let err = client.send_push_message("blocked", b"one").unwrap_err();
+            assert!(err.to_string().contains("NO_BROKER"), "{}", err);
+            // 服务端继续处理其他 broker
+            assert_eq!(client.send_push_message("events", b"two").unwrap().offset, 0);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_errors_carry_status_codes() {
+        use sonicrab_client::{ServerError, StatusCode};
+
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let code = |err: Box<dyn std::error::Error>| err.downcast_ref::<ServerError>().map(|e| e.code);
+            let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                .read_timeout(Duration::from_secs(5))
+                .build();
+            // 不存在的 broker 回复错误而不是让客户端等待记录
+            assert_eq!(code(client.fetch_messages("missing", 0).unwrap_err()), Some(StatusCode::NoBroker));
+            // 错误回复完整读出，同一连接上的下一个请求不受影响
+            client.send_push_message("events", b"one").unwrap();
+            assert_eq!(client.fetch_messages("events", 0).unwrap().unwrap().1, b"one");
+            let intruder = sonicrab_client::Client::new("127.0.0.1", address.port(), "wrong_key");
+            assert_eq!(code(intruder.fetch_messages("events", 0).unwrap_err()), Some(StatusCode::AuthFailed));
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_broker_is_created_by_server() {
+        let extra = "[brokers.jobs]\nmax_redeliveries = 1\n[[keys]]\nname = \"tenant-a\"\nkey = \"key_a\"\nmax_brokers = 1\n";
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), extra)).await;
+        tokio::task::spawn_blocking(move || {
+            let tenant = sonicrab_client::Client::new("127.0.0.1", address.port(), "key_a");
+            tenant.send_push_message("jobs", b"poison").unwrap();
+            for _ in 0..2 {
+                assert_eq!(tenant.fetch_as_consumer("jobs", "worker", 0).unwrap()[0].0, 0);
+            }
+            assert!(tenant.fetch_as_consumer("jobs", "worker", 0).unwrap().is_empty());
+            // 死信 broker 不属于租户，租户已用完 max_brokers 也能创建
+            assert_eq!(tenant.get_meta("jobs.dlq", "created_by").unwrap(), None);
+            assert_eq!(tenant.fetch_batch("jobs.dlq", 0, None).unwrap(), vec![(0, b"poison".to_vec())]);
+        })
+        .await
+        .unwrap();
+
+        // 达到 broker_limit 时无法创建死信 broker，回复错误，记录留在原位
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), extra);
+        config.server.broker_limit = 1;
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let tenant = sonicrab_client::Client::new("127.0.0.1", address.port(), "key_a");
+            tenant.send_push_message("jobs", b"poison").unwrap();
+            for _ in 0..2 {
+                assert_eq!(tenant.fetch_as_consumer("jobs", "worker", 0).unwrap()[0].0, 0);
+            }
+            let err = tenant.fetch_as_consumer("jobs", "worker", 0).unwrap_err();
+            assert_eq!(err.to_string(), "DLQ_UNAVAILABLE");
+            assert_eq!(tenant.fetch_as_consumer("jobs", "worker", 0).unwrap_err().to_string(), "DLQ_UNAVAILABLE");
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("jobs.dlq").exists());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_pulls_move_record_to_dead_letter_broker() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "[brokers.jobs]\nmax_redeliveries = 2\n")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for job in [b"ok".as_slice(), b"poison", b"next"] {
+                client.send_push_message("jobs", job).unwrap();
+            }
+            client.commit_offset("jobs", "worker", 1).unwrap();
+            // 消费者处理 1 时崩溃，反复从 1 重新拉取：首次投递加两次重新投递
+            for _ in 0..3 {
+                assert_eq!(client.fetch_as_consumer("jobs", "worker", 1).unwrap()[0], (1, b"poison".to_vec()));
+            }
+            let batch = client.fetch_as_consumer("jobs", "worker", 1).unwrap();
+            assert_eq!(batch, vec![(2, b"next".to_vec())]);
+            assert_eq!(client.fetch_batch("jobs.dlq", 0, None).unwrap(), vec![(0, b"poison".to_vec())]);
+            // 之后从 1 拉取仍然跳过，不会重复写入死信 broker
+            assert_eq!(client.fetch_as_consumer("jobs", "worker", 1).unwrap()[0].0, 2);
+            assert_eq!(client.fetch_batch("jobs.dlq", 0, None).unwrap()[0].0, 0);
+
+            // 读到末尾后等待新消息的拉取不计数，其他消费者单独计数
+            for _ in 0..5 {
+                assert!(client.fetch_as_consumer("jobs", "worker", 3).unwrap().is_empty());
+            }
+            client.send_push_message("jobs", b"later").unwrap();
+            assert_eq!(client.fetch_as_consumer("jobs", "worker", 3).unwrap()[0].1, b"later");
+            assert_eq!(client.fetch_as_consumer("jobs", "other", 1).unwrap()[0].0, 1);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clients_connect_over_ipv6_loopback() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind(socket_address("::1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, Arc::new(DashMap::new()), Arc::new(LiveConfig::new(test_config(dir.path(), ""), Vec::new())), false, watch::channel(false).1));
+
+        // 带或不带方括号的 IPv6 地址都可以使用
+        let async_client = sonicrab_client::AsyncClient::new("::1", port, "test_key");
+        assert_eq!(async_client.send_push_message("events", b"one").await.unwrap().offset, 0);
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("[::1]", port, "test_key");
+            assert_eq!(client.send_push_message("events", b"two").unwrap().offset, 1);
+            let client = sonicrab_client::Client::new("::1", port, "test_key");
+            assert_eq!(client.fetch_messages("events", 1).unwrap(), Some((1, b"two".to_vec())));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_broker_limit_rejects_or_evicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.broker_limit = 2;
+        let address = spawn_server(config.clone()).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("first", b"one").unwrap();
+            client.send_push_message("second", b"two").unwrap();
+            let err = client.send_push_message("third", b"three").unwrap_err();
+            assert!(err.to_string().contains("BROKER_LIMIT_REACHED"), "{}", err);
+            // 已有的 broker 不受影响
+            client.send_push_message("first", b"again").unwrap();
+        })
+        .await
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.broker_limit = 2;
+        config.server.evict_idle = Some(true);
+        config.server.evict_idle_after = Some("0s".to_string());
+        let (address, brokers) = spawn_server_with_brokers(config).await;
+        let listed = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("first", b"one").unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+            client.send_push_message("second", b"two").unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+            // 最久未使用的 first 被卸载
+            client.send_push_message("third", b"three").unwrap();
+            // 卸载的 broker 仍然列出，偏移是卸载时的值
+            let listed: Vec<(String, u64)> = client.list_brokers().unwrap().into_iter().map(|b| (b.name, b.position_offset)).collect();
+            // 卸载的 broker 数据保留，只读命令和写入都会重新打开它
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(client.fetch_messages("first", 0).unwrap(), Some((0, b"one".to_vec())));
+            std::thread::sleep(Duration::from_millis(5));
+            assert_eq!(client.send_push_message("first", b"back").unwrap().offset, 1);
+            listed
+        })
+        .await
+        .unwrap();
+        let expected = [("first", 1), ("second", 1), ("third", 1)].map(|(name, next)| (name.to_string(), next));
+        assert_eq!(listed, expected);
+        assert_eq!(brokers.len(), 2);
+        assert!(brokers.contains_key("first"));
+    }
+
+    #[tokio::test]
+    async fn test_earliest_and_latest_offsets() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            // 只有一条记录时两者相同
+            client.send_push_message("single", b"x").unwrap();
+            assert_eq!(client.fetch_messages("single", EARLIEST).unwrap(), Some((0, b"x".to_vec())));
+            assert_eq!(client.fetch_messages("single", LATEST).unwrap(), Some((0, b"x".to_vec())));
+            for payload in [&b"first"[..], b"second", b"last"] {
+                client.send_push_message("events", payload).unwrap();
+            }
+            assert_eq!(client.fetch_messages("events", EARLIEST).unwrap(), Some((0, b"first".to_vec())));
+            assert_eq!(client.fetch_messages("events", LATEST).unwrap(), Some((2, b"last".to_vec())));
+            assert_eq!(client.fetch_all("events", EARLIEST, 10, usize::MAX).unwrap().1, 3);
+        })
+        .await
+        .unwrap();
+
+    }
+
+    #[tokio::test]
+    async fn test_pull_with_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for i in 0..10u8 {
+                client.send_push_message("events", &[i; 100]).unwrap();
+            }
+            let large = client.fetch_messages_limited("events", 1, 1024 * 1024).unwrap();
+            assert_eq!(large.len(), 9);
+            // 每条记录加上 12 字节的帧头为 112 字节，300 字节只能容纳两条
+            let small = client.fetch_messages_limited("events", 1, 300).unwrap();
+            assert_eq!(small.iter().map(|(offset, _)| *offset).collect::<Vec<_>>(), vec![1, 2]);
+            // 上限小于第一条记录时仍然返回这一条
+            assert_eq!(client.fetch_messages_limited("events", 5, 1).unwrap(), vec![(5, vec![5; 100])]);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pull_with_max_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "[brokers.packed]\ncompression = \"zstd\"\n");
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for broker in ["events", "packed"] {
+                for i in 0..10u8 {
+                    client.send_push_message(broker, &[i; 10]).unwrap();
+                }
+                let records = client.fetch_batch(broker, 1, Some(3)).unwrap();
+                assert_eq!(records, vec![(1, vec![1; 10]), (2, vec![2; 10]), (3, vec![3; 10])]);
+                // 从最后一条记录的下一个偏移继续
+                let next = records.last().unwrap().0 + 1;
+                assert_eq!(next, 4);
+                let records = client.fetch_batch(broker, 8, Some(3)).unwrap();
+                assert_eq!(records.iter().map(|r| r.0).collect::<Vec<_>>(), vec![8, 9]);
+                assert!(client.fetch_batch(broker, 10, Some(3)).unwrap().is_empty());
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peek_single_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.storage.max_file_size = "1k".to_string();
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            // 跨越多个文件，前面的记录在历史文件中
+            for i in 0..20u8 {
+                client.send_push_message("events", &[i; 200]).unwrap();
+            }
+            assert_eq!(client.peek("events", 0).unwrap(), Some((0, vec![0; 200])));
+            assert_eq!(client.peek("events", 10).unwrap(), Some((10, vec![10; 200])));
+            assert_eq!(client.peek("events", 19).unwrap(), Some((19, vec![19; 200])));
+            assert_eq!(client.peek("events", 20).unwrap(), None);
+            assert!(client.peek("missing", 0).is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_raises_broker_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        std::fs::create_dir(&data).unwrap();
+        let file = dir.path().join("config.toml");
+        let config_file = file.clone();
+        let write = move |broker_limit: u16| {
+            let content = format!(
+                "[server]\naddress = \"127.0.0.1\"\nport = 0\npath = \"{}\"\nbroker_limit = {}\n\
+                 authorization = \"test_key\"\nadmin_authorization = \"admin_key\"\n\
+                 [storage]\nmax_file_size = \"1m\"\npull_max_limit = \"1m\"\ncache_limit = 10\n",
+                data.display(),
+                broker_limit
+            );
+            std::fs::write(&config_file, content).unwrap();
+        };
+        write(1);
+        let config = Arc::new(LiveConfig::new(load_config(std::slice::from_ref(&file)).unwrap(), vec![file.clone()]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Arc::new(DashMap::new()), config, false, watch::channel(false).1));
+
+        let client = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            client.send_push_message("first", b"one").unwrap();
+            let refused = client.send_push_message("second", b"two").unwrap_err();
+            assert!(refused.to_string().contains("BROKER_LIMIT_REACHED"));
+            assert!(client.reload_config().is_err());
+
+            write(2);
+            admin.reload_config().unwrap();
+            // 已有的连接不断开，新的 broker_limit 立即生效
+            assert_eq!(client.send_push_message("second", b"two").unwrap().offset, 0);
+            assert!(client.send_push_message("third", b"three").is_err());
+            client
+        })
+        .await
+        .unwrap();
+
+        // 数据目录不能在运行中修改，拒绝后继续使用原来的配置
+        std::fs::write(&file, std::fs::read_to_string(&file).unwrap().replace("broker_limit = 2", "broker_limit = 3").replace("/data", "/moved")).unwrap();
+        tokio::task::spawn_blocking(move || {
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let err = admin.reload_config().unwrap_err();
+            assert!(err.to_string().contains("server.path cannot change without a restart"));
+            assert!(client.send_push_message("third", b"three").is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_seek_timestamp_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "[brokers.events]\ntimestamps = true\n");
+        config.storage.max_file_size = "1k".to_string();
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            // 每个文件只能放下几条记录，查找跨越历史文件和当前文件
+            let mut timestamps = Vec::new();
+            for i in 0..30u8 {
+                timestamps.push(client.send_push_message("events", &[i; 200]).unwrap().timestamp);
+                std::thread::sleep(Duration::from_millis(2));
+            }
+            assert_eq!(client.seek_timestamp("events", timestamps[17]).unwrap(), 17);
+            assert_eq!(client.seek_timestamp("events", timestamps[17] - 1).unwrap(), 17);
+            assert_eq!(client.seek_timestamp("events", timestamps[0] - 1000).unwrap(), 0);
+            assert_eq!(client.seek_timestamp("events", timestamps[29]).unwrap(), 29);
+            assert_eq!(client.seek_timestamp("events", timestamps[29] + 1).unwrap(), 30);
+            let offset = client.seek_timestamp("events", timestamps[9] + 1).unwrap();
+            assert_eq!(client.fetch_batch("events", offset, Some(1)).unwrap()[0].0, 10);
+
+            client.send_push_message("plain", b"x").unwrap();
+            assert!(client.seek_timestamp("plain", 0).unwrap_err().to_string().contains("NO_TIMESTAMPS"));
+            assert!(client.seek_timestamp("missing", 0).is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_records_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let extra = "[brokers.events]\ntimestamps = true\nmessage_ttl = \"1s\"\n";
+        let address = spawn_server(test_config(dir.path(), extra)).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"old0").unwrap();
+            client.send_push_message("events", b"old1").unwrap();
+            assert_eq!(client.fetch_batch("events", 1, None).unwrap().len(), 1);
+            std::thread::sleep(Duration::from_millis(1100));
+            client.send_push_message("events", b"new").unwrap();
+
+            // 过期的记录不返回，从第一条未过期的记录开始
+            let batch = client.fetch_batch("events", 1, None).unwrap();
+            assert_eq!(batch.len(), 1);
+            assert_eq!((batch[0].0, &batch[0].1[8..]), (2, &b"new"[..]));
+            assert_eq!(client.peek("events", 1).unwrap(), None);
+            assert_eq!(client.peek("events", 2).unwrap().map(|(offset, _)| offset), Some(2));
+            let mut stream = client.stream("events", 1).poll_interval(Duration::from_millis(10));
+            assert_eq!(stream.next().unwrap().unwrap().0, 2);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_client_refuses_pushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let producer = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            producer.send_push_message("events", b"one").unwrap();
+            let consumer = sonicrab_client::Client::new_consumer("127.0.0.1", address.port(), "test_key");
+            let read_only = |e: Box<dyn std::error::Error>| {
+                e.downcast_ref::<sonicrab_client::ClientError>() == Some(&sonicrab_client::ClientError::ReadOnly)
+            };
+            assert!(read_only(consumer.send_push_message("events", b"two").unwrap_err()));
+            assert!(read_only(consumer.send_push_batch("events", &[b"two"]).unwrap_err()));
+            assert!(read_only(consumer.send_push_keyed("events", "k", b"two").unwrap_err()));
+            assert_eq!(consumer.fetch_messages("events", 0).unwrap(), Some((0, b"one".to_vec())));
+            assert_eq!(consumer.peek("events", 0).unwrap(), Some((0, b"one".to_vec())));
+            consumer.commit_offset("events", "billing", 1).unwrap();
+            // 被拒绝的写入没有发送给服务端
+            assert_eq!(producer.send_push_message("events", b"two").unwrap().offset, 1);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_brokers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "");
+        let fresh = Broker::new("fresh".to_string(), &config).await.unwrap();
+        let (address, brokers) = spawn_server_with_brokers(config).await;
+        brokers.insert("fresh".to_string(), Arc::new(RwLock::new(fresh)));
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"one").unwrap();
+            let listed = client.list_brokers().unwrap();
+            assert_eq!(listed.len(), 2);
+            assert_eq!(listed[0].name, "events");
+            assert_eq!((listed[0].base_offset, listed[0].position_offset), (0, 1));
+            assert!(listed[0].data_len > 0);
+            assert_eq!(
+                listed[1],
+                sonicrab_client::BrokerInfo {
+                    name: "fresh".to_string(),
+                    base_offset: 0,
+                    position_offset: 0,
+                    data_len: 0,
+                }
+            );
+        })
+        .await
+        .unwrap();
+
+        // 写锁被长时间持有（如 COMPACT）时也能列出；重新打开存储后列出的仍是当前的位置
+        let events = brokers.get("events").unwrap().clone();
+        let mut guard = events.write().await;
+        let events_dir = guard.dir.clone();
+        guard.reopen("events", events_dir, &test_config(dir.path(), "")).await.unwrap();
+        guard.receive_message(b"two".to_vec()).await.unwrap();
+        let listing = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.list_brokers().unwrap()
+        });
+        let listed = time::timeout(Duration::from_secs(5), listing).await.unwrap().unwrap();
+        assert_eq!(listed[0].position_offset, 2);
+        drop(guard);
+    }
+
+    #[tokio::test]
+    async fn test_delete_broker() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"one").unwrap();
+            assert!(client.delete_broker("events").is_err());
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            admin.delete_broker("events").unwrap();
+            assert!(admin.delete_broker("events").is_err());
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("events").exists());
+
+        // 删除后 PULL 不会重新创建 broker
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut pull = Vec::new();
+        for field in [&b"test_key"[..], b"PULL", b"events"] {
+            pull.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            pull.extend_from_slice(field);
+        }
+        pull.extend_from_slice(&0u64.to_be_bytes());
+        stream.write_all(&(pull.len() as u32).to_be_bytes()).await.unwrap();
+        stream.write_all(&pull).await.unwrap();
+        assert_eq!(stream.read_u32().await.unwrap(), PULL_REFUSED);
+        let len = stream.read_u32().await.unwrap();
+        let mut response = vec![0u8; len as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"\x01NO_BROKER");
+        assert!(!dir.path().join("events").exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_broker_waits_for_holders() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "");
+        let brokers: Brokers = Arc::new(DashMap::new());
+        // 模拟一个已经取到 broker、尚未拿到锁的请求
+        let holder = get_broker(&brokers, "events".to_string(), &config, "test_key").await.unwrap();
+        let err = delete_broker(&brokers, "events", &config).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert!(brokers.contains_key("events"));
+
+        // 删除失败后该请求的写入仍然落在 broker 的文件中
+        assert_eq!(push_message(&holder, b"late".to_vec()).await.unwrap().0, 0);
+        assert_eq!(holder.read().await.read_record(0).await.unwrap(), Some(b"late".to_vec()));
+        drop(holder);
+        assert!(delete_broker(&brokers, "events", &config).await.unwrap());
+        assert!(!dir.path().join("events").exists());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_broker() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        let new_path = target.path().to_str().unwrap().to_string();
+        tokio::task::spawn_blocking(move || {
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            admin.send_push_message("events", b"one").unwrap();
+            admin.send_push_message("events", b"two").unwrap();
+            admin.migrate_broker("events", &new_path).unwrap();
+
+            assert_eq!(admin.send_push_message("events", b"three").unwrap().offset, 2);
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            assert_eq!(client.fetch_messages("events", 1).unwrap(), Some((1, b"two".to_vec())));
+            // 目标目录已存在时拒绝迁移，原数据保持不变
+            assert!(admin.migrate_broker("events", &new_path).is_err());
+            assert!(admin.migrate_broker("missing", &new_path).unwrap_err().to_string().contains("NO_BROKER"));
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("missing").exists());
+        assert!(!target.path().join("missing").exists());
+
+        let link = dir.path().join("events");
+        assert!(link.is_symlink());
+        assert_eq!(std::fs::read_link(&link).unwrap(), target.path().join("events"));
+        assert!(target.path().join("events/000000000000.data").exists());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_push_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                .compression(sonicrab_client::compression::Codec::Lz4, 64)
+                .build();
+            let payload = b"sonicrab ".repeat(100);
+            assert_eq!(client.send_push_message("events", b"small").unwrap().offset, 0);
+            assert_eq!(client.send_push_message("events", &payload).unwrap().offset, 1);
+            assert_eq!(client.fetch_messages("events", 1).unwrap(), Some((1, payload)));
+        })
+        .await
+        .unwrap();
+
+        // 解压后超过 max_message_size 的消息被拒绝，压缩后的帧再小也一样
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "[brokers.codecs]\nrecord_codecs = true\n");
+        config.server.max_message_size = Some("1k".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            for codec in [sonicrab_client::compression::Codec::Lz4, sonicrab_client::compression::Codec::Zstd] {
+                let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                    .compression(codec, 64)
+                    .build();
+                for broker in ["events", "codecs"] {
+                    let err = client.send_push_message(broker, &[b'x'; 2048]).unwrap_err();
+                    assert!(err.to_string().contains("BAD_COMPRESSION"), "{}", err);
+                    assert!(client.send_push_message(broker, &[b'x'; 512]).is_ok());
+                }
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_record_codecs_keep_compressed_payloads() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "[brokers.events]\nrecord_codecs = true\n")).await;
+        tokio::task::spawn_blocking(move || {
+            use sonicrab_client::compression::{compress, Codec};
+            let payload = b"sonicrab ".repeat(100);
+            let plain = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            plain.send_push_message("events", &payload).unwrap();
+            for codec in [Codec::Lz4, Codec::Zstd] {
+                let client = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                    .compression(codec, 0)
+                    .build();
+                client.send_push_message("events", &payload).unwrap();
+            }
+
+            // 不解压的客户端读到保存的 [codec][压缩数据]
+            let (_, stored) = plain.fetch_messages("events", 2).unwrap().unwrap();
+            assert_eq!(stored, compress(Codec::Zstd, &payload));
+            let (_, stored) = plain.fetch_messages("events", 1).unwrap().unwrap();
+            assert_eq!(stored, compress(Codec::Lz4, &payload));
+
+            let reader = sonicrab_client::Client::builder("127.0.0.1", address.port(), "test_key")
+                .decompress_records(true)
+                .build();
+            let records = reader.fetch_batch("events", 1, None).unwrap();
+            assert_eq!(records.len(), 2);
+            assert!(records.iter().all(|(_, record)| record == &payload));
+            assert_eq!(reader.peek("events", 0).unwrap(), Some((0, payload.clone())));
+            assert_eq!(reader.peek("events", 2).unwrap(), Some((2, payload)));
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_pulls_are_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        // 阈值为 0 时每次 PULL 都算慢查询
+        config.server.slow_pull_ms = Some(0);
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("events", b"hello").unwrap();
+            let slow_pulls = |client: &sonicrab_client::Client| {
+                client.stats().unwrap().into_iter().find(|(name, _)| name == "slow_pulls").unwrap().1
+            };
+            let before = slow_pulls(&client);
+            client.fetch_messages("events", 0).unwrap();
+            let reader = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            assert!(slow_pulls(&reader) > before);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_committed_offset_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            // 提交偏移不会创建 broker
+            assert!(client.commit_offset("orders", "billing", 1).is_err());
+            assert!(client.fetch_committed("orders", "billing").is_err());
+            client.send_push_message("orders", b"first").unwrap();
+            assert_eq!(client.fetch_committed("orders", "billing").unwrap(), None);
+            client.commit_offset("orders", "billing", 17).unwrap();
+            client.commit_offset("orders", "billing", 18).unwrap();
+            client.commit_offset("orders", "search", 3).unwrap();
+            assert_eq!(client.fetch_committed("orders", "billing").unwrap(), Some(18));
+            assert_eq!(client.fetch_committed("orders", "search").unwrap(), Some(3));
+            assert_eq!(client.fetch_committed("orders", "unknown").unwrap(), None);
+            assert!(client.commit_offset("orders", "", 1).is_err());
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_broker_meta_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.set_meta("orders", "owner", "payments-team").unwrap();
+            client.set_meta("orders", "env", "prod").unwrap();
+            client.set_meta("orders", "env", "staging").unwrap();
+            assert!(client.set_meta("orders", "", "x").is_err());
+            assert!(client.set_meta("orders", "k", &"v".repeat(5000)).is_err());
+        })
+        .await
+        .unwrap();
+
+        // 新的服务端实例从目录中重新加载 broker；测试服务启动时不加载已有目录，GET_META 不会打开它，先写入一条记录
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("orders", b"reopen").unwrap();
+            assert_eq!(client.get_meta("orders", "owner").unwrap().as_deref(), Some("payments-team"));
+            assert_eq!(client.get_meta("orders", "env").unwrap().as_deref(), Some("staging"));
+            assert_eq!(client.get_meta("orders", "missing").unwrap(), None);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_push_requires_consumers() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "[brokers.live]\nrequire_consumers = true\n")).await;
+        tokio::task::spawn_blocking(move || {
+            let producer = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let err = producer.send_push_message("live", b"lost").unwrap_err();
+            assert_eq!(err.to_string(), "NO_CONSUMERS");
+
+            let subscriber = std::thread::spawn(move || {
+                let consumer = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+                let mut received = None;
+                consumer
+                    .subscribe("live", 0, |offset, record| {
+                        received = Some((offset, record));
+                        false
+                    })
+                    .unwrap();
+                received.unwrap()
+            });
+            // 订阅建立后写入成功，并推送给订阅者
+            let offset = loop {
+                match producer.send_push_message("live", b"delivered") {
+                    Ok(ack) => break ack.offset,
+                    Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                }
+            };
+            assert_eq!(subscriber.join().unwrap(), (offset, b"delivered".to_vec()));
+
+            // 订阅者断开后重新拒绝写入
+            let mut rejected = false;
+            for _ in 0..100 {
+                if producer.send_push_message("live", b"late").is_err() {
+                    rejected = true;
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            assert!(rejected);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_reaped_after_missed_heartbeats() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.server.subscriber_heartbeat_ms = Some(50);
+        config.server.subscriber_heartbeat_misses = Some(2);
+        let (address, brokers) = spawn_server_with_brokers(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("live", b"created").unwrap();
+        })
+        .await
+        .unwrap();
+        let broker = brokers.get("live").unwrap().clone();
+        let subscribers = broker.read().await.subscribers.clone();
+
+        // 客户端订阅自动发送心跳
+        let live = tokio::task::spawn_blocking(move || {
+            let consumer = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let mut received = Vec::new();
+            consumer
+                .subscribe("live", 1, |_, record| {
+                    received.push(record);
+                    false
+                })
+                .unwrap();
+            received
+        });
+
+        // 手工订阅、之后不再发送心跳的订阅者
+        let mut frame = Vec::new();
+        for field in [&b"test_key"[..], b"SUBSCRIBE", b"live"] {
+            frame.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            frame.extend_from_slice(field);
+        }
+        frame.extend_from_slice(&1u64.to_be_bytes());
+        let mut dead = TcpStream::connect(address).await.unwrap();
+        dead.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+        dead.write_all(&frame).await.unwrap();
+        let mut reply = [0u8; 11];
+        dead.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[4..], b"\0OK\0\0\0\x32");
+
+        // 服务端在错过两次心跳后断开它，持续发送心跳的订阅者保留
+        let mut buf = [0u8; 1];
+        let read = time::timeout(Duration::from_secs(5), dead.read(&mut buf))
+            .await
+            .expect("server did not drop the silent subscriber");
+        assert_eq!(read.unwrap_or(0), 0);
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(subscribers.load(Ordering::SeqCst), 1);
+
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("live", b"delivered").unwrap();
+        })
+        .await
+        .unwrap();
+        assert_eq!(live.await.unwrap(), vec![b"delivered".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_receives_push_promptly() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            client.send_push_message("live", b"old").unwrap();
+            client.send_push_message("live", b"current").unwrap();
+        })
+        .await
+        .unwrap();
+
+        // 从 LATEST 订阅：先收到最新的一条，之后等待新的记录
+        let subscriber = tokio::task::spawn_blocking(move || {
+            let consumer = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let mut received = Vec::new();
+            consumer
+                .subscribe("live", LATEST, |offset, record| {
+                    received.push((offset, record, std::time::Instant::now()));
+                    received.len() < 2
+                })
+                .unwrap();
+            received
+        });
+        time::sleep(Duration::from_millis(200)).await;
+        let pushed = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let pushed = std::time::Instant::now();
+            client.send_push_message("live", b"new").unwrap();
+            pushed
+        })
+        .await
+        .unwrap();
+        let received = time::timeout(Duration::from_secs(5), subscriber).await.unwrap().unwrap();
+        assert_eq!(received[0].0, 1);
+        assert_eq!((received[1].0, received[1].1.as_slice()), (2, &b"new"[..]));
+        // 订阅者由追加通知唤醒，不需要轮询
+        assert!(received[1].2.duration_since(pushed) < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_list_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.storage.max_file_size = "1k".to_string();
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for _ in 0..10 {
+                client.send_push_message("orders", &[7u8; 200]).unwrap();
+            }
+            assert_eq!(client.list_segments("orders").unwrap_err().to_string(), "FORBIDDEN");
+
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            let segments = admin.list_segments("orders").unwrap();
+            assert!(segments.len() > 1);
+            let (active, sealed) = segments.split_last().unwrap();
+            assert!(active.active);
+            assert!(sealed.iter().all(|segment| !segment.active));
+            // 每个文件的索引项数与下一个文件的 base_offset 衔接，索引文件足够容纳已使用的索引项
+            for pair in segments.windows(2) {
+                assert_eq!(pair[0].base_offset + pair[0].index_entries, pair[1].base_offset);
+            }
+            assert_eq!(active.base_offset + active.index_entries, 10);
+            for segment in &segments {
+                assert!(segment.data_bytes >= segment.index_entries * 212);
+                assert!(segment.index_bytes >= segment.index_entries * 12);
+            }
+            // 连接在读完整个列表后仍可继续使用
+            assert_eq!(admin.list_segments("orders").unwrap(), segments);
+            assert!(admin.list_segments("missing").unwrap_err().to_string().contains("NO_BROKER"));
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn test_push_ack_offsets_increase() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let mut previous = None;
+            for i in 0..5u8 {
+                let ack = client.send_push_message("orders", &[i; 8]).unwrap();
+                assert_eq!(ack.offset, previous.map_or(0, |offset| offset + 1));
+                previous = Some(ack.offset);
+                // 用返回的偏移读回刚写入的消息
+                assert_eq!(client.fetch_messages("orders", ack.offset).unwrap().unwrap().1, vec![i; 8]);
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pinned_segment_survives_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.storage.max_file_size = "1k".to_string();
+        config.server.admin_authorization = Some("admin_key".to_string());
+        let address = spawn_server(config).await;
+        let segments = tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            for _ in 0..12 {
+                client.send_push_message("orders", &[7u8; 200]).unwrap();
+            }
+            assert_eq!(client.pin_segment("orders", 0).unwrap_err().to_string(), "FORBIDDEN");
+
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            admin.pin_segment("orders", 0).unwrap();
+            assert_eq!(admin.pin_segment("orders", 1).unwrap_err().to_string(), "NOT_FOUND");
+            // 固定列表是保留的元数据键，不能直接修改
+            assert_eq!(admin.set_meta("orders", "pinned_segments", "").unwrap_err().to_string(), "BAD_META");
+            admin.list_segments("orders").unwrap()
+        })
+        .await
+        .unwrap();
+        assert!(segments.len() >= 3);
+
+        // 只保留最新的一个文件（数据和索引两个文件），固定的第一个文件不受影响
+        let broker_dir = dir.path().join("orders");
+        let data_file = |base_offset: u64| broker_dir.join(format!("{:012}.data", base_offset));
+        delete_old_files(dir.path().to_str().unwrap(), 2).await.unwrap();
+        assert!(data_file(0).exists());
+        assert!(broker_dir.join("000000000000.index").exists());
+        assert!(!data_file(segments[1].base_offset).exists());
+        assert!(data_file(segments.last().unwrap().base_offset).exists());
+
+        tokio::task::spawn_blocking(move || {
+            let admin = sonicrab_client::Client::new("127.0.0.1", address.port(), "admin_key");
+            admin.unpin_segment("orders", 0).unwrap();
+            assert_eq!(admin.unpin_segment("orders", 0).unwrap_err().to_string(), "NOT_FOUND");
+            // 固定不存在的 broker 的文件不会创建它
+            assert_eq!(admin.pin_segment("missing", 0).unwrap_err().to_string(), "NO_BROKER");
+            assert_eq!(admin.unpin_segment("missing", 0).unwrap_err().to_string(), "NO_BROKER");
+        })
+        .await
+        .unwrap();
+        assert!(!dir.path().join("missing").exists());
+        delete_old_files(dir.path().to_str().unwrap(), 2).await.unwrap();
+        assert!(!data_file(0).exists());
+    }
+
+    #[tokio::test]
+    async fn test_retention_coordinates_with_live_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_config(dir.path(), "");
+        config.storage.max_file_size = "1k".to_string();
+        let (address, brokers) = spawn_server_with_brokers(config.clone()).await;
+        let push = move |count: u8| {
+            tokio::task::spawn_blocking(move || {
+                let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+                for i in 0..count {
+                    client.send_push_message("orders", &[i; 200]).unwrap();
+                }
+            })
+        };
+        let broker_dir = dir.path().join("orders");
+        // 把目录中所有文件（含当前文件）的修改时间设为 10 天前
+        let age_files = || {
+            for entry in std::fs::read_dir(&broker_dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_file() {
+                    let file = std::fs::File::options().write(true).open(&path).unwrap();
+                    file.set_modified(std::time::SystemTime::now() - Duration::from_secs(10 * 24 * 60 * 60)).unwrap();
+                }
+            }
+        };
+        let retention = Duration::from_secs(7 * 24 * 60 * 60);
+        push(12).await.unwrap();
+
+        // 已打开的 broker：存储决定哪些是历史文件，删除后历史文件列表同步更新
+        age_files();
+        expire_segments(&brokers, &config, retention).await.unwrap();
+        let broker = brokers.get("orders").unwrap().value().clone();
+        let active = broker.read().await.store.active_base_offset();
+        assert!(active > 0);
+        assert_eq!(storage::sealed_segments_in(&broker_dir, u64::MAX).unwrap(), vec![active]);
+        assert!(broker_dir.join(format!("{:012}.index", active)).exists());
+        assert_eq!(broker.read().await.store.first_offset().await, active);
+        drop(broker);
+
+        // 被卸载的 broker：当前文件取卸载时记录的位置
+        push(12).await.unwrap();
+        assert!(evict_idle_broker(&brokers, 0, &config).await);
+        age_files();
+        expire_segments(&brokers, &config, retention).await.unwrap();
+        let remaining = storage::sealed_segments_in(&broker_dir, u64::MAX).unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(remaining[0] > active);
+        let broker = lookup_broker(&brokers, "orders", &config, "test_key").await.unwrap();
+        assert_eq!(broker.read().await.store.first_offset().await, remaining[0]);
+        assert_eq!(broker.read().await.store.read_record(23).await.unwrap(), Some(vec![11; 200]));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frame_gets_bad_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        async fn exchange(stream: &mut TcpStream, frame: &[u8]) -> Vec<u8> {
+            stream.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+            stream.write_all(frame).await.unwrap();
+            let len = stream.read_u32().await.unwrap();
+            let mut response = vec![0u8; len as usize];
+            stream.read_exact(&mut response).await.unwrap();
+            response
+        }
+
+        // 截断的密钥和缺少偏移的 PULL 都得到状态码为 3 的 BAD_REQUEST，连接继续可用
+        assert_eq!(exchange(&mut stream, &[0, 9, b'k']).await, b"\x03BAD_REQUEST: truncated key");
+        let mut pull = Vec::new();
+        for field in [&b"test_key"[..], b"PULL", b"orders"] {
+            pull.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            pull.extend_from_slice(field);
+        }
+        // PULL 的错误回复前有 PULL_REFUSED 标记
+        stream.write_all(&(pull.len() as u32).to_be_bytes()).await.unwrap();
+        stream.write_all(&pull).await.unwrap();
+        assert_eq!(stream.read_u32().await.unwrap(), PULL_REFUSED);
+        let len = stream.read_u32().await.unwrap();
+        let mut response = vec![0u8; len as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, b"\x03BAD_REQUEST: truncated offset");
+        let ping = [&pull[..10], &[0, 4], b"PING", &[0, 0]].concat();
+        assert_eq!(exchange(&mut stream, &ping).await, b"\0PONG");
+    }
+
+    #[test]
+    fn test_env_log_filter() {
+        assert!(env_log_filter(None).unwrap().is_none());
+        assert!(env_log_filter(Some("")).unwrap().is_none());
+        assert_eq!(env_log_filter(Some("WARN")).unwrap().unwrap().max_level_hint(), Some(LevelFilter::WARN));
+        let filter = env_log_filter(Some("info,sonicrab_mq::storage=debug")).unwrap().unwrap();
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::DEBUG));
+        // 拼错的级别启动时报错
+        assert_eq!(env_log_filter(Some("wran")).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(env_log_filter(Some("info,sonicrab_mq=loud")).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_server_runs_on_configured_worker_threads() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "[runtime]\nworker_threads = 2\n");
+        let runtime = build_runtime(config.runtime.as_ref()).unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 2);
+        runtime.block_on(async {
+            let address = spawn_server(config).await;
+            tokio::task::spawn_blocking(move || {
+                let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+                client.send_push_message("events", b"hello").unwrap();
+                assert_eq!(client.fetch_messages("events", EARLIEST).unwrap(), Some((0, b"hello".to_vec())));
+            })
+            .await
+            .unwrap();
+        });
+
+        // 0 个线程作为配置错误返回，tokio 不会 panic
+        let config = test_config(dir.path(), "[runtime]\nworker_threads = 0\n");
+        assert!(build_runtime(config.runtime.as_ref()).is_err_and(|e| e.kind() == io::ErrorKind::InvalidInput));
+    }
+
+    #[tokio::test]
+    async fn test_empty_push_gets_bad_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        async fn push(stream: &mut TcpStream, broker: &[u8], payload: &[u8]) -> Vec<u8> {
+            let mut frame = Vec::new();
+            for field in [&b"test_key"[..], b"PUSH", broker] {
+                frame.extend_from_slice(&(field.len() as u16).to_be_bytes());
+                frame.extend_from_slice(field);
+            }
+            frame.extend_from_slice(payload);
+            stream.write_all(&(frame.len() as u32).to_be_bytes()).await.unwrap();
+            stream.write_all(&frame).await.unwrap();
+            let len = stream.read_u32().await.unwrap();
+            let mut response = vec![0u8; len as usize];
+            stream.read_exact(&mut response).await.unwrap();
+            response
+        }
+
+        assert_eq!(push(&mut stream, b"", b"hello").await, b"\x03BAD_REQUEST: empty broker");
+        assert_eq!(push(&mut stream, b"orders", b"").await, b"\x03BAD_REQUEST: empty payload");
+        // 被拒绝的写入不会创建 broker
+        assert!(!dir.path().join("orders").exists());
+        assert!(push(&mut stream, b"orders", b"hello").await.starts_with(b"\0OK"));
+    }
+
+    #[tokio::test]
+    async fn test_push_batch_throughput() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = spawn_server(test_config(dir.path(), "")).await;
+        tokio::task::spawn_blocking(move || {
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let payloads: Vec<Vec<u8>> = (0..50u32).map(|i| format!("event-{}", i).into_bytes()).collect();
+            let payloads: Vec<&[u8]> = payloads.iter().map(|p| p.as_slice()).collect();
+
+            let started = std::time::Instant::now();
+            for payload in &payloads {
+                client.send_push_message("single", payload).unwrap();
+            }
+            let single = started.elapsed();
+            let started = std::time::Instant::now();
+            assert_eq!(client.send_push_batch("batched", &payloads).unwrap(), payloads.len());
+            let batched = started.elapsed();
+            assert!(batched < single, "batch {:?} vs single {:?}", batched, single);
+
+            // 批量写入的消息与逐条写入的偏移和内容相同
+            let fetched = client.fetch_all("batched", 0, payloads.len(), usize::MAX).unwrap().0;
+            assert_eq!(fetched, client.fetch_all("single", 0, payloads.len(), usize::MAX).unwrap().0);
+            assert_eq!(fetched.len(), payloads.len());
+            assert_eq!(fetched[49], (49, b"event-49".to_vec()));
+            assert_eq!(client.send_push_batch("batched", &[]).unwrap(), 0);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_pushes_share_a_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(dir.path(), "[brokers.metrics]\ncoalesce = true\ncoalesce_window_ms = 200\ncoalesce_max_bytes = \"1k\"\n");
+        let address = spawn_server(config).await;
+        tokio::task::spawn_blocking(move || {
+            // 同一窗口内并发写入的消息合并为一条记录，共用一个偏移
+            let producers: Vec<_> = (0..8u8)
+                .map(|i| {
+                    std::thread::spawn(move || {
+                        let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+                        client.send_push_message("metrics", &[i; 10]).unwrap().offset
+                    })
+                })
+                .collect();
+            let offsets: Vec<u64> = producers.into_iter().map(|p| p.join().unwrap()).collect();
+            let client = sonicrab_client::Client::new("127.0.0.1", address.port(), "test_key");
+            let mut distinct = offsets.clone();
+            distinct.sort();
+            distinct.dedup();
+            assert!(distinct.len() < offsets.len(), "{:?}", offsets);
+
+            // 消费者拆分合并记录，得到全部消息
+            let mut messages = Vec::new();
+            for offset in distinct {
+                let (_, record) = client.fetch_messages("metrics", offset).unwrap().unwrap();
+                for message in sonicrab_client::coalesce::split_coalesced(&record).unwrap() {
+                    messages.push(message.to_vec());
+                }
+            }
+            messages.sort();
+            assert_eq!(messages, (0..8u8).map(|i| vec![i; 10]).collect::<Vec<_>>());
+
+            // 达到大小上限的消息立即写入，不等待窗口结束；PUSH_BATCH 整批合并为一条记录
+            let started = std::time::Instant::now();
+            let offset = client.send_push_message("metrics", &[9u8; 2000]).unwrap().offset;
+            assert!(started.elapsed() < std::time::Duration::from_millis(200));
+            assert_eq!(client.send_push_batch("metrics", &[b"a", b"b", b"c"]).unwrap(), 3);
+            let (batch_offset, record) = client.fetch_messages("metrics", offset + 1).unwrap().unwrap();
+            assert_eq!(batch_offset, offset + 1);
+            assert_eq!(sonicrab_client::coalesce::split_coalesced(&record).unwrap(), vec![b"a", b"b", b"c"]);
+        })
+        .await
+        .unwrap();
     }
-    
 }
@@ -0,0 +1,90 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const META_FILE: &str = "meta.json";
+pub const MAX_META_KEY_LEN: usize = 128;
+pub const MAX_META_VALUE_LEN: usize = 4096;
+pub const MAX_META_ENTRIES: usize = 256;
+// 保留的元数据键：PIN_SEGMENT 固定的文件 base_offset，逗号分隔，清理任务不会删除这些文件
+pub const PINNED_SEGMENTS_KEY: &str = "pinned_segments";
+// 保留的元数据键：工作队列模式下该偏移之前的记录都已通过 ACK_LEASE 确认
+pub const LEASE_ACKED_KEY: &str = "lease_acked";
+// 保留的元数据键：自动创建该 broker 的租户名称（[[keys]] 中的 name）
+pub const CREATED_BY_KEY: &str = "created_by";
+
+// 由服务端维护、不能通过 SET_META 修改的键
+pub fn is_reserved_key(key: &str) -> bool {
+    key == PINNED_SEGMENTS_KEY || key == LEASE_ACKED_KEY || key == CREATED_BY_KEY
+}
+
+// broker 的自定义元数据（负责人、说明、环境标签等），保存在 broker 目录下的 meta.json
+pub struct BrokerMeta {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl BrokerMeta {
+    pub fn open(broker_dir: &Path) -> io::Result<Self> {
+        let path = broker_dir.join(META_FILE);
+        let values = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(BrokerMeta { path, values })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+
+    // 校验长度后写入，先写临时文件再替换，保证文件完整
+    pub fn set(&mut self, key: &str, value: &str) -> io::Result<()> {
+        if key.is_empty() || key.len() > MAX_META_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("metadata key must be 1 to {} bytes", MAX_META_KEY_LEN),
+            ));
+        }
+        if value.len() > MAX_META_VALUE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("metadata value must be at most {} bytes", MAX_META_VALUE_LEN),
+            ));
+        }
+        if !self.values.contains_key(key) && self.values.len() >= MAX_META_ENTRIES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("at most {} metadata keys per broker", MAX_META_ENTRIES),
+            ));
+        }
+        let mut values = self.values.clone();
+        values.insert(key.to_string(), value.to_string());
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&values)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.values = values;
+        Ok(())
+    }
+
+    pub fn pinned_segments(&self) -> BTreeSet<u64> {
+        self.get(PINNED_SEGMENTS_KEY)
+            .map(|value| value.split(',').filter_map(|offset| offset.trim().parse().ok()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn set_pinned_segments(&mut self, pins: &BTreeSet<u64>) -> io::Result<()> {
+        let value = pins.iter().map(|offset| offset.to_string()).collect::<Vec<_>>().join(",");
+        self.set(PINNED_SEGMENTS_KEY, &value)
+    }
+
+    pub fn lease_acked(&self) -> u64 {
+        self.get(LEASE_ACKED_KEY).and_then(|value| value.parse().ok()).unwrap_or(0)
+    }
+
+    pub fn set_lease_acked(&mut self, offset: u64) -> io::Result<()> {
+        self.set(LEASE_ACKED_KEY, &offset.to_string())
+    }
+}
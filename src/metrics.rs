@@ -0,0 +1,97 @@
+use std::sync::atomic::AtomicU64;
+#[cfg(feature = "metrics-http")]
+use std::sync::atomic::Ordering;
+
+#[cfg(feature = "metrics-http")]
+use std::io;
+#[cfg(feature = "metrics-http")]
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(feature = "metrics-http")]
+use tokio::net::{TcpListener, TcpStream};
+
+#[cfg(feature = "metrics-http")]
+use tracing::debug;
+
+// 进程级的计数器，通过 STATS 命令输出
+pub static SLOW_PULLS: AtomicU64 = AtomicU64::new(0); // 耗时超过 slow_pull_ms 的 PULL 次数
+// 以下计数器由 handle_client 更新，通过 [metrics] 配置的 HTTP 端口以 Prometheus 文本格式输出
+pub static PUSHES: AtomicU64 = AtomicU64::new(0); // 写入成功的消息数，PUSH_BATCH 按写入的条数计
+pub static PULLS: AtomicU64 = AtomicU64::new(0); // PULL 请求数
+pub static BYTES_SENT: AtomicU64 = AtomicU64::new(0); // PULL 通过 sendfile 发送的字节数
+pub static BAD_REQUESTS: AtomicU64 = AtomicU64::new(0); // 回复 BAD_REQUEST 的请求数
+pub static AUTH_FAILURES: AtomicU64 = AtomicU64::new(0); // 密钥无效或没有 broker 权限的请求数
+
+// Prometheus 文本格式（0.0.4）的指标，brokers 为当前加载的 broker 数
+#[cfg(feature = "metrics-http")]
+pub fn render(brokers: usize) -> String {
+    let counters: [(&str, &str, &AtomicU64); 6] = [
+        ("sonicrab_pushed_messages_total", "Messages appended by push commands", &PUSHES),
+        ("sonicrab_pulls_total", "PULL requests served", &PULLS),
+        ("sonicrab_pull_bytes_sent_total", "Bytes sent to PULL requests", &BYTES_SENT),
+        ("sonicrab_bad_requests_total", "Requests answered with BAD_REQUEST", &BAD_REQUESTS),
+        ("sonicrab_auth_failures_total", "Requests rejected for an invalid key or missing broker access", &AUTH_FAILURES),
+        ("sonicrab_slow_pulls_total", "PULL requests slower than slow_pull_ms", &SLOW_PULLS),
+    ];
+    let mut text = String::new();
+    for (name, help, counter) in counters {
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} counter\n{} {}\n",
+            name,
+            help,
+            name,
+            name,
+            counter.load(Ordering::Relaxed)
+        ));
+    }
+    text.push_str(&format!(
+        "# HELP sonicrab_brokers Brokers currently loaded\n# TYPE sonicrab_brokers gauge\nsonicrab_brokers {}\n",
+        brokers
+    ));
+    text
+}
+
+// 指标 HTTP 服务：只响应 GET /metrics，每个连接处理一个请求后关闭
+#[cfg(feature = "metrics-http")]
+pub async fn serve_metrics<F>(listener: TcpListener, brokers: F) -> io::Result<()>
+where
+    F: Fn() -> usize + Send + Sync + Clone + 'static,
+{
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let brokers = brokers.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer_scrape(stream, brokers()).await {
+                debug!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(feature = "metrics-http")]
+async fn answer_scrape(mut stream: TcpStream, brokers: usize) -> io::Result<()> {
+    // 只需要请求行，读到请求头结束或 8k 为止
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = render(brokers);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
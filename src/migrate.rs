@@ -0,0 +1,57 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// 复制 src 目录中已封存（base_offset 小于 active_offset）的数据和索引文件，返回已复制的文件名
+pub fn copy_sealed_segments(src: &Path, dst: &Path, active_offset: u64) -> io::Result<HashSet<String>> {
+    let mut copied = HashSet::new();
+    for path in list_files(src)? {
+        let sealed = matches!(
+            path.extension().and_then(|s| s.to_str()),
+            Some("data") | Some("index")
+        ) && path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+            .is_some_and(|offset| offset < active_offset);
+        if sealed && copy_file(&path, dst)? {
+            copied.insert(file_name(&path));
+        }
+    }
+    Ok(copied)
+}
+
+// 复制 src 目录中尚未复制的其余文件（当前文件、去重日志等）
+pub fn copy_remaining(src: &Path, dst: &Path, copied: &HashSet<String>) -> io::Result<()> {
+    for path in list_files(src)? {
+        if !copied.contains(&file_name(&path)) {
+            copy_file(&path, dst)?;
+        }
+    }
+    Ok(())
+}
+
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+}
+
+// 复制过程中被清理任务删除的文件直接跳过，返回是否复制
+fn copy_file(path: &Path, dst: &Path) -> io::Result<bool> {
+    match fs::copy(path, dst.join(path.file_name().unwrap_or_default())) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,159 @@
+//! Several requests in flight on one connection.
+//!
+//! After a `MUX` request the server tags every frame with a request id: requests are sent as
+//! `[len: u32][request_id: u32][request]` and handled concurrently, and all replies to one
+//! request come back as `[len: u32][request_id: u32][replies]` in the order the requests
+//! finish. A background thread reads the replies and hands each to the thread waiting for it.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Cursor, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::transport::{connect_any, unbracket};
+use crate::{
+    parse_push_response, read_frame, read_pull_reply, read_reply, Client, ClientError, FetchedMessage, PushAck,
+    ServerError, TimeoutError, MUX_COMMAND, PULL_COMMAND, PUSH_COMMAND,
+};
+
+// 等待回复的请求；连接断开后为 None，之后的请求直接失败
+type Pending = Arc<Mutex<Option<HashMap<u32, Sender<Vec<u8>>>>>>;
+
+/// A connection shared by several threads, each with its own request in flight, created by
+/// [`Client::multiplexed`]. The server handles the requests concurrently and each reply is
+/// matched to its request by id, so a slow pull does not hold up a push sent after it.
+/// Requests are not retried; after a broken connection every call fails and a new
+/// connection has to be opened.
+pub struct Multiplexed<'a> {
+    client: &'a Client,
+    writer: Mutex<TcpStream>,
+    pending: Pending,
+    next_id: AtomicU32,
+}
+
+impl<'a> Multiplexed<'a> {
+    pub(crate) fn connect(client: &'a Client) -> Result<Self, Box<dyn Error>> {
+        #[cfg(feature = "tls")]
+        if client.tls.is_some() {
+            return Err("multiplexed connections do not support TLS".into());
+        }
+        let mut stream = connect_any((unbracket(&client.server_ip), client.server_port))?;
+        stream.set_read_timeout(client.read_timeout)?;
+        stream.set_write_timeout(client.write_timeout)?;
+        let message = client.build_message(MUX_COMMAND, &[], &[], None)?;
+        stream.write_all(&(message.len() as u32).to_be_bytes())?;
+        stream.write_all(&message)?;
+        let response = read_reply(&mut stream)??;
+        if response != b"OK" {
+            return Err(ServerError::unexpected(&response).into());
+        }
+        // 读取线程一直等待回复，每个请求的读超时在等待回复时单独计算
+        stream.set_read_timeout(None)?;
+
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let mut reader = stream.try_clone()?;
+        let dispatch = pending.clone();
+        std::thread::spawn(move || {
+            while let Ok(mut frame) = read_frame(&mut reader) {
+                if frame.len() < 4 {
+                    break;
+                }
+                let request_id = u32::from_be_bytes(frame[..4].try_into().unwrap());
+                frame.drain(..4);
+                // 已经超时放弃的请求没有等待者，回复直接丢弃
+                let waiter = dispatch.lock().unwrap().as_mut().and_then(|waiters| waiters.remove(&request_id));
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(frame);
+                }
+            }
+            // 连接断开或被关闭：丢弃所有等待者，等待的线程收到连接错误
+            dispatch.lock().unwrap().take();
+        });
+
+        Ok(Self {
+            client,
+            writer: Mutex::new(stream),
+            pending,
+            next_id: AtomicU32::new(0),
+        })
+    }
+
+    /// Sends a message like [`Client::send_push_message`], without compression
+    pub fn send_push_message(&self, broker_name: &str, payload: &[u8]) -> Result<PushAck, Box<dyn Error>> {
+        if payload.is_empty() {
+            return Err(ClientError::EmptyPayload.into());
+        }
+        let message = self.client.build_message(PUSH_COMMAND, broker_name.as_bytes(), payload, None)?;
+        let response = read_reply(&mut Cursor::new(self.request(&message)?))??;
+        let (ack, pressure) = parse_push_response(&response)?;
+        self.client.last_pressure.store(pressure, Ordering::Relaxed);
+        Ok(ack)
+    }
+
+    /// Fetches the record at `offset` like [`Client::fetch_messages`]
+    pub fn fetch_messages(&self, broker_name: &str, offset: u64) -> Result<Option<FetchedMessage>, Box<dyn Error>> {
+        Ok(self.fetch_batch(broker_name, offset, None)?.into_iter().next())
+    }
+
+    /// Fetches the records one PULL from `offset` returns, like [`Client::fetch_batch`]
+    pub fn fetch_batch(&self, broker_name: &str, offset: u64, max_count: Option<u32>) -> Result<Vec<FetchedMessage>, Box<dyn Error>> {
+        let count_bytes = max_count.map(u32::to_be_bytes);
+        let data = count_bytes.as_ref().map_or(&[][..], |bytes| &bytes[..]);
+        let message = self.client.build_message(PULL_COMMAND, broker_name.as_bytes(), data, Some(offset))?;
+        let (records, _) = read_pull_reply(&mut Cursor::new(self.request(&message)?))??;
+        self.client.verify_records(records)
+    }
+
+    // 发送一个请求并等待它的回复，返回的字节与普通连接上该请求收到的回复相同
+    fn request(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let request_id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (waiter, reply) = mpsc::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(waiters) => waiters.insert(request_id, waiter),
+            None => return Err(connection_closed().into()),
+        };
+
+        let mut frame = Vec::with_capacity(message.len() + 8);
+        frame.extend_from_slice(&(message.len() as u32 + 4).to_be_bytes());
+        frame.extend_from_slice(&request_id.to_be_bytes());
+        frame.extend_from_slice(message);
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writer.write_all(&frame) {
+            // 写了一半的帧无法恢复，关闭连接
+            let _ = writer.shutdown(Shutdown::Both);
+            return Err(e.into());
+        }
+        drop(writer);
+
+        let result = match self.client.read_timeout {
+            Some(timeout) => reply.recv_timeout(timeout),
+            None => reply.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match result {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(waiters) = self.pending.lock().unwrap().as_mut() {
+                    waiters.remove(&request_id);
+                }
+                Err(Box::new(TimeoutError(io::ErrorKind::TimedOut.into())))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(connection_closed().into()),
+        }
+    }
+}
+
+impl Drop for Multiplexed<'_> {
+    // 关闭套接字后读取线程退出
+    fn drop(&mut self) {
+        if let Ok(writer) = self.writer.get_mut() {
+            let _ = writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, "multiplexed connection closed")
+}
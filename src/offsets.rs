@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const OFFSETS_FILE: &str = "offsets.json";
+pub const MAX_CONSUMER_ID_LEN: usize = 128;
+
+// 消费者提交的偏移（下一条待处理的消息），保存在 broker 目录下的 offsets.json，消费者重启后从这里继续
+pub struct OffsetStore {
+    path: PathBuf,
+    offsets: BTreeMap<String, u64>,
+}
+
+impl OffsetStore {
+    pub fn open(broker_dir: &Path) -> io::Result<Self> {
+        let path = broker_dir.join(OFFSETS_FILE);
+        let offsets = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(OffsetStore { path, offsets })
+    }
+
+    pub fn get(&self, consumer_id: &str) -> Option<u64> {
+        self.offsets.get(consumer_id).copied()
+    }
+
+    // 先写临时文件再替换，崩溃时保留上一次提交的偏移
+    pub fn commit(&mut self, consumer_id: &str, offset: u64) -> io::Result<()> {
+        if consumer_id.is_empty() || consumer_id.len() > MAX_CONSUMER_ID_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("consumer id must be 1 to {} bytes", MAX_CONSUMER_ID_LEN),
+            ));
+        }
+        let mut offsets = self.offsets.clone();
+        offsets.insert(consumer_id.to_string(), offset);
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&offsets)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.offsets = offsets;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_committed_offsets_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = OffsetStore::open(dir.path()).unwrap();
+        store.commit("billing", 42).unwrap();
+        store.commit("billing", 43).unwrap();
+        assert!(store.commit("", 1).is_err());
+
+        let store = OffsetStore::open(dir.path()).unwrap();
+        assert_eq!(store.get("billing"), Some(43));
+        assert_eq!(store.get("search"), None);
+    }
+}
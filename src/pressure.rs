@@ -0,0 +1,52 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use dashmap::DashMap;
+
+// 每个 broker 排队等待写锁的 PUSH 数量，用于计算回复给生产者的压力等级
+// 计数放在 broker 的读写锁之外，排队中的 PUSH 不需要先拿到锁
+pub struct IngestQueues {
+    queues: DashMap<PathBuf, Arc<AtomicUsize>>,
+}
+
+// PUSH 完成写入后离开队列
+pub struct QueueGuard {
+    depth: Arc<AtomicUsize>,
+    ahead: usize, // 进入队列时排在前面的 PUSH 数量
+}
+
+impl IngestQueues {
+    pub fn global() -> &'static IngestQueues {
+        static GLOBAL: OnceLock<IngestQueues> = OnceLock::new();
+        GLOBAL.get_or_init(|| IngestQueues { queues: DashMap::new() })
+    }
+
+    pub fn enter(&self, broker_dir: &Path) -> QueueGuard {
+        let depth = self.queue(broker_dir);
+        let ahead = depth.fetch_add(1, Ordering::SeqCst);
+        QueueGuard { depth, ahead }
+    }
+
+    #[cfg(test)]
+    pub fn depth(&self, broker_dir: &Path) -> usize {
+        self.queue(broker_dir).load(Ordering::SeqCst)
+    }
+
+    fn queue(&self, broker_dir: &Path) -> Arc<AtomicUsize> {
+        self.queues.entry(broker_dir.to_path_buf()).or_default().clone()
+    }
+}
+
+impl QueueGuard {
+    // 按进入队列时的深度相对 limit 折算为 0-255 的压力等级，达到 limit 时为 255
+    pub fn level(&self, limit: usize) -> u8 {
+        (self.ahead.min(limit) * 255 / limit.max(1)) as u8
+    }
+}
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        self.depth.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+
+// 请求帧（不含长度前缀）：[key_len: u16][key][cmd_len: u16][cmd][broker_len: u16][broker][命令相关的数据]
+pub struct Frame {
+    pub key: String,
+    pub command: String,
+    pub broker: String,
+    pub body: Vec<u8>, // broker 之后的数据，由各命令自行解析
+}
+
+// 请求格式错误，回复 "BAD_REQUEST: <原因>"，连接继续可用
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    Truncated(&'static str), // 字段不完整
+    InvalidUtf8(&'static str), // 字段不是合法的 UTF-8
+    UnknownCommand(String),
+    EmptyBroker, // 写入命令没有 broker 名称
+    EmptyPayload, // PUSH 的消息体为空
+    NotMultiplexable(String), // 不能在多路复用连接上使用的命令
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated(field) => write!(f, "truncated {}", field),
+            ProtocolError::InvalidUtf8(field) => write!(f, "{} is not valid UTF-8", field),
+            ProtocolError::UnknownCommand(command) => write!(f, "unknown command {}", command),
+            ProtocolError::EmptyBroker => write!(f, "empty broker"),
+            ProtocolError::EmptyPayload => write!(f, "empty payload"),
+            ProtocolError::NotMultiplexable(command) => write!(f, "{} is not allowed on a multiplexed connection", command),
+        }
+    }
+}
+
+pub fn parse_frame(buf: &[u8]) -> Result<Frame, ProtocolError> {
+    let mut reader = Reader(buf);
+    let key = reader.string("key")?;
+    let command = reader.string("command")?;
+    let broker = reader.string("broker name")?;
+    Ok(Frame {
+        key,
+        command,
+        broker,
+        body: reader.0.to_vec(),
+    })
+}
+
+impl Frame {
+    // 数据开头的 u64：PULL、SUBSCRIBE 等命令的偏移，KICK 的连接编号
+    pub fn offset(&self) -> Result<u64, ProtocolError> {
+        Reader(&self.body).u64("offset")
+    }
+
+    // PULL 的数据：[offset: u64][max_count: u32]，省略 max_count 时按 pull_max_limit 返回尽量多的记录
+    pub fn max_count(&self) -> Result<Option<u32>, ProtocolError> {
+        let mut reader = Reader(&self.body);
+        reader.u64("offset")?;
+        if reader.0.is_empty() {
+            return Ok(None);
+        }
+        reader.u32("max count").map(Some)
+    }
+
+    // PULL 的数据可以在 max_count 之后带上 [max_bytes: u32]，只对本次请求收紧 pull_max_limit
+    pub fn max_bytes(&self) -> Result<Option<u32>, ProtocolError> {
+        let mut reader = Reader(&self.body);
+        reader.u64("offset")?;
+        if reader.0.is_empty() {
+            return Ok(None);
+        }
+        reader.u32("max count")?;
+        if reader.0.is_empty() {
+            return Ok(None);
+        }
+        reader.u32("max bytes").map(Some)
+    }
+
+    // PUSH_BATCH 的数据：[count: u32]([len: u32][消息体])*
+    pub fn batch(&self) -> Result<Vec<&[u8]>, ProtocolError> {
+        let mut reader = Reader(&self.body);
+        let count = reader.u32("batch count")?;
+        // 数量来自客户端，预分配时不超过数据本身能容纳的消息数
+        let mut payloads = Vec::with_capacity((count as usize).min(self.body.len() / 4));
+        for _ in 0..count {
+            let len = reader.u32("batch record")? as usize;
+            payloads.push(reader.take(len, "batch record")?);
+        }
+        Ok(payloads)
+    }
+
+    // PUSH_ID 的数据：[id_len: u16][消息ID][消息体]
+    pub fn message_id(&self) -> Result<(String, &[u8]), ProtocolError> {
+        let mut reader = Reader(&self.body);
+        let id = reader.string("message id")?;
+        Ok((id, reader.0))
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, field: &'static str) -> Result<&'a [u8], ProtocolError> {
+        if self.0.len() < len {
+            return Err(ProtocolError::Truncated(field));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self, field: &'static str) -> Result<u32, ProtocolError> {
+        Ok(u32::from_be_bytes(self.take(4, field)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self, field: &'static str) -> Result<u64, ProtocolError> {
+        Ok(u64::from_be_bytes(self.take(8, field)?.try_into().unwrap()))
+    }
+
+    // [len: u16][UTF-8 字符串]
+    fn string(&mut self, field: &'static str) -> Result<String, ProtocolError> {
+        let len = u16::from_be_bytes(self.take(2, field)?.try_into().unwrap()) as usize;
+        let bytes = self.take(len, field)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidUtf8(field))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(fields: &[&[u8]], body: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for field in fields {
+            buf.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            buf.extend_from_slice(field);
+        }
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        let buf = frame(&[b"key", b"PULL", b"orders"], &7u64.to_be_bytes());
+        let parsed = parse_frame(&buf).unwrap();
+        assert_eq!(parsed.key, "key");
+        assert_eq!(parsed.command, "PULL");
+        assert_eq!(parsed.broker, "orders");
+        assert_eq!(parsed.offset(), Ok(7));
+        assert_eq!(parsed.max_count(), Ok(None));
+
+        let buf = frame(&[b"key", b"PULL", b"orders"], &[7u64.to_be_bytes().as_slice(), &3u32.to_be_bytes()].concat());
+        assert_eq!(parse_frame(&buf).unwrap().max_count(), Ok(Some(3)));
+        assert_eq!(parse_frame(&buf).unwrap().max_bytes(), Ok(None));
+
+        let body = [7u64.to_be_bytes().as_slice(), &3u32.to_be_bytes(), &512u32.to_be_bytes()].concat();
+        let buf = frame(&[b"key", b"PULL", b"orders"], &body);
+        assert_eq!(parse_frame(&buf).unwrap().max_bytes(), Ok(Some(512)));
+
+        let buf = frame(&[b"key", b"PUSH_ID", b"orders", b"id-1"], b"payload");
+        let parsed = parse_frame(&buf).unwrap();
+        assert_eq!(parsed.message_id(), Ok(("id-1".to_string(), &b"payload"[..])));
+
+        let buf = frame(&[b"key", b"PUSH_BATCH", b"orders"], &[0, 0, 0, 2, 0, 0, 0, 1, b'a', 0, 0, 0, 0]);
+        let parsed = parse_frame(&buf).unwrap();
+        assert_eq!(parsed.batch(), Ok(vec![&b"a"[..], &b""[..]]));
+    }
+
+    #[test]
+    fn test_parse_malformed_frames() {
+        let valid = frame(&[b"key", b"PULL", b"orders"], &[]);
+        // 在任意位置截断都返回错误而不是 panic
+        for len in 0..valid.len() {
+            assert!(matches!(parse_frame(&valid[..len]), Err(ProtocolError::Truncated(_))), "{}", len);
+        }
+        assert_eq!(parse_frame(&[]).err(), Some(ProtocolError::Truncated("key")));
+        assert_eq!(parse_frame(&[0, 9, b'k']).err(), Some(ProtocolError::Truncated("key")));
+        assert_eq!(
+            parse_frame(&frame(&[b"key", b"PU\xffLL", b"orders"], &[])).err(),
+            Some(ProtocolError::InvalidUtf8("command"))
+        );
+        assert_eq!(
+            parse_frame(&frame(&[b"key", b"PULL"], &[])).err(),
+            Some(ProtocolError::Truncated("broker name"))
+        );
+        // 偏移不足 8 字节
+        let parsed = parse_frame(&frame(&[b"key", b"PULL", b"orders"], &[0, 0, 1])).unwrap();
+        assert_eq!(parsed.offset(), Err(ProtocolError::Truncated("offset")));
+        let parsed = parse_frame(&frame(&[b"key", b"PUSH_ID", b"orders"], &[0, 4, b'i'])).unwrap();
+        assert_eq!(parsed.message_id().err(), Some(ProtocolError::Truncated("message id")));
+        // 批量消息的数量多于实际数据
+        let parsed = parse_frame(&frame(&[b"key", b"PUSH_BATCH", b"orders"], &[0, 0, 0, 2, 0, 0, 0, 1, b'a'])).unwrap();
+        assert_eq!(parsed.batch().err(), Some(ProtocolError::Truncated("batch record")));
+        assert_eq!(
+            ProtocolError::UnknownCommand("NOPE".to_string()).to_string(),
+            "unknown command NOPE"
+        );
+    }
+}
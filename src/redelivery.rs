@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+// 死信 broker 名称的后缀
+pub const DLQ_SUFFIX: &str = ".dlq";
+
+// CONSUMER_PULL 对一条记录的处理
+#[derive(Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Deliver, // 正常从该偏移返回
+    DeadLetter, // 超过 max_redeliveries，写入死信 broker 后从下一条开始返回
+    Skip, // 已经写入死信 broker，直接从下一条开始返回
+}
+
+// 按消费者记录最近一次 CONSUMER_PULL 的起始偏移和从该偏移拉取的次数。
+// 只保存在内存中，服务重启后重新计数
+pub struct RedeliveryTracker {
+    max_redeliveries: u32,
+    pulls: HashMap<String, Pulls>, // consumer_id -> 当前起始偏移的拉取情况
+}
+
+struct Pulls {
+    offset: u64,
+    count: u32,
+    dead_lettered: bool, // 该记录已写入死信 broker
+}
+
+impl RedeliveryTracker {
+    pub fn new(max_redeliveries: u32) -> Self {
+        RedeliveryTracker { max_redeliveries, pulls: HashMap::new() }
+    }
+
+    // 记录一次从 offset 开始的拉取；写入死信 broker 失败时下一次拉取仍返回 DeadLetter
+    pub fn pull(&mut self, consumer_id: &str, offset: u64) -> Delivery {
+        let pulls = self
+            .pulls
+            .entry(consumer_id.to_string())
+            .or_insert(Pulls { offset, count: 0, dead_lettered: false });
+        if pulls.offset != offset {
+            *pulls = Pulls { offset, count: 0, dead_lettered: false };
+        }
+        if pulls.dead_lettered {
+            return Delivery::Skip;
+        }
+        pulls.count = pulls.count.saturating_add(1);
+        // 第一次之后的每次拉取都是一次重新投递
+        if pulls.count - 1 <= self.max_redeliveries {
+            Delivery::Deliver
+        } else {
+            Delivery::DeadLetter
+        }
+    }
+
+    // pull 返回 DeadLetter 的记录已写入死信 broker
+    pub fn dead_lettered(&mut self, consumer_id: &str) {
+        if let Some(pulls) = self.pulls.get_mut(consumer_id) {
+            pulls.dead_lettered = true;
+        }
+    }
+
+    // 消费者提交的偏移越过了正在计数的记录，之后的拉取重新计数
+    pub fn committed(&mut self, consumer_id: &str, offset: u64) {
+        if self.pulls.get(consumer_id).is_some_and(|pulls| pulls.offset < offset) {
+            self.pulls.remove(consumer_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_is_dead_lettered_once_after_max_redeliveries() {
+        let mut tracker = RedeliveryTracker::new(2);
+        // 首次投递加两次重新投递
+        for _ in 0..3 {
+            assert_eq!(tracker.pull("billing", 5), Delivery::Deliver);
+        }
+        assert_eq!(tracker.pull("billing", 5), Delivery::DeadLetter);
+        // 写入死信 broker 失败时再次尝试
+        assert_eq!(tracker.pull("billing", 5), Delivery::DeadLetter);
+        tracker.dead_lettered("billing");
+        assert_eq!(tracker.pull("billing", 5), Delivery::Skip);
+        // 其他消费者单独计数
+        assert_eq!(tracker.pull("search", 5), Delivery::Deliver);
+
+        // 提交越过该记录或从其他偏移拉取后重新计数
+        tracker.committed("search", 5);
+        tracker.pull("search", 5);
+        tracker.pull("search", 5);
+        tracker.committed("search", 6);
+        for _ in 0..3 {
+            assert_eq!(tracker.pull("search", 5), Delivery::Deliver);
+        }
+        assert_eq!(tracker.pull("billing", 6), Delivery::Deliver);
+    }
+}
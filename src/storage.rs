@@ -1,147 +1,276 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, Seek, SeekFrom, Write};
 
 use byteorder::{BigEndian, ReadBytesExt};
-use memmap2::MmapMut;
-use nix::errno::Errno;
-use nix::sys::sendfile::sendfile;
 
-use std::os::unix::io::AsFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::sync::RwLock;
-use std::os::unix::prelude::BorrowedFd;
-use crate::config::{Storage,parse_size};
+use crate::config::{BrokerOverride,FsyncPolicy,Storage,parse_size};
+use tracing::{debug, info, warn};
+use crate::governor::{IndexGovernor, IndexSlot};
+use crate::index::{open_index, IndexAccess, INDEX_ENTRY_SIZE};
+use crate::zerocopy::read_exact_at;
+use sonicrab_client::{EARLIEST, LATEST};
 
 
-const INDEX_ENTRY_SIZE: usize = 12;
+const RECORD_HEADER_SIZE: usize = 12; // 记录头：[len: u32][offset: u64]
+const MAX_RECORD_ALIGN: u64 = 4096;
 const INITIAL_INDEX_SIZE: usize = 1024 * INDEX_ENTRY_SIZE; // Initial index file size
 const INDEX_EXPANSION_SIZE: usize = 512 * INDEX_ENTRY_SIZE; // Index expansion size
+const COMPACTING_DIR: &str = ".compacting"; // 正在写入的压缩结果
+const COMPACTED_DIR: &str = ".compacted"; // 已写完、等待替换原有文件的压缩结果
+const CORRUPT_DIR: &str = "corrupt"; // 启动时发现的不成对或为空的文件移到这里，由运维人员检查
+const POSITION_FILE: &str = "position.meta"; // 当前文件的 [base_offset: u64][position_offset: u64]
 
 
 type Offset = AtomicU64;
+// 存储之外也会读取的位置，通过 StorePosition 共享
+type SharedOffset = Arc<AtomicU64>;
+
+// 当前文件的位置，持有它的一方不需要获取存储所在的锁就能读取
+#[derive(Clone)]
+pub struct StorePosition {
+    base_offset: SharedOffset,
+    position_offset: SharedOffset,
+    data_len: SharedOffset,
+}
+
+impl StorePosition {
+    // [当前文件的 base_offset, 下一个待分配的偏移, 当前数据文件的字节数]
+    pub fn snapshot(&self) -> [u64; 3] {
+        [
+            self.base_offset.load(Ordering::SeqCst),
+            self.position_offset.load(Ordering::SeqCst),
+            self.data_len.load(Ordering::SeqCst),
+        ]
+    }
+}
+
+// 存储层的错误，调用方可以按失败原因分别处理；需要 io::Error 的地方通过 From 转换，保留对应的 ErrorKind
+#[derive(Debug)]
+pub enum StorageError {
+    IndexNotFound, // 索引文件未打开，或该偏移没有索引项
+    OffsetOutOfRange { offset: u64, next: u64 }, // 偏移不在已打开的文件中，next 为下一条消息将被分配的偏移
+    DataFileMissing, // 当前数据文件未打开
+    Inconsistent(String), // 启动恢复时发现文件之间的偏移不连续（strict_recovery）
+    Io(io::Error),
+}
+
+impl StorageError {
+    // 转换为 io::Error 时使用的 ErrorKind
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            StorageError::IndexNotFound | StorageError::DataFileMissing => io::ErrorKind::NotFound,
+            StorageError::OffsetOutOfRange { .. } => io::ErrorKind::NotFound,
+            StorageError::Inconsistent(_) => io::ErrorKind::InvalidData,
+            StorageError::Io(e) => e.kind(),
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::IndexNotFound => write!(f, "index not found"),
+            StorageError::OffsetOutOfRange { offset, next } => {
+                write!(f, "offset {} is out of range (next offset {})", offset, next)
+            }
+            StorageError::DataFileMissing => write!(f, "data file not set"),
+            StorageError::Inconsistent(message) => write!(f, "{}", message),
+            StorageError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<StorageError> for io::Error {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::Io(e) => e,
+            other => io::Error::new(other.kind(), other),
+        }
+    }
+}
 #[derive(Clone)]
 struct IndexEntry {
     start:u64,
     size:u32,
 }
 
+// SEGMENTS 命令返回的单个文件统计
+pub struct SegmentStats {
+    pub base_offset: u64,
+    pub data_bytes: u64,
+    pub index_bytes: u64,
+    pub index_entries: u64, // 索引文件中结束标记之前的索引项数量
+    pub active: bool,
+}
+
+// 一次 PULL 发送的连续记录：同一个文件中从 start 开始的 size 个字节，共 count 条记录
+pub struct RecordRange {
+    pub segment: u64, // 所在文件的 base_offset
+    pub first: u64, // 第一条记录的偏移
+    pub start: u64,
+    pub size: usize,
+    pub count: u32,
+}
+
 struct FileEntry {
     base_offset: u64, //历史索引文件的基础偏移
     data_file: File, // 数据文件
-    data: MmapMut, // 索引内存映射
+    index: Arc<IndexSlot>, // 索引内存映射，可能被全局内存限制淘汰，访问时重新映射
 }
 
 pub struct DataStorage {
     data_dir: PathBuf,
-    base_offset: Offset, // 当前索引文件的基础偏移
-    position_offset: Offset, // 当前索引文件的偏移位置
-    index_len: Offset, //索引文件长度
-    data_len: Offset, //数据文件长度
+    base_offset: SharedOffset, // 当前索引文件的基础偏移
+    position_offset: SharedOffset, // 当前索引文件的偏移位置
+    index_len: Offset, //当前索引文件的长度，与磁盘上的文件长度一致，扩展索引文件时更新
+    data_len: SharedOffset, //数据文件长度
+    indexed_len: Offset, //当前数据文件中已经建立索引的长度
+    indexed_offset: Offset, //当前数据文件中下一条要建立索引的记录的偏移
     data_file: Option<RwLock<File>>, //当前数据文件
     index_file: Option<RwLock<File>>, //当前索引文件
-    index_map: Option<RwLock<MmapMut>>, //当前索引文件的内存映射
+    index_map: Option<RwLock<Box<dyn IndexAccess>>>, //当前索引文件的内存映射（或不使用映射时的文件读写）
     files: RwLock<Vec<FileEntry>>, //历史文件项
     max_file_size: usize,
+    max_records: Option<u64>, // 每个数据文件的记录数上限
     pull_max_limit: usize,
     cache_limit: usize,
+    archive: bool, // 归档模式：写入时不建立索引，读取前或切换文件时批量补建
+    align: u64, // 记录数据部分的对齐边界，1 表示不对齐
+    governor: Arc<IndexGovernor>, // 全局索引内存映射统计
+    strict_recovery: bool, // 启动时发现重复或不连续的文件时拒绝启动，而不是修复并继续
+    use_mmap: bool, // 索引是否使用内存映射，映射失败时仍会回退到直接读写文件
+    fsync_always: bool, // fsync = "always"：每次写入返回前把数据和索引同步到磁盘
+    #[cfg(test)]
+    fail_index_expansion: bool,
+    #[cfg(test)]
+    synced_segments: Vec<u64>, // 切换文件时已落盘的文件 base_offset
+    #[cfg(test)]
+    append_syncs: usize, // fsync = "always" 时写入后落盘的次数
+    #[cfg(test)]
+    restored_position: bool, // 启动时使用了 position.meta 中保存的位置，没有扫描索引
 }
 
 impl DataStorage {
-    pub async fn new(data_dir: PathBuf,config:&Storage) -> io::Result<Self> {
+    pub async fn new(data_dir: PathBuf,config:&Storage,broker:&BrokerOverride) -> Result<Self, StorageError> {
+        Self::with_governor(data_dir, config, broker, IndexGovernor::global()).await
+    }
+
+    pub async fn with_governor(
+        data_dir: PathBuf,
+        config: &Storage,
+        broker: &BrokerOverride,
+        governor: Arc<IndexGovernor>,
+    ) -> Result<Self, StorageError> {
+        let align = broker.align.unwrap_or(1) as u64;
+        if !align.is_power_of_two() || align > MAX_RECORD_ALIGN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("record alignment must be a power of two up to {}", MAX_RECORD_ALIGN),
+            )
+            .into());
+        }
         
+        let invalid_size = |name: &str, e: String| io::Error::new(io::ErrorKind::InvalidInput, format!("storage.{}: {}", name, e));
+        let max_file_size = parse_size(&config.max_file_size).map_err(|e| invalid_size("max_file_size", e))?;
+        let pull_max_limit = parse_size(&config.pull_max_limit).map_err(|e| invalid_size("pull_max_limit", e))?;
+        let fsync = config.fsync_policy().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        if config.max_records_per_file == Some(0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "storage.max_records_per_file must be greater than zero").into());
+        }
+
+        // 上次压缩在替换文件的过程中中断时，先完成替换
+        finish_compaction(&data_dir)?;
         let mut storage = Self {
             data_dir,
-            base_offset: AtomicU64::new(0),
-            position_offset: AtomicU64::new(0),
+            base_offset: Arc::new(AtomicU64::new(0)),
+            position_offset: Arc::new(AtomicU64::new(0)),
             index_len: AtomicU64::new(0),
-            data_len: AtomicU64::new(0),
+            data_len: Arc::new(AtomicU64::new(0)),
+            indexed_len: AtomicU64::new(0),
+            indexed_offset: AtomicU64::new(0),
             data_file: None,
             index_file: None,
             index_map: None,
             files: Vec::new().into(),
-            max_file_size: parse_size(config.max_file_size.as_str()).unwrap_or_else(|_|1024 * 1024 * 100 as usize),
-            pull_max_limit: parse_size(config.pull_max_limit.as_str()).unwrap_or_else(|_|1024 * 1024 * 50 as usize),
+            max_file_size,
+            max_records: config.max_records_per_file,
+            pull_max_limit,
             cache_limit: config.cache_limit,
+            archive: broker.archive,
+            align,
+            governor,
+            strict_recovery: config.strict_recovery.unwrap_or(false),
+            use_mmap: !config.file_index.unwrap_or(false),
+            fsync_always: fsync == FsyncPolicy::Always,
+            #[cfg(test)]
+            fail_index_expansion: false,
+            #[cfg(test)]
+            synced_segments: Vec::new(),
+            #[cfg(test)]
+            append_syncs: 0,
+            #[cfg(test)]
+            restored_position: false,
         };
         storage.initialize_files().await?;
         Ok(storage)
     }
 
-    async fn get_index_len(&self) -> io::Result<u64> {
-        if let Some(index_file_lock) = &self.index_file {
-            let index_file = index_file_lock.read().await; // 获取读锁
-            let metadata = index_file.metadata()?; // 获取元数据
-            Ok(metadata.len()) // 返回文件长度
-        } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Appropriate index file not set",
-            ))
-        }
-    }
-
-    async fn set_index_len(&self, new_size: u64) -> io::Result<()> {
+    async fn set_index_len(&self, new_size: u64) -> Result<(), StorageError> {
         if let Some(index_file_lock) = &self.index_file {
             let index_file = index_file_lock.write().await; // 获取读锁
             index_file.set_len(new_size)?;
             Ok(())
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Appropriate index file not set",
-            ))
+            Err(StorageError::IndexNotFound)
         }
     }
 
-    async fn get_data_len(&self) -> io::Result<u64> {
+    async fn get_data_len(&self) -> Result<u64, StorageError> {
         if let Some(data_file_lock) = &self.data_file {
             let data_file = data_file_lock.read().await; // 获取读锁
             let metadata = data_file.metadata()?; // 获取元数据
             Ok(metadata.len()) // 返回文件长度
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Appropriate data file not set",
-            ))
+            Err(StorageError::DataFileMissing)
         }
     }
 
-    async fn read_index(&self, postion: usize) -> io::Result<IndexEntry> {
+    async fn read_index(&self, postion: usize) -> Result<IndexEntry, StorageError> {
         if let Some(index_map_lock) = &self.index_map {
-            let index_map = index_map_lock.read().await;
-            let start = (&index_map[postion..postion + 8usize]).read_u64::<BigEndian>()?;
-            let size = (&index_map[postion+8usize..postion + 12usize]).read_u32::<BigEndian>()?;
+            let (start, size) = index_map_lock.read().await.read_entry(postion)?;
             Ok(IndexEntry{
                 start,
                 size
             })
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Appropriate index map read failed",
-            ))
+            Err(StorageError::IndexNotFound)
         }
     }
 
     // 从目录中恢复 DataStorage 的相关字段 
-    async fn initialize_files(&mut self) -> io::Result<()> {
-        let mut offsets = vec![];
-
-        for entry in std::fs::read_dir(&self.data_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            // 目录中读取文件名作为历史文件的 base_offset
-            if path.extension().and_then(|s| s.to_str()) == Some("data") {
-                if let Some(offset) = path
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .and_then(|s| s.parse::<u64>().ok())
-                {
-                    offsets.push(offset);
-                }
-            }
-        }
+    async fn initialize_files(&mut self) -> Result<(), StorageError> {
+        let mut offsets = collect_segments(&self.data_dir, self.strict_recovery)?;
         // 判断目录是否为空
         if offsets.is_empty() {
             // 数据目录为空，创建新的数据和索引文件
@@ -152,6 +281,8 @@ impl DataStorage {
             if let Some(&last_offset) = offsets.last() {
                 // 设置当前基础偏移为最新文件的基础偏移
                 self.base_offset.store(last_offset, Ordering::SeqCst);
+                // 后一个文件的基础偏移，用于检查相邻文件之间的偏移是否连续
+                let mut next_base = last_offset;
                 for file_name in offsets.iter().rev() {
                     if *file_name == last_offset {
                         // 当前文件后续进行操作
@@ -163,39 +294,111 @@ impl DataStorage {
                             break;
                         }
                         let data_file = self.open_data_file(*file_name,true).await?;
-                        let (_, map) = self.open_index_file(*file_name).await?;
+                        // 历史索引文件丢失或与数据文件不一致时从数据文件重建
+                        if !self.sealed_index_intact(*file_name, &data_file)? {
+                            let records = rebuild_segment_index(&self.data_dir, *file_name, self.align)?;
+                            warn!("Rebuilt missing or corrupt index of segment {} ({} records)", file_name, records);
+                        }
+                        let records = count_index_entries(&self.index_path(*file_name))?;
+                        let expected = next_base - *file_name;
+                        if records != expected {
+                            let problem = if records < expected { "gap" } else { "overlap" };
+                            let message = format!(
+                                "Segment {} holds {} records but the next segment starts at {} ({} in offsets {}..{})",
+                                file_name, records, next_base, problem,
+                                file_name + records.min(expected), file_name + records.max(expected)
+                            );
+                            if self.strict_recovery {
+                                return Err(StorageError::Inconsistent(message));
+                            }
+                            warn!("{}", message);
+                        }
+                        next_base = *file_name;
+                        debug!("Loaded segment {} of {:?}: {} records", file_name, self.data_dir, records);
+                        let index = self.governor.register(self.index_path(*file_name), self.use_mmap)?;
                         
                         files.push(FileEntry {
                             base_offset: *file_name,
-                            data_file: data_file,
-                            data: map,
-                            
+                            data_file,
+                            index,
                         });
                     }
                 }
                 // 创建当前文件
                 self.create_new_files(last_offset).await?;
 
-                let index_len = self.get_index_len().await?;
-                self.index_len.swap(index_len, Ordering::SeqCst);
-                // 从索引文件读取当前偏移位置 position_offset
-                for index in (0..index_len).step_by(INDEX_ENTRY_SIZE) {
-                    let index_entry = self.read_index(index as usize).await?;
-                    
-                    if index_entry.start == 0 && index_entry.size == 0 {
-                        if index > 0 {
-                            self.position_offset.swap(
-                                index / (INDEX_ENTRY_SIZE as u64) + last_offset,
-                                Ordering::SeqCst,
-                            );
-                        } else {
-                            self.position_offset.swap(last_offset, Ordering::SeqCst);
+                // create_new_files 已经按文件长度设置了 index_len
+                let index_len = self.index_len.load(Ordering::SeqCst);
+                // 从索引文件读取当前偏移位置 position_offset：position.meta 中保存的位置与索引一致时
+                // 直接使用，文件缺失或已过时则扫描索引寻找结束标记
+                let mut marker = self.saved_marker(last_offset, index_len).await?;
+                #[cfg(test)]
+                {
+                    self.restored_position = marker.is_some();
+                }
+                if marker.is_none() {
+                    for index in (0..index_len).step_by(INDEX_ENTRY_SIZE) {
+                        let index_entry = self.read_index(index as usize).await?;
+                        if index_entry.start == 0 && index_entry.size == 0 {
+                            marker = Some(index);
+                            break;
                         }
-                        break;
                     }
                 }
+                let marker_found = marker.is_some();
+                if let Some(index) = marker {
+                if index > 0 {
+                    self.position_offset.swap(
+                        index / (INDEX_ENTRY_SIZE as u64) + last_offset,
+                        Ordering::SeqCst,
+                    );
+                    self.indexed_offset.store(index / (INDEX_ENTRY_SIZE as u64) + last_offset, Ordering::SeqCst);
+                    let last_entry = self
+                        .read_index(index as usize - INDEX_ENTRY_SIZE)
+                        .await?;
+                    self.indexed_len.store(
+                        last_entry.start + last_entry.size as u64,
+                        Ordering::SeqCst,
+                    );
+                } else {
+                    self.position_offset.swap(last_offset, Ordering::SeqCst);
+                }
+                }
                 self.data_len
                     .swap(self.get_data_len().await?, Ordering::SeqCst);
+                // 索引被截断或写满时没有结束标记，无法判断索引是否完整；索引项损坏时同样不可信
+                // 这两种情况都清空当前索引，改为从数据文件的记录头推导
+                let intact = match &self.index_map {
+                    Some(index_map_lock) => {
+                        let data_len = self.data_len.load(Ordering::SeqCst);
+                        index_entries_consistent(&index_map_lock.read().await.contents()?, data_len, false)
+                    }
+                    None => false,
+                };
+                if marker_found && !intact {
+                    warn!("Index of segment {} is corrupt, rebuilding from data file", last_offset);
+                    if let Some(index_map_lock) = &self.index_map {
+                        index_map_lock.write().await.clear()?;
+                    }
+                }
+                if !marker_found || !intact {
+                    warn!(
+                        "Index of segment {} has no end marker, recovering position from data file",
+                        last_offset
+                    );
+                    self.position_offset.store(last_offset, Ordering::SeqCst);
+                    self.indexed_len.store(0, Ordering::SeqCst);
+                    self.indexed_offset.store(last_offset, Ordering::SeqCst);
+                }
+                // 数据文件尾部可能有尚未建立索引的记录（归档模式，或写入数据后索引尚未写入时崩溃）
+                self.catch_up_index().await?;
+                self.recover_truncate().await?;
+                debug!(
+                    "Loaded active segment {} of {:?}: {} records",
+                    last_offset,
+                    self.data_dir,
+                    self.position_offset.load(Ordering::SeqCst) - last_offset
+                );
             }
         }
 
@@ -203,18 +406,22 @@ impl DataStorage {
     }
 
     async fn create_new_files(&mut self, offset: u64) -> io::Result<()> {
-        // 创建数据文件
-        let data_file = self.open_data_file(offset,false).await?;
+        // 创建数据文件，已存在的文件从末尾继续写入
+        let mut data_file = self.open_data_file(offset,false).await?;
+        data_file.seek(SeekFrom::End(0))?;
         // 采用 offset 作为文件名创建索引文件
         let (index_file, map) = self.create_index_file(offset).await?;
         // 设置 storage 各个字段
         let data_len = data_file.metadata()?.len();
         self.data_len.swap(data_len, Ordering::SeqCst);
-        let index_len = index_file.metadata()?.len();
-        self.index_len.swap(index_len, Ordering::SeqCst);
+        self.indexed_len.store(0, Ordering::SeqCst);
+        self.indexed_offset.store(offset, Ordering::SeqCst);
+        self.index_len.store(index_file.metadata()?.len(), Ordering::SeqCst);
 
         self.data_file = Some(RwLock::new(data_file));
         self.index_file = Some(RwLock::new(index_file));
+        let released = self.active_index_map_len().await;
+        self.governor.adjust(released, map.resident_bytes());
         self.index_map = Some(RwLock::new(map));
         Ok(())
     }
@@ -228,53 +435,102 @@ impl DataStorage {
             .read(true)
             .write(true)
             .create(true) // Do not create a new file; only open an existing one
+            .truncate(false)
             .open(&path)?
         };
         Ok(file)
     }
 
-    async fn open_index_file(&self, offset: u64) -> io::Result<(File, MmapMut)> {
-        let path = self.data_dir.join(format!("{:012}.index", offset));
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true) // Do not create a new file; only open an existing one
-            .open(&path)?;
+    async fn active_index_map_len(&self) -> u64 {
+        match &self.index_map {
+            Some(index_map_lock) => index_map_lock.read().await.resident_bytes(),
+            None => 0,
+        }
+    }
 
-        let mmap = unsafe { MmapMut::map_mut(&file)? };
-        Ok((file, mmap))
+    fn index_path(&self, offset: u64) -> PathBuf {
+        self.data_dir.join(format!("{:012}.index", offset))
     }
 
-    async fn create_index_file(&self, offset: u64) -> io::Result<(File, MmapMut)> {
+    async fn create_index_file(&self, offset: u64) -> io::Result<(File, Box<dyn IndexAccess>)> {
         let path = self.data_dir.join(format!("{:012}.index", offset));
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)?;
-        file.set_len(INITIAL_INDEX_SIZE as u64)?; // Preallocate initial space
-        let mmap = unsafe { MmapMut::map_mut(&file)? };
-        Ok((file, mmap))
+        file.set_len(self.initial_index_size())?; // Preallocate initial space
+        let index = open_index(&path, true, self.use_mmap)?;
+        Ok((file, index))
     }
 
+    // 新索引文件的长度：配置了记录数上限时正好容纳该数量的索引项和结束标记，不再扩展
+    fn initial_index_size(&self) -> u64 {
+        match self.max_records {
+            Some(max_records) => (max_records + 1) * INDEX_ENTRY_SIZE as u64,
+            None => INITIAL_INDEX_SIZE as u64,
+        }
+    }
+
+    // 当前数据文件还能写入的记录数，未配置记录数上限时不限制
+    fn records_left(&self) -> u64 {
+        let records = self.position_offset.load(Ordering::SeqCst) - self.base_offset.load(Ordering::SeqCst);
+        self.max_records.map_or(u64::MAX, |max_records| max_records.saturating_sub(records))
+    }
+
+    // 扩展索引文件并重新映射（映射失败时改为直接读写文件），失败时恢复原来的文件长度，返回 StorageFull 错误
     async fn expand_index_file(&mut self, new_size: u64) -> io::Result<()> {
-        self.set_index_len(new_size).await?;
+        #[cfg(test)]
+        if self.fail_index_expansion {
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "index expansion failed: injected failure",
+            ));
+        }
+        let old_size = self.index_len.load(Ordering::SeqCst);
+        if let Err(e) = self.set_index_len(new_size).await {
+            let _ = self.set_index_len(old_size).await;
+            return Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!("index expansion failed: {}", e),
+            ));
+        }
         if let Some(index_file_lock) = &self.index_file {
             let file = index_file_lock.read().await;
-            let mmap = unsafe { MmapMut::map_mut(&*file)? };
-            self.index_map = Some(RwLock::new(mmap));
+            let base_offset = self.base_offset.load(Ordering::SeqCst);
+            let index = match open_index(&self.index_path(base_offset), true, self.use_mmap) {
+                Ok(index) => index,
+                Err(e) => {
+                    let _ = file.set_len(old_size);
+                    return Err(io::Error::new(
+                        io::ErrorKind::StorageFull,
+                        format!("index remap failed: {}", e),
+                    ));
+                }
+            };
+            drop(file);
+            let released = self.active_index_map_len().await;
+            self.governor.adjust(released, index.resident_bytes());
+            self.index_map = Some(RwLock::new(index));
+            self.index_len.store(new_size, Ordering::SeqCst);
             Ok(())
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Appropriate data file not set",
-            ))
+            Err(StorageError::IndexNotFound.into())
         }
     }
-    // 将消息写入文件中并建立索引
-    pub async fn append_data(&mut self, data: &[u8]) -> io::Result<()> {
+    // 当前数据文件放不下 len 字节的消息，或记录数已达到上限时切换到新文件。
+    // 超过 max_file_size 的消息写入空文件时不切换，否则新文件同样放不下，只会留下空的历史文件
+    async fn roll_if_full(&mut self, len: u64) -> io::Result<()> {
+        let data_len = self.data_len.load(Ordering::SeqCst);
         // 超过阈值创立新文件
-        if self.data_len.load(Ordering::SeqCst) + data.len() as u64 > self.max_file_size as u64 {
+        if (data_len > 0 && data_len + len > self.max_file_size as u64) || self.records_left() == 0 {
+            // 归档模式下先补全当前文件的索引，再将其作为历史文件
+            if self.archive {
+                self.catch_up_index().await?;
+            }
+            // 封存前把数据和索引落盘，封存的文件随后可能被备份或清理
+            self.sync_active_segment().await?;
             let position = self.position_offset.load(Ordering::SeqCst);
             self.create_new_files(position).await?;
             // 因为创建了新文件，把当前文件重新只读打开放入历史文件列表
@@ -284,171 +540,2035 @@ impl DataStorage {
             }
             let base_offset = self.base_offset.swap(position, Ordering::SeqCst);
             let data_file = self.open_data_file(base_offset,true).await?;
-            let (_, map) = self.open_index_file(base_offset).await?;
+            let index = self.governor.register(self.index_path(base_offset), self.use_mmap)?;
             files.push(FileEntry {
-                base_offset: base_offset,
-                data_file: data_file,
-                data: map,
+                base_offset,
+                data_file,
+                index,
             });
-            
         }
-        
-        let base_offset = self.base_offset.load(Ordering::SeqCst);
+        Ok(())
+    }
+
+    // 把当前数据文件和索引（内存映射中的修改以及文件长度）同步到磁盘
+    async fn sync_active_segment(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        #[cfg(test)]
+        self.synced_segments.push(self.base_offset.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    // 把当前文件的数据和索引写入磁盘并保存当前位置，用于切换文件、定期落盘和服务关闭
+    pub async fn flush(&self) -> io::Result<()> {
+        self.sync_files().await?;
+        self.save_position()
+    }
+
+    // 保存当前文件的 base_offset 和 position_offset，重启时不必扫描索引寻找结束标记。
+    // 先写临时文件再改名，中途崩溃时保留上一次保存的内容
+    pub fn save_position(&self) -> io::Result<()> {
+        let mut contents = Vec::with_capacity(16);
+        contents.extend_from_slice(&self.base_offset.load(Ordering::SeqCst).to_be_bytes());
+        contents.extend_from_slice(&self.position_offset.load(Ordering::SeqCst).to_be_bytes());
+        let temp = self.data_dir.join(format!("{}.tmp", POSITION_FILE));
+        std::fs::write(&temp, &contents)?;
+        std::fs::rename(&temp, self.data_dir.join(POSITION_FILE))
+    }
+
+    // position.meta 中保存的位置对应的结束标记在当前索引中的字节位置。文件缺失、属于其他文件，
+    // 或者该位置不是索引中第一个空索引项（保存之后又有写入、索引被截断或重建）时返回 None
+    async fn saved_marker(&self, base_offset: u64, index_len: u64) -> Result<Option<u64>, StorageError> {
+        let Ok(contents) = std::fs::read(self.data_dir.join(POSITION_FILE)) else {
+            return Ok(None);
+        };
+        let Ok(contents) = <[u8; 16]>::try_from(contents) else {
+            return Ok(None);
+        };
+        let saved_base = u64::from_be_bytes(contents[..8].try_into().unwrap());
+        let saved_position = u64::from_be_bytes(contents[8..].try_into().unwrap());
+        if saved_base != base_offset || saved_position < base_offset {
+            return Ok(None);
+        }
+        let index = (saved_position - base_offset).saturating_mul(INDEX_ENTRY_SIZE as u64);
+        if index >= index_len {
+            return Ok(None);
+        }
+        let is_marker = |entry: IndexEntry| entry.start == 0 && entry.size == 0;
+        if !is_marker(self.read_index(index as usize).await?) {
+            return Ok(None);
+        }
+        if index > 0 && is_marker(self.read_index(index as usize - INDEX_ENTRY_SIZE).await?) {
+            return Ok(None);
+        }
+        Ok(Some(index))
+    }
+
+    async fn sync_files(&self) -> io::Result<()> {
+        if let Some(data_file_lock) = &self.data_file {
+            data_file_lock.read().await.sync_all()?;
+        }
+        if let Some(index_map_lock) = &self.index_map {
+            index_map_lock.read().await.flush()?;
+        }
+        if let Some(index_file_lock) = &self.index_file {
+            index_file_lock.read().await.sync_all()?;
+        }
+        Ok(())
+    }
+
+    // fsync = "always" 时在写入返回（即回复客户端）之前落盘；失败时返回错误，客户端不会收到 OK
+    async fn sync_appended(&mut self) -> io::Result<()> {
+        if self.fsync_always {
+            self.sync_files().await?;
+            #[cfg(test)]
+            {
+                self.append_syncs += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // 将消息写入文件中并建立索引，返回分配给该消息的偏移量
+    pub async fn append_data(&mut self, data: &[u8]) -> Result<u64, StorageError> {
+        self.roll_if_full(data.len() as u64).await?;
         let position = self.position_offset.load(Ordering::SeqCst);
-        let new_size = (position + 2 - base_offset) * (INDEX_ENTRY_SIZE as u64);
-        let old_size = self.get_index_len().await?;
-        // 新增的索引项超过索引文件的长度，需要扩展索引文件
-        if new_size > old_size {
-            self.expand_index_file(old_size + INDEX_EXPANSION_SIZE as u64)
-                .await?;
-            self.index_len
-                .swap(INDEX_EXPANSION_SIZE as u64, Ordering::SeqCst);
+        // 扩展必须在写入数据之前完成，失败时直接拒绝本次写入，不留下孤立的数据
+        if !self.archive {
+            self.reserve_index(position).await?;
         }
 
         if let Some(data_file_lock) = &self.data_file {
             let start = self.get_data_len().await?;
             let mut data_file = data_file_lock.write().await; // 获取读锁
-            let mut header = [0u8; 12];
+            // 对齐模式下数据文件开头先写入填充，使第一条记录的数据部分对齐
+            let start = if start == 0 && self.align > 1 {
+                let pad = leading_pad(self.align);
+                if let Err(e) = data_file.write_all(&vec![0u8; pad as usize]) {
+                    let _ = data_file.set_len(0);
+                    let _ = data_file.seek(SeekFrom::Start(0));
+                    return Err(e.into());
+                }
+                self.data_len.fetch_add(pad, Ordering::SeqCst);
+                pad
+            } else {
+                start
+            };
+            let pad = trailing_pad(data.len() as u64, self.align) as usize;
+            let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + data.len() + pad);
             let data_len = data.len() as u32;
-            header[0..4].copy_from_slice(&data_len.to_be_bytes());
-            header[4..12].copy_from_slice(&position.to_be_bytes());
-            // 写入记录头和数据
-            data_file.write_all(&header)?;
-            data_file.write_all(data)?;
-            let end = header.len() as u32 + data.len() as u32;
+            record.extend_from_slice(&data_len.to_be_bytes());
+            record.extend_from_slice(&position.to_be_bytes());
+            record.extend_from_slice(data);
+            // 记录末尾补齐，使下一条记录的数据部分对齐，索引项的长度包含填充
+            record.resize(record.len() + pad, 0);
+            // 写入记录头和数据，写入失败时截断回写入前的长度
+            if let Err(e) = data_file.write_all(&record) {
+                let _ = data_file.set_len(start);
+                let _ = data_file.seek(SeekFrom::Start(start));
+                return Err(e.into());
+            }
+            drop(data_file);
+            let end = record.len() as u32;
             self.data_len
-                .fetch_add(header.len() as u64 + data.len() as u64, Ordering::SeqCst);
-            if let Some(index_map_lock) = &self.index_map {
-                // 将记录位置写入索引
-                let mut index_map = index_map_lock.write().await;
-                let entry_start = (position - base_offset) as usize * INDEX_ENTRY_SIZE;
-                (&mut index_map[entry_start..entry_start + 8usize])
-                    .copy_from_slice(&start.to_be_bytes());
-                (&mut index_map[entry_start + 8usize..entry_start + 12usize])
-                    .copy_from_slice(&end.to_be_bytes());
-                // 在最新索引项后面加入0，以便重启的时候设置position_offset
-                (&mut index_map[entry_start + 12usize..entry_start + 20usize])
-                    .copy_from_slice(&0u64.to_be_bytes());
-                (&mut index_map[entry_start + 20usize..entry_start + 24usize])
-                    .copy_from_slice(&0u32.to_be_bytes());
-                self.position_offset.fetch_add(1, Ordering::SeqCst);
-                Ok(())
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Appropriate index file not set",
-                ))
+                .fetch_add(record.len() as u64, Ordering::SeqCst);
+            if !self.archive {
+                self.write_index_entry(position, start, end).await?;
             }
+            self.position_offset.fetch_add(1, Ordering::SeqCst);
+            self.sync_appended().await?;
+            Ok(position)
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                "Appropriate data file not set",
-            ))
+            Err(StorageError::DataFileMissing)
         }
     }
-    
-    // 在当前或者历史文件定位数据并通过sendfile发送
-    pub async fn sendfile<S>(&self, since_offset: u64, sock_fd: S) -> io::Result<usize>
-    where
-        S: AsFd + Clone,
-    {
-        // Find the correct index file by range
+
+    // 按顺序写入多条消息，返回写入的数量。同一个数据文件中的消息合并为一次写入，索引空间一次预留；
+    // 后面的消息写入失败时保留已写入的消息并返回较小的数量，只有一条都没有写入时才返回错误
+    pub async fn append_batch(&mut self, records: &[Vec<u8>]) -> io::Result<usize> {
+        let mut appended = 0;
+        while appended < records.len() {
+            match self.append_group(&records[appended..]).await {
+                Ok(count) => appended += count,
+                Err(e) if appended == 0 => return Err(e),
+                Err(e) => {
+                    warn!("Batch append stopped after {} of {} records: {}", appended, records.len(), e);
+                    break;
+                }
+            }
+        }
+        // 整批写入后落盘一次，中途切换文件时封存的文件已经落盘
+        self.sync_appended().await?;
+        Ok(appended)
+    }
+
+    // 写入当前数据文件能容纳的前若干条消息（至少一条，必要时先切换文件），全部成功或全部不写入
+    async fn append_group(&mut self, records: &[Vec<u8>]) -> io::Result<usize> {
+        self.roll_if_full(records[0].len() as u64).await?;
+        let first = self.position_offset.load(Ordering::SeqCst);
+        let records_left = self.records_left();
+        let start = self.get_data_len().await?;
+        // 对齐模式下数据文件开头先写入填充
+        let mut end = if start == 0 && self.align > 1 { leading_pad(self.align) } else { start };
+        let mut buffer = vec![0u8; (end - start) as usize];
+        let mut entries = Vec::new();
+        for data in records {
+            // 与 append_data 相同的切换条件，放不下的消息留给下一个文件
+            if !entries.is_empty() && (end + data.len() as u64 > self.max_file_size as u64 || entries.len() as u64 >= records_left) {
+                break;
+            }
+            let position = first + entries.len() as u64;
+            let pad = trailing_pad(data.len() as u64, self.align) as usize;
+            buffer.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(&position.to_be_bytes());
+            buffer.extend_from_slice(data);
+            buffer.resize(buffer.len() + pad, 0);
+            let size = (RECORD_HEADER_SIZE + data.len() + pad) as u32;
+            entries.push((position, end, size));
+            end += size as u64;
+        }
+
+        // 索引空间在写入数据之前一次预留到最后一条消息
+        if !self.archive {
+            self.reserve_index(first + entries.len() as u64 - 1).await?;
+        }
+
+        if let Some(data_file_lock) = &self.data_file {
+            let mut data_file = data_file_lock.write().await;
+            if let Err(e) = data_file.write_all(&buffer) {
+                let _ = data_file.set_len(start);
+                let _ = data_file.seek(SeekFrom::Start(start));
+                return Err(e);
+            }
+        } else {
+            return Err(StorageError::DataFileMissing.into());
+        }
+        self.data_len.fetch_add(buffer.len() as u64, Ordering::SeqCst);
+        if !self.archive {
+            for &(position, start, size) in &entries {
+                self.write_index_entry(position, start, size).await?;
+            }
+        }
+        self.position_offset.fetch_add(entries.len() as u64, Ordering::SeqCst);
+        Ok(entries.len())
+    }
+
+    // 确保索引文件能容纳 position 的索引项及其后的结束标记，不够时扩展索引文件
+    async fn reserve_index(&mut self, position: u64) -> io::Result<()> {
         let base_offset = self.base_offset.load(Ordering::SeqCst);
-        let position = self.position_offset.load(Ordering::SeqCst);
-        let offset = if since_offset == 0 && position>0 {
-            position-1
+        // 索引项和其后的结束标记都必须落在索引文件内，偏移异常时拒绝写入而不是溢出
+        let new_size = position
+            .checked_sub(base_offset)
+            .and_then(|entries| entries.checked_add(2))
+            .and_then(|entries| entries.checked_mul(INDEX_ENTRY_SIZE as u64))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("offset {} cannot be indexed in segment {}", position, base_offset),
+                )
+            })?;
+        let old_size = self.index_len.load(Ordering::SeqCst);
+        // 新增的索引项超过索引文件的长度，按扩展段的整数倍一次扩展到足够的长度
+        if new_size > old_size {
+            let step = INDEX_EXPANSION_SIZE as u64;
+            self.expand_index_file(old_size + (new_size - old_size).div_ceil(step) * step)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // 将记录位置写入索引，调用前需要通过 reserve_index 确保索引空间足够
+    async fn write_index_entry(&self, position: u64, start: u64, size: u32) -> io::Result<()> {
+        let base_offset = self.base_offset.load(Ordering::SeqCst);
+        if let Some(index_map_lock) = &self.index_map {
+            let entry_start = (position - base_offset) as usize * INDEX_ENTRY_SIZE;
+            // 在最新索引项后面加入0，以便重启的时候设置position_offset
+            index_map_lock.write().await.write_entry(entry_start, start, size)?;
+            self.indexed_len
+                .store(start + size as u64, Ordering::SeqCst);
+            self.indexed_offset.store(position + 1, Ordering::SeqCst);
+            Ok(())
         } else {
-            since_offset
+            Err(StorageError::IndexNotFound.into())
+        }
+    }
+
+    // 归档模式下当前数据文件是否有尚未建立索引的记录，只读共享的原子量，调用方持有读锁即可
+    pub fn needs_index_catch_up(&self) -> bool {
+        self.archive
+            && self.indexed_len.load(Ordering::SeqCst).max(leading_pad(self.align)) < self.data_len.load(Ordering::SeqCst)
+    }
+
+    // 扫描当前数据文件中尚未建立索引的记录头，补建索引并更新 position_offset
+    pub async fn catch_up_index(&mut self) -> Result<(), StorageError> {
+        let data_len = self.data_len.load(Ordering::SeqCst);
+        let mut start = self.indexed_len.load(Ordering::SeqCst);
+        if start == 0 {
+            start = leading_pad(self.align);
+        }
+        while start + RECORD_HEADER_SIZE as u64 <= data_len {
+            let mut header = [0u8; RECORD_HEADER_SIZE];
+            if let Some(data_file_lock) = &self.data_file {
+                read_exact_at(&*data_file_lock.read().await, &mut header, start)?;
+            }
+            let len = (&header[0..4]).read_u32::<BigEndian>()? as u64;
+            let size = (RECORD_HEADER_SIZE as u64 + len + trailing_pad(len, self.align)) as u32;
+            let position = (&header[4..12]).read_u64::<BigEndian>()?;
+            if start + size as u64 > data_len {
+                break;
+            }
+            // 记录头损坏时偏移不连续，停在这里，不按损坏的偏移写入索引
+            let expected = self.indexed_offset.load(Ordering::SeqCst);
+            if position != expected {
+                warn!(
+                    "Record header at byte {} of segment {} has offset {}, expected {}; indexing stopped",
+                    start,
+                    self.base_offset.load(Ordering::SeqCst),
+                    position,
+                    expected
+                );
+                break;
+            }
+            self.reserve_index(position).await?;
+            self.write_index_entry(position, start, size).await?;
+            self.position_offset.store(position + 1, Ordering::SeqCst);
+            start += size as u64;
+        }
+        Ok(())
+    }
+
+    // 崩溃时写了一半的记录留在当前数据文件末尾：截断到最后一条完整记录的结尾。
+    // 否则之后追加的记录接在残缺的字节后面，重启扫描记录头时会把残缺的记录当作完整记录
+    async fn recover_truncate(&mut self) -> Result<(), StorageError> {
+        let records = self.position_offset.load(Ordering::SeqCst) - self.base_offset.load(Ordering::SeqCst);
+        let valid_len = if records == 0 { 0 } else { self.indexed_len.load(Ordering::SeqCst) };
+        let data_len = self.data_len.load(Ordering::SeqCst);
+        if data_len <= valid_len {
+            return Ok(());
+        }
+        warn!(
+            "Truncating {} bytes of partial record at the end of segment {}",
+            data_len - valid_len,
+            self.base_offset.load(Ordering::SeqCst)
+        );
+        if let Some(data_file_lock) = &self.data_file {
+            let mut data_file = data_file_lock.write().await;
+            data_file.set_len(valid_len)?;
+            data_file.seek(SeekFrom::Start(valid_len))?;
+            data_file.sync_all()?;
+        }
+        self.data_len.store(valid_len, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // 已封存文件的索引存在，且索引项首尾相接地覆盖整个数据文件
+    fn sealed_index_intact(&self, offset: u64, data_file: &File) -> io::Result<bool> {
+        let index = match open_index(&self.index_path(offset), false, self.use_mmap) {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
         };
-        // 在当前文件中
-        if offset >= base_offset && position > offset {
-            let index_position = (offset - base_offset) as usize * INDEX_ENTRY_SIZE;
-            let index_entry = self.read_index(index_position).await?;
-           
-            let len = self.data_len.load(Ordering::SeqCst);
-            // 不大于阈值的情况下，尽量多的返回记录
-            let size = if len - index_entry.start > self.pull_max_limit as u64 {
-                index_entry.size as usize
-            } else {
-                (len - index_entry.start) as usize
-            };
+        Ok(index_entries_consistent(&index.contents()?, data_file.metadata()?.len(), true))
+    }
 
-            if let Some(data_file_locked) = &self.data_file {
-                let data_file = data_file_locked.read().await;
-                let in_fd = data_file.as_fd();
-                // 发送当前文件的数据
-                let _size = call_sendfile(sock_fd,in_fd, index_entry.start, size);
-                Ok(size - _size)
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Appropriate data file not set",
-                ));
+    // 丢弃现有索引，从数据文件的记录头重建所有已打开文件的索引，返回重建的记录数
+    pub async fn rebuild_index(&mut self) -> io::Result<u64> {
+        let mut records = 0;
+        let mut files = self.files.write().await;
+        for entry in files.iter_mut() {
+            records += rebuild_segment_index(&self.data_dir, entry.base_offset, self.align)?;
+            // 重建的索引是新文件，旧的映射失效，重新注册
+            entry.index = self.governor.register(self.index_path(entry.base_offset), self.use_mmap)?;
+        }
+        drop(files);
+        // 当前文件的索引在原映射上清零后重新扫描
+        if let Some(index_map_lock) = &self.index_map {
+            index_map_lock.write().await.clear()?;
+        }
+        let base_offset = self.base_offset.load(Ordering::SeqCst);
+        self.position_offset.store(base_offset, Ordering::SeqCst);
+        self.indexed_len.store(0, Ordering::SeqCst);
+        self.indexed_offset.store(base_offset, Ordering::SeqCst);
+        self.catch_up_index().await?;
+        records += self.position_offset.load(Ordering::SeqCst) - base_offset;
+        Ok(records)
+    }
+
+    // 磁盘上所有文件（含未加载的历史文件）的 base_offset，最后一个是当前文件
+    async fn segment_offsets(&self) -> io::Result<Vec<u64>> {
+        let (_, mut offsets) = self.sealed_segments().await?;
+        offsets.push(self.base_offset.load(Ordering::SeqCst));
+        Ok(offsets)
+    }
+
+    // 按偏移顺序读取磁盘上所有文件中的记录交给 f 处理，读取期间被清理的文件跳过
+    pub async fn for_each_record<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(u64, Vec<u8>) -> io::Result<()>,
+    {
+        for base_offset in self.segment_offsets().await? {
+            let Some(data_file) = self.open_segment_data(base_offset)? else {
+                continue;
+            };
+            for (position, start, size) in scan_records(&data_file, base_offset, self.align)? {
+                f(position, read_record_at(&data_file, start, size)?)?;
             }
-        } else {
-            let mut selected_file = None;
-            let guard = self.files.read().await;
-            let len = guard.len();
-            // 在历史文件中定位索引项
-            if len > 0 {
-                let mut pre = 0;
-                for i in 0..len {
-                    let base = guard[i].base_offset;
-                    if offset < base && offset > guard[pre].base_offset {
-                        selected_file = Some(&guard[pre]);
-                        break;
+        }
+        Ok(())
+    }
+
+    // 把 superseded 之外的记录按原来的顺序写入数据目录下的 .compacting，偏移从 0 重新编号，
+    // 落盘后返回写入的记录数。原有文件保持不变，由 commit_compaction 替换；失败时删除 .compacting
+    pub async fn write_compacted(&self, config: &Storage, broker: &BrokerOverride, superseded: &HashSet<u64>) -> io::Result<u64> {
+        let staging = self.data_dir.join(COMPACTING_DIR);
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir(&staging)?;
+        let result = async {
+            let mut compacted = DataStorage::with_governor(staging.clone(), config, broker, self.governor.clone()).await?;
+            for base_offset in self.segment_offsets().await? {
+                let Some(data_file) = self.open_segment_data(base_offset)? else {
+                    continue;
+                };
+                let mut records = vec![];
+                for (position, start, size) in scan_records(&data_file, base_offset, self.align)? {
+                    if !superseded.contains(&position) {
+                        records.push(read_record_at(&data_file, start, size)?);
                     }
-                    pre = i
                 }
-                if selected_file.is_none() {
-                    if offset < base_offset && offset >= guard[len - 1].base_offset {
-                        selected_file = Some(&guard[len - 1]);
+                if !records.is_empty() {
+                    let appended = compacted.append_batch(&records).await?;
+                    if appended < records.len() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::StorageFull,
+                            format!("compaction wrote {} of {} records of segment {}", appended, records.len(), base_offset),
+                        ));
                     }
                 }
             }
-            // 找到匹配的索引文件获取索引项并根据索引项发送数据
-            if let Some(entry) = selected_file {
-                let index_position = (offset - entry.base_offset) as usize * INDEX_ENTRY_SIZE;
-                let start =
-                    (&entry.data[index_position..index_position + 8]).read_u64::<BigEndian>()?;
-                let end = start + (&entry.data[index_position + 8..index_position + 12])
-                    .read_u32::<BigEndian>()? as u64;
-                let len = entry.data_file.metadata()?.len();
-                let size = if len - start > self.pull_max_limit as u64 {
-                    (end - start) as usize
-                } else {
-                    (len - start) as usize
-                };
-                let in_fd = entry.data_file.as_fd();
-                let _size = call_sendfile(sock_fd,in_fd, start, size);
-                Ok(size - _size)
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "index file not match",
-                ));
-            }
+            compacted.flush().await?;
+            Ok(compacted.next_offset())
         }
+        .await;
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&staging);
+        }
+        result
     }
-}
-// 调用 linux 函数 sendfile 零拷贝发送数据
-fn call_sendfile<S>(sock_fd: S, in_fd: BorrowedFd<'_>, start: u64, size: usize) -> usize where S: AsFd + Clone {
-    let mut _size = size;
-    let mut _start = start as i64;
-    loop {
-        match sendfile(sock_fd.clone(), in_fd, Some(&mut _start), _size) {
-            Ok(sent_count) => {
-                if sent_count == 0 {
-                    break;
-                }
-                _size = _size - sent_count
+
+    // 只读打开一个文件的数据文件，文件已被清理时返回 None
+    fn open_segment_data(&self, base_offset: u64) -> io::Result<Option<File>> {
+        match File::open(self.data_dir.join(format!("{:012}.data", base_offset))) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // 该偏移的 PULL 是否由当前文件提供，偏移已经由 resolve_offset 解析
+    pub fn is_active(&self, offset: u64) -> bool {
+        offset >= self.base_offset.load(Ordering::SeqCst)
+    }
+
+    pub fn pull_max_limit(&self) -> usize {
+        self.pull_max_limit
+    }
+
+    // RELOAD_CONFIG 后使用新的文件大小、PULL 上限和历史文件数量，下一次写入或 PULL 生效
+    pub fn apply_limits(&mut self, config: &Storage) -> io::Result<()> {
+        let invalid_size = |name: &str, e: String| io::Error::new(io::ErrorKind::InvalidInput, format!("storage.{}: {}", name, e));
+        self.max_file_size = parse_size(&config.max_file_size).map_err(|e| invalid_size("max_file_size", e))?;
+        self.pull_max_limit = parse_size(&config.pull_max_limit).map_err(|e| invalid_size("pull_max_limit", e))?;
+        self.cache_limit = config.cache_limit;
+        Ok(())
+    }
+
+    // 下一条消息将被分配的偏移
+    pub fn next_offset(&self) -> u64 {
+        self.position_offset.load(Ordering::SeqCst)
+    }
+
+    // 仍可读取的第一条记录的偏移：最早的历史文件的 base_offset，没有历史文件时为当前文件的 base_offset
+    pub async fn first_offset(&self) -> u64 {
+        let active = self.base_offset.load(Ordering::SeqCst);
+        self.files.read().await.iter().map(|entry| entry.base_offset).min().unwrap_or(active).min(active)
+    }
+
+    // PULL 的起始偏移：EARLIEST（0）是仍然保存的最早一条记录，LATEST 是最新的一条记录，其他偏移不变
+    pub async fn resolve_offset(&self, offset: u64) -> u64 {
+        match offset {
+            EARLIEST => self.first_offset().await,
+            LATEST => self.next_offset().saturating_sub(1),
+            offset => offset,
+        }
+    }
+
+    pub fn active_base_offset(&self) -> u64 {
+        self.base_offset.load(Ordering::SeqCst)
+    }
+
+    // 与存储共享的当前位置，随写入更新，读取时不需要存储所在的锁
+    pub fn position(&self) -> StorePosition {
+        StorePosition {
+            base_offset: self.base_offset.clone(),
+            position_offset: self.position_offset.clone(),
+            data_len: self.data_len.clone(),
+        }
+    }
+
+    // 替换 previous 时接管它共享出去的位置：把本存储的位置写入 previous 的原子变量并改用它们，
+    // 之前通过 position() 取得的 StorePosition 继续反映替换后的存储
+    pub fn share_position(&mut self, previous: &DataStorage) {
+        for (own, shared) in [
+            (&mut self.base_offset, &previous.base_offset),
+            (&mut self.position_offset, &previous.position_offset),
+            (&mut self.data_len, &previous.data_len),
+        ] {
+            shared.store(own.load(Ordering::SeqCst), Ordering::SeqCst);
+            *own = shared.clone();
+        }
+    }
+
+    // 返回数据目录以及所有已封存的历史文件的 base_offset（不含当前文件）
+    pub async fn sealed_segments(&self) -> io::Result<(PathBuf, Vec<u64>)> {
+        let offsets = sealed_segments_in(&self.data_dir, self.base_offset.load(Ordering::SeqCst))?;
+        Ok((self.data_dir.clone(), offsets))
+    }
+
+    // 从历史文件列表中移除已被清理的文件，之后的读取不再使用它们
+    pub async fn forget_segments(&self, removed: &[u64]) {
+        self.files.write().await.retain(|entry| !removed.contains(&entry.base_offset));
+    }
+
+    // 每个文件（含当前文件）在磁盘上的数据文件、索引文件大小和已使用的索引项数，按 base_offset 排序
+    pub async fn segment_stats(&self) -> io::Result<Vec<SegmentStats>> {
+        let (_, mut offsets) = self.sealed_segments().await?;
+        let active = self.base_offset.load(Ordering::SeqCst);
+        offsets.push(active);
+        let mut stats = Vec::with_capacity(offsets.len());
+        for base_offset in offsets {
+            let data_path = self.data_dir.join(format!("{:012}.data", base_offset));
+            let index_path = self.index_path(base_offset);
+            // 索引文件可能缺失（例如归档模式尚未补建），此时按 0 统计
+            let (index_bytes, index_entries) = match std::fs::metadata(&index_path) {
+                Ok(metadata) => (metadata.len(), count_index_entries(&index_path)?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (0, 0),
+                Err(e) => return Err(e),
+            };
+            stats.push(SegmentStats {
+                base_offset,
+                data_bytes: std::fs::metadata(&data_path)?.len(),
+                index_bytes,
+                index_entries,
+                active: base_offset == active,
+            });
+        }
+        Ok(stats)
+    }
+
+    // 通过普通读取返回指定偏移的单条记录（不含记录头），偏移不存在时返回 None
+    pub async fn read_record(&self, offset: u64) -> io::Result<Option<Vec<u8>>> {
+        let base_offset = self.base_offset.load(Ordering::SeqCst);
+        let position = self.position_offset.load(Ordering::SeqCst);
+        if offset >= position {
+            return Ok(None);
+        }
+        if offset >= base_offset {
+            let index_entry = self
+                .read_index((offset - base_offset) as usize * INDEX_ENTRY_SIZE)
+                .await?;
+            if let Some(data_file_lock) = &self.data_file {
+                let data_file = data_file_lock.read().await;
+                return read_record_at(&data_file, index_entry.start, index_entry.size).map(Some);
             }
-            Err(e) => {
-                if e == Errno::EAGAIN {
-                    continue;
-                }
+            return Err(StorageError::DataFileMissing.into());
+        }
+        let guard = self.files.read().await;
+        // 历史文件中 base_offset 不大于 offset 的最大者即为目标文件
+        let entry = guard
+            .iter()
+            .filter(|entry| entry.base_offset <= offset)
+            .max_by_key(|entry| entry.base_offset);
+        if let Some(entry) = entry {
+            let index_position = (offset - entry.base_offset) as usize * INDEX_ENTRY_SIZE;
+            match entry.index.read_entry(index_position)? {
+                Some((start, size)) => read_record_at(&entry.data_file, start, size).map(Some),
+                None => Ok(None),
             }
-        };
+        } else {
+            Ok(None)
+        }
+    }
+
+    // 当前数据文件最后 n 个字节的范围 (start, size)，起点不早于文件开头，可能落在记录中间
+    pub fn tail_range(&self, n: u64) -> (u64, usize) {
+        let len = self.data_len.load(Ordering::SeqCst);
+        let start = len.saturating_sub(n);
+        (start, (len - start) as usize)
+    }
+
+    // 当前数据文件的另一个句柄，用于在释放 broker 的锁之后发送其中的原始字节
+    pub async fn active_file(&self) -> io::Result<File> {
+        match &self.data_file {
+            Some(data_file_locked) => data_file_locked.read().await.try_clone(),
+            None => Err(StorageError::DataFileMissing.into()),
+        }
+    }
+
+    // 在当前或者历史文件定位数据并通过sendfile发送
+    // 从 since_offset 开始（EARLIEST、LATEST 按 resolve_offset 解析）沿索引累计最多 max_count 条连续记录，总字节数不超过
+    // max_bytes（至少一条），不跨越文件；该偏移没有记录时返回 None
+    pub async fn locate_records(&self, since_offset: u64, max_count: u32, max_bytes: usize) -> io::Result<Option<RecordRange>> {
+        let base_offset = self.base_offset.load(Ordering::SeqCst);
+        let position = self.position_offset.load(Ordering::SeqCst);
+        let offset = self.resolve_offset(since_offset).await;
+        if offset >= position {
+            return Ok(None);
+        }
+        let mut range: Option<RecordRange> = None;
+        let files = self.files.read().await;
+        let (segment, history) = if offset >= base_offset {
+            (base_offset, None)
+        } else {
+            // 包含 offset 的历史文件：base_offset 不大于 offset 的最后一个文件
+            match files.iter().filter(|entry| entry.base_offset <= offset).max_by_key(|entry| entry.base_offset) {
+                Some(entry) => (entry.base_offset, Some(entry)),
+                None => return Ok(None),
+            }
+        };
+        for next in offset..offset.saturating_add(max_count as u64) {
+            let index_position = (next - segment) as usize * INDEX_ENTRY_SIZE;
+            let (start, size) = match history {
+                Some(entry) => match entry.index.read_entry(index_position)? {
+                    Some(found) => found,
+                    None => break,
+                },
+                None if next < position => {
+                    let entry = self.read_index(index_position).await?;
+                    // 全 0 的结束标记表示该偏移尚未建立索引（归档模式补建之前），不能当作文件开头的记录发送
+                    if entry.start == 0 && entry.size == 0 {
+                        break;
+                    }
+                    (entry.start, entry.size)
+                }
+                None => break,
+            };
+            match range.as_mut() {
+                Some(range) => {
+                    let size = (start + size as u64 - range.start) as usize;
+                    if size > max_bytes {
+                        break;
+                    }
+                    range.size = size;
+                    range.count += 1;
+                }
+                None => {
+                    range = Some(RecordRange { segment, first: offset, start, size: size as usize, count: 1 });
+                }
+            }
+        }
+        Ok(range)
+    }
+
+    // 复制 locate_records 找到的记录所在文件的句柄，调用方释放 broker 的锁之后再通过它发送，
+    // 慢消费者不会阻塞写入；之后文件被清理也不影响已经复制的句柄
+    pub async fn range_file(&self, range: &RecordRange) -> io::Result<File> {
+        if range.segment == self.base_offset.load(Ordering::SeqCst) {
+            if let Some(data_file_lock) = &self.data_file {
+                return data_file_lock.read().await.try_clone();
+            }
+        } else if let Some(entry) = self.files.read().await.iter().find(|entry| entry.base_offset == range.segment) {
+            return entry.data_file.try_clone();
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("segment {} is no longer open", range.segment),
+        ))
+    }
+
+    // 按偏移顺序导出历史文件和当前文件中的所有记录，每条一行 JSON：{"offset":N,"len":M,"payload":"<base64>"}。
+    // 记录按保存时的原样导出（含校验和、时间戳等前缀），使用缓冲读取而不是 sendfile，返回导出的记录数
+    pub async fn export<W: Write>(&self, writer: W) -> io::Result<u64> {
+        self.export_with(writer, Ok).await
+    }
+
+    // 与 export 相同，每条记录先经过 decode 再写出，len 是解码后的长度
+    pub async fn export_with<W, F>(&self, mut writer: W, decode: F) -> io::Result<u64>
+    where
+        W: Write,
+        F: Fn(Vec<u8>) -> io::Result<Vec<u8>>,
+    {
+        use base64::Engine;
+        let mut exported = 0;
+        for offset in self.first_offset().await..self.next_offset() {
+            // 已被清理的记录跳过
+            let Some(stored) = self.read_record(offset).await? else {
+                continue;
+            };
+            let record = decode(stored)?;
+            let line = ExportedRecord {
+                offset,
+                len: record.len(),
+                payload: base64::engine::general_purpose::STANDARD.encode(&record),
+            };
+            serde_json::to_writer(&mut writer, &line)?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+
+    // PULL 要发送的记录：locate_records 找到的范围，偏移不在已打开的文件中时返回 OffsetOutOfRange
+    pub async fn pull_range(&self, since_offset: u64, max_count: u32, max_bytes: usize) -> Result<RecordRange, StorageError> {
+        if let Some(range) = self.locate_records(since_offset, max_count, max_bytes).await? {
+            return Ok(range);
+        }
+        let position = self.position_offset.load(Ordering::SeqCst);
+        let offset = self.resolve_offset(since_offset).await;
+        Err(StorageError::OffsetOutOfRange { offset, next: position })
+    }
+}
+impl Drop for DataStorage {
+    fn drop(&mut self) {
+        if let Some(index_map_lock) = self.index_map.take() {
+            self.governor
+                .adjust(index_map_lock.into_inner().resident_bytes(), 0);
+        }
+    }
+}
+
+// export 输出的一行
+#[derive(serde::Serialize)]
+struct ExportedRecord {
+    offset: u64,
+    len: usize,
+    payload: String, // base64 编码的记录
+}
+
+// 在线校验的结果：校验过的文件数、记录数以及发现的问题
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub segments: u32,
+    pub records: u64,
+    pub errors: Vec<String>,
+}
+
+// 校验已封存的历史文件：索引项连续、记录头与索引一致、数据文件没有未索引的尾部数据
+pub fn verify_segments(data_dir: &Path, base_offsets: &[u64]) -> io::Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    for &base_offset in base_offsets {
+        let data_path = data_dir.join(format!("{:012}.data", base_offset));
+        let index_path = data_dir.join(format!("{:012}.index", base_offset));
+        let opened = File::open(&data_path).and_then(|data_file| Ok((data_file, open_index(&index_path, false, true)?)));
+        let (data_file, index) = match opened {
+            Ok(opened) => opened,
+            // 校验过程中被清理掉的文件直接跳过
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let index = index.contents()?;
+        let data_len = data_file.metadata()?.len();
+        report.segments += 1;
+
+        let mut expected_start = 0u64;
+        for (i, entry) in index.chunks_exact(INDEX_ENTRY_SIZE).enumerate() {
+            let start = (&entry[0..8]).read_u64::<BigEndian>()?;
+            let size = (&entry[8..12]).read_u32::<BigEndian>()?;
+            if start == 0 && size == 0 {
+                break;
+            }
+            let offset = base_offset + i as u64;
+            // 对齐模式下第一条记录之前有填充
+            if i == 0 {
+                expected_start = start;
+            }
+            if start != expected_start {
+                report.errors.push(format!(
+                    "segment {}: offset {} starts at {}, expected {}",
+                    base_offset, offset, start, expected_start
+                ));
+            }
+            if start + size as u64 > data_len || (size as usize) < RECORD_HEADER_SIZE {
+                report.errors.push(format!(
+                    "segment {}: offset {} range {}+{} exceeds data length {}",
+                    base_offset, offset, start, size, data_len
+                ));
+                break;
+            }
+            let mut header = [0u8; RECORD_HEADER_SIZE];
+            read_exact_at(&data_file, &mut header, start)?;
+            let record_len = (&header[0..4]).read_u32::<BigEndian>()?;
+            let record_offset = (&header[4..12]).read_u64::<BigEndian>()?;
+            if record_len as usize + RECORD_HEADER_SIZE > size as usize || record_offset != offset {
+                report.errors.push(format!(
+                    "segment {}: offset {} header mismatch (len {}, offset {})",
+                    base_offset, offset, record_len, record_offset
+                ));
+            }
+            report.records += 1;
+            expected_start = start + size as u64;
+        }
+        if expected_start != data_len {
+            report.errors.push(format!(
+                "segment {}: {} bytes of data not covered by the index",
+                base_offset,
+                data_len.saturating_sub(expected_start)
+            ));
+        }
+    }
+    Ok(report)
+}
+
+// 读取 start 处长度为 size 的记录（含12字节记录头），返回去掉记录头的数据
+// 从数据文件的记录头扫描出所有完整的记录，返回 (偏移, 起始位置, 含填充的长度)；
+// 偏移必须从 base_offset 开始连续，遇到不连续的记录头（损坏）时停止
+fn scan_records(data_file: &File, base_offset: u64, align: u64) -> io::Result<Vec<(u64, u64, u32)>> {
+    let data_len = data_file.metadata()?.len();
+    let mut records = vec![];
+    let mut start = leading_pad(align);
+    while start + RECORD_HEADER_SIZE as u64 <= data_len {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        read_exact_at(data_file, &mut header, start)?;
+        let len = (&header[0..4]).read_u32::<BigEndian>()? as u64;
+        let position = (&header[4..12]).read_u64::<BigEndian>()?;
+        let size = RECORD_HEADER_SIZE as u64 + len + trailing_pad(len, align);
+        if start + size > data_len {
+            break;
+        }
+        let expected = base_offset + records.len() as u64;
+        if position != expected {
+            warn!(
+                "Record header at byte {} of segment {} has offset {}, expected {}; ignoring the rest of the file",
+                start,
+                base_offset,
+                position,
+                expected
+            );
+            break;
+        }
+        records.push((position, start, size as u32));
+        start += size;
+    }
+    Ok(records)
+}
+
+// 检查索引项是否首尾相接且不超出数据文件；sealed 为 true 时还要求覆盖到数据文件末尾
+fn index_entries_consistent(index: &[u8], data_len: u64, sealed: bool) -> bool {
+    let mut end = None;
+    for entry in index.chunks_exact(INDEX_ENTRY_SIZE) {
+        let start = u64::from_be_bytes(entry[0..8].try_into().unwrap());
+        let size = u32::from_be_bytes(entry[8..12].try_into().unwrap()) as u64;
+        if start == 0 && size == 0 {
+            break;
+        }
+        if size < RECORD_HEADER_SIZE as u64 || start.saturating_add(size) > data_len {
+            return false;
+        }
+        // 第一项之前可能有对齐填充
+        if end.is_some_and(|end| start != end) || (end.is_none() && start > MAX_RECORD_ALIGN) {
+            return false;
+        }
+        end = Some(start + size);
+    }
+    match end {
+        Some(end) => !sealed || end == data_len,
+        None => !sealed || data_len <= MAX_RECORD_ALIGN,
+    }
+}
+
+// 读取目录中的数据文件，返回排序后的基础偏移
+// 文件名不同但解析出相同基础偏移的数据文件（如崩溃时留下的未补零文件名）只保留最大的一个，
+// 其余改名为 .dup 留待人工检查；严格模式下直接报错
+fn collect_segments(data_dir: &Path, strict: bool) -> io::Result<Vec<u64>> {
+    let mut segments: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // 目录中读取文件名作为历史文件的 base_offset
+        if path.extension().and_then(|s| s.to_str()) == Some("data") {
+            if let Some(offset) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                segments.entry(offset).or_default().push(path);
+            } else {
+                warn!("Ignoring data file {:?}: name is not a segment offset", path);
+            }
+        }
+    }
+    let mut offsets = Vec::with_capacity(segments.len());
+    quarantine_unpaired(data_dir, &mut segments, strict)?;
+    for (offset, mut paths) in segments {
+        let canonical = data_dir.join(format!("{:012}.data", offset));
+        if paths.len() > 1 {
+            let message = format!("Segment {} has duplicate data files: {:?}", offset, paths);
+            if strict {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            warn!("{}", message);
+        }
+        // 保留数据最多的文件，大小相同时优先保留规范文件名
+        let mut sizes = Vec::with_capacity(paths.len());
+        for path in &paths {
+            sizes.push((std::fs::metadata(path)?.len(), *path == canonical));
+        }
+        let keep = (0..paths.len()).max_by_key(|&i| sizes[i]).unwrap();
+        let kept = paths.swap_remove(keep);
+        for path in paths {
+            let aside = path.with_extension("data.dup");
+            warn!("Moving duplicate data file {:?} to {:?}", path, aside);
+            std::fs::rename(&path, &aside)?;
+        }
+        if kept != canonical {
+            // 规范文件名的索引属于被移走的数据文件，一并移走，之后从数据文件重建
+            let index_path = data_dir.join(format!("{:012}.index", offset));
+            if index_path.exists() {
+                std::fs::rename(&index_path, index_path.with_extension("index.dup"))?;
+            }
+            warn!("Renaming data file {:?} to {:?}", kept, canonical);
+            std::fs::rename(&kept, &canonical)?;
+        }
+        offsets.push(offset);
+    }
+    Ok(offsets)
+}
+
+// data_dir 中 base_offset 小于当前文件 active 的数据文件，按偏移排序
+pub fn sealed_segments_in(data_dir: &Path, active: u64) -> io::Result<Vec<u64>> {
+    let mut offsets = vec![];
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|s| s.to_str()) == Some("data") {
+            if let Some(offset) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                if offset < active {
+                    offsets.push(offset);
+                }
+            }
+        }
+    }
+    offsets.sort();
+    Ok(offsets)
+}
+
+// 检查数据文件和索引文件是否成对：没有数据文件的索引，以及除最新文件外长度为 0 的数据文件（连同其索引）
+// 移到 corrupt/ 子目录，避免之后在同一偏移创建文件时沿用残留的索引；最新文件缺少索引时从数据文件恢复。
+// 清理历史文件时先删除数据文件，中途失败会留下偏移小于最新文件的索引，这样的索引不会再被使用，直接删除。
+// strict 模式下除此之外的情况都拒绝启动
+fn quarantine_unpaired(data_dir: &Path, segments: &mut HashMap<u64, Vec<PathBuf>>, strict: bool) -> io::Result<()> {
+    let refuse = |message: String| -> io::Result<()> {
+        if strict {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        warn!("{}", message);
+        Ok(())
+    };
+    let newest = segments.keys().max().copied();
+    let mut orphans = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|s| s.to_str()) != Some("index") {
+            continue;
+        }
+        let offset = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok());
+        if !offset.is_some_and(|offset| segments.contains_key(&offset)) {
+            if offset.zip(newest).is_some_and(|(offset, newest)| offset < newest) {
+                info!("Removing index file {:?} left behind by segment cleanup", path);
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            refuse(format!("Index file {:?} has no data file", path))?;
+            orphans.push(path);
+        }
+    }
+    let mut empty = Vec::new();
+    for (&offset, paths) in segments.iter() {
+        if Some(offset) == newest {
+            if !data_dir.join(format!("{:012}.index", offset)).exists() {
+                refuse(format!("Active segment {} has no index file, recovering it from the data file", offset))?;
+            }
+            continue;
+        }
+        let mut lengths = Vec::with_capacity(paths.len());
+        for path in paths {
+            lengths.push(std::fs::metadata(path)?.len());
+        }
+        if lengths.iter().all(|&len| len == 0) {
+            refuse(format!("Segment {} has an empty data file", offset))?;
+            empty.push(offset);
+        }
+    }
+    for offset in empty {
+        orphans.extend(segments.remove(&offset).unwrap_or_default());
+        let index_path = data_dir.join(format!("{:012}.index", offset));
+        if index_path.exists() {
+            orphans.push(index_path);
+        }
+    }
+    if orphans.is_empty() {
+        return Ok(());
+    }
+    let corrupt = data_dir.join(CORRUPT_DIR);
+    std::fs::create_dir_all(&corrupt)?;
+    for path in orphans {
+        let target = corrupt.join(path.file_name().unwrap_or_default());
+        warn!("Moving {:?} to {:?}", path, target);
+        std::fs::rename(&path, &target)?;
+    }
+    Ok(())
+}
+
+// 统计索引文件中结束标记之前的索引项数量
+fn count_index_entries(index_path: &Path) -> io::Result<u64> {
+    let index = std::fs::read(index_path)?;
+    let count = index
+        .chunks_exact(INDEX_ENTRY_SIZE)
+        .take_while(|entry| entry.iter().any(|&b| b != 0))
+        .count();
+    Ok(count as u64)
+}
+
+// 用 write_compacted 写好的文件替换数据目录中的数据和索引文件。先把 .compacting 改名为 .compacted，
+// 之后即使替换中断，下次打开存储时也会由 finish_compaction 完成替换，调用后需要重新打开存储
+pub fn commit_compaction(data_dir: &Path) -> io::Result<()> {
+    std::fs::rename(data_dir.join(COMPACTING_DIR), data_dir.join(COMPACTED_DIR))?;
+    finish_compaction(data_dir)
+}
+
+// 存在 .compacted 时用其中的文件替换原有的数据和索引文件；没有写完的 .compacting 直接丢弃。
+// 替换过程中 .compacted 保持完整，最后整体改名为 .compacting 再删除，中断后重复执行结果相同
+fn finish_compaction(data_dir: &Path) -> io::Result<()> {
+    let staging = data_dir.join(COMPACTING_DIR);
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    let compacted = data_dir.join(COMPACTED_DIR);
+    if !compacted.exists() {
+        return Ok(());
+    }
+    let mut names = HashSet::new();
+    for entry in std::fs::read_dir(&compacted)? {
+        names.insert(entry?.file_name());
+    }
+    // 删除压缩结果中没有的原有文件，同名文件在下面直接替换
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        let segment_file = path.is_file()
+            && matches!(path.extension().and_then(|s| s.to_str()), Some("data") | Some("index"))
+            && path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()).is_some();
+        if segment_file && !names.contains(path.file_name().unwrap_or_default()) {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    for name in &names {
+        let target = data_dir.join(name);
+        if target.exists() {
+            std::fs::remove_file(&target)?;
+        }
+        let source = compacted.join(name);
+        if std::fs::hard_link(&source, &target).is_err() {
+            std::fs::copy(&source, &target)?;
+        }
+    }
+    std::fs::rename(&compacted, &staging)?;
+    std::fs::remove_dir_all(&staging)
+}
+
+// 根据数据文件重写一个已封存文件的索引，先写临时文件再替换，返回记录数
+fn rebuild_segment_index(data_dir: &Path, base_offset: u64, align: u64) -> io::Result<u64> {
+    let data_file = File::open(data_dir.join(format!("{:012}.data", base_offset)))?;
+    let records = scan_records(&data_file, base_offset, align)?;
+    // 末尾保留一个全零的结束标记
+    let len = ((records.len() + 1) * INDEX_ENTRY_SIZE).max(INITIAL_INDEX_SIZE);
+    let mut index = vec![0u8; len];
+    for (position, start, size) in &records {
+        let entry_start = (position - base_offset) as usize * INDEX_ENTRY_SIZE;
+        if entry_start + 2 * INDEX_ENTRY_SIZE > index.len() {
+            index.resize(entry_start + 2 * INDEX_ENTRY_SIZE, 0);
+        }
+        index[entry_start..entry_start + 8].copy_from_slice(&start.to_be_bytes());
+        index[entry_start + 8..entry_start + 12].copy_from_slice(&size.to_be_bytes());
+    }
+    let index_path = data_dir.join(format!("{:012}.index", base_offset));
+    let tmp_path = index_path.with_extension("index.tmp");
+    std::fs::write(&tmp_path, &index)?;
+    std::fs::rename(&tmp_path, &index_path)?;
+    Ok(records.len() as u64)
+}
+
+fn read_record_at(file: &File, start: u64, size: u32) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; size as usize];
+    read_exact_at(file, &mut buf, start)?;
+    // 对齐模式下记录末尾带有填充，按记录头中的长度截取数据
+    let len = (&buf[0..4]).read_u32::<BigEndian>()? as usize;
+    let mut data = buf.split_off(RECORD_HEADER_SIZE);
+    data.truncate(len);
+    Ok(data)
+}
+
+// 对齐模式下数据文件开头的填充长度
+fn leading_pad(align: u64) -> u64 {
+    (align - RECORD_HEADER_SIZE as u64 % align) % align
+}
+
+// 对齐模式下长度为 len 的记录末尾的填充长度
+fn trailing_pad(len: u64, align: u64) -> u64 {
+    (align - (RECORD_HEADER_SIZE as u64 + len) % align) % align
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_storage_config() -> Storage {
+        Storage {
+            max_file_size: "100m".to_string(),
+            pull_max_limit: "1m".to_string(),
+            cache_limit: 10,
+            index_memory_limit: None,
+            max_buffered_response_bytes: None,
+            strict_recovery: None,
+            file_index: None,
+            retention: None,
+            max_records_per_file: None,
+            fsync: None,
+            fsync_interval_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_index_expansion_writes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &test_storage_config(), &BrokerOverride::default())
+            .await
+            .unwrap();
+        let capacity = (INITIAL_INDEX_SIZE / INDEX_ENTRY_SIZE) as u64;
+        // 写满初始索引，直到下一次写入需要扩展索引
+        for i in 0..capacity - 1 {
+            storage.append_data(format!("m{}", i).as_bytes()).await.unwrap();
+        }
+        let data_len = storage.data_len.load(Ordering::SeqCst);
+        let file_len = storage.get_data_len().await.unwrap();
+
+        storage.fail_index_expansion = true;
+        let err = storage.append_data(b"rejected").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        assert_eq!(storage.position_offset.load(Ordering::SeqCst), capacity - 1);
+        assert_eq!(storage.data_len.load(Ordering::SeqCst), data_len);
+        assert_eq!(storage.get_data_len().await.unwrap(), file_len);
+
+        storage.fail_index_expansion = false;
+        assert_eq!(storage.append_data(b"accepted").await.unwrap(), capacity - 1);
+        assert_eq!(
+            storage.read_record(capacity - 1).await.unwrap(),
+            Some(b"accepted".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_writes_records_in_offset_order() {
+        use base64::Engine;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default()).await.unwrap();
+        let payloads: Vec<Vec<u8>> = (0..30u8).map(|i| vec![i; 100]).collect();
+        for payload in &payloads {
+            storage.append_data(payload).await.unwrap();
+        }
+        assert!(!storage.sealed_segments().await.unwrap().1.is_empty());
+
+        let mut out = Vec::new();
+        assert_eq!(storage.export(&mut out).await.unwrap(), 30);
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), payloads.len());
+        for (offset, (line, payload)) in lines.iter().zip(&payloads).enumerate() {
+            assert_eq!(line["offset"], offset as u64);
+            assert_eq!(line["len"], 100);
+            let decoded = base64::engine::general_purpose::STANDARD.decode(line["payload"].as_str().unwrap()).unwrap();
+            assert_eq!(&decoded, payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_index_len_tracks_file_size_across_expansions() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &test_storage_config(), &BrokerOverride::default())
+            .await
+            .unwrap();
+        let on_disk = || std::fs::metadata(dir.path().join(format!("{:012}.index", 0))).unwrap().len();
+        let capacity = (INITIAL_INDEX_SIZE / INDEX_ENTRY_SIZE) as u64;
+        let records = capacity + 3 * (INDEX_EXPANSION_SIZE / INDEX_ENTRY_SIZE) as u64;
+        let mut expansions = 0;
+        for i in 0..records {
+            let before = storage.index_len.load(Ordering::SeqCst);
+            storage.append_data(&i.to_be_bytes()).await.unwrap();
+            let after = storage.index_len.load(Ordering::SeqCst);
+            if after != before {
+                assert_eq!(after, before + INDEX_EXPANSION_SIZE as u64);
+                expansions += 1;
+            }
+            assert_eq!(after, on_disk());
+        }
+        assert!(expansions >= 3);
+        drop(storage);
+
+        // 恢复时同样以磁盘上的文件长度为准
+        let storage = DataStorage::new(dir.path().to_path_buf(), &test_storage_config(), &BrokerOverride::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.index_len.load(Ordering::SeqCst), on_disk());
+        assert_eq!(storage.next_offset(), records);
+    }
+
+    #[tokio::test]
+    async fn test_position_restored_from_saved_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let (config, broker) = (test_storage_config(), BrokerOverride::default());
+        let open = || DataStorage::new(dir.path().to_path_buf(), &config, &broker);
+        let mut storage = open().await.unwrap();
+        for i in 0..20u64 {
+            storage.append_data(&i.to_be_bytes()).await.unwrap();
+        }
+        storage.flush().await.unwrap();
+        drop(storage);
+
+        // 保存的位置与索引一致，不扫描索引
+        let mut storage = open().await.unwrap();
+        assert!(storage.restored_position);
+        assert_eq!(storage.next_offset(), 20);
+        assert_eq!(storage.read_record(19).await.unwrap().unwrap(), 19u64.to_be_bytes());
+
+        // 保存之后又有写入，保存的位置已过时，回退到扫描索引
+        for i in 20..25u64 {
+            storage.append_data(&i.to_be_bytes()).await.unwrap();
+        }
+        drop(storage);
+        let mut storage = open().await.unwrap();
+        assert!(!storage.restored_position);
+        assert_eq!(storage.next_offset(), 25);
+        assert_eq!(storage.append_data(b"next").await.unwrap(), 25);
+        drop(storage);
+
+        // 文件缺失时同样扫描索引
+        std::fs::remove_file(dir.path().join(POSITION_FILE)).unwrap();
+        let storage = open().await.unwrap();
+        assert!(!storage.restored_position);
+        assert_eq!(storage.next_offset(), 26);
+    }
+
+    #[tokio::test]
+    async fn test_appends_across_index_boundary() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &test_storage_config(), &BrokerOverride::default())
+            .await
+            .unwrap();
+        let capacity = (INITIAL_INDEX_SIZE / INDEX_ENTRY_SIZE) as u64;
+        // 最后一个索引项之后没有放结束标记的空间，写到边界之前的记录不需要扩展
+        for i in 0..capacity + 2 {
+            assert_eq!(storage.append_data(&i.to_be_bytes()).await.unwrap(), i);
+            if i == capacity - 3 {
+                assert_eq!(storage.index_len.load(Ordering::SeqCst), INITIAL_INDEX_SIZE as u64);
+            }
+        }
+        // 一次写入需要扩展多于一个扩展段
+        let batch: Vec<Vec<u8>> = (capacity + 2..capacity + 1500).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(storage.append_batch(&batch).await.unwrap(), batch.len());
+        assert!(storage.index_len.load(Ordering::SeqCst) >= (capacity + 1501) * INDEX_ENTRY_SIZE as u64);
+        for i in (capacity - 3..capacity + 3).chain(capacity + 1495..capacity + 1500) {
+            assert_eq!(storage.read_record(i).await.unwrap(), Some(i.to_be_bytes().to_vec()));
+        }
+        drop(storage);
+
+        let storage = DataStorage::new(dir.path().to_path_buf(), &test_storage_config(), &BrokerOverride::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.next_offset(), capacity + 1500);
+        assert_eq!(storage.read_record(capacity + 1499).await.unwrap(), Some((capacity + 1499).to_be_bytes().to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_pull_range_out_of_range_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &test_storage_config(), &BrokerOverride::default())
+            .await
+            .unwrap();
+        // 空的 broker 没有最早的消息
+        assert!(matches!(
+            storage.pull_range(0, u32::MAX, storage.pull_max_limit()).await,
+            Err(StorageError::OffsetOutOfRange { offset: 0, next: 0 })
+        ));
+        for i in 0..3u64 {
+            storage.append_data(format!("m{}", i).as_bytes()).await.unwrap();
+        }
+        let range = storage.pull_range(2, u32::MAX, storage.pull_max_limit()).await.unwrap();
+        assert_eq!((range.first, range.count), (2, 1));
+        let Err(err) = storage.pull_range(7, u32::MAX, storage.pull_max_limit()).await else { panic!("offset 7 is past the end") };
+        assert!(matches!(err, StorageError::OffsetOutOfRange { offset: 7, next: 3 }));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_fsync_always_syncs_every_append_before_returning() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.fsync = Some("always".to_string());
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        for i in 0..3u64 {
+            assert_eq!(storage.append_data(format!("m{}", i).as_bytes()).await.unwrap(), i);
+        }
+        let batch = vec![b"b0".to_vec(), b"b1".to_vec()];
+        assert_eq!(storage.append_batch(&batch).await.unwrap(), 2);
+        // 每次写入和每个批次各落盘一次
+        assert_eq!(storage.append_syncs, 4);
+        // 不经过 flush 直接丢弃，模拟写入后进程退出
+        drop(storage);
+
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.next_offset(), 5);
+        let mut payloads = Vec::new();
+        storage
+            .for_each_record(|_, payload| {
+                payloads.push(payload);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(payloads, vec![&b"m0"[..], b"m1", b"m2", b"b0", b"b1"]);
+
+        // 默认策略不在写入时落盘，未知的策略拒绝打开
+        let other = tempfile::tempdir().unwrap();
+        let mut storage = DataStorage::new(other.path().to_path_buf(), &test_storage_config(), &BrokerOverride::default())
+            .await
+            .unwrap();
+        storage.append_data(b"m").await.unwrap();
+        assert_eq!(storage.append_syncs, 0);
+        config.fsync = Some("sometimes".to_string());
+        assert!(DataStorage::new(other.path().to_path_buf(), &config, &BrokerOverride::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_flush_then_reopen_recovers_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_storage_config();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        for i in 0..5u64 {
+            assert_eq!(storage.append_data(format!("m{}", i).as_bytes()).await.unwrap(), i);
+        }
+        storage.flush().await.unwrap();
+        drop(storage);
+
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.position_offset.load(Ordering::SeqCst), 5);
+        assert_eq!(storage.read_record(4).await.unwrap(), Some(b"m4".to_vec()));
+        assert_eq!(storage.append_data(b"m5").await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_partial_trailing_record_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_storage_config();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        for i in 0..3u8 {
+            storage.append_data(&[i; 100]).await.unwrap();
+        }
+        let complete_len = storage.indexed_len.load(Ordering::SeqCst) - (RECORD_HEADER_SIZE as u64 + 100);
+        drop(storage);
+
+        // 模拟崩溃：最后一条记录只写入了一部分，索引项却指向完整的长度
+        let data_path = dir.path().join(format!("{:012}.data", 0));
+        let file = OpenOptions::new().write(true).open(&data_path).unwrap();
+        file.set_len(complete_len + 40).unwrap();
+        drop(file);
+
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.position_offset.load(Ordering::SeqCst), 2);
+        assert_eq!(storage.data_len.load(Ordering::SeqCst), complete_len);
+        assert_eq!(std::fs::metadata(&data_path).unwrap().len(), complete_len);
+        assert_eq!(storage.read_record(2).await.unwrap(), None);
+
+        // 新记录接在最后一条完整记录后面，重启后仍然可以读取
+        assert_eq!(storage.append_data(b"after crash").await.unwrap(), 2);
+        drop(storage);
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        assert_eq!(storage.position_offset.load(Ordering::SeqCst), 3);
+        assert_eq!(storage.read_record(1).await.unwrap(), Some(vec![1; 100]));
+        assert_eq!(storage.read_record(2).await.unwrap(), Some(b"after crash".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_earliest_resolves_to_oldest_retained_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let broker_dir = dir.path().join("orders");
+        std::fs::create_dir(&broker_dir).unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let mut storage = DataStorage::new(broker_dir.clone(), &config, &BrokerOverride::default()).await.unwrap();
+        for i in 0..20u64 {
+            storage.append_data(&[i as u8; 100]).await.unwrap();
+        }
+        assert_eq!(storage.resolve_offset(EARLIEST).await, 0);
+        assert_eq!(storage.resolve_offset(LATEST).await, 19);
+        assert_eq!(storage.resolve_offset(7).await, 7);
+        drop(storage);
+
+        // 只保留当前文件（数据和索引两个文件）
+        crate::fileclear::delete_old_files(dir.path().to_str().unwrap(), 2).await.unwrap();
+        let storage = DataStorage::new(broker_dir, &config, &BrokerOverride::default()).await.unwrap();
+        let oldest = storage.active_base_offset();
+        assert!(oldest > 0);
+        assert_eq!(storage.resolve_offset(EARLIEST).await, oldest);
+        let range = storage.pull_range(EARLIEST, 1, storage.pull_max_limit()).await.unwrap();
+        assert_eq!(range.first, oldest);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_compaction_is_finished_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..20u64 {
+            storage.append_data(&[i as u8; 100]).await.unwrap();
+        }
+        let superseded: HashSet<u64> = (0..20).filter(|offset| offset % 2 == 0).collect();
+        assert_eq!(storage.write_compacted(&config, &broker, &superseded).await.unwrap(), 10);
+        // 替换之前原有文件不变
+        assert_eq!(storage.read_record(0).await.unwrap(), Some(vec![0; 100]));
+
+        // 模拟改名为 .compacted 之后、替换文件之前中断
+        std::fs::rename(dir.path().join(COMPACTING_DIR), dir.path().join(COMPACTED_DIR)).unwrap();
+        drop(storage);
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        assert!(!dir.path().join(COMPACTED_DIR).exists());
+        assert_eq!(storage.next_offset(), 10);
+        for i in 0..10u64 {
+            assert_eq!(storage.read_record(i).await.unwrap(), Some(vec![(2 * i + 1) as u8; 100]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_archive_mode_builds_index_lazily() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride {
+            archive: true,
+            ..Default::default()
+        };
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker)
+            .await
+            .unwrap();
+        for i in 0..10u64 {
+            assert_eq!(storage.append_data(&[i as u8; 100]).await.unwrap(), i);
+        }
+        // 当前文件写入时没有建立索引
+        assert!(storage.indexed_len.load(Ordering::SeqCst) < storage.data_len.load(Ordering::SeqCst));
+        assert!(storage.needs_index_catch_up());
+        storage.catch_up_index().await.unwrap();
+        assert!(!storage.needs_index_catch_up());
+        for i in 0..10u64 {
+            assert_eq!(storage.read_record(i).await.unwrap(), Some(vec![i as u8; 100]));
+        }
+        storage.append_data(b"tail").await.unwrap();
+        drop(storage);
+
+        // 重启后恢复尚未建立索引的记录
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker)
+            .await
+            .unwrap();
+        assert_eq!(storage.position_offset.load(Ordering::SeqCst), 11);
+        assert_eq!(storage.read_record(10).await.unwrap(), Some(b"tail".to_vec()));
+        assert_eq!(storage.append_data(b"next").await.unwrap(), 11);
+    }
+
+    #[tokio::test]
+    async fn test_locate_records_at_tail_returns_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let broker = BrokerOverride {
+            archive: true,
+            ..Default::default()
+        };
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &test_storage_config(), &broker)
+            .await
+            .unwrap();
+        for i in 0..3u8 {
+            storage.append_data(&[i; 100]).await.unwrap();
+        }
+        // 尚未建立索引的偏移读到全 0 的结束标记，不能从数据文件开头重新发送
+        assert!(storage.locate_records(0, u32::MAX, usize::MAX).await.unwrap().is_none());
+
+        storage.catch_up_index().await.unwrap();
+        let range = storage.locate_records(0, u32::MAX, usize::MAX).await.unwrap().unwrap();
+        assert_eq!((range.first, range.count, range.start), (0, 3, 0));
+        assert_eq!(range.size, 3 * (RECORD_HEADER_SIZE + 100));
+        // 正好在最后一条记录之后：没有数据，下一个偏移不变
+        assert!(storage.locate_records(3, u32::MAX, usize::MAX).await.unwrap().is_none());
+        assert!(matches!(
+            storage.pull_range(3, u32::MAX, usize::MAX).await,
+            Err(StorageError::OffsetOutOfRange { offset: 3, next: 3 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_sealed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        for i in 0..20u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        let (data_dir, offsets) = storage.sealed_segments().await.unwrap();
+        assert!(offsets.len() > 1);
+        let report = verify_segments(&data_dir, &offsets).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        assert_eq!(report.segments as usize, offsets.len());
+        assert_eq!(report.records, storage.base_offset.load(Ordering::SeqCst));
+
+        // 截断第一个历史数据文件后应该报告不一致
+        let data_path = data_dir.join(format!("{:012}.data", offsets[0]));
+        let file = OpenOptions::new().write(true).open(&data_path).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 10).unwrap();
+        let report = verify_segments(&data_dir, &offsets).unwrap();
+        assert!(!report.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_roll_syncs_outgoing_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        for i in 0..20u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        // 每个封存的文件都在切换时落盘，当前文件尚未封存
+        let (data_dir, offsets) = storage.sealed_segments().await.unwrap();
+        assert!(offsets.len() > 1);
+        assert_eq!(storage.synced_segments, offsets);
+        assert!(!storage.synced_segments.contains(&storage.active_base_offset()));
+        let report = verify_segments(&data_dir, &offsets).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+    }
+
+    #[tokio::test]
+    async fn test_roll_by_record_count_or_bytes() {
+        // 只由记录数触发切换：每个文件 5 条记录，索引文件正好容纳 5 个索引项和结束标记
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_records_per_file = Some(5);
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        for i in 0..7u8 {
+            storage.append_data(&[i; 10]).await.unwrap();
+        }
+        let batch: Vec<Vec<u8>> = (7..12u8).map(|i| vec![i; 10]).collect();
+        assert_eq!(storage.append_batch(&batch).await.unwrap(), 5);
+        let (data_dir, offsets) = storage.sealed_segments().await.unwrap();
+        assert_eq!(offsets, vec![0, 5]);
+        assert_eq!(storage.active_base_offset(), 10);
+        for base in [0, 5, 10] {
+            let index_len = std::fs::metadata(data_dir.join(format!("{:012}.index", base))).unwrap().len();
+            assert_eq!(index_len, 6 * INDEX_ENTRY_SIZE as u64);
+        }
+        assert!(verify_segments(&data_dir, &offsets).unwrap().errors.is_empty());
+        for i in 0..12u8 {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 10]));
+        }
+
+        // 记录数上限较大时仍按字节数切换
+        let dir = tempfile::tempdir().unwrap();
+        config.max_records_per_file = Some(1000);
+        config.max_file_size = "1k".to_string();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default())
+            .await
+            .unwrap();
+        for i in 0..10u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        let (_, offsets) = storage.sealed_segments().await.unwrap();
+        assert_eq!(offsets, vec![0, 4]);
+        assert_eq!(storage.active_base_offset(), 8);
+
+        config.max_records_per_file = Some(0);
+        assert!(DataStorage::new(dir.path().to_path_buf(), &config, &BrokerOverride::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_record_alignment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride {
+            align: Some(8),
+            ..Default::default()
+        };
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker)
+            .await
+            .unwrap();
+        let payloads: Vec<Vec<u8>> = (1..40u8).map(|i| vec![i; i as usize * 7]).collect();
+        for payload in &payloads {
+            storage.append_data(payload).await.unwrap();
+        }
+        let base_offset = storage.base_offset.load(Ordering::SeqCst);
+        let position = storage.position_offset.load(Ordering::SeqCst);
+        for offset in base_offset..position {
+            let entry = storage
+                .read_index((offset - base_offset) as usize * INDEX_ENTRY_SIZE)
+                .await
+                .unwrap();
+            assert_eq!((entry.start + RECORD_HEADER_SIZE as u64) % 8, 0);
+            assert_eq!(entry.size % 8, 0);
+        }
+        for (offset, payload) in payloads.iter().enumerate() {
+            assert_eq!(storage.read_record(offset as u64).await.unwrap().as_ref(), Some(payload));
+        }
+        let (data_dir, offsets) = storage.sealed_segments().await.unwrap();
+        let report = verify_segments(&data_dir, &offsets).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+        let broker = BrokerOverride {
+            align: Some(6),
+            ..Default::default()
+        };
+        assert!(DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_index_governor_evicts_across_brokers() {
+        let governor = IndexGovernor::new(0);
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let mut storages = vec![];
+        for dir in &dirs {
+            let mut storage = DataStorage::with_governor(
+                dir.path().to_path_buf(),
+                &config,
+                &BrokerOverride::default(),
+                governor.clone(),
+            )
+            .await
+            .unwrap();
+            for i in 0..20u8 {
+                storage.append_data(&[i; 200]).await.unwrap();
+            }
+            storages.push(storage);
+        }
+        let resident = governor.resident();
+        assert!(resident > 0);
+
+        // 限制为只能容纳两个当前索引加一个历史索引
+        let limit = 3 * INITIAL_INDEX_SIZE as u64;
+        governor.set_limit(limit);
+        assert!(governor.resident() <= limit);
+        assert!(governor.evictions() > 0);
+
+        // 被淘汰的历史索引在读取时重新映射
+        for storage in &storages {
+            for i in 0..20u8 {
+                assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+            }
+        }
+        assert!(governor.resident() <= limit);
+
+        drop(storages);
+        assert_eq!(governor.resident(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_file_index_matches_mmap_index() {
+        let mut dirs = vec![];
+        for file_index in [false, true] {
+            let governor = IndexGovernor::new(0);
+            let mut config = test_storage_config();
+            // 每个文件约 1000 条记录，超过初始索引容量，会扩展索引并切换文件
+            config.max_file_size = "16k".to_string();
+            config.file_index = Some(file_index);
+            let dir = tempfile::tempdir().unwrap();
+            let broker = BrokerOverride::default();
+            let mut storage = DataStorage::with_governor(dir.path().to_path_buf(), &config, &broker, governor.clone())
+                .await
+                .unwrap();
+            for i in 0..1500u32 {
+                storage.append_data(&i.to_be_bytes()).await.unwrap();
+            }
+            // 不使用内存映射时索引不占用映射内存
+            assert_eq!(governor.resident() == 0, file_index);
+            // 关闭时只释放计入统计的字节，不使用内存映射时统计不会回绕
+            drop(storage);
+            assert_eq!(governor.resident(), 0);
+
+            let mut storage = DataStorage::with_governor(dir.path().to_path_buf(), &config, &broker, governor)
+                .await
+                .unwrap();
+            for i in (0..1500u32).step_by(7) {
+                assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(i.to_be_bytes().to_vec()));
+            }
+            assert_eq!(storage.append_data(b"next").await.unwrap(), 1500);
+            dirs.push(dir);
+        }
+
+        // 两种方式写出的索引文件完全相同
+        let mut names: Vec<_> = std::fs::read_dir(dirs[0].path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        assert!(names.iter().filter(|name| name.to_string_lossy().ends_with(".index")).count() > 1);
+        for name in names {
+            let mmap = std::fs::read(dirs[0].path().join(&name)).unwrap();
+            let file = std::fs::read(dirs[1].path().join(&name)).unwrap();
+            assert!(mmap == file, "{:?} differs", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_index_from_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        // 删除全部索引文件，重启时从数据文件恢复
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|s| s.to_str()) == Some("index") {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+        }
+        assert_eq!(storage.append_data(b"next").await.unwrap(), 12);
+
+        assert_eq!(storage.rebuild_index().await.unwrap(), 13);
+        for i in 0..12u8 {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+        }
+        assert_eq!(storage.read_record(12).await.unwrap(), Some(b"next".to_vec()));
+        assert_eq!(storage.append_data(b"after").await.unwrap(), 13);
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_stops_at_corrupt_record_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        // 把文件 4 和当前文件 8 中第二条记录头里的偏移改成文件之前的偏移，并删除全部索引
+        let record_size = (RECORD_HEADER_SIZE + 200) as u64;
+        for segment in ["000000000004.data", "000000000008.data"] {
+            let mut file = OpenOptions::new().write(true).open(dir.path().join(segment)).unwrap();
+            file.seek(SeekFrom::Start(record_size + 4)).unwrap();
+            file.write_all(&0u64.to_be_bytes()).unwrap();
+        }
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|s| s.to_str()) == Some("index") {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in [0u8, 3, 4, 8] {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+        }
+        // 当前文件在损坏的记录处结束
+        assert_eq!(storage.next_offset(), 9);
+    }
+
+    #[tokio::test]
+    async fn test_recover_from_corrupt_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        // 破坏所有索引文件的第二个索引项
+        for entry in std::fs::read_dir(dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|s| s.to_str()) == Some("index") {
+                let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+                file.seek(SeekFrom::Start(INDEX_ENTRY_SIZE as u64)).unwrap();
+                file.write_all(&[0xff; 12]).unwrap();
+            }
+        }
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+        }
+        assert_eq!(storage.append_data(b"next").await.unwrap(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_recover_duplicate_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        // 模拟崩溃留下的同一基础偏移、文件名未补零且只写了一半的数据文件
+        let data = std::fs::read(dir.path().join("000000000004.data")).unwrap();
+        std::fs::write(dir.path().join("4.data"), &data[..100]).unwrap();
+
+        config.strict_recovery = Some(true);
+        let err = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        config.strict_recovery = None;
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+        }
+        assert!(!dir.path().join("4.data").exists());
+        assert!(dir.path().join("4.data.dup").exists());
+    }
+
+    #[tokio::test]
+    async fn test_recover_segment_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        // 中间的文件丢失，偏移 4..8 不再连续
+        std::fs::remove_file(dir.path().join("000000000004.data")).unwrap();
+        std::fs::remove_file(dir.path().join("000000000004.index")).unwrap();
+
+        config.strict_recovery = Some(true);
+        let err = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("gap in offsets 4..8"));
+
+        config.strict_recovery = None;
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in (0..4u8).chain(8..12) {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+        }
+        assert_eq!(storage.append_data(b"next").await.unwrap(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_recover_removes_index_left_by_cleanup() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        // 清理在删除数据文件之后、删除索引之前中断
+        std::fs::remove_file(dir.path().join("000000000000.data")).unwrap();
+        config.strict_recovery = Some(true);
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        assert!(!dir.path().join("000000000000.index").exists());
+        assert!(!dir.path().join(CORRUPT_DIR).exists());
+        assert_eq!(storage.first_offset().await, 4);
+        assert_eq!(storage.read_record(4).await.unwrap(), Some(vec![4; 200]));
+    }
+
+    #[tokio::test]
+    async fn test_recover_unpaired_segment_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        // 没有数据文件的索引（偏移 20 之后会被新文件使用），以及一个空的历史数据文件
+        std::fs::copy(dir.path().join("000000000004.index"), dir.path().join("000000000020.index")).unwrap();
+        std::fs::write(dir.path().join("000000000002.data"), b"").unwrap();
+        std::fs::write(dir.path().join("backup.data"), b"not a segment").unwrap();
+
+        config.strict_recovery = Some(true);
+        let err = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("has no data file"));
+
+        config.strict_recovery = None;
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..12u8 {
+            assert_eq!(storage.read_record(i as u64).await.unwrap(), Some(vec![i; 200]));
+        }
+        assert_eq!(storage.append_data(b"next").await.unwrap(), 12);
+        let corrupt = dir.path().join(CORRUPT_DIR);
+        assert!(corrupt.join("000000000020.index").exists());
+        assert!(corrupt.join("000000000002.data").exists());
+        assert!(!dir.path().join("000000000020.index").exists());
+        assert!(dir.path().join("backup.data").exists());
+        drop(storage);
+
+        // 当前文件缺少索引时 strict 模式拒绝启动，否则从数据文件恢复
+        std::fs::remove_file(dir.path().join("000000000008.index")).unwrap();
+        config.strict_recovery = Some(true);
+        let err = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.err().unwrap();
+        assert!(err.to_string().contains("Active segment 8 has no index file"));
+        config.strict_recovery = None;
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        assert_eq!(storage.next_offset(), 13);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_record_does_not_leave_empty_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        // 空文件直接容纳超大记录；之后的写入切换到新文件，超大记录再次写入新的空文件
+        assert_eq!(storage.append_data(&[1; 2000]).await.unwrap(), 0);
+        assert_eq!(storage.active_base_offset(), 0);
+        assert_eq!(storage.append_data(&[2; 10]).await.unwrap(), 1);
+        assert_eq!(storage.append_batch(&[vec![3; 2000], vec![4; 10]]).await.unwrap(), 2);
+        assert_eq!(storage.sealed_segments().await.unwrap().1, vec![0, 1, 2]);
+        assert_eq!(storage.active_base_offset(), 3);
+        drop(storage);
+
+        // 没有空的历史文件，严格恢复也能启动
+        config.strict_recovery = Some(true);
+        let storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for (offset, record) in [vec![1; 2000], vec![2; 10], vec![3; 2000], vec![4; 10]].into_iter().enumerate() {
+            assert_eq!(storage.read_record(offset as u64).await.unwrap(), Some(record));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_append_batch_matches_single_appends() {
+        // 跨文件的批量写入（带对齐），以及需要多次扩展索引的大批量写入
+        for (max_file_size, align, records) in [
+            ("1k", Some(64), (0..12u8).map(|i| vec![i; 200]).collect::<Vec<_>>()),
+            ("100m", None, (0..2000u32).map(|i| i.to_be_bytes().to_vec()).collect()),
+        ] {
+            let mut config = test_storage_config();
+            config.max_file_size = max_file_size.to_string();
+            let broker = BrokerOverride { align, ..Default::default() };
+            let single = tempfile::tempdir().unwrap();
+            let batch = tempfile::tempdir().unwrap();
+
+            let mut storage = DataStorage::new(single.path().to_path_buf(), &config, &broker).await.unwrap();
+            for record in &records {
+                storage.append_data(record).await.unwrap();
+            }
+            drop(storage);
+            let mut storage = DataStorage::new(batch.path().to_path_buf(), &config, &broker).await.unwrap();
+            assert_eq!(storage.append_batch(&records).await.unwrap(), records.len());
+            assert_eq!(storage.next_offset(), records.len() as u64);
+            drop(storage);
+
+            // 数据文件和索引项与逐条写入完全一致，重启后可以正常读取
+            let mut names: Vec<_> = std::fs::read_dir(single.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+            names.sort();
+            for name in names.iter().filter(|name| name.to_string_lossy().ends_with(".data")) {
+                let expected = std::fs::read(single.path().join(name)).unwrap();
+                assert_eq!(std::fs::read(batch.path().join(name)).unwrap(), expected, "{:?}", name);
+                let index = Path::new(name).with_extension("index");
+                assert_eq!(
+                    count_index_entries(&batch.path().join(&index)).unwrap(),
+                    count_index_entries(&single.path().join(&index)).unwrap()
+                );
+            }
+            let storage = DataStorage::new(batch.path().to_path_buf(), &config, &broker).await.unwrap();
+            assert_eq!(storage.next_offset(), records.len() as u64);
+            for (offset, record) in records.iter().enumerate() {
+                assert_eq!(storage.read_record(offset as u64).await.unwrap().as_ref(), Some(record));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recovery_reports_segment_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = test_storage_config();
+        config.max_file_size = "1k".to_string();
+        let broker = BrokerOverride::default();
+        let mut storage = DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        for i in 0..10u8 {
+            storage.append_data(&[i; 200]).await.unwrap();
+        }
+        drop(storage);
+
+        crate::events::init_test_logging();
+        let mut events = crate::events::subscribe();
+        DataStorage::new(dir.path().to_path_buf(), &config, &broker).await.unwrap();
+        let dir_name = format!("{:?}", dir.path());
+        let mut progress = Vec::new();
+        loop {
+            match events.try_recv() {
+                Ok(event) if event.level == tracing::Level::DEBUG && event.message.contains(&dir_name) => {
+                    progress.push(event.message);
+                }
+                Ok(_) | Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
+            }
+        }
+        assert_eq!(
+            progress,
+            vec![
+                format!("Loaded segment 4 of {}: 4 records", dir_name),
+                format!("Loaded segment 0 of {}: 4 records", dir_name),
+                format!("Loaded active segment 8 of {}: 2 records", dir_name),
+            ]
+        );
     }
-    _size
 }
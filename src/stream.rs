@@ -0,0 +1,74 @@
+//! Continuous consumption of a broker as an iterator.
+//!
+//! [`MessageStream`] pulls batches from its current offset, yields the records one by one and
+//! advances past each record it yields. Once it has caught up it polls again after a pause.
+//! The iterator never ends by itself. A failed request is yielded as an `Err` item, and the
+//! next call reconnects and retries from the same offset.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::thread;
+use std::time::Duration;
+
+use crate::{Client, FetchedMessage};
+
+pub(crate) const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Iterator over the records of a broker, created by [`Client::stream`]
+pub struct MessageStream<'a> {
+    client: &'a Client,
+    broker: String,
+    next: u64,
+    poll_interval: Duration,
+    buffered: VecDeque<FetchedMessage>,
+}
+
+impl<'a> MessageStream<'a> {
+    pub(crate) fn new(client: &'a Client, broker: &str, start_offset: u64) -> Self {
+        Self {
+            client,
+            broker: broker.to_string(),
+            next: start_offset,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    /// How long to wait before polling again once the stream has caught up; 500 ms by default
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Offset of the next record the stream fetches from the server
+    pub fn position(&self) -> u64 {
+        self.buffered.front().map_or(self.next, |(offset, _)| *offset)
+    }
+}
+
+impl Iterator for MessageStream<'_> {
+    type Item = Result<FetchedMessage, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.buffered.pop_front() {
+                return Some(Ok(record));
+            }
+            match self.client.fetch_with_next(&self.broker, self.next, None) {
+                Ok((batch, next_offset)) => {
+                    self.buffered.extend(batch);
+                    match self.buffered.back() {
+                        Some((last, _)) => self.next = last + 1,
+                        // 当前位置的记录已被清理，从服务端给出的偏移继续
+                        None if next_offset > self.next => self.next = next_offset,
+                        None => thread::sleep(self.poll_interval),
+                    }
+                }
+                Err(e) => {
+                    self.client.disconnect();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
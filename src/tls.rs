@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::server::TlsStream;
+
+use crate::zerocopy::{write_file_range, ZeroCopySend};
+
+// 接受的客户端连接：明文 TCP，或配置了 [tls] 时握手后的 TLS 连接。
+// Multiplexed 是多路复用连接中的一个请求，回复写入内存管道，由连接的写任务加上请求编号后发出
+pub enum ServerStream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+    Multiplexed(DuplexStream, SocketAddr),
+}
+
+impl ServerStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ServerStream::Plain(stream) => stream.peer_addr(),
+            #[cfg(feature = "tls")]
+            ServerStream::Tls(stream) => stream.get_ref().0.peer_addr(),
+            ServerStream::Multiplexed(_, peer) => Ok(*peer),
+        }
+    }
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            ServerStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            ServerStream::Multiplexed(stream, _) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            ServerStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            ServerStream::Multiplexed(stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            ServerStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            ServerStream::Multiplexed(stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            ServerStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            ServerStream::Multiplexed(stream, _) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+// TLS 在用户态加密，内存管道没有文件描述符，sendfile 都无法使用，读入缓冲区后写出
+impl ZeroCopySend for ServerStream {
+    async fn send_file_range(&mut self, file: &File, start: u64, size: usize) -> io::Result<usize> {
+        match self {
+            ServerStream::Plain(stream) => stream.send_file_range(file, start, size).await,
+            #[cfg(feature = "tls")]
+            ServerStream::Tls(stream) => write_file_range(stream, file, start, size).await,
+            ServerStream::Multiplexed(stream, _) => write_file_range(stream, file, start, size).await,
+        }
+    }
+}
+
+// 读取 [tls] 配置的证书链和私钥（PEM），启动时出错直接返回，不回退到明文
+#[cfg(feature = "tls")]
+pub fn acceptor(tls: &crate::config::Tls) -> io::Result<tokio_rustls::TlsAcceptor> {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let invalid = |path: &str, e: rustls::pki_types::pem::Error| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("cannot read {}: {}", path, e))
+    };
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(&tls.cert, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no certificate in {}", tls.cert)));
+    }
+    let key = PrivateKeyDer::from_pem_file(&tls.key).map_err(|e| invalid(&tls.key, e))?;
+    let server_config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -0,0 +1,153 @@
+//! The connection a [`Client`](crate::Client) talks to the server over: plain TCP, or TLS
+//! when the client is built with [`ClientBuilder::tls`](crate::ClientBuilder::tls).
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::error::Error;
+#[cfg(feature = "tls")]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use rustls::pki_types::pem::PemObject;
+#[cfg(feature = "tls")]
+use rustls::pki_types::{CertificateDer, ServerName};
+#[cfg(feature = "tls")]
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+/// Trusted CA certificates and the name the server's certificate must be issued for
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+#[cfg(feature = "tls")]
+impl TlsConfig {
+    /// Trusts the PEM certificates in `ca_file`, which may be the server's self-signed
+    /// certificate, and expects the server to present a certificate for `server_name`
+    pub fn new(ca_file: impl AsRef<Path>, server_name: &str) -> Result<Self, Box<dyn Error>> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca_file.as_ref())? {
+            roots.add(cert?)?;
+        }
+        if roots.is_empty() {
+            return Err(format!("no certificate in {}", ca_file.as_ref().display()).into());
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            config: Arc::new(config),
+            server_name: ServerName::try_from(server_name.to_string())?,
+        })
+    }
+}
+
+// 去掉 IPv6 地址的方括号，"[::1]" 与 "::1" 等价；主机名和 IPv4 地址不变
+pub(crate) fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host)
+}
+
+// 依次连接解析出的每个地址，返回第一个成功的连接；全部失败时返回最后一个错误
+pub(crate) fn connect_any<A: ToSocketAddrs>(addresses: A) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in addresses.to_socket_addrs()? {
+        match TcpStream::connect(address) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address resolved to no addresses")))
+}
+
+pub(crate) enum Transport {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Transport {
+    pub(crate) fn plain(stream: TcpStream) -> Self {
+        Transport::Plain(stream)
+    }
+
+    // 握手在第一次读写时进行，出错时与其他 I/O 错误一样丢弃连接
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls(stream: TcpStream, tls: &TlsConfig) -> io::Result<Self> {
+        let connection = ClientConnection::new(tls.config.clone(), tls.server_name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Transport::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Transport::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.get_ref(),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket().set_read_timeout(timeout)
+    }
+
+    pub(crate) fn shutdown(&self) -> io::Result<()> {
+        self.socket().shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{SocketAddr, TcpListener};
+
+    #[test]
+    fn test_connect_any_skips_unreachable_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        // 绑定后立即释放的端口上没有监听
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        // 模拟解析出多个地址的主机名，第一个地址无法连接
+        let resolved: [SocketAddr; 2] = [dead, live];
+        let stream = connect_any(&resolved[..]).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live);
+        assert!(connect_any(&[dead][..]).is_err());
+        assert!(connect_any(&[][..] as &[SocketAddr]).is_err());
+        assert_eq!(unbracket("[::1]"), "::1");
+        assert_eq!(unbracket("localhost"), "localhost");
+    }
+}
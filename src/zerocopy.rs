@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::future::Future;
+use std::io;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[cfg(target_os = "linux")]
+use nix::sys::sendfile::sendfile;
+#[cfg(target_os = "linux")]
+use std::os::fd::AsFd;
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+
+// 非 Linux 平台每次从文件读入缓冲区的字节数
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+// 把数据文件中的一段字节发送到客户端连接：Linux 上使用 sendfile 零拷贝，
+// 其他平台（macOS、Windows 等）的 sendfile 签名不同或不存在，读入缓冲区后写入套接字
+pub trait ZeroCopySend {
+    // 发送 file 中从 start 开始的 size 个字节，套接字暂时不可写时等待而不是空转，
+    // 返回实际发送的字节数（文件比预期短时少于 size），连接出错时返回错误
+    fn send_file_range(&mut self, file: &File, start: u64, size: usize) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+#[cfg(target_os = "linux")]
+impl ZeroCopySend for TcpStream {
+    async fn send_file_range(&mut self, file: &File, start: u64, size: usize) -> io::Result<usize> {
+        call_sendfile(self, file, start, size).await
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ZeroCopySend for TcpStream {
+    async fn send_file_range(&mut self, file: &File, start: u64, size: usize) -> io::Result<usize> {
+        copy_file_range(self, file, start, size).await
+    }
+}
+
+// 调用 linux 函数 sendfile 零拷贝发送数据；套接字发送缓冲区已满（EAGAIN）时等待 tokio 通知可写
+#[cfg(target_os = "linux")]
+async fn call_sendfile(socket: &TcpStream, file: &File, start: u64, size: usize) -> io::Result<usize> {
+    let mut offset = start as i64;
+    let mut sent = 0;
+    while sent < size {
+        socket.writable().await?;
+        // try_io 在 EAGAIN 时清除就绪状态，下一次 writable 会真正等待
+        match socket.try_io(Interest::WRITABLE, || {
+            sendfile(socket.as_fd(), file.as_fd(), Some(&mut offset), size - sent).map_err(io::Error::from)
+        }) {
+            // 已到文件末尾
+            Ok(0) => break,
+            Ok(n) => sent += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+// 用户态拷贝：分块读入缓冲区后写入套接字，套接字暂时不可写时等待，返回发送的字节数
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+async fn copy_file_range(socket: &TcpStream, file: &File, start: u64, size: usize) -> io::Result<usize> {
+    let mut buf = vec![0u8; COPY_CHUNK_SIZE.min(size)];
+    let mut sent = 0;
+    while sent < size {
+        let chunk = &mut buf[..COPY_CHUNK_SIZE.min(size - sent)];
+        read_exact_at(file, chunk, start + sent as u64)?;
+        let mut written = 0;
+        while written < chunk.len() {
+            socket.writable().await?;
+            match socket.try_write(&chunk[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        sent += chunk.len();
+    }
+    Ok(sent)
+}
+
+// 用户态拷贝到任意异步写入端，用于无法直接写套接字的连接（如 TLS、多路复用连接中的请求），返回发送的字节数
+pub async fn write_file_range<W>(writer: &mut W, file: &File, start: u64, size: usize) -> io::Result<usize>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    let mut buf = vec![0u8; COPY_CHUNK_SIZE.min(size)];
+    let mut sent = 0;
+    while sent < size {
+        let chunk = &mut buf[..COPY_CHUNK_SIZE.min(size - sent)];
+        read_exact_at(file, chunk, start + sent as u64)?;
+        writer.write_all(chunk).await?;
+        sent += chunk.len();
+    }
+    writer.flush().await?;
+    Ok(sent)
+}
+
+// 按位置读取，不移动文件游标，多个连接可以同时读取同一个文件
+#[cfg(unix)]
+pub fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+pub fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::io::AsyncReadExt;
+    use tokio::time::{sleep, Duration};
+
+    // 套接字对，以及一个每次只读少量数据并停顿的慢速读取端，迫使发送端多次遇到缓冲区已满
+    async fn send_to_slow_reader<F, Fut>(expected: usize, send: F) -> (io::Result<usize>, Vec<u8>)
+    where
+        F: FnOnce(TcpStream) -> Fut,
+        Fut: Future<Output = io::Result<usize>>,
+    {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buf = [0u8; 16 * 1024];
+            while received.len() < expected {
+                match server.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+                sleep(Duration::from_millis(1)).await;
+            }
+            received
+        });
+        let result = send(client).await;
+        (result, reader.await.unwrap())
+    }
+
+    fn test_file(len: usize) -> (File, Vec<u8>) {
+        let mut file = tempfile::tempfile().unwrap();
+        let content: Vec<u8> = (0..len as u32).map(|i| (i % 251) as u8).collect();
+        file.write_all(&content).unwrap();
+        (file, content)
+    }
+
+    #[tokio::test]
+    async fn test_send_file_range_waits_for_slow_reader() {
+        // 远大于套接字缓冲区，发送过程中必然出现部分发送和 EAGAIN
+        let (file, content) = test_file(4 * 1024 * 1024 + 10);
+        let size = content.len() - 10;
+        let (result, received) = send_to_slow_reader(size, |mut socket| async move {
+            socket.send_file_range(&file, 10, size).await
+        })
+        .await;
+        assert_eq!(result.unwrap(), size);
+        assert_eq!(received, &content[10..]);
+    }
+
+    #[tokio::test]
+    async fn test_copy_file_range_waits_for_slow_reader() {
+        let (file, content) = test_file(2 * 1024 * 1024);
+        let size = content.len() - 10;
+        let (result, received) = send_to_slow_reader(size, |socket| async move {
+            copy_file_range(&socket, &file, 10, size).await
+        })
+        .await;
+        assert_eq!(result.unwrap(), size);
+        assert_eq!(received, &content[10..]);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_closed_connection_fails() {
+        let (file, _) = test_file(4 * 1024 * 1024);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        drop(listener.accept().await.unwrap());
+        // 对端关闭后报告错误，而不是空转或返回错误的字节数
+        assert!(client.send_file_range(&file, 0, 4 * 1024 * 1024).await.is_err());
+        assert!(copy_file_range(&client, &file, 0, 4 * 1024 * 1024).await.is_err());
+    }
+}
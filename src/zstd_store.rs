@@ -0,0 +1,103 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+
+const DICTIONARY_FILE: &str = "zstd.dict";
+const DICTIONARY_ID: u32 = 1; // 每个 broker 只训练一个字典，0 表示不使用字典
+const DICTIONARY_MAX_SIZE: usize = 16 * 1024;
+const COMPRESSION_LEVEL: i32 = 3;
+
+// broker 的 zstd 静态压缩：记录压缩后保存，格式为 [字典编号: u32][zstd 数据]
+// 开启字典训练时，用前 samples 条记录训练字典并保存在 broker 目录，之后的记录使用字典压缩；
+// 训练前写入的记录仍按编号 0 不使用字典解压
+pub struct ZstdStore {
+    path: PathBuf,
+    dictionary: Option<Vec<u8>>,
+    samples: Option<Vec<Vec<u8>>>, // 等待训练的样本，None 表示不训练或已有字典
+    sample_target: usize,
+}
+
+impl ZstdStore {
+    pub fn open(dir: &Path, train: bool, sample_target: usize) -> io::Result<Self> {
+        let path = dir.join(DICTIONARY_FILE);
+        let dictionary = match fs::read(&path) {
+            Ok(dictionary) => Some(dictionary),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let samples = (train && dictionary.is_none()).then(Vec::new);
+        Ok(ZstdStore {
+            path,
+            dictionary,
+            samples,
+            sample_target: sample_target.max(1),
+        })
+    }
+
+    pub fn encode(&mut self, record: &[u8]) -> io::Result<Vec<u8>> {
+        let (id, compressed) = match &self.dictionary {
+            Some(dictionary) => {
+                let mut compressor = zstd::bulk::Compressor::with_dictionary(COMPRESSION_LEVEL, dictionary)?;
+                (DICTIONARY_ID, compressor.compress(record)?)
+            }
+            None => (0, zstd::bulk::compress(record, COMPRESSION_LEVEL)?),
+        };
+        if let Some(samples) = self.samples.as_mut() {
+            samples.push(record.to_vec());
+            if samples.len() >= self.sample_target {
+                self.train();
+            }
+        }
+        let mut stored = Vec::with_capacity(compressed.len() + 4);
+        stored.extend_from_slice(&id.to_be_bytes());
+        stored.extend_from_slice(&compressed);
+        Ok(stored)
+    }
+
+    pub fn decode(&self, stored: &[u8]) -> io::Result<Vec<u8>> {
+        if stored.len() < 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated zstd record"));
+        }
+        let id = u32::from_be_bytes(stored[..4].try_into().unwrap());
+        let mut record = Vec::new();
+        match (id, &self.dictionary) {
+            (0, _) => {
+                zstd::stream::Decoder::new(&stored[4..])?.read_to_end(&mut record)?;
+            }
+            (DICTIONARY_ID, Some(dictionary)) => {
+                zstd::stream::Decoder::with_dictionary(&stored[4..], dictionary)?.read_to_end(&mut record)?;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("record compressed with unknown dictionary {}", id),
+                ))
+            }
+        }
+        Ok(record)
+    }
+
+    // 训练并保存字典；样本不足以训练时丢弃这批样本，等下一批再试
+    fn train(&mut self) {
+        let samples = self.samples.take().unwrap_or_default();
+        let result = zstd::dict::from_samples(&samples, DICTIONARY_MAX_SIZE).and_then(|dictionary| {
+            // 先写临时文件再替换，崩溃时不会留下不完整的字典
+            let tmp = self.path.with_extension("dict.tmp");
+            fs::write(&tmp, &dictionary)?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(dictionary)
+        });
+        match result {
+            Ok(dictionary) => {
+                info!("Trained zstd dictionary {:?} ({} bytes) from {} records", self.path, dictionary.len(), samples.len());
+                self.dictionary = Some(dictionary);
+            }
+            Err(e) => {
+                warn!("Training zstd dictionary {:?} failed: {}", self.path, e);
+                self.samples = Some(Vec::new());
+            }
+        }
+    }
+}